@@ -43,22 +43,23 @@ def index_files_logcloud(file_paths: List[str], column_name: str, name = uuid.uu
     polars.concat([polars.read_parquet(f"{i}.maui") for i in range(num_groups)]).write_parquet(f"{name}.maui")
     rottnest.index_logcloud(name, num_groups, wavelet_tree = wavelet)
 
-def index_files_bm25(file_paths: list[str], column_name: str, name = uuid.uuid4().hex, index_mode = "physical", tokenizer_file = None):
+def index_files_bm25(file_paths: list[str], column_name: str, name = uuid.uuid4().hex, index_mode = "physical", tokenizer_file = None, positional = False):
 
     arr, uid, file_data = get_physical_layout(file_paths, column_name) if index_mode == "physical" else get_virtual_layout(file_paths, column_name, "uid")
 
-    cache_ranges = rottnest.build_lava_bm25(f"{name}.lava", arr, uid, tokenizer_file)
+    cache_ranges = rottnest.build_lava_bm25(f"{name}.lava", arr, uid, tokenizer_file, positional)
 
     # do not attempt to manually edit the metadata. It is Parquet, but it is Varsity Parquet to ensure performance.
     file_data = file_data.to_arrow()
     file_data = file_data.replace_schema_metadata({"cache_ranges": json.dumps(cache_ranges)})
     pq.write_table(file_data, f"{name}.meta", write_statistics = False, compression = 'zstd')
 
-def index_files_substring(file_paths: list[str], column_name: str, name = uuid.uuid4().hex, index_mode = "physical", tokenizer_file = None, token_skip_factor = None, remote = None, char_index = False):
+def index_files_substring(file_paths: list[str], column_name: str, name = uuid.uuid4().hex, index_mode = "physical", tokenizer_file = None, token_skip_factor = None, remote = None, char_index = False, normalization = None, positional = False, sa_sample_rate = None):
 
     arr, uid, file_data = get_physical_layout(file_paths, column_name, remote = remote) if index_mode == "physical" else get_virtual_layout(file_paths, column_name, "uid", remote = remote)
 
-    cache_ranges = rottnest.build_lava_substring(f"{name}.lava", arr, uid, tokenizer_file, token_skip_factor, char_index)
+    # normalization is a json policy, e.g. '{"lowercase": false, "skip": ""}' for case and punctuation sensitive search
+    cache_ranges = rottnest.build_lava_substring(f"{name}.lava", arr, uid, tokenizer_file, token_skip_factor, char_index, normalization, positional, sa_sample_rate)
 
     file_data = file_data.to_arrow()
     file_data = file_data.replace_schema_metadata({"cache_ranges": json.dumps(cache_ranges)})
@@ -202,6 +203,12 @@ def index_files_vector(file_paths: list[str], column_name: str, name = uuid.uuid
     pq.write_table(file_data, f"{name}.meta", write_statistics = False, compression = 'zstd')
 
 
+def embed_index(index_name: str, file_path: str):
+
+    # the index bytes go right before the Parquet footer, search_lava_* can then be pointed at file_path directly.
+    # the metadata file is still needed to map uids back to pages.
+    rottnest.embed_lava_in_parquet(f"{index_name}.lava", file_path)
+
 def merge_metadatas(index_names: List[str], suffix = "meta"):
     assert len(index_names) > 1
     metadatas = daft.table.read_parquet_into_pyarrow_bulk([f"{index_name}.{suffix}" for index_name in index_names], io_config = get_daft_io_config_from_file_path(index_names[0]))
@@ -221,11 +228,14 @@ def merge_index_bm25(new_index_name: str, index_names: List[str]):
     file_data = file_data.to_arrow().replace_schema_metadata({"cache_ranges": json.dumps(cache_ranges)})
     pq.write_table(file_data, f"{new_index_name}.meta", write_statistics = False, compression = 'zstd')
 
-def merge_index_substring(new_index_name: str, index_names: List[str]):
+def merge_index_substring(new_index_name: str, index_names: List[str], char_index = False):
     
     offsets, file_data = merge_metadatas(index_names)
     
-    cache_ranges = rottnest.merge_lava_generic(f"{new_index_name}.lava", [f"{name}.lava" for name in index_names], offsets, 1)
+    # char indices are stored as wavelet trees and merged by their own mode, in memory. Char
+    # indices built with a suffix array sample rate or of more than 2^27 symbols together can't
+    # be merged and raise, rebuild them from the texts
+    cache_ranges = rottnest.merge_lava_generic(f"{new_index_name}.lava", [f"{name}.lava" for name in index_names], offsets, 3 if char_index else 1)
     
     file_data = file_data.to_arrow().replace_schema_metadata({"cache_ranges": json.dumps(cache_ranges)})
     pq.write_table(file_data, f"{new_index_name}.meta", write_statistics = False, compression = 'zstd')
@@ -356,6 +366,26 @@ def search_index_substring(indices: List[str], query: str, K: int, sample_factor
 
     return return_full_result(result, metadata, column_name, columns)
 
+def search_index_regex(indices: List[str], pattern: str, K: int, columns = []):
+
+    # needs char substring indices, the literals of the regex narrow down the pages to read
+    metadata = get_metadata_and_populate_cache(indices)
+    
+    index_search_results = rottnest.search_lava_substring_regex([f"{index_name}.lava" for index_name in indices], pattern, "aws")
+
+    if len(index_search_results) == 0:
+        return None
+    
+    if len(index_search_results) > 10000:
+        return "Brute Force Please"
+
+    result, column_name, metadata = get_result_from_index_result(metadata, index_search_results)
+    # confirmed with the same regex syntax the literals were taken from
+    result = result.filter(rottnest.regex_matches(result[column_name].combine_chunks(), pattern))
+    result = polars.from_arrow(result).head(K)
+
+    return return_full_result(result, metadata, column_name, columns)
+
 def search_index_vector(indices: List[str], query: np.array, K: int, columns = [], nprobes = 500, refine = 500):
 
     import time
@@ -437,22 +467,24 @@ def search_index_bm25(indices: List[str], query: str, K: int, query_expansion =
 
     assert query_expansion in {"bge", "openai", "keyword", "none"}
     
-    tokenizer_vocab = rottnest.get_tokenizer_vocab([f"{index_name}.lava" for index_name in indices])
+    # built-in analyzers have no vocabulary, those indices can only be searched with query_expansion = "none"
+    if query_expansion != "none":
+        tokenizer_vocab = rottnest.get_tokenizer_vocab([f"{index_name}.lava" for index_name in indices])
 
     if query_expansion in {"bge","openai"}:
         tokens, token_ids, weights = query_expansion_llm(tokenizer_vocab, query, method = query_expansion, expansion_tokens=expansion_tokens, cache_dir = cache_dir)
     elif query_expansion == "keyword":
         tokens, token_ids, weights = query_expansion_keyword(tokenizer_vocab, query)
     else:
-        from tokenizers import Tokenizer
-        tok = Tokenizer.from_file("../tok/tokenizer.json")
-        token_ids = tok.encode(query).ids
-        tokens = [tokenizer_vocab[i] for i in token_ids]
-        weights = [1] * len(token_ids)
-        print(tokens)
+        # tokenized with the tokenizer embedded in the index
+        tokens, token_ids, weights = query.split(), None, None
 
     # metadata_file = f"{index_name}.meta"
-    index_search_results = rottnest.search_lava_bm25([f"{index_name}.lava" for index_name in indices], token_ids, weights, int(K * quality_factor), reader_type = reader_type)
+    if token_ids is None:
+        index_search_results = rottnest.search_lava_bm25_text([f"{index_name}.lava" for index_name in indices], query, int(K * quality_factor), reader_type = reader_type)
+        index_search_results = [(file_id, uid) for file_id, uid, _ in index_search_results]
+    else:
+        index_search_results = rottnest.search_lava_bm25([f"{index_name}.lava" for index_name in indices], token_ids, weights, int(K * quality_factor), reader_type = reader_type)
     
     if len(index_search_results) == 0:
         return None
@@ -68,10 +68,102 @@ def read_row_groups(file_paths: list, row_groups: list, row_ranges: list, column
 
     return results
 
+ARROW_IPC_SUFFIXES = (".arrow", ".feather", ".ipc")
+
+def is_arrow_ipc_file(file_path: str):
+    return file_path.endswith(ARROW_IPC_SUFFIXES)
+
+def get_physical_layout_arrow_ipc(file_paths: list, column_name: str, type = "str", remote = None):
+
+    # same contract as get_physical_layout, except that every record batch is one uid.
+    assert type in {"str", "binary"}
+
+    metadatas = []
+    all_arrs = []
+    all_uids = []
+    for file_path in file_paths:
+        arrs, layout = rottnest.get_arrow_ipc_layout(column_name, file_path)
+        arr = pyarrow.concat_arrays([i.cast(pyarrow.large_string() if type == 'str' else pyarrow.large_binary()) for i in arrs])
+        batch_num_rows = np.array(layout.batch_num_rows)
+        uid = np.repeat(np.arange(len(batch_num_rows)), batch_num_rows) + 1
+
+        metadata = polars.from_dict({
+                "uid": np.arange(len(batch_num_rows) + 1),
+                "file_path": [file_path if remote is None else remote + file_path] * (len(batch_num_rows) + 1),
+                "column_name": [column_name] * (len(batch_num_rows) + 1),
+                "metadata_bytes": [layout.metadata_bytes]  + [None] * (len(batch_num_rows)),
+                "batch_offsets": [-1] + layout.batch_offsets,
+                "batch_sizes": [-1] + layout.batch_sizes,
+                "batch_row_offset": np.hstack([[-1], np.cumsum(np.hstack([[0], batch_num_rows[:-1]]))]).astype(np.int64)
+            }
+        )
+
+        metadatas.append(metadata)
+        all_arrs.append(arr)
+        all_uids.append(uid)
+
+    metadata_lens = [len(metadata) for metadata in metadatas]
+    offsets = np.cumsum([0] + metadata_lens)[:-1]
+    metadatas = [metadata.with_columns(polars.col("uid") + offsets[i]) for i, metadata in enumerate(metadatas)]
+    all_uids = np.hstack([uid + offsets[i] for i, uid in enumerate(all_uids)])
+
+    return pyarrow.concat_arrays(all_arrs), pyarrow.array(all_uids.astype(np.uint64)), polars.concat(metadatas)
+
+LINES_SUFFIXES = (".log", ".txt", ".jsonl", ".zst", ".zstd")
+
+def is_lines_file(file_path: str):
+    return file_path.endswith(LINES_SUFFIXES)
+
+def lines_json_field(file_path: str, column_name: str):
+    # for JSONL the column name is the field to extract, plain text lines are indexed whole.
+    stem = file_path.removesuffix(".zstd").removesuffix(".zst")
+    return column_name if stem.endswith(".jsonl") else None
+
+def get_physical_layout_lines(file_paths: list, column_name: str, type = "str", remote = None):
+
+    # every block of lines is one uid, blocks are ~1MB of text or one group of seekable zstd frames.
+    assert type == "str"
+
+    metadatas = []
+    all_arrs = []
+    all_uids = []
+    for file_path in file_paths:
+        arrs, layout = rottnest.get_lines_layout(file_path, lines_json_field(file_path, column_name))
+        arr = pyarrow.concat_arrays([i.cast(pyarrow.large_string()) for i in arrs])
+        block_num_lines = np.array(layout.block_num_lines)
+        uid = np.repeat(np.arange(len(block_num_lines)), block_num_lines) + 1
+
+        metadata = polars.from_dict({
+                "uid": np.arange(len(block_num_lines) + 1),
+                "file_path": [file_path if remote is None else remote + file_path] * (len(block_num_lines) + 1),
+                "column_name": [column_name] * (len(block_num_lines) + 1),
+                "metadata_bytes": [None] * (len(block_num_lines) + 1),
+                "block_offsets": [-1] + layout.block_offsets,
+                "block_sizes": [-1] + layout.block_sizes,
+                "block_first_lines": [-1] + layout.block_first_lines,
+            }
+        )
+
+        metadatas.append(metadata)
+        all_arrs.append(arr)
+        all_uids.append(uid)
+
+    metadata_lens = [len(metadata) for metadata in metadatas]
+    offsets = np.cumsum([0] + metadata_lens)[:-1]
+    metadatas = [metadata.with_columns(polars.col("uid") + offsets[i]) for i, metadata in enumerate(metadatas)]
+    all_uids = np.hstack([uid + offsets[i] for i, uid in enumerate(all_uids)])
+
+    return pyarrow.concat_arrays(all_arrs), pyarrow.array(all_uids.astype(np.uint64)), polars.concat(metadatas)
+
 def get_physical_layout(file_paths: list, column_name: str, type = "str", remote = None):
 
     assert type in {"str", "binary"}
 
+    if all(is_arrow_ipc_file(file_path) for file_path in file_paths):
+        return get_physical_layout_arrow_ipc(file_paths, column_name, type = type, remote = remote)
+    if all(is_lines_file(file_path) for file_path in file_paths):
+        return get_physical_layout_lines(file_paths, column_name, type = type, remote = remote)
+
     metadatas = []
     all_arrs = []
     all_uids = []
@@ -162,6 +254,31 @@ def get_result_from_index_result(metadata: polars.DataFrame, index_search_result
 
     file_metadatas = {d["file_path"]: d["metadata_bytes"] for d in file_metadatas.to_dicts()}
 
+    if "block_offsets" in metadata.columns:
+        file_paths = metadata["file_path"].to_list()
+        result = []
+        # the json field differs between plain and JSONL files, so fetch them separately but keep the order
+        for json_field in set(lines_json_field(f, column_name) for f in file_paths):
+            idx = [i for i, f in enumerate(file_paths) if lines_json_field(f, column_name) == json_field]
+            arrs = rottnest.read_indexed_lines([file_paths[i] for i in idx], [metadata["block_offsets"][i] for i in idx],
+                                               [metadata["block_sizes"][i] for i in idx], json_field, "aws")
+            result.extend(zip(idx, arrs))
+        result = [arr for _, arr in sorted(result, key = lambda x: x[0])]
+        line_numbers = [pyarrow.array(np.arange(metadata['block_first_lines'][i], metadata['block_first_lines'][i] + len(arr))) for i, arr in enumerate(result)]
+        metadata_key = [pyarrow.array(np.ones(len(arr)).astype(np.uint32) * i) for i, arr in enumerate(result)]
+        result = pyarrow.table([pyarrow.chunked_array(result), pyarrow.chunked_array(line_numbers),
+                                pyarrow.chunked_array(metadata_key)], names = [column_name, '__row_group_rownr__', '__metadata_key__'])
+        return result, column_name, metadata.with_row_count('__metadata_key__')
+
+    if "batch_offsets" in metadata.columns:
+        result = rottnest.read_indexed_batches(column_name, metadata["file_path"].to_list(), metadata["batch_offsets"].to_list(),
+                                               metadata["batch_sizes"].to_list(), "aws", file_metadatas)
+        batch_rownr = [pyarrow.array(np.arange(metadata['batch_row_offset'][i], metadata['batch_row_offset'][i] + len(arr))) for i, arr in enumerate(result)]
+        metadata_key = [pyarrow.array(np.ones(len(arr)).astype(np.uint32) * i) for i, arr in enumerate(result)]
+        result = pyarrow.table([pyarrow.chunked_array(result), pyarrow.chunked_array(batch_rownr),
+                                pyarrow.chunked_array(metadata_key)], names = [column_name, '__row_group_rownr__', '__metadata_key__'])
+        return result, column_name, metadata.with_row_count('__metadata_key__')
+
     result = rottnest.read_indexed_pages(column_name, metadata["file_path"].to_list(), metadata["row_groups"].to_list(),
                                      metadata["data_page_offsets"].to_list(), metadata["data_page_sizes"].to_list(), metadata["dictionary_page_sizes"].to_list(),
                                      "aws", file_metadatas)
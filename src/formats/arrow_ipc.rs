@@ -0,0 +1,308 @@
+use arrow::array::{Array, ArrayData, ArrayRef};
+use arrow::buffer::Buffer;
+use arrow::datatypes::{DataType, SchemaRef};
+use arrow::error::ArrowError;
+use arrow_ipc::reader::{read_footer_length, FileDecoder};
+use arrow_ipc::{convert::fb_to_schema, root_as_footer, Block};
+
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use itertools::{izip, Itertools};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+
+use crate::{
+    formats::readers::{get_file_size_and_reader, get_reader, AsyncReader},
+    lava::error::LavaError,
+};
+
+use super::readers::ReaderType;
+
+// an Arrow IPC file ends with <footer flatbuffer><footer length: i32><b"ARROW1">
+const IPC_TRAILER_SIZE: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct ArrowIpcLayout {
+    pub num_batches: usize,
+    pub metadata_bytes: Bytes, // the raw footer flatbuffer, enough to decode batches later
+    pub batch_offsets: Vec<usize>,
+    pub batch_sizes: Vec<usize>, // message metadata + body
+    pub batch_num_rows: Vec<usize>,
+}
+
+async fn get_footer_bytes(reader: &mut AsyncReader, file_size: usize) -> Result<Bytes, LavaError> {
+    if file_size < IPC_TRAILER_SIZE {
+        return Err(LavaError::Parse(
+            "Invalid Arrow IPC file. Size is smaller than footer".to_string(),
+        ));
+    }
+
+    let trailer: [u8; IPC_TRAILER_SIZE] = reader
+        .read_range((file_size - IPC_TRAILER_SIZE) as u64, file_size as u64)
+        .await?
+        .as_ref()
+        .try_into()
+        .unwrap();
+    let footer_len = read_footer_length(trailer)?;
+
+    if footer_len + IPC_TRAILER_SIZE > file_size {
+        return Err(LavaError::Parse(
+            "Invalid Arrow IPC file. Size is smaller than footer".to_string(),
+        ));
+    }
+
+    let start = (file_size - IPC_TRAILER_SIZE - footer_len) as u64;
+    reader.read_range(start, start + footer_len as u64).await
+}
+
+struct IpcFileMetadata {
+    schema: SchemaRef,
+    version: arrow_ipc::MetadataVersion,
+    dictionaries: Vec<Block>,
+    batches: Vec<Block>,
+}
+
+fn decode_footer(footer_bytes: &[u8]) -> Result<IpcFileMetadata, LavaError> {
+    let footer = root_as_footer(footer_bytes)
+        .map_err(|e| LavaError::Parse(format!("Unable to get root as footer: {:?}", e)))?;
+    let ipc_schema = footer
+        .schema()
+        .ok_or_else(|| LavaError::Parse("Arrow IPC footer is missing the schema".to_string()))?;
+
+    Ok(IpcFileMetadata {
+        schema: Arc::new(fb_to_schema(ipc_schema)),
+        version: footer.version(),
+        dictionaries: footer.dictionaries().map(|d| d.iter().copied().collect()).unwrap_or_default(),
+        batches: footer.recordBatches().map(|b| b.iter().copied().collect()).unwrap_or_default(),
+    })
+}
+
+fn block_range(block: &Block) -> (u64, u64) {
+    let start = block.offset() as u64;
+    (start, start + block.metaDataLength() as u64 + block.bodyLength() as u64)
+}
+
+/// Builds a decoder projected to a single column. Dictionary batches are only fetched when the
+/// column is dictionary encoded, in which case the decoded arrays are cast back to their values.
+async fn get_column_decoder(
+    reader: &mut AsyncReader,
+    metadata: &IpcFileMetadata,
+    column_name: &str,
+    file_path: &str,
+) -> Result<(FileDecoder, bool), LavaError> {
+    let column_index = metadata.schema.index_of(column_name).map_err(|_| {
+        LavaError::Parse(format!(
+            "column {} not found in arrow ipc file {}",
+            column_name, file_path
+        ))
+    })?;
+
+    let mut decoder = FileDecoder::new(metadata.schema.clone(), metadata.version)
+        .with_projection(vec![column_index]);
+
+    let is_dictionary = matches!(
+        metadata.schema.field(column_index).data_type(),
+        DataType::Dictionary(_, _)
+    );
+    if is_dictionary {
+        for block in metadata.dictionaries.iter() {
+            let (start, end) = block_range(block);
+            let bytes = reader.read_range(start, end).await?;
+            decoder.read_dictionary(block, &Buffer::from(bytes.as_ref()))?;
+        }
+    }
+
+    Ok((decoder, is_dictionary))
+}
+
+fn decode_batch(
+    decoder: &FileDecoder,
+    block: &Block,
+    bytes: Bytes,
+    is_dictionary: bool,
+) -> Result<ArrayData, LavaError> {
+    let batch = decoder
+        .read_record_batch(block, &Buffer::from(bytes.as_ref()))?
+        .ok_or_else(|| LavaError::Parse("Expected a record batch message".to_string()))?;
+    let mut array: ArrayRef = batch.column(0).clone();
+
+    if is_dictionary {
+        if let DataType::Dictionary(_, value_type) = array.data_type().clone() {
+            array = arrow::compute::cast(&array, &value_type)?;
+        }
+    }
+
+    match array.data_type() {
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Binary | DataType::LargeBinary => {
+            Ok(array.to_data())
+        }
+        other => Err(LavaError::from(ArrowError::ParseError(format!(
+            "Expects string or binary column, got {:?}",
+            other
+        )))),
+    }
+}
+
+#[tokio::main]
+pub async fn get_arrow_ipc_layout(
+    column_name: &str,
+    file_path: &str,
+    reader_type: ReaderType,
+) -> Result<(Vec<ArrayData>, ArrowIpcLayout), LavaError> {
+    let (file_size, mut reader) =
+        get_file_size_and_reader(file_path.to_string(), reader_type).await?;
+    let metadata_bytes = get_footer_bytes(&mut reader, file_size).await?;
+    let metadata = decode_footer(&metadata_bytes)?;
+
+    let (decoder, is_dictionary) =
+        get_column_decoder(&mut reader, &metadata, column_name, file_path).await?;
+
+    let mut layout = ArrowIpcLayout {
+        num_batches: metadata.batches.len(),
+        metadata_bytes: metadata_bytes.clone(),
+        batch_offsets: vec![],
+        batch_sizes: vec![],
+        batch_num_rows: vec![],
+    };
+
+    // each record batch becomes one uid, so unlike Parquet we hand back exactly one array per batch.
+    let mut arrays: Vec<ArrayData> = Vec::with_capacity(metadata.batches.len());
+    for block in metadata.batches.iter() {
+        let (start, end) = block_range(block);
+        let bytes = reader.read_range(start, end).await?;
+        let data = decode_batch(&decoder, block, bytes, is_dictionary)?;
+
+        layout.batch_offsets.push(start as usize);
+        layout.batch_sizes.push((end - start) as usize);
+        layout.batch_num_rows.push(data.len());
+        arrays.push(data);
+    }
+
+    Ok((arrays, layout))
+}
+
+pub async fn read_indexed_batches_async(
+    column_name: String,
+    file_paths: Vec<String>,
+    batch_offsets: Vec<u64>,
+    batch_sizes: Vec<usize>,
+    reader_type: ReaderType,
+    file_metadatas: Option<HashMap<String, Bytes>>,
+    in_order: Option<bool>,
+) -> Result<Vec<ArrayData>, LavaError> {
+    let in_order: bool = in_order.unwrap_or(true);
+
+    let reader = get_reader(file_paths[0].clone(), reader_type.clone()).await?;
+
+    // decoders are built once per file, footers are either provided or fetched here.
+    let mut decoders: HashMap<String, (Arc<FileDecoder>, bool, HashMap<u64, Block>)> =
+        HashMap::new();
+    for file_path in file_paths.iter().unique() {
+        let mut reader_c = reader.clone();
+        reader_c.update_filename(file_path.clone())?;
+
+        let footer_bytes = match file_metadatas.as_ref().and_then(|m| m.get(file_path)) {
+            Some(bytes) => bytes.clone(),
+            None => {
+                let (file_size, mut reader_f) =
+                    get_file_size_and_reader(file_path.clone(), reader_type.clone()).await?;
+                get_footer_bytes(&mut reader_f, file_size).await?
+            }
+        };
+        let metadata = decode_footer(&footer_bytes)?;
+        let (decoder, is_dictionary) =
+            get_column_decoder(&mut reader_c, &metadata, &column_name, file_path).await?;
+        let blocks: HashMap<u64, Block> = metadata
+            .batches
+            .iter()
+            .map(|block| (block.offset() as u64, *block))
+            .collect();
+        decoders.insert(file_path.clone(), (Arc::new(decoder), is_dictionary, blocks));
+    }
+
+    let start = std::time::Instant::now();
+
+    let mut future_handles: Vec<tokio::task::JoinHandle<Result<ArrayData, LavaError>>> = vec![];
+    let mut join_set = JoinSet::new();
+
+    let iter = izip!(file_paths, batch_offsets, batch_sizes);
+    let _: Vec<_> = stream::iter(iter)
+        .map(|(file_path, batch_offset, batch_size)| {
+            let (decoder, is_dictionary, blocks) = &decoders[&file_path];
+            let decoder = decoder.clone();
+            let is_dictionary = *is_dictionary;
+            let block = blocks.get(&batch_offset).copied();
+
+            let mut reader_c = reader.clone();
+            reader_c.update_filename(file_path.clone()).unwrap();
+
+            let future = async move {
+                let block = block.ok_or_else(|| {
+                    LavaError::Parse(format!(
+                        "no record batch at offset {} in arrow ipc file {}",
+                        batch_offset, file_path
+                    ))
+                })?;
+                let bytes = reader_c
+                    .read_range(batch_offset, batch_offset + batch_size as u64)
+                    .await?;
+                decode_batch(&decoder, &block, bytes, is_dictionary)
+            };
+
+            if in_order {
+                future_handles.push(tokio::spawn(future));
+            } else {
+                join_set.spawn(future);
+            }
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+    // same as read_indexed_pages, results must come back in request order unless told otherwise.
+    let result: Vec<ArrayData> = if in_order {
+        let res = futures::future::join_all(future_handles).await;
+        res.into_iter()
+            .map(|res| res.unwrap())
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        let mut result_inner: Vec<ArrayData> = vec![];
+        while let Some(res) = join_set.join_next().await {
+            result_inner.push(res.unwrap()?);
+        }
+        result_inner
+    };
+
+    join_set.shutdown().await;
+
+    log::debug!("read_indexed_batches_async took {:?}", start.elapsed());
+
+    Ok(result)
+}
+
+pub fn read_indexed_batches(
+    column_name: String,
+    file_paths: Vec<String>,
+    batch_offsets: Vec<u64>,
+    batch_sizes: Vec<usize>,
+    reader_type: ReaderType,
+    file_metadatas: Option<HashMap<String, Bytes>>,
+    in_order: Option<bool>,
+) -> Result<Vec<ArrayData>, LavaError> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let res = rt.block_on(read_indexed_batches_async(
+        column_name,
+        file_paths,
+        batch_offsets,
+        batch_sizes,
+        reader_type,
+        file_metadatas,
+        in_order,
+    ));
+    rt.shutdown_background();
+    res
+}
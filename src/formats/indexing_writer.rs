@@ -0,0 +1,355 @@
+use arrow::array::{Array, ArrayData, ArrayRef, UInt64Array};
+use arrow::compute::{cast, concat, sort_to_indices, take};
+use arrow::datatypes::DataType;
+use arrow::record_batch::RecordBatch;
+use arrow_schema::SchemaRef;
+use bytes::Bytes;
+use parquet::arrow::{arrow_to_parquet_schema, ArrowWriter};
+use parquet::file::metadata::RowGroupMetaDataPtr;
+use parquet::file::properties::WriterProperties;
+use parquet::thrift::TSerializable;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use thrift::protocol::{TCompactOutputProtocol, TOutputProtocol};
+
+use crate::formats::parquet::{decode_string_pages, parse_column_chunk, ParquetLayout};
+use crate::lava::error::LavaError;
+use crate::lava::{build_lava_bm25, build_lava_substring, build_lava_substring_char, build_lava_uuid};
+
+/// Which index `IndexingArrowWriter` builds, with the same options as the `build_lava_*` functions.
+#[derive(Debug, Clone)]
+pub enum WriteTimeIndex {
+    Bm25 {
+        tokenizer_file: Option<String>,
+        positional: Option<bool>,
+    },
+    Substring {
+        tokenizer_file: Option<String>,
+        token_skip_factor: Option<u32>,
+        char_index: bool,
+        normalization: Option<String>,
+        positional: Option<bool>,
+        sa_sample_rate: Option<u32>,
+    },
+    Uuid,
+}
+
+// bytes that went to the sink but whose row group has not been indexed yet
+struct TeeBuffer {
+    start: usize, // absolute file offset of buf[0]
+    buf: Vec<u8>,
+}
+
+/// Passes everything through to the real sink while keeping a copy of the bytes, so column chunks
+/// can be parsed as soon as their row group is flushed.
+pub struct TeeWriter<W: Write> {
+    inner: Option<W>,
+    buffer: Arc<Mutex<TeeBuffer>>,
+}
+
+impl<W: Write> Write for TeeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.as_mut().unwrap().write(buf)?;
+        self.buffer.lock().unwrap().buf.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+/// Wraps `ArrowWriter` and indexes one string column while the file is being written. The uid of
+/// a row is one plus the position of its data page in the returned `ParquetLayout`, uid 0 is left
+/// for the file itself, like the `.meta` tables of `get_physical_layout`.
+pub struct IndexingArrowWriter<W: Write + Send> {
+    writer: ArrowWriter<TeeWriter<W>>,
+    buffer: Arc<Mutex<TeeBuffer>>,
+    column_index: usize,
+    processed_row_groups: usize,
+    parquet_layout: ParquetLayout,
+    texts: Vec<ArrayData>,
+    uids: Vec<u64>,
+}
+
+impl<W: Write + Send> IndexingArrowWriter<W> {
+    pub fn try_new(
+        writer: W,
+        schema: SchemaRef,
+        column_name: &str,
+        props: Option<WriterProperties>,
+    ) -> Result<Self, LavaError> {
+        let buffer = Arc::new(Mutex::new(TeeBuffer { start: 0, buf: vec![] }));
+        let tee = TeeWriter {
+            inner: Some(writer),
+            buffer: buffer.clone(),
+        };
+        let column_index = arrow_to_parquet_schema(&schema)?
+            .columns()
+            .iter()
+            .position(|column| column.name() == column_name)
+            .ok_or_else(|| LavaError::Parse(format!("column {} not found in schema", column_name)))?;
+        let writer = ArrowWriter::try_new(tee, schema, props)?;
+
+        Ok(Self {
+            writer,
+            buffer,
+            column_index,
+            processed_row_groups: 0,
+            parquet_layout: ParquetLayout {
+                num_row_groups: 0,
+                metadata_bytes: Bytes::new(),
+                dictionary_page_sizes: vec![],
+                data_page_sizes: vec![],
+                data_page_offsets: vec![],
+                data_page_num_rows: vec![],
+                row_group_data_pages: vec![],
+            },
+            texts: vec![],
+            uids: vec![],
+        })
+    }
+
+    pub fn write(&mut self, batch: &RecordBatch) -> Result<(), LavaError> {
+        self.writer.write(batch)?;
+        let row_groups = self.writer.flushed_row_groups().to_vec();
+        self.process_row_groups(&row_groups, false)
+    }
+
+    pub fn flush(&mut self) -> Result<(), LavaError> {
+        self.writer.flush()?;
+        let row_groups = self.writer.flushed_row_groups().to_vec();
+        self.process_row_groups(&row_groups, false)
+    }
+
+    // the file writer buffers internally, so a flushed row group may not have reached the sink yet.
+    // Those are left for a later call, `wait` is only set once everything has been written out.
+    fn process_row_groups(
+        &mut self,
+        row_groups: &[RowGroupMetaDataPtr],
+        wait: bool,
+    ) -> Result<(), LavaError> {
+        while self.processed_row_groups < row_groups.len() {
+            let row_group = &row_groups[self.processed_row_groups];
+            let column = row_group.column(self.column_index);
+            let start = column
+                .dictionary_page_offset()
+                .unwrap_or_else(|| column.data_page_offset()) as usize;
+            let end = start + column.compressed_size() as usize;
+
+            let column_chunk_bytes = {
+                let mut buffer = self.buffer.lock().unwrap();
+                if buffer.start + buffer.buf.len() < end {
+                    if wait {
+                        return Err(LavaError::Parse(format!(
+                            "column chunk of row group {} was never written",
+                            self.processed_row_groups
+                        )));
+                    }
+                    return Ok(());
+                }
+                let bytes =
+                    Bytes::copy_from_slice(&buffer.buf[start - buffer.start..end - buffer.start]);
+                // nothing before the end of this row group is needed anymore
+                let row_group_end = row_group
+                    .columns()
+                    .iter()
+                    .map(|c| {
+                        c.dictionary_page_offset().unwrap_or_else(|| c.data_page_offset())
+                            + c.compressed_size()
+                    })
+                    .max()
+                    .unwrap() as usize;
+                let drain_to = row_group_end.min(buffer.start + buffer.buf.len()) - buffer.start;
+                buffer.buf.drain(..drain_to);
+                buffer.start += drain_to;
+                bytes
+            };
+
+            let first_page = self.parquet_layout.data_page_num_rows.len();
+            let (pages, num_values) = parse_column_chunk(
+                &column_chunk_bytes,
+                start as u64,
+                column.compression(),
+                &mut self.parquet_layout,
+            )?;
+            for (page, num_rows) in self.parquet_layout.data_page_num_rows[first_page..]
+                .iter()
+                .enumerate()
+            {
+                self.uids
+                    .extend(std::iter::repeat((first_page + page + 1) as u64).take(*num_rows));
+            }
+            self.texts.extend(decode_string_pages(
+                vec![pages],
+                column.column_descr_ptr(),
+                num_values,
+            )?);
+
+            self.parquet_layout.num_row_groups += 1;
+            self.processed_row_groups += 1;
+        }
+        Ok(())
+    }
+
+    /// Finishes the Parquet file and writes the index to `index_file`. Returns the inner writer,
+    /// the index cache ranges and the layout that maps uids back to pages.
+    pub fn finish(
+        mut self,
+        index_file: &str,
+        index: WriteTimeIndex,
+    ) -> Result<(W, Vec<(usize, usize)>, ParquetLayout), LavaError> {
+        let file_metadata = self.writer.finish()?;
+        let row_groups = self.writer.flushed_row_groups().to_vec();
+        self.process_row_groups(&row_groups, true)?;
+
+        let mut metadata_bytes: Vec<u8> = Vec::new();
+        {
+            let mut prot = TCompactOutputProtocol::new(&mut metadata_bytes);
+            file_metadata.write_to_out_protocol(&mut prot)?;
+            prot.flush()?;
+        }
+        self.parquet_layout.metadata_bytes = Bytes::from(metadata_bytes);
+
+        let inner = self.writer.inner_mut().inner.take().unwrap();
+
+        let texts: Vec<ArrayRef> = self
+            .texts
+            .iter()
+            .map(|data| cast(&arrow::array::make_array(data.clone()), &DataType::LargeUtf8))
+            .collect::<Result<_, _>>()?;
+        let texts: Vec<&dyn Array> = texts.iter().map(|x| x.as_ref()).collect();
+        let mut array: ArrayRef = if texts.is_empty() {
+            arrow::array::new_empty_array(&DataType::LargeUtf8)
+        } else {
+            concat(&texts)?
+        };
+        let mut uid: ArrayRef = Arc::new(UInt64Array::from(std::mem::take(&mut self.uids)));
+
+        let index_file = index_file.to_string();
+        let cache_ranges = match index {
+            WriteTimeIndex::Bm25 {
+                tokenizer_file,
+                positional,
+            } => build_lava_bm25(
+                index_file,
+                array.to_data(),
+                uid.to_data(),
+                tokenizer_file,
+                positional,
+            )?,
+            WriteTimeIndex::Substring {
+                tokenizer_file,
+                token_skip_factor,
+                char_index,
+                normalization,
+                positional,
+                sa_sample_rate,
+            } => {
+                if char_index {
+                    build_lava_substring_char(
+                        index_file,
+                        array.to_data(),
+                        uid.to_data(),
+                        token_skip_factor,
+                        normalization,
+                        positional,
+                        sa_sample_rate,
+                    )?
+                } else if positional.unwrap_or(false) {
+                    return Err(LavaError::Unsupported(
+                        "positions are only stored by char substring indices".to_string(),
+                    ));
+                } else if sa_sample_rate.is_some() {
+                    return Err(LavaError::Unsupported(
+                        "sampled suffix arrays are only built for char substring indices".to_string(),
+                    ));
+                } else {
+                    build_lava_substring(
+                        index_file,
+                        array.to_data(),
+                        uid.to_data(),
+                        tokenizer_file,
+                        token_skip_factor,
+                        normalization,
+                    )?
+                }
+            }
+            WriteTimeIndex::Uuid => {
+                // the trie is built from sorted keys
+                let indices = sort_to_indices(&array, None, None)?;
+                array = take(&array, &indices, None)?;
+                uid = take(&uid, &indices, None)?;
+                build_lava_uuid(index_file, array.to_data(), uid.to_data())?
+            }
+        };
+
+        Ok((inner, cache_ranges, self.parquet_layout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::parquet::get_parquet_layout;
+    use crate::formats::readers::ReaderType;
+    use crate::lava::search_lava_uuid;
+    use arrow::array::LargeStringArray;
+    use std::fs::File;
+
+    #[test]
+    fn test_indexing_arrow_writer() {
+        let dir = std::env::temp_dir().join(format!("rottnest_indexing_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let parquet_file = dir.join("data.parquet").to_str().unwrap().to_string();
+        let lava_file = dir.join("data.lava").to_str().unwrap().to_string();
+
+        let texts: Vec<String> = (0..5000).map(|i| format!("key-{:05}", i)).collect();
+        let column: ArrayRef = Arc::new(LargeStringArray::from(texts.clone()));
+        let batch = RecordBatch::try_from_iter(vec![("key", column)]).unwrap();
+        // several row groups of several pages each
+        let props = WriterProperties::builder()
+            .set_max_row_group_size(1500)
+            .set_data_page_row_count_limit(400)
+            .set_write_batch_size(100)
+            .set_dictionary_enabled(false)
+            .build();
+        let mut writer = IndexingArrowWriter::try_new(
+            File::create(&parquet_file).unwrap(),
+            batch.schema(),
+            "key",
+            Some(props),
+        )
+        .unwrap();
+        for start in (0..texts.len()).step_by(1000) {
+            writer.write(&batch.slice(start, 1000)).unwrap();
+        }
+        writer.flush().unwrap();
+        let (_, _, layout) = writer.finish(&lava_file, WriteTimeIndex::Uuid).unwrap();
+
+        let (_, file_layout) =
+            get_parquet_layout("key", &parquet_file, ReaderType::default()).unwrap();
+        assert!(file_layout.num_row_groups > 1);
+        assert_eq!(layout.num_row_groups, file_layout.num_row_groups);
+        assert_eq!(layout.data_page_offsets, file_layout.data_page_offsets);
+        assert_eq!(layout.data_page_num_rows, file_layout.data_page_num_rows);
+        assert_eq!(layout.row_group_data_pages, file_layout.row_group_data_pages);
+
+        // row i is in the page whose rows, counted from the start of the file, cover i
+        let mut page_ends = vec![];
+        for num_rows in &file_layout.data_page_num_rows {
+            page_ends.push(page_ends.last().unwrap_or(&0) + num_rows);
+        }
+        assert!(page_ends.len() > file_layout.num_row_groups);
+        assert_eq!(*page_ends.last().unwrap(), texts.len());
+        for row in (0..texts.len()).step_by(37).chain([1499, 1500, 4999]) {
+            let page = page_ends.iter().position(|&end| row < end).unwrap();
+            let result =
+                search_lava_uuid(vec![lava_file.clone()], texts[row].clone(), 10, ReaderType::default())
+                    .unwrap();
+            assert_eq!(result, vec![(0, page as u64 + 1)]);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,443 @@
+use arrow::array::{Array, ArrayData, LargeStringArray};
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use itertools::izip;
+use tokio::task::JoinSet;
+
+use crate::{
+    formats::readers::{get_file_size_and_reader, get_reader, AsyncReader},
+    lava::error::LavaError,
+};
+
+use super::readers::ReaderType;
+
+// https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184D2A5E;
+const SEEK_TABLE_FOOTER_SIZE: usize = 9;
+const SKIPPABLE_FRAME_HEADER_SIZE: usize = 8;
+
+// plain text files are cut into blocks of roughly this many bytes, always at a newline.
+const DEFAULT_BLOCK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct LinesLayout {
+    pub compressed: bool,
+    pub block_offsets: Vec<usize>, // offsets in the file on disk, i.e. compressed offsets for zstd
+    pub block_sizes: Vec<usize>,
+    pub block_first_lines: Vec<usize>, // line number of the first line in the block, from the start of the file
+    pub block_num_lines: Vec<usize>,
+}
+
+pub fn is_zstd_file(file_path: &str) -> bool {
+    file_path.ends_with(".zst") || file_path.ends_with(".zstd")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SeekTableEntry {
+    pub compressed_size: u32,
+    pub decompressed_size: u32,
+}
+
+/// Parses the seek table of a seekable zstd file. `tail` must hold at least the last
+/// `seek_table_size` bytes of the file.
+pub(crate) fn parse_seek_table(tail: &[u8]) -> Result<Vec<SeekTableEntry>, LavaError> {
+    if tail.len() < SEEK_TABLE_FOOTER_SIZE + SKIPPABLE_FRAME_HEADER_SIZE {
+        return Err(LavaError::Parse("zstd file too small for a seek table".to_string()));
+    }
+    let footer = &tail[tail.len() - SEEK_TABLE_FOOTER_SIZE..];
+    if LittleEndian::read_u32(&footer[5..9]) != SEEKABLE_MAGIC {
+        return Err(LavaError::Unsupported(
+            "zstd file has no seek table, only seekable zstd files can be fetched by range"
+                .to_string(),
+        ));
+    }
+    let num_frames = LittleEndian::read_u32(&footer[0..4]) as usize;
+    let has_checksum = footer[4] & 0x80 != 0;
+    let entry_size = if has_checksum { 12 } else { 8 };
+
+    let table_size = seek_table_size(num_frames, has_checksum);
+    if tail.len() < table_size {
+        return Err(LavaError::Parse("seek table is truncated".to_string()));
+    }
+    let table = &tail[tail.len() - table_size..];
+    if LittleEndian::read_u32(&table[0..4]) != SKIPPABLE_FRAME_MAGIC {
+        return Err(LavaError::Parse("seek table is not a skippable frame".to_string()));
+    }
+
+    let entries = &table[SKIPPABLE_FRAME_HEADER_SIZE..table.len() - SEEK_TABLE_FOOTER_SIZE];
+    Ok(entries
+        .chunks_exact(entry_size)
+        .map(|entry| SeekTableEntry {
+            compressed_size: LittleEndian::read_u32(&entry[0..4]),
+            decompressed_size: LittleEndian::read_u32(&entry[4..8]),
+        })
+        .collect())
+}
+
+fn seek_table_size(num_frames: usize, has_checksum: bool) -> usize {
+    let entry_size = if has_checksum { 12 } else { 8 };
+    SKIPPABLE_FRAME_HEADER_SIZE + num_frames * entry_size + SEEK_TABLE_FOOTER_SIZE
+}
+
+async fn read_seek_table(
+    reader: &mut AsyncReader,
+    file_size: usize,
+) -> Result<Vec<SeekTableEntry>, LavaError> {
+    if file_size < SEEK_TABLE_FOOTER_SIZE {
+        return Err(LavaError::Parse("zstd file too small for a seek table".to_string()));
+    }
+    let footer = reader
+        .read_range((file_size - SEEK_TABLE_FOOTER_SIZE) as u64, file_size as u64)
+        .await?;
+    let num_frames = LittleEndian::read_u32(&footer[0..4]) as usize;
+    let table_size = seek_table_size(num_frames, footer[4] & 0x80 != 0);
+    if table_size > file_size {
+        return Err(LavaError::Parse("seek table is larger than the file".to_string()));
+    }
+    let tail = reader
+        .read_range((file_size - table_size) as u64, file_size as u64)
+        .await?;
+    parse_seek_table(&tail)
+}
+
+/// Returns the offsets right after every newline at which a block should end, so that blocks are
+/// roughly `block_size` bytes and never split a line.
+pub(crate) fn split_at_newlines(data: &[u8], block_size: usize) -> Vec<usize> {
+    let mut ends = vec![];
+    let mut start = 0;
+    while start < data.len() {
+        let target = (start + block_size).min(data.len());
+        let end = match data[target - 1..].iter().position(|&b| b == b'\n') {
+            Some(pos) => target + pos,
+            None => data.len(),
+        };
+        ends.push(end);
+        start = end;
+    }
+    ends
+}
+
+fn extract_line(line: &str, json_field: Option<&str>) -> String {
+    match json_field {
+        None => line.to_string(),
+        Some(field) => match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(serde_json::Value::Object(map)) => match map.get(field) {
+                Some(serde_json::Value::String(s)) => s.clone(),
+                Some(serde_json::Value::Null) | None => String::new(),
+                Some(other) => other.to_string(),
+            },
+            _ => String::new(),
+        },
+    }
+}
+
+fn decode_block(data: &[u8], json_field: Option<&str>) -> Result<ArrayData, LavaError> {
+    let text = String::from_utf8_lossy(data);
+    let text = text.strip_suffix('\n').unwrap_or(&text);
+    let lines: Vec<String> = if data.is_empty() {
+        vec![]
+    } else {
+        text.split('\n')
+            .map(|line| extract_line(line.strip_suffix('\r').unwrap_or(line), json_field))
+            .collect()
+    };
+    Ok(LargeStringArray::from(lines).to_data())
+}
+
+fn decompress_block(bytes: &[u8]) -> Result<Vec<u8>, LavaError> {
+    zstd::stream::decode_all(bytes).map_err(|e| LavaError::Compression(e.to_string()))
+}
+
+#[tokio::main]
+pub async fn get_lines_layout(
+    file_path: &str,
+    json_field: Option<String>,
+    block_size: Option<usize>,
+    reader_type: ReaderType,
+) -> Result<(Vec<ArrayData>, LinesLayout), LavaError> {
+    let (file_size, mut reader) =
+        get_file_size_and_reader(file_path.to_string(), reader_type).await?;
+    let json_field = json_field.as_deref();
+    let compressed = is_zstd_file(file_path);
+
+    let mut layout = LinesLayout {
+        compressed,
+        block_offsets: vec![],
+        block_sizes: vec![],
+        block_first_lines: vec![],
+        block_num_lines: vec![],
+    };
+    let mut arrays: Vec<ArrayData> = vec![];
+    let mut line_number = 0;
+
+    let mut push_block = |layout: &mut LinesLayout, offset: usize, size: usize, data: &[u8]| {
+        let array = decode_block(data, json_field)?;
+        layout.block_offsets.push(offset);
+        layout.block_sizes.push(size);
+        layout.block_first_lines.push(line_number);
+        layout.block_num_lines.push(array.len());
+        line_number += array.len();
+        arrays.push(array);
+        Ok::<(), LavaError>(())
+    };
+
+    if compressed {
+        // frames written by other tools need not end on a newline, so consecutive frames are
+        // grouped until one does. Each group is a block and starts at a line boundary.
+        let seek_table = read_seek_table(&mut reader, file_size).await?;
+        let mut offset = 0;
+        let mut block_start = 0;
+        let mut pending: Vec<u8> = vec![];
+        for (i, entry) in seek_table.iter().enumerate() {
+            let frame_end = offset + entry.compressed_size as usize;
+            let frame = reader.read_range(offset as u64, frame_end as u64).await?;
+            pending.extend(decompress_block(&frame)?);
+            offset = frame_end;
+
+            if pending.ends_with(b"\n") || i == seek_table.len() - 1 {
+                push_block(&mut layout, block_start, offset - block_start, &pending)?;
+                pending.clear();
+                block_start = offset;
+            }
+        }
+    } else {
+        let block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+        let mut offset = 0;
+        while offset < file_size {
+            // read a little past the target so the block can be extended to the next newline
+            let mut end = (offset + block_size).min(file_size);
+            let mut data = reader.read_range(offset as u64, end as u64).await?.to_vec();
+            while !data[data.len().min(block_size) - 1..].contains(&b'\n') && end < file_size {
+                let next = (end + block_size).min(file_size);
+                data.extend(reader.read_range(end as u64, next as u64).await?);
+                end = next;
+            }
+            let block_end = split_at_newlines(&data, block_size)[0];
+            push_block(&mut layout, offset, block_end, &data[..block_end])?;
+            offset += block_end;
+        }
+    }
+
+    Ok((arrays, layout))
+}
+
+pub async fn read_indexed_lines_async(
+    file_paths: Vec<String>,
+    block_offsets: Vec<u64>,
+    block_sizes: Vec<usize>,
+    json_field: Option<String>,
+    reader_type: ReaderType,
+    in_order: Option<bool>,
+) -> Result<Vec<ArrayData>, LavaError> {
+    let in_order: bool = in_order.unwrap_or(true);
+
+    let reader = get_reader(file_paths[0].clone(), reader_type.clone()).await?;
+
+    let start = std::time::Instant::now();
+
+    let mut future_handles: Vec<tokio::task::JoinHandle<Result<ArrayData, LavaError>>> = vec![];
+    let mut join_set = JoinSet::new();
+
+    let iter = izip!(file_paths, block_offsets, block_sizes);
+    let _: Vec<_> = stream::iter(iter)
+        .map(|(file_path, block_offset, block_size)| {
+            let compressed = is_zstd_file(&file_path);
+            let json_field = json_field.clone();
+            let mut reader_c = reader.clone();
+            reader_c.update_filename(file_path).unwrap();
+
+            let future = async move {
+                let bytes: Bytes = reader_c
+                    .read_range(block_offset, block_offset + block_size as u64)
+                    .await?;
+                if compressed {
+                    decode_block(&decompress_block(&bytes)?, json_field.as_deref())
+                } else {
+                    decode_block(&bytes, json_field.as_deref())
+                }
+            };
+
+            if in_order {
+                future_handles.push(tokio::spawn(future));
+            } else {
+                join_set.spawn(future);
+            }
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+    let result: Vec<ArrayData> = if in_order {
+        let res = futures::future::join_all(future_handles).await;
+        res.into_iter()
+            .map(|res| res.unwrap())
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        let mut result_inner: Vec<ArrayData> = vec![];
+        while let Some(res) = join_set.join_next().await {
+            result_inner.push(res.unwrap()?);
+        }
+        result_inner
+    };
+
+    join_set.shutdown().await;
+
+    log::debug!("read_indexed_lines_async took {:?}", start.elapsed());
+
+    Ok(result)
+}
+
+pub fn read_indexed_lines(
+    file_paths: Vec<String>,
+    block_offsets: Vec<u64>,
+    block_sizes: Vec<usize>,
+    json_field: Option<String>,
+    reader_type: ReaderType,
+    in_order: Option<bool>,
+) -> Result<Vec<ArrayData>, LavaError> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let res = rt.block_on(read_indexed_lines_async(
+        file_paths,
+        block_offsets,
+        block_sizes,
+        json_field,
+        reader_type,
+        in_order,
+    ));
+    rt.shutdown_background();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seek_table(entries: &[(u32, u32)], checksum: bool) -> Vec<u8> {
+        let mut table = vec![];
+        table.extend(SKIPPABLE_FRAME_MAGIC.to_le_bytes());
+        let entry_size = if checksum { 12 } else { 8 };
+        table.extend(((entries.len() * entry_size + SEEK_TABLE_FOOTER_SIZE) as u32).to_le_bytes());
+        for (c, d) in entries {
+            table.extend(c.to_le_bytes());
+            table.extend(d.to_le_bytes());
+            if checksum {
+                table.extend(0u32.to_le_bytes());
+            }
+        }
+        table.extend((entries.len() as u32).to_le_bytes());
+        table.push(if checksum { 0x80 } else { 0 });
+        table.extend(SEEKABLE_MAGIC.to_le_bytes());
+        table
+    }
+
+    #[test]
+    fn test_parse_seek_table() {
+        for checksum in [false, true] {
+            let mut tail = vec![1, 2, 3];
+            tail.extend(seek_table(&[(10, 100), (20, 200)], checksum));
+            let entries = parse_seek_table(&tail).unwrap();
+            assert_eq!(
+                entries,
+                vec![
+                    SeekTableEntry { compressed_size: 10, decompressed_size: 100 },
+                    SeekTableEntry { compressed_size: 20, decompressed_size: 200 },
+                ]
+            );
+        }
+        assert!(parse_seek_table(&[0u8; 32]).is_err());
+    }
+
+    #[test]
+    fn test_split_at_newlines() {
+        let data = b"aaaa\nbb\ncccccc\nd";
+        assert_eq!(split_at_newlines(data, 3), vec![5, 8, 15, 16]);
+        assert_eq!(split_at_newlines(data, 100), vec![16]);
+        assert_eq!(split_at_newlines(b"", 3), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_decode_block_json() {
+        let data = b"{\"msg\": \"hello\"}\n{\"other\": 1}\n{\"msg\": 42}\n";
+        let array = make_array_strings(decode_block(data, Some("msg")).unwrap());
+        assert_eq!(array, vec!["hello", "", "42"]);
+        let array = make_array_strings(decode_block(b"a\r\nb\n", None).unwrap());
+        assert_eq!(array, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_read_indexed_lines() {
+        // some lines are longer than a block, and the zstd frames don't end on newlines
+        let lines: Vec<String> = (0..500)
+            .map(|i| "x".repeat(i * 37 % 101) + &i.to_string())
+            .collect();
+        let text = lines.join("\n") + "\n";
+
+        let dir = std::env::temp_dir();
+        let plain = dir.join(format!("rottnest_lines_{}.txt", std::process::id()));
+        std::fs::write(&plain, &text).unwrap();
+        let seekable = dir.join(format!("rottnest_lines_{}.zst", std::process::id()));
+        let mut data = vec![];
+        let mut entries = vec![];
+        for frame in text.as_bytes().chunks(333) {
+            let compressed = zstd::stream::encode_all(frame, 0).unwrap();
+            entries.push((compressed.len() as u32, frame.len() as u32));
+            data.extend(compressed);
+        }
+        data.extend(seek_table(&entries, false));
+        std::fs::write(&seekable, data).unwrap();
+
+        for file in [&plain, &seekable] {
+            let file = file.to_str().unwrap().to_string();
+            let (arrays, layout) =
+                get_lines_layout(&file, None, Some(64), Default::default()).unwrap();
+            if layout.compressed {
+                assert!(layout.block_offsets.len() < entries.len());
+            }
+            assert!(layout.block_offsets.len() > 1);
+            let first_lines: Vec<usize> = arrays
+                .iter()
+                .scan(0, |first, array| {
+                    let this = *first;
+                    *first += array.len();
+                    Some(this)
+                })
+                .collect();
+            assert_eq!(layout.block_first_lines, first_lines);
+            let all: Vec<String> = arrays.into_iter().flat_map(make_array_strings).collect();
+            assert_eq!(all, lines);
+
+            // fetch every other block, as a search does
+            let blocks: Vec<usize> = (0..layout.block_offsets.len()).step_by(2).collect();
+            let fetched = read_indexed_lines(
+                vec![file.clone(); blocks.len()],
+                blocks
+                    .iter()
+                    .map(|&b| layout.block_offsets[b] as u64)
+                    .collect(),
+                blocks.iter().map(|&b| layout.block_sizes[b]).collect(),
+                None,
+                Default::default(),
+                None,
+            )
+            .unwrap();
+            for (&b, array) in blocks.iter().zip(fetched) {
+                let first = layout.block_first_lines[b];
+                assert_eq!(
+                    make_array_strings(array),
+                    lines[first..first + layout.block_num_lines[b]]
+                );
+            }
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    fn make_array_strings(data: ArrayData) -> Vec<String> {
+        let array = LargeStringArray::from(data);
+        array.iter().map(|x| x.unwrap().to_string()).collect()
+    }
+}
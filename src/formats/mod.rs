@@ -1,9 +1,20 @@
 pub mod readers;
 pub mod cache;
 pub mod parquet;
+pub mod arrow_ipc;
+pub mod lines;
+pub mod indexing_writer;
 
+pub use parquet::embed_lava_in_parquet;
 pub use parquet::get_parquet_layout;
 pub use parquet::read_indexed_pages;
 pub use parquet::MatchResult;
 pub use parquet::ParquetLayout;
+pub use arrow_ipc::get_arrow_ipc_layout;
+pub use arrow_ipc::read_indexed_batches;
+pub use arrow_ipc::ArrowIpcLayout;
+pub use lines::get_lines_layout;
+pub use lines::read_indexed_lines;
+pub use lines::LinesLayout;
+pub use indexing_writer::{IndexingArrowWriter, WriteTimeIndex};
 pub use cache::populate_cache;
\ No newline at end of file
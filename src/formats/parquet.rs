@@ -16,13 +16,16 @@ use parquet::{
         reader::*,
         statistics, FOOTER_SIZE,
     },
-    format::{PageHeader, PageType},
+    format::{FileMetaData, KeyValue, PageHeader, PageType},
+    schema::types::ColumnDescPtr,
     thrift::TSerializable,
     util::InMemoryPageIterator,
 };
-use thrift::protocol::TCompactInputProtocol;
+use thrift::protocol::{TCompactInputProtocol, TCompactOutputProtocol, TOutputProtocol};
 
 use bytes::Bytes;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
 use std::{collections::BTreeMap, hash::Hash, io::Read};
 use std::{convert::TryFrom, sync::Arc};
 
@@ -71,6 +74,129 @@ async fn get_metadata_bytes(
     Ok(bytes)
 }
 
+// key-value metadata entries pointing at an index embedded between the last row group and the footer
+pub const EMBEDDED_LAVA_OFFSET_KEY: &str = "rottnest.lava.offset";
+pub const EMBEDDED_LAVA_LENGTH_KEY: &str = "rottnest.lava.length";
+const PARQUET_MAGIC: &[u8; 4] = b"PAR1";
+
+/// Returns the (offset, length) of the index embedded in a Parquet file, if there is one.
+pub(crate) async fn get_embedded_lava_range(
+    reader: &mut AsyncReader,
+    file_size: usize,
+) -> Result<Option<(u64, u64)>, LavaError> {
+    let metadata_bytes = get_metadata_bytes(reader, file_size).await?;
+    let metadata = decode_metadata(metadata_bytes.to_byte_slice())?;
+
+    let lookup = |key: &str| -> Result<Option<u64>, LavaError> {
+        let value = metadata
+            .file_metadata()
+            .key_value_metadata()
+            .and_then(|kvs| kvs.iter().find(|kv| kv.key == key))
+            .and_then(|kv| kv.value.clone());
+        value
+            .map(|v| {
+                v.parse::<u64>()
+                    .map_err(|_| LavaError::Parse(format!("invalid value {} for {}", v, key)))
+            })
+            .transpose()
+    };
+
+    match (lookup(EMBEDDED_LAVA_OFFSET_KEY)?, lookup(EMBEDDED_LAVA_LENGTH_KEY)?) {
+        (Some(offset), Some(length)) => Ok(Some((offset, length))),
+        _ => Ok(None),
+    }
+}
+
+/// Appends the index in `lava_file` to the local Parquet file `parquet_file`, right before its
+/// footer, and registers it in the footer's key-value metadata. An index embedded earlier is
+/// replaced. Row group and page offsets are unaffected, so Parquet readers keep working.
+/// Both files are streamed, only the footer is held in memory. Object stores cannot append to a
+/// file in place, so remote files are rejected, embed locally and upload the result.
+pub fn embed_lava_in_parquet(lava_file: &str, parquet_file: &str) -> Result<(), LavaError> {
+    let is_remote = |file: &str| {
+        file.starts_with("s3://") || file.starts_with("http://") || file.starts_with("https://")
+    };
+    if is_remote(lava_file) || is_remote(parquet_file) {
+        return Err(LavaError::Unsupported(
+            "indices can only be embedded in local Parquet files".to_string(),
+        ));
+    }
+
+    let corrupt = || {
+        LavaError::from(ParquetError::General(
+            "Invalid Parquet file. Corrupt footer".to_string(),
+        ))
+    };
+    let mut parquet = File::open(parquet_file)?;
+    let file_size = parquet.metadata()?.len() as usize;
+    if file_size < FOOTER_SIZE {
+        return Err(corrupt());
+    }
+    let mut footer = [0u8; FOOTER_SIZE];
+    parquet.seek(SeekFrom::Start((file_size - FOOTER_SIZE) as u64))?;
+    parquet.read_exact(&mut footer)?;
+    if &footer[4..] != PARQUET_MAGIC {
+        return Err(corrupt());
+    }
+    let metadata_len = decode_footer(&footer)?;
+    if FOOTER_SIZE + metadata_len > file_size {
+        return Err(corrupt());
+    }
+    let metadata_start = file_size - FOOTER_SIZE - metadata_len;
+
+    let mut old_metadata_bytes = vec![0u8; metadata_len];
+    parquet.seek(SeekFrom::Start(metadata_start as u64))?;
+    parquet.read_exact(&mut old_metadata_bytes)?;
+    let mut prot = TCompactInputProtocol::new(&old_metadata_bytes[..]);
+    let mut file_metadata = FileMetaData::read_from_in_protocol(&mut prot)?;
+
+    let mut key_value_metadata = file_metadata.key_value_metadata.take().unwrap_or_default();
+    let previous_offset = key_value_metadata
+        .iter()
+        .find(|kv| kv.key == EMBEDDED_LAVA_OFFSET_KEY)
+        .and_then(|kv| kv.value.as_ref())
+        .and_then(|v| v.parse::<usize>().ok());
+    key_value_metadata
+        .retain(|kv| kv.key != EMBEDDED_LAVA_OFFSET_KEY && kv.key != EMBEDDED_LAVA_LENGTH_KEY);
+
+    // a previously embedded index sits between the data and the footer, drop it.
+    let data_end = previous_offset.unwrap_or(metadata_start);
+    let lava_len = std::fs::metadata(lava_file)?.len();
+
+    key_value_metadata.push(KeyValue::new(
+        EMBEDDED_LAVA_OFFSET_KEY.to_string(),
+        Some(data_end.to_string()),
+    ));
+    key_value_metadata.push(KeyValue::new(
+        EMBEDDED_LAVA_LENGTH_KEY.to_string(),
+        Some(lava_len.to_string()),
+    ));
+    file_metadata.key_value_metadata = Some(key_value_metadata);
+
+    let mut metadata_bytes: Vec<u8> = Vec::new();
+    {
+        let mut prot = TCompactOutputProtocol::new(&mut metadata_bytes);
+        file_metadata.write_to_out_protocol(&mut prot)?;
+        TOutputProtocol::flush(&mut prot)?;
+    }
+
+    // write next to the file and rename, so a failure never leaves a truncated Parquet file behind
+    let tmp_file = format!("{}.tmp", parquet_file);
+    {
+        let mut output = BufWriter::new(File::create(&tmp_file)?);
+        parquet.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut (&mut parquet).take(data_end as u64), &mut output)?;
+        std::io::copy(&mut File::open(lava_file)?, &mut output)?;
+        output.write_all(&metadata_bytes)?;
+        output.write_all(&(metadata_bytes.len() as u32).to_le_bytes())?;
+        output.write_all(PARQUET_MAGIC)?;
+        output.flush()?;
+    }
+    std::fs::rename(&tmp_file, parquet_file)?;
+
+    Ok(())
+}
+
 pub(crate) fn decode_page(
     page_header: PageHeader,
     buffer: Bytes,
@@ -240,6 +366,143 @@ pub struct ParquetLayout {
     pub row_group_data_pages: Vec<usize>,
 }
 
+/// Splits the bytes of one column chunk into pages and records their layout. Returns the decoded
+/// pages, to be turned into arrays by `decode_string_pages`, and the number of values in them.
+pub(crate) fn parse_column_chunk(
+    column_chunk_bytes: &Bytes,
+    column_chunk_offset: u64,
+    compression_scheme: parquet::basic::Compression,
+    parquet_layout: &mut ParquetLayout,
+) -> Result<(Vec<Page>, usize), LavaError> {
+    let codec_options = CodecOptionsBuilder::default()
+        .set_backward_compatible_lz4(false)
+        .build();
+    let mut codec = create_codec(compression_scheme, &codec_options).unwrap();
+
+    let mut total_data_pages: usize = 0;
+    let mut total_values: usize = 0;
+    let mut column_chunk_pages: Vec<parquet::column::page::Page> = Vec::new();
+
+    let end = column_chunk_bytes.len() as u64;
+    let mut start: u64 = 0;
+
+    let mut dictionary_page_size: usize = 0;
+
+    while start != end {
+        // this takes a slice of the entire thing for each page, granted it won't read the entire thing,
+        // the thrift will terminate after reading the necessary things. @Rain the alternative is to feed it
+        // chunks at a time in a loop until a valid header is returned, like before how we are using the reader in rust-test
+
+        let (header_len, header) = read_page_header(column_chunk_bytes, start)?;
+        // println!("{} {} {:?}", start, header_len, header);
+
+        let page_header = header.clone();
+
+        let page: Page = match page_header.type_ {
+            PageType::DICTIONARY_PAGE => {
+                dictionary_page_size = page_header.compressed_page_size as usize + header_len;
+                let page: Page = decode_page(
+                    page_header,
+                    column_chunk_bytes.slice(
+                        (start as usize + header_len)
+                            ..(start as usize + dictionary_page_size as usize),
+                    ),
+                    Type::BYTE_ARRAY,
+                    codec.as_mut(),
+                )
+                .unwrap();
+                start += dictionary_page_size as u64;
+                page
+            }
+            PageType::DATA_PAGE | PageType::DATA_PAGE_V2 => {
+                let compressed_page_size = page_header.compressed_page_size;
+                parquet_layout
+                    .data_page_sizes
+                    .push(compressed_page_size as usize + header_len);
+                parquet_layout
+                    .data_page_offsets
+                    .push((column_chunk_offset + start) as usize);
+
+                parquet_layout
+                    .dictionary_page_sizes
+                    .push(dictionary_page_size);
+                total_data_pages += 1;
+
+                let page = decode_page(
+                    page_header,
+                    column_chunk_bytes.slice(
+                        (start as usize + header_len)
+                            ..(start as usize + header_len + compressed_page_size as usize),
+                    ),
+                    Type::BYTE_ARRAY,
+                    codec.as_mut(),
+                )
+                .unwrap();
+
+                parquet_layout
+                    .data_page_num_rows
+                    .push(page.num_values() as usize);
+                total_values += page.num_values() as usize;
+
+                start += compressed_page_size as u64 + header_len as u64;
+                page
+            }
+            _ => {
+                // For unknown page type (e.g., INDEX_PAGE), skip and read next.
+                unimplemented!("Page type {:?} is not supported", page_header.type_)
+            }
+        };
+
+        column_chunk_pages.push(page);
+    }
+
+    parquet_layout.row_group_data_pages.push(total_data_pages);
+    Ok((column_chunk_pages, total_values))
+}
+
+/// Decodes pages of a string or binary column, one `Vec<Page>` per column chunk, into arrays.
+pub(crate) fn decode_string_pages(
+    pages: Vec<Vec<Page>>,
+    column_descriptor: ColumnDescPtr,
+    total_values: usize,
+) -> Result<Vec<ArrayData>, LavaError> {
+    let page_iterator = InMemoryPageIterator::new(pages);
+    let mut array_reader =
+        make_byte_array_reader(Box::new(page_iterator), column_descriptor, None).unwrap();
+    // let array = array_reader.next_batch(total_values as usize).unwrap();
+
+    // instead of reading in total_values at once, we need to read 10_000 at a time and collect results into a Vec<Arc<dyn Array>>
+
+    let mut arrays: Vec<ArrayData> = Vec::new();
+
+    for _ in (0..total_values).step_by(10_000) {
+        let array = array_reader.next_batch(10_000).unwrap();
+        let new_array: Result<
+            &arrow_array::GenericByteArray<arrow::datatypes::GenericStringType<i32>>,
+            ArrowError,
+        > = array.as_any().downcast_ref::<StringArray>().ok_or_else(|| {
+            ArrowError::ParseError("Expects string array as first argument".to_string())
+        });
+
+        let data = match new_array {
+            Ok(_) => new_array.unwrap().to_data(),
+            Err(_) => array
+                .as_any()
+                .downcast_ref::<BinaryArray>()
+                .ok_or_else(|| {
+                    ArrowError::ParseError(
+                        "Expects string or binary array as first argument".to_string(),
+                    )
+                })
+                .unwrap()
+                .to_data(),
+        };
+        arrays.push(data);
+    }
+
+    Ok(arrays)
+}
+
 #[tokio::main]
 pub async fn get_parquet_layout(
     column_name: &str,
@@ -251,10 +514,6 @@ pub async fn get_parquet_layout(
     let metadata_bytes = get_metadata_bytes(&mut reader, file_size as usize).await?;
     let metadata = decode_metadata(metadata_bytes.to_byte_slice()).map_err(LavaError::from)?;
 
-    let codec_options = CodecOptionsBuilder::default()
-        .set_backward_compatible_lz4(false)
-        .build();
-
     let mut parquet_layout = ParquetLayout {
         num_row_groups: metadata.num_row_groups(),
         metadata_bytes: metadata_bytes,
@@ -284,136 +543,28 @@ pub async fn get_parquet_layout(
 
     for row_group in 0..metadata.num_row_groups() {
         let column = metadata.row_group(row_group).column(column_index);
-        let mut start = column
+        let start = column
             .dictionary_page_offset()
             .unwrap_or_else(|| column.data_page_offset()) as u64;
         let end = start + column.compressed_size() as u64;
 
-        let compression_scheme = column.compression();
-        let mut codec = create_codec(compression_scheme, &codec_options).unwrap();
-        //.unwrap();
-
-        let mut total_data_pages: usize = 0;
-
         let column_chunk_bytes = reader.read_range(start, end).await?;
 
-        let mut column_chunk_pages: Vec<parquet::column::page::Page> = Vec::new();
-
-        let end = end - start;
-        let column_chunk_offset = start;
-        start = 0;
-
-        let mut dictionary_page_size: usize = 0;
-
-        while start != end {
-            // this takes a slice of the entire thing for each page, granted it won't read the entire thing,
-            // the thrift will terminate after reading the necessary things. @Rain the alternative is to feed it
-            // chunks at a time in a loop until a valid header is returned, like before how we are using the reader in rust-test
-
-            let (header_len, header) = read_page_header(&column_chunk_bytes, start)?;
-            // println!("{} {} {:?}", start, header_len, header);
-
-            let page_header = header.clone();
-
-            let page: Page = match page_header.type_ {
-                PageType::DICTIONARY_PAGE => {
-                    dictionary_page_size = page_header.compressed_page_size as usize + header_len;
-                    let page: Page = decode_page(
-                        page_header,
-                        column_chunk_bytes.slice(
-                            (start as usize + header_len)
-                                ..(start as usize + dictionary_page_size as usize),
-                        ),
-                        Type::BYTE_ARRAY,
-                        codec.as_mut(),
-                    )
-                    .unwrap();
-                    start += dictionary_page_size as u64;
-                    page
-                }
-                PageType::DATA_PAGE | PageType::DATA_PAGE_V2 => {
-                    let compressed_page_size = page_header.compressed_page_size;
-                    parquet_layout
-                        .data_page_sizes
-                        .push(compressed_page_size as usize + header_len);
-                    parquet_layout
-                        .data_page_offsets
-                        .push((column_chunk_offset + start) as usize);
-
-                    parquet_layout
-                        .dictionary_page_sizes
-                        .push(dictionary_page_size);
-                    total_data_pages += 1;
-
-                    let page = decode_page(
-                        page_header,
-                        column_chunk_bytes.slice(
-                            (start as usize + header_len)
-                                ..(start as usize + header_len + compressed_page_size as usize),
-                        ),
-                        Type::BYTE_ARRAY,
-                        codec.as_mut(),
-                    )
-                    .unwrap();
-
-                    parquet_layout
-                        .data_page_num_rows
-                        .push(page.num_values() as usize);
-                    total_values += page.num_values() as usize;
-
-                    start += compressed_page_size as u64 + header_len as u64;
-                    page
-                }
-                _ => {
-                    // For unknown page type (e.g., INDEX_PAGE), skip and read next.
-                    unimplemented!("Page type {:?} is not supported", page_header.type_)
-                }
-            };
-
-            column_chunk_pages.push(page);
-        }
-
+        let (column_chunk_pages, num_values) = parse_column_chunk(
+            &column_chunk_bytes,
+            start,
+            column.compression(),
+            &mut parquet_layout,
+        )?;
+        total_values += num_values;
         pages.push(column_chunk_pages);
-        parquet_layout.row_group_data_pages.push(total_data_pages);
     }
 
-    let page_iterator = InMemoryPageIterator::new(pages);
-    let mut array_reader = make_byte_array_reader(
-        Box::new(page_iterator),
+    let arrays = decode_string_pages(
+        pages,
         metadata.row_group(0).schema_descr().column(column_index),
-        None,
-    )
-    .unwrap();
-    // let array = array_reader.next_batch(total_values as usize).unwrap();
-
-    // instead of reading in total_values at once, we need to read 10_000 at a time and collect results into a Vec<Arc<dyn Array>>
-
-    let mut arrays: Vec<ArrayData> = Vec::new();
-
-    for _ in (0..total_values).step_by(10_000) {
-        let array = array_reader.next_batch(10_000).unwrap();
-        let new_array: Result<
-            &arrow_array::GenericByteArray<arrow::datatypes::GenericStringType<i32>>,
-            ArrowError,
-        > = array.as_any().downcast_ref::<StringArray>().ok_or_else(|| {
-            ArrowError::ParseError("Expects string array as first argument".to_string())
-        });
-
-        let data = match new_array {
-            Ok(_) => new_array.unwrap().to_data(),
-            Err(_) => array
-                .as_any()
-                .downcast_ref::<BinaryArray>()
-                .ok_or_else(|| {
-                    ArrowError::ParseError(
-                        "Expects string or binary array as first argument".to_string(),
-                    )
-                })
-                .unwrap()
-                .to_data(),
-        };
-        arrays.push(data);
-    }
+        total_values,
+    )?;
 
     Ok((arrays, parquet_layout))
 }
@@ -646,3 +797,67 @@ pub fn read_indexed_pages(
     rt.shutdown_background();
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lava::{build_lava_substring_char, search_lava_substring_char_occurrences};
+    use arrow_array::{LargeStringArray, RecordBatch, UInt64Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use parquet::arrow::ArrowWriter;
+
+    #[test]
+    fn test_embed_lava_in_parquet() {
+        let dir = std::env::temp_dir().join(format!("rottnest_embed_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let parquet_file = dir.join("data.parquet").to_str().unwrap().to_string();
+        let lava_file = dir.join("data.lava").to_str().unwrap().to_string();
+
+        let texts: Vec<String> = (0..100)
+            .map(|i| format!("row {} {}", i, if i == 42 { "needle" } else { "hay" }))
+            .collect();
+        let column = LargeStringArray::from(texts.clone());
+        let batch =
+            RecordBatch::try_from_iter(vec![("text", Arc::new(column.clone()) as _)]).unwrap();
+        let mut writer =
+            ArrowWriter::try_new(File::create(&parquet_file).unwrap(), batch.schema(), None)
+                .unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let uids = UInt64Array::from((0..100).collect::<Vec<u64>>());
+        build_lava_substring_char(
+            lava_file.clone(),
+            column.to_data(),
+            uids.to_data(),
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        embed_lava_in_parquet(&lava_file, &parquet_file).unwrap();
+        // embedding again replaces the first index
+        embed_lava_in_parquet(&lava_file, &parquet_file).unwrap();
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&parquet_file).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<RecordBatch> = reader.map(|batch| batch.unwrap()).collect();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0], batch);
+
+        let result = search_lava_substring_char_occurrences(
+            vec![parquet_file.clone()],
+            "needle".to_string(),
+            10,
+            ReaderType::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(result, vec![(0, 42, 1, vec![])]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
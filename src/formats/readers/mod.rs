@@ -1,4 +1,4 @@
-use crate::formats::cache;
+use crate::formats::{cache, parquet};
 use crate::lava::error::LavaError;
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -31,6 +31,9 @@ pub const WRITER_BUFFER_SIZE: usize = 4 * 1024 * 1024;
 pub struct AsyncReader {
     pub reader: ClonableAsyncReader,
     pub filename: String,
+    // (start, length) of a region of the file that should be read as if it were the whole file,
+    // used for indices embedded in another file.
+    pub window: Option<(u64, u64)>,
 }
 
 impl Deref for AsyncReader {
@@ -56,6 +59,7 @@ impl Clone for AsyncReader {
                 ClonableAsyncReader::Http(reader) => ClonableAsyncReader::Http(reader.clone()),
             },
             filename: self.filename.clone(),
+            window: self.window,
         }
     }
 }
@@ -94,7 +98,16 @@ pub static READ_RANGE_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 impl AsyncReader {
     pub fn new(reader: ClonableAsyncReader, filename: String) -> Self {
-        Self { reader, filename }
+        Self {
+            reader,
+            filename,
+            window: None,
+        }
+    }
+
+    pub fn with_window(mut self, start: u64, length: u64) -> Self {
+        self.window = Some((start, length));
+        self
     }
 
     pub fn update_filename(&mut self, filename: String) -> Result<(), LavaError> {
@@ -136,7 +149,15 @@ impl AsyncReader {
             }
         }
 
-        self.deref_mut().read_range(from, to).await
+        match self.window {
+            Some((start, length)) => {
+                if to > length {
+                    return Err(LavaError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                }
+                self.deref_mut().read_range(start + from, start + to).await
+            }
+            None => self.deref_mut().read_range(from, to).await,
+        }
     }
 
     // theoretically we should try to return different types here, but Vec<u64> is def. the most common
@@ -155,9 +176,27 @@ impl AsyncReader {
     }
 
     pub async fn read_usize_from_end(&mut self, n: u64) -> Result<Vec<u64>, LavaError> {
-        self.deref_mut()
-            .read_usize_from_end(-8 * (n as i64), n)
-            .await
+        match self.window {
+            Some((_, length)) => self.read_usize_from_start(length - 8 * n, n).await,
+            None => {
+                self.deref_mut()
+                    .read_usize_from_end(-8 * (n as i64), n)
+                    .await
+            }
+        }
+    }
+
+    pub async fn read_usize_from_start(&mut self, offset: u64, n: u64) -> Result<Vec<u64>, LavaError> {
+        match self.window {
+            Some(_) => {
+                let bytes = self.read_range(offset, offset + 8 * n).await?;
+                Ok(bytes
+                    .chunks_exact(8)
+                    .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+                    .collect())
+            }
+            None => self.deref_mut().read_usize_from_start(offset, n).await,
+        }
     }
 }
 
@@ -219,6 +258,29 @@ pub async fn get_file_sizes_and_readers(
     Ok((file_sizes, readers))
 }
 
+/// Opens files that are searched as indices. Parquet files are expected to carry an embedded
+/// index, their readers are narrowed to it and the reported size is the size of the index.
+pub async fn get_index_file_sizes_and_readers(
+    files: &[String],
+    reader_type: ReaderType,
+) -> Result<(Vec<usize>, Vec<AsyncReader>), LavaError> {
+    let (mut file_sizes, readers) = get_file_sizes_and_readers(files, reader_type).await?;
+
+    let mut index_readers = Vec::with_capacity(readers.len());
+    for ((file, file_size), mut reader) in files.iter().zip(file_sizes.iter_mut()).zip(readers) {
+        if file.ends_with(".parquet") {
+            let (start, length) = parquet::get_embedded_lava_range(&mut reader, *file_size)
+                .await?
+                .ok_or_else(|| LavaError::Parse(format!("no rottnest index embedded in {}", file)))?;
+            reader = reader.with_window(start, length);
+            *file_size = length as usize;
+        }
+        index_readers.push(reader);
+    }
+
+    Ok((file_sizes, index_readers))
+}
+
 pub async fn get_readers(
     files: &[String],
     reader_type: ReaderType,
@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use tokenizers::normalizers::StripAccents;
+use tokenizers::tokenizer::{NormalizedString, Normalizer};
+
+use super::stemmer::stem_english;
+use crate::lava::error::LavaError;
+
+// Lucene's english stop words, sorted for the binary search
+const ENGLISH_STOP_WORDS: [&str; 33] = [
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+fn default_language() -> String {
+    "english".to_string()
+}
+
+/// One step of the analysis chain, applied to every token in order. A filter can drop a token.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "filter", rename_all = "snake_case")]
+pub(crate) enum TokenFilter {
+    Lowercase,
+    Nfkc,
+    /// Strips accents and replaces the letters that have no decomposition, "Æøß" gives "aeoss".
+    AsciiFolding,
+    /// Drops the given words, or the english stop words without a list. Put it after the
+    /// filters that normalize the tokens, the words are compared as they are.
+    StopWords {
+        #[serde(default)]
+        words: Option<Vec<String>>,
+    },
+    Stemmer {
+        #[serde(default = "default_language")]
+        language: String,
+    },
+}
+
+fn fold_letter(c: char) -> Option<&'static str> {
+    Some(match c {
+        'ß' => "ss",
+        'æ' => "ae",
+        'Æ' => "AE",
+        'œ' => "oe",
+        'Œ' => "OE",
+        'ø' => "o",
+        'Ø' => "O",
+        'ł' => "l",
+        'Ł' => "L",
+        'đ' | 'ð' => "d",
+        'Đ' | 'Ð' => "D",
+        'þ' => "th",
+        'Þ' => "TH",
+        'ı' => "i",
+        _ => return None,
+    })
+}
+
+impl TokenFilter {
+    pub(crate) fn validate(&mut self) -> Result<(), LavaError> {
+        match self {
+            TokenFilter::StopWords { words: Some(words) } => {
+                words.sort();
+                words.dedup();
+            }
+            TokenFilter::Stemmer { language } if language != "english" => {
+                return Err(LavaError::Unsupported(format!(
+                    "no stemmer for {}, only english is supported",
+                    language
+                )));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Whether the filter only rewrites characters, those also apply to wildcard patterns.
+    pub(crate) fn normalizes(&self) -> bool {
+        matches!(
+            self,
+            TokenFilter::Lowercase | TokenFilter::Nfkc | TokenFilter::AsciiFolding
+        )
+    }
+
+    pub(crate) fn apply(&self, token: String) -> Option<String> {
+        match self {
+            TokenFilter::Lowercase => Some(token.to_lowercase()),
+            TokenFilter::Nfkc => {
+                let mut normalized = NormalizedString::from(token.as_str());
+                normalized.nfkc();
+                Some(normalized.get().to_string())
+            }
+            TokenFilter::AsciiFolding => {
+                if token.is_ascii() {
+                    return Some(token);
+                }
+                let mut normalized = NormalizedString::from(token.as_str());
+                normalized.nfd();
+                StripAccents.normalize(&mut normalized).ok()?;
+                let mut folded = String::with_capacity(token.len());
+                for c in normalized.get().chars() {
+                    match fold_letter(c) {
+                        Some(replacement) => folded.push_str(replacement),
+                        None => folded.push(c),
+                    }
+                }
+                Some(folded)
+            }
+            TokenFilter::StopWords { words } => {
+                let stop = match words {
+                    Some(words) => words.binary_search(&token).is_ok(),
+                    None => ENGLISH_STOP_WORDS.binary_search(&token.as_str()).is_ok(),
+                };
+                (!stop).then_some(token)
+            }
+            TokenFilter::Stemmer { .. } => Some(stem_english(&token)),
+        }
+        .filter(|token| !token.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters() {
+        let chain = |filters: &[TokenFilter], token: &str| {
+            filters
+                .iter()
+                .try_fold(token.to_string(), |token, filter| filter.apply(token))
+        };
+        let filters = vec![
+            TokenFilter::Nfkc,
+            TokenFilter::Lowercase,
+            TokenFilter::AsciiFolding,
+            TokenFilter::StopWords { words: None },
+            TokenFilter::Stemmer {
+                language: "english".to_string(),
+            },
+        ];
+        assert_eq!(chain(&filters, "Connections").as_deref(), Some("connect"));
+        assert_eq!(chain(&filters, "Ｃａｆé").as_deref(), Some("cafe"));
+        assert_eq!(chain(&filters, "Straße").as_deref(), Some("strass"));
+        assert_eq!(chain(&filters, "The"), None);
+        assert!(ENGLISH_STOP_WORDS.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::filters::TokenFilter;
+use super::{AnalyzerKind, BuiltinAnalyzer, DEFAULT_BUCKETS};
+use crate::lava::error::LavaError;
+
+/// Language of the texts whatlang cannot tell reliably, they go to the default analyzer.
+pub(crate) const UNDETERMINED: &str = "und";
+
+fn standard_languages() -> BTreeMap<String, BuiltinAnalyzer> {
+    let words = |filters: Vec<TokenFilter>| BuiltinAnalyzer {
+        kind: AnalyzerKind::UnicodeWords,
+        filters,
+        buckets: DEFAULT_BUCKETS,
+    };
+    BTreeMap::from([
+        (
+            "eng".to_string(),
+            words(vec![
+                TokenFilter::Lowercase,
+                TokenFilter::StopWords { words: None },
+                TokenFilter::Stemmer {
+                    language: "english".to_string(),
+                },
+            ]),
+        ),
+        (
+            "deu".to_string(),
+            words(vec![TokenFilter::Lowercase, TokenFilter::AsciiFolding]),
+        ),
+        // chinese has no spaces between words, unigrams and bigrams of the characters instead
+        (
+            "cmn".to_string(),
+            BuiltinAnalyzer {
+                kind: AnalyzerKind::CharNgram { min_n: 1, max_n: 2 },
+                filters: vec![TokenFilter::Lowercase],
+                buckets: DEFAULT_BUCKETS,
+            },
+        ),
+    ])
+}
+
+fn standard_default() -> BuiltinAnalyzer {
+    BuiltinAnalyzer {
+        kind: AnalyzerKind::UnicodeWords,
+        filters: vec![TokenFilter::Lowercase],
+        buckets: DEFAULT_BUCKETS,
+    }
+}
+
+/// Detects the language of every text and analyzes it with the analyzer of that language, keyed
+/// by whatlang code ("eng", "deu", "cmn", ...). Texts in other or undetermined languages go to
+/// `default`. All analyzers hash into the same buckets, so their tokens share one id space.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "analyzer", rename = "language")]
+pub(crate) struct LanguageAnalyzer {
+    #[serde(default = "standard_languages")]
+    pub languages: BTreeMap<String, BuiltinAnalyzer>,
+    #[serde(default = "standard_default")]
+    pub default: BuiltinAnalyzer,
+}
+
+impl LanguageAnalyzer {
+    pub(crate) fn validate(mut self) -> Result<Self, LavaError> {
+        self.default = self.default.validate()?;
+        for (code, analyzer) in std::mem::take(&mut self.languages) {
+            if whatlang::Lang::from_code(code.as_str()).is_none() {
+                return Err(LavaError::Parse(format!("unknown language code {}", code)));
+            }
+            if analyzer.buckets != self.default.buckets {
+                return Err(LavaError::Parse(
+                    "all analyzers of a language analyzer need the same buckets".to_string(),
+                ));
+            }
+            self.languages.insert(code, analyzer.validate()?);
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn buckets(&self) -> u32 {
+        self.default.buckets
+    }
+
+    /// The whatlang code of the text, `UNDETERMINED` when the detection is not reliable.
+    pub(crate) fn detect(text: &str) -> String {
+        match whatlang::detect(text) {
+            Some(info) if info.is_reliable() => info.lang().code().to_string(),
+            _ => UNDETERMINED.to_string(),
+        }
+    }
+
+    fn analyzer(&self, language: &str) -> &BuiltinAnalyzer {
+        self.languages.get(language).unwrap_or(&self.default)
+    }
+
+    pub(crate) fn encode_document(&self, text: &str) -> (Vec<u32>, String) {
+        let language = Self::detect(text);
+        (self.analyzer(&language).encode(text), language)
+    }
+
+    pub(crate) fn document_terms(&self, text: &str) -> Vec<String> {
+        self.analyzer(&Self::detect(text)).terms(text)
+    }
+
+    /// The ids of a term as filtered by each of the analyzers, the term does not tell which
+    /// language it came from. They all hash into the same buckets.
+    pub(crate) fn term_ids(&self, term: &str) -> Vec<u32> {
+        let mut ids: Vec<u32> = self
+            .languages
+            .values()
+            .chain([&self.default])
+            .filter_map(|analyzer| analyzer.term_id(term))
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    /// The pattern as normalized by each of the analyzers.
+    pub(crate) fn normalize_patterns(&self, pattern: &str) -> Vec<String> {
+        let mut patterns: Vec<String> = self
+            .languages
+            .values()
+            .chain([&self.default])
+            .map(|analyzer| analyzer.normalize(pattern))
+            .collect();
+        patterns.sort();
+        patterns.dedup();
+        patterns
+    }
+
+    /// Whether texts in `language` can be written in the script of the text, the default
+    /// analyzer and texts without letters fit any language.
+    fn fits_script(language: &str, script: Option<whatlang::Script>) -> bool {
+        match (whatlang::Lang::from_code(language), script) {
+            (Some(lang), Some(script)) => script.langs().contains(&lang),
+            _ => true,
+        }
+    }
+
+    /// The encoding with the analyzer of the query language. When that is ambiguous, one
+    /// encoding per analyzer used by the documents in `present` languages written in the script
+    /// of the query, or by any language if nothing is known about the documents.
+    pub(crate) fn encode_query(&self, text: &str, present: &BTreeSet<String>) -> Vec<Vec<u32>> {
+        let language = Self::detect(text);
+        if language != UNDETERMINED {
+            return vec![self.analyzer(&language).encode(text)];
+        }
+
+        let script = whatlang::detect_script(text);
+        let mut analyzers: Vec<&BuiltinAnalyzer> = if present.is_empty() {
+            self.languages
+                .iter()
+                .filter(|(language, _)| Self::fits_script(language, script))
+                .map(|(_, analyzer)| analyzer)
+                .chain([&self.default])
+                .collect()
+        } else {
+            present
+                .iter()
+                .filter(|language| Self::fits_script(language, script))
+                .map(|language| self.analyzer(language))
+                .collect()
+        };
+        if analyzers.is_empty() {
+            analyzers.push(&self.default);
+        }
+        analyzers.dedup_by(|a, b| a == b);
+        let mut encodings: Vec<Vec<u32>> = analyzers.iter().map(|a| a.encode(text)).collect();
+        encodings.sort();
+        encodings.dedup();
+        encodings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_language_routing() {
+        let analyzer = LanguageAnalyzer {
+            languages: standard_languages(),
+            default: standard_default(),
+        }
+        .validate()
+        .unwrap();
+
+        let (english, language) = analyzer
+            .encode_document("The connections between the servers were failing all night long");
+        assert_eq!(language, "eng");
+        assert_eq!(english.len(), 8);
+
+        let (_, language) =
+            analyzer.encode_document("Die Verbindungen zwischen den Servern sind heute ausgefallen");
+        assert_eq!(language, "deu");
+
+        let (chinese, language) = analyzer.encode_document("数据库连接超时");
+        assert_eq!(language, "cmn");
+        // 7 unigrams and 6 bigrams
+        assert_eq!(chinese.len(), 13);
+
+        // too short to tell, every analyzer of the present languages
+        let present = BTreeSet::from(["eng".to_string(), "deu".to_string(), "cmn".to_string()]);
+        assert_eq!(analyzer.encode_query("connections", &present).len(), 2);
+    }
+}
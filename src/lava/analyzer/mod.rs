@@ -0,0 +1,521 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::Read;
+use tokenizers::tokenizer::{NormalizedString, Normalizer, Tokenizer};
+use unicode_segmentation::UnicodeSegmentation;
+use zstd::stream::encode_all;
+use zstd::stream::read::Decoder;
+
+use crate::lava::error::LavaError;
+
+mod filters;
+mod language;
+mod stemmer;
+
+use filters::TokenFilter;
+use language::LanguageAnalyzer;
+
+/*
+Every lava file that tokenizes text starts with its compressed, serialized analyzer. That is either
+a Hugging Face tokenizer json, as it always was, or the json of a built-in analyzer, which is told
+apart by its "analyzer" key. Built-in analyzers are asked for with a "builtin:" prefix, e.g.
+"builtin:log", and one is the default. They need no vocabulary file or download: tokens are
+hashed into a fixed number of buckets, so the same token gets the same id in every file and files
+built separately can still be merged and searched together.
+
+The tokens of a built-in analyzer go through its filters, e.g.
+{"analyzer": "unicode_words", "filters": ["nfkc", "lowercase", "ascii_folding", "stop_words", "stemmer"]}
+Filters that take options are objects: {"filter": "stop_words", "words": ["a", "the"]}. The chain
+is part of the stored analyzer, so queries are analyzed exactly like the indexed text.
+
+{"analyzer": "language"} detects the language of every document and picks the analyzer for it,
+see `LanguageAnalyzer`.
+*/
+
+const DEFAULT_BUCKETS: u32 = 1 << 18;
+
+fn default_filters() -> Vec<TokenFilter> {
+    vec![TokenFilter::Lowercase]
+}
+
+fn default_buckets() -> u32 {
+    DEFAULT_BUCKETS
+}
+
+fn default_min_n() -> usize {
+    3
+}
+
+fn default_max_n() -> usize {
+    3
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "analyzer", rename_all = "snake_case")]
+pub(crate) enum AnalyzerKind {
+    /// Unicode (UAX #29) word boundaries, punctuation is dropped.
+    UnicodeWords,
+    /// Splits on whitespace only.
+    Whitespace,
+    /// Character n-grams of every run of letters and digits, shorter runs are kept whole. Runs
+    /// are not split at word boundaries, so this also works for scripts without spaces.
+    CharNgram {
+        #[serde(default = "default_min_n")]
+        min_n: usize,
+        #[serde(default = "default_max_n")]
+        max_n: usize,
+    },
+    /// For log lines: splits on punctuation and whitespace, then on camelCase and letter/digit
+    /// boundaries, e.g. "getUserID=42" gives get, user, id, 42.
+    Log,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BuiltinAnalyzer {
+    #[serde(flatten)]
+    pub kind: AnalyzerKind,
+    #[serde(default = "default_filters")]
+    pub filters: Vec<TokenFilter>,
+    #[serde(default = "default_buckets")]
+    pub buckets: u32,
+}
+
+pub(crate) enum Analyzer {
+    HuggingFace(Box<Tokenizer>),
+    Builtin(BuiltinAnalyzer),
+    Language(LanguageAnalyzer),
+}
+
+// FNV-1a, stable across platforms and releases unlike the std hasher
+fn hash_token(token: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in token.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Splits a run of letters and digits at camelCase and letter/digit boundaries. An upper case
+/// letter followed by a lower case one starts a new word, so "HTTPServer" gives HTTP, Server.
+fn split_log_word(word: &str, tokens: &mut Vec<String>) {
+    let chars: Vec<char> = word.chars().collect();
+    let mut start = 0;
+    for i in 1..chars.len() {
+        let (prev, cur) = (chars[i - 1], chars[i]);
+        let boundary = (prev.is_lowercase() && cur.is_uppercase())
+            || (prev.is_alphabetic() != cur.is_alphabetic())
+            || (prev.is_uppercase()
+                && cur.is_uppercase()
+                && chars.get(i + 1).map_or(false, |next| next.is_lowercase()));
+        if boundary {
+            tokens.push(chars[start..i].iter().collect());
+            start = i;
+        }
+    }
+    if start < chars.len() {
+        tokens.push(chars[start..].iter().collect());
+    }
+}
+
+/// Marks a built-in analyzer where a tokenizer file is expected, so a file named "log" is a file.
+const BUILTIN_PREFIX: &str = "builtin:";
+
+/// A built-in analyzer from its name, e.g. "builtin:log", or its json, e.g.
+/// builtin:{"analyzer": "char_ngram", "min_n": 2, "max_n": 4}. None without the prefix.
+fn parse_spec(spec: &str) -> Option<Result<serde_json::Value, LavaError>> {
+    let spec = spec.trim().strip_prefix(BUILTIN_PREFIX)?.trim();
+    let json = if spec.starts_with('{') {
+        spec.to_string()
+    } else if matches!(
+        spec,
+        "unicode_words" | "whitespace" | "char_ngram" | "log" | "language"
+    ) {
+        format!("{{\"analyzer\": \"{}\"}}", spec)
+    } else {
+        return Some(Err(LavaError::Parse(format!(
+            "unknown built-in analyzer {}, expected unicode_words, whitespace, char_ngram, log, \
+             language or a json object",
+            spec
+        ))));
+    };
+    Some(
+        serde_json::from_str(&json)
+            .map(|mut value| {
+                expand_filters(&mut value);
+                value
+            })
+            .map_err(|e| LavaError::Parse(format!("invalid analyzer {}: {}", spec, e))),
+    )
+}
+
+// filters without options can be given by name, also in nested analyzers
+fn expand_filters(value: &mut serde_json::Value) {
+    if let serde_json::Value::Object(map) = value {
+        for (key, value) in map.iter_mut() {
+            if key == "filters" {
+                if let Some(filters) = value.as_array_mut() {
+                    for filter in filters.iter_mut() {
+                        if filter.is_string() {
+                            *filter = serde_json::json!({ "filter": filter.take() });
+                        }
+                    }
+                }
+            } else {
+                expand_filters(value);
+            }
+        }
+    }
+}
+
+impl BuiltinAnalyzer {
+    fn validate(mut self) -> Result<Self, LavaError> {
+        for filter in self.filters.iter_mut() {
+            filter.validate()?;
+        }
+        if self.buckets == 0 {
+            return Err(LavaError::Parse("analyzer needs at least one bucket".to_string()));
+        }
+        if let AnalyzerKind::CharNgram { min_n, max_n } = self.kind {
+            if min_n == 0 || min_n > max_n {
+                return Err(LavaError::Parse(format!(
+                    "invalid n-gram range {}..={}",
+                    min_n, max_n
+                )));
+            }
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn tokens(&self, text: &str) -> Vec<String> {
+        self.words(text)
+            .into_iter()
+            .filter_map(|token| self.filter(token))
+            .collect()
+    }
+
+    /// The tokens of a text before the filters.
+    fn words(&self, text: &str) -> Vec<String> {
+        let mut tokens: Vec<String> = vec![];
+        match &self.kind {
+            AnalyzerKind::UnicodeWords => {
+                tokens.extend(text.unicode_words().map(|word| word.to_string()))
+            }
+            AnalyzerKind::Whitespace => {
+                tokens.extend(text.split_whitespace().map(|word| word.to_string()))
+            }
+            AnalyzerKind::CharNgram { min_n, max_n } => {
+                for word in text.split(|c: char| !c.is_alphanumeric()) {
+                    let chars: Vec<char> = word.chars().collect();
+                    if chars.is_empty() {
+                        continue;
+                    }
+                    if chars.len() < *min_n {
+                        tokens.push(word.to_string());
+                        continue;
+                    }
+                    for n in *min_n..=(*max_n).min(chars.len()) {
+                        tokens.extend(chars.windows(n).map(|gram| gram.iter().collect::<String>()));
+                    }
+                }
+            }
+            AnalyzerKind::Log => {
+                for word in text.split(|c: char| !c.is_alphanumeric()) {
+                    split_log_word(word, &mut tokens);
+                }
+            }
+        }
+        tokens
+    }
+
+    fn filter(&self, token: String) -> Option<String> {
+        self.filters
+            .iter()
+            .try_fold(token, |token, filter| filter.apply(token))
+    }
+
+    /// The tokens of a text that are indexed, with only their characters normalized. Unlike the
+    /// tokens they are not stemmed, so wildcard patterns match the words as written.
+    pub(crate) fn terms(&self, text: &str) -> Vec<String> {
+        self.words(text)
+            .into_iter()
+            .filter(|word| self.filter(word.clone()).is_some())
+            .map(|word| self.normalize(&word))
+            .collect()
+    }
+
+    /// The token id of a term, after the filters that `terms` left out. None for stop words.
+    fn term_id(&self, term: &str) -> Option<u32> {
+        self.filter(term.to_string()).map(|token| self.token_id(&token))
+    }
+
+    pub(crate) fn encode(&self, text: &str) -> Vec<u32> {
+        self.tokens(text)
+            .iter()
+            .map(|token| self.token_id(token))
+            .collect()
+    }
+
+    fn token_id(&self, token: &str) -> u32 {
+        (hash_token(token) % self.buckets as u64) as u32
+    }
+
+    /// Applies the filters that only rewrite characters, e.g. to a wildcard pattern.
+    pub(crate) fn normalize(&self, text: &str) -> String {
+        self.filters
+            .iter()
+            .filter(|filter| filter.normalizes())
+            .fold(text.to_string(), |text, filter| {
+                filter.apply(text.clone()).unwrap_or(text)
+            })
+    }
+}
+
+impl Analyzer {
+    /// `tokenizer` is a Hugging Face tokenizer json file or a built-in analyzer (see
+    /// `parse_spec`). Without it the offline unicode_words analyzer is used. Also returns
+    /// the compressed analyzer that goes into the file header.
+    pub(crate) fn load(tokenizer: Option<String>) -> Result<(Self, Vec<u8>), LavaError> {
+        let analyzer = match tokenizer {
+            Some(tokenizer) => match parse_spec(&tokenizer) {
+                Some(value) => Analyzer::from_value(value?)?,
+                None => {
+                    if !std::path::Path::new(&tokenizer).exists() {
+                        return Err(LavaError::Parse(format!(
+                            "Tokenizer file {} does not exist, built-in analyzers are passed as \
+                             {}name",
+                            tokenizer, BUILTIN_PREFIX
+                        )));
+                    }
+                    Analyzer::HuggingFace(Box::new(Tokenizer::from_file(tokenizer)?))
+                }
+            },
+            None => Analyzer::from_value(serde_json::json!({"analyzer": "unicode_words"}))?,
+        };
+
+        let serialized = match &analyzer {
+            Analyzer::HuggingFace(tokenizer) => serde_json::to_string(tokenizer.as_ref()),
+            Analyzer::Builtin(builtin) => serde_json::to_string(builtin),
+            Analyzer::Language(language) => serde_json::to_string(language),
+        }
+        .map_err(|e| LavaError::Parse(format!("failed to serialize analyzer: {}", e)))?;
+        let compressed = encode_all(serialized.as_bytes(), 0)
+            .map_err(|e| LavaError::Compression(e.to_string()))?;
+        Ok((analyzer, compressed))
+    }
+
+    pub(crate) fn from_compressed(compressed: &[u8]) -> Result<Self, LavaError> {
+        let mut serialized: Vec<u8> = Vec::with_capacity(compressed.len());
+        Decoder::new(compressed)?.read_to_end(&mut serialized)?;
+
+        let value: serde_json::Value = serde_json::from_slice(&serialized)
+            .map_err(|e| LavaError::Parse(format!("failed to load embedded tokenizer: {}", e)))?;
+        if value.get("analyzer").is_some() {
+            return Analyzer::from_value(value);
+        }
+        Tokenizer::from_bytes(serialized)
+            .map(|tokenizer| Analyzer::HuggingFace(Box::new(tokenizer)))
+            .map_err(|e| LavaError::Parse(format!("failed to load embedded tokenizer: {}", e)))
+    }
+
+    fn from_value(value: serde_json::Value) -> Result<Self, LavaError> {
+        let invalid = |e: serde_json::Error| LavaError::Parse(format!("invalid analyzer: {}", e));
+        match value.get("analyzer").and_then(|a| a.as_str()) {
+            Some("language") => {
+                let language: LanguageAnalyzer = serde_json::from_value(value).map_err(invalid)?;
+                Ok(Analyzer::Language(language.validate()?))
+            }
+            Some("sparse") => Err(LavaError::Unsupported(
+                "learned sparse files have no analyzer, search them with a sparse query vector"
+                    .to_string(),
+            )),
+            _ => {
+                let builtin: BuiltinAnalyzer = serde_json::from_value(value).map_err(invalid)?;
+                Ok(Analyzer::Builtin(builtin.validate()?))
+            }
+        }
+    }
+
+    pub(crate) fn encode(&self, text: &str) -> Result<Vec<u32>, LavaError> {
+        match self {
+            Analyzer::HuggingFace(tokenizer) => Ok(tokenizer
+                .encode(text, false)
+                .map_err(|e| LavaError::Parse(format!("failed to tokenize: {}", e)))?
+                .get_ids()
+                .to_vec()),
+            Analyzer::Builtin(builtin) => Ok(builtin.encode(text)),
+            Analyzer::Language(language) => Ok(language.encode_document(text).0),
+        }
+    }
+
+    /// Also returns the language of the text if this analyzer detects it.
+    pub(crate) fn encode_document(
+        &self,
+        text: &str,
+    ) -> Result<(Vec<u32>, Option<String>), LavaError> {
+        match self {
+            Analyzer::Language(language) => {
+                let (encoding, language) = language.encode_document(text);
+                Ok((encoding, Some(language)))
+            }
+            _ => Ok((self.encode(text)?, None)),
+        }
+    }
+
+    /// Every way the query may have to be analyzed, the first one is the most likely. Only a
+    /// language analyzer gives more than one, when the query language is ambiguous, see
+    /// `LanguageAnalyzer::encode_query`.
+    pub(crate) fn encode_query(
+        &self,
+        text: &str,
+        present_languages: &BTreeSet<String>,
+    ) -> Result<Vec<Vec<u32>>, LavaError> {
+        match self {
+            Analyzer::Language(language) => Ok(language.encode_query(text, present_languages)),
+            _ => Ok(vec![self.encode(text)?]),
+        }
+    }
+
+    /// Whether token ids are hashes of the terms, the files then keep the terms themselves.
+    pub(crate) fn hashes_terms(&self) -> bool {
+        !matches!(self, Analyzer::HuggingFace(_))
+    }
+
+    /// The terms of a text before they become token ids.
+    pub(crate) fn document_terms(&self, text: &str) -> Result<Vec<String>, LavaError> {
+        match self {
+            Analyzer::HuggingFace(tokenizer) => Ok(tokenizer
+                .encode(text, false)
+                .map_err(|e| LavaError::Parse(format!("failed to tokenize: {}", e)))?
+                .get_tokens()
+                .to_vec()),
+            Analyzer::Builtin(builtin) => Ok(builtin.terms(text)),
+            Analyzer::Language(language) => Ok(language.document_terms(text)),
+        }
+    }
+
+    /// The token ids of a term of `document_terms`, none if a tokenizer does not know it.
+    pub(crate) fn term_ids(&self, term: &str) -> Vec<u32> {
+        match self {
+            Analyzer::HuggingFace(tokenizer) => tokenizer.token_to_id(term).into_iter().collect(),
+            Analyzer::Builtin(builtin) => builtin.term_id(term).into_iter().collect(),
+            Analyzer::Language(language) => language.term_ids(term),
+        }
+    }
+
+    /// The terms of a tokenizer vocabulary, None for analyzers that hash their terms.
+    pub(crate) fn vocabulary_terms(&self) -> Option<Vec<String>> {
+        match self {
+            Analyzer::HuggingFace(tokenizer) => {
+                Some(tokenizer.get_vocab(false).into_keys().collect())
+            }
+            _ => None,
+        }
+    }
+
+    /// A wildcard pattern normalized the way the analyzer normalizes the characters of its terms,
+    /// once for every way it may do that.
+    pub(crate) fn normalize_patterns(&self, pattern: &str) -> Result<Vec<String>, LavaError> {
+        match self {
+            Analyzer::HuggingFace(tokenizer) => match tokenizer.get_normalizer() {
+                Some(normalizer) => {
+                    let mut normalized = NormalizedString::from(pattern);
+                    normalizer.normalize(&mut normalized)?;
+                    Ok(vec![normalized.get().to_string()])
+                }
+                None => Ok(vec![pattern.to_string()]),
+            },
+            Analyzer::Builtin(builtin) => Ok(vec![builtin.normalize(pattern)]),
+            Analyzer::Language(language) => Ok(language.normalize_patterns(pattern)),
+        }
+    }
+
+    /// Token ids are below this.
+    pub(crate) fn vocab_size(&self) -> usize {
+        match self {
+            Analyzer::HuggingFace(tokenizer) => tokenizer.get_vocab_size(false),
+            Analyzer::Builtin(builtin) => builtin.buckets as usize,
+            Analyzer::Language(language) => language.buckets() as usize,
+        }
+    }
+
+    /// The token of every id, built-in analyzers hash their tokens and have no vocabulary.
+    pub(crate) fn vocabulary(&self) -> Result<Vec<String>, LavaError> {
+        match self {
+            Analyzer::HuggingFace(tokenizer) => Ok((0..tokenizer.get_vocab_size(false))
+                .map(|i| tokenizer.decode(&[i as u32], false).unwrap())
+                .collect()),
+            Analyzer::Builtin(_) | Analyzer::Language(_) => Err(LavaError::Unsupported(
+                "built-in analyzers have no vocabulary, query them with text".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builtin(spec: &str) -> BuiltinAnalyzer {
+        let spec = format!("{}{}", BUILTIN_PREFIX, spec);
+        match Analyzer::from_value(parse_spec(&spec).unwrap().unwrap()).unwrap() {
+            Analyzer::Builtin(builtin) => builtin,
+            _ => panic!("not a built-in analyzer"),
+        }
+    }
+
+    #[test]
+    fn test_builtin_tokens() {
+        assert_eq!(
+            builtin("unicode_words").tokens("Hello, wörld! can't"),
+            vec!["hello", "wörld", "can't"]
+        );
+        assert_eq!(builtin("whitespace").tokens("a-b  C"), vec!["a-b", "c"]);
+        assert_eq!(
+            builtin("{\"analyzer\": \"char_ngram\", \"min_n\": 2, \"max_n\": 3}").tokens("abcd x"),
+            vec!["ab", "bc", "cd", "abc", "bcd", "x"]
+        );
+        assert_eq!(
+            builtin("log").tokens("ERROR getUserID=42 in HTTPServer/v2"),
+            vec!["error", "get", "user", "id", "42", "in", "http", "server", "v", "2"]
+        );
+        assert_eq!(
+            builtin("{\"analyzer\": \"unicode_words\", \"filters\": [\"lowercase\", \"stop_words\", \"stemmer\"]}")
+                .tokens("The connections are connecting"),
+            vec!["connect", "connect"]
+        );
+        assert!(parse_spec("tokenizer.json").is_none());
+        assert!(parse_spec("log").is_none());
+        assert!(parse_spec("builtin:logs").unwrap().is_err());
+        assert!(Analyzer::from_value(
+            parse_spec("builtin:{\"analyzer\": \"char_ngram\", \"min_n\": 0}").unwrap().unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_builtin_roundtrip() {
+        let (analyzer, compressed) = Analyzer::load(Some("builtin:log".to_string())).unwrap();
+        let loaded = Analyzer::from_compressed(&compressed).unwrap();
+        assert_eq!(loaded.vocab_size(), DEFAULT_BUCKETS as usize);
+        assert_eq!(
+            analyzer.encode("fooBar").unwrap(),
+            loaded.encode("foo bar").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_default_analyzer() {
+        // A file that happens to be named like a built-in analyzer is still a tokenizer file.
+        let missing = Analyzer::load(Some("log".to_string()));
+        assert!(matches!(missing, Err(LavaError::Parse(_))));
+
+        let (analyzer, compressed) = Analyzer::load(None).unwrap();
+        assert!(matches!(analyzer, Analyzer::Builtin(_)));
+        let loaded = Analyzer::from_compressed(&compressed).unwrap();
+        assert_eq!(
+            analyzer.encode("Hello, World").unwrap(),
+            loaded.encode("hello world").unwrap()
+        );
+    }
+}
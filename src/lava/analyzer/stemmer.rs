@@ -0,0 +1,284 @@
+/*
+The Snowball English (Porter2) stemmer, see https://snowballstem.org/algorithms/english/stemmer.html
+Works on lower case tokens, anything that is not ascii is returned as is.
+*/
+
+const EXCEPTIONS: [(&str, &str); 18] = [
+    ("skis", "ski"),
+    ("skies", "sky"),
+    ("dying", "die"),
+    ("lying", "lie"),
+    ("tying", "tie"),
+    ("idly", "idl"),
+    ("gently", "gentl"),
+    ("ugly", "ugli"),
+    ("early", "earli"),
+    ("only", "onli"),
+    ("singly", "singl"),
+    ("sky", "sky"),
+    ("news", "news"),
+    ("howe", "howe"),
+    ("atlas", "atlas"),
+    ("cosmos", "cosmos"),
+    ("bias", "bias"),
+    ("andes", "andes"),
+];
+
+// left alone after step 1a
+const EXCEPTIONS_1A: [&str; 8] = [
+    "inning", "outing", "canning", "herring", "earring", "proceed", "exceed", "succeed",
+];
+
+const STEP_2: [(&str, &str); 24] = [
+    ("tional", "tion"),
+    ("enci", "ence"),
+    ("anci", "ance"),
+    ("abli", "able"),
+    ("entli", "ent"),
+    ("izer", "ize"),
+    ("ization", "ize"),
+    ("ational", "ate"),
+    ("ation", "ate"),
+    ("ator", "ate"),
+    ("alism", "al"),
+    ("aliti", "al"),
+    ("alli", "al"),
+    ("fulness", "ful"),
+    ("ousli", "ous"),
+    ("ousness", "ous"),
+    ("iveness", "ive"),
+    ("iviti", "ive"),
+    ("biliti", "ble"),
+    ("bli", "ble"),
+    ("ogi", "og"),
+    ("fulli", "ful"),
+    ("lessli", "less"),
+    ("li", ""),
+];
+
+const STEP_3: [(&str, &str); 9] = [
+    ("tional", "tion"),
+    ("ational", "ate"),
+    ("alize", "al"),
+    ("icate", "ic"),
+    ("iciti", "ic"),
+    ("ical", "ic"),
+    ("ful", ""),
+    ("ness", ""),
+    ("ative", ""),
+];
+
+const STEP_4: [&str; 18] = [
+    "al", "ance", "ence", "er", "ic", "able", "ible", "ant", "ement", "ment", "ent", "ism", "ate",
+    "iti", "ous", "ive", "ize", "ion",
+];
+
+fn is_vowel(c: u8) -> bool {
+    matches!(c, b'a' | b'e' | b'i' | b'o' | b'u' | b'y')
+}
+
+fn is_double(w: &[u8]) -> bool {
+    let n = w.len();
+    n >= 2
+        && w[n - 1] == w[n - 2]
+        && matches!(w[n - 1], b'b' | b'd' | b'f' | b'g' | b'm' | b'n' | b'p' | b'r' | b't')
+}
+
+fn is_li_ending(c: u8) -> bool {
+    matches!(c, b'c' | b'd' | b'e' | b'g' | b'h' | b'k' | b'm' | b'n' | b'r' | b't')
+}
+
+/// Start of the region after the first non-vowel that follows a vowel, searching from `from`.
+fn region(w: &[u8], from: usize) -> usize {
+    for i in from + 1..w.len() {
+        if is_vowel(w[i - 1]) && !is_vowel(w[i]) {
+            return i + 1;
+        }
+    }
+    w.len()
+}
+
+fn ends_with_short_syllable(w: &[u8]) -> bool {
+    let n = w.len();
+    if n == 2 {
+        return is_vowel(w[0]) && !is_vowel(w[1]);
+    }
+    n >= 3
+        && !is_vowel(w[n - 3])
+        && is_vowel(w[n - 2])
+        && !is_vowel(w[n - 1])
+        && !matches!(w[n - 1], b'w' | b'x' | b'Y')
+}
+
+fn longest_suffix<'a>(w: &[u8], suffixes: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    suffixes
+        .filter(|s| w.ends_with(s.as_bytes()))
+        .max_by_key(|s| s.len())
+}
+
+fn contains_vowel(w: &[u8]) -> bool {
+    w.iter().any(|c| is_vowel(*c))
+}
+
+pub(crate) fn stem_english(word: &str) -> String {
+    if word.len() <= 2 || !word.is_ascii() {
+        return word.to_string();
+    }
+    if let Some((_, stem)) = EXCEPTIONS.iter().find(|(w, _)| *w == word) {
+        return stem.to_string();
+    }
+
+    let mut w: Vec<u8> = word.trim_start_matches('\'').as_bytes().to_vec();
+    if w.len() <= 2 {
+        return String::from_utf8(w).unwrap();
+    }
+    // y as a consonant
+    for i in 0..w.len() {
+        if w[i] == b'y' && (i == 0 || is_vowel(w[i - 1])) {
+            w[i] = b'Y';
+        }
+    }
+
+    let r1 = if w.starts_with(b"gener") || w.starts_with(b"arsen") {
+        5
+    } else if w.starts_with(b"commun") {
+        6
+    } else {
+        region(&w, 0)
+    };
+    let r2 = region(&w, r1);
+
+    // step 0
+    if let Some(s) = longest_suffix(&w, ["'", "'s", "'s'"].into_iter()) {
+        w.truncate(w.len() - s.len());
+    }
+
+    // step 1a
+    if w.ends_with(b"sses") {
+        w.truncate(w.len() - 2);
+    } else if w.ends_with(b"ied") || w.ends_with(b"ies") {
+        let keep = if w.len() > 4 { 1 } else { 2 };
+        w.truncate(w.len() - 3 + keep);
+    } else if w.len() >= 3
+        && w.ends_with(b"s")
+        && !w.ends_with(b"us")
+        && !w.ends_with(b"ss")
+        && contains_vowel(&w[..w.len() - 2])
+    {
+        w.pop();
+    }
+
+    let word: String = String::from_utf8(w.clone()).unwrap();
+    if EXCEPTIONS_1A.contains(&word.as_str()) {
+        return word;
+    }
+
+    // step 1b
+    if let Some(s) = longest_suffix(&w, ["eed", "eedly", "ed", "edly", "ing", "ingly"].into_iter())
+    {
+        let start = w.len() - s.len();
+        if s.starts_with("ee") {
+            if start >= r1 {
+                w.truncate(start + 2);
+            }
+        } else if contains_vowel(&w[..start]) {
+            w.truncate(start);
+            if w.ends_with(b"at") || w.ends_with(b"bl") || w.ends_with(b"iz") {
+                w.push(b'e');
+            } else if is_double(&w) {
+                w.pop();
+            } else if r1 >= w.len() && ends_with_short_syllable(&w) {
+                w.push(b'e');
+            }
+        }
+    }
+
+    // step 1c
+    let n = w.len();
+    if n > 2 && matches!(w[n - 1], b'y' | b'Y') && !is_vowel(w[n - 2]) {
+        w[n - 1] = b'i';
+    }
+
+    // step 2
+    if let Some(s) = longest_suffix(&w, STEP_2.iter().map(|(s, _)| *s)) {
+        let start = w.len() - s.len();
+        let allowed = match s {
+            "ogi" => start > 0 && w[start - 1] == b'l',
+            "li" => start > 0 && is_li_ending(w[start - 1]),
+            _ => true,
+        };
+        if start >= r1 && allowed {
+            let replacement = STEP_2.iter().find(|(suffix, _)| *suffix == s).unwrap().1;
+            w.truncate(start);
+            w.extend_from_slice(replacement.as_bytes());
+        }
+    }
+
+    // step 3
+    if let Some(s) = longest_suffix(&w, STEP_3.iter().map(|(s, _)| *s)) {
+        let start = w.len() - s.len();
+        if start >= r1 && (s != "ative" || start >= r2) {
+            let replacement = STEP_3.iter().find(|(suffix, _)| *suffix == s).unwrap().1;
+            w.truncate(start);
+            w.extend_from_slice(replacement.as_bytes());
+        }
+    }
+
+    // step 4
+    if let Some(s) = longest_suffix(&w, STEP_4.iter().copied()) {
+        let start = w.len() - s.len();
+        let allowed = s != "ion" || (start > 0 && matches!(w[start - 1], b's' | b't'));
+        if start >= r2 && allowed {
+            w.truncate(start);
+        }
+    }
+
+    // step 5
+    let n = w.len();
+    if w.ends_with(b"e") {
+        if n - 1 >= r2 || (n - 1 >= r1 && !ends_with_short_syllable(&w[..n - 1])) {
+            w.pop();
+        }
+    } else if w.ends_with(b"l") && n - 1 >= r2 && n >= 2 && w[n - 2] == b'l' {
+        w.pop();
+    }
+
+    String::from_utf8(w).unwrap().replace('Y', "y")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stem_english() {
+        let cases = [
+            ("connect", "connect"),
+            ("connecting", "connect"),
+            ("connection", "connect"),
+            ("connections", "connect"),
+            ("consignment", "consign"),
+            ("caresses", "caress"),
+            ("ponies", "poni"),
+            ("ties", "tie"),
+            ("cats", "cat"),
+            ("gas", "gas"),
+            ("hopping", "hop"),
+            ("hoped", "hope"),
+            ("running", "run"),
+            ("agreed", "agre"),
+            ("generously", "generous"),
+            ("happiness", "happi"),
+            ("relational", "relat"),
+            ("rational", "ration"),
+            ("controlling", "control"),
+            ("skies", "sky"),
+            ("cry", "cri"),
+            ("by", "by"),
+            ("youth", "youth"),
+        ];
+        for (word, stem) in cases {
+            assert_eq!(stem_english(word), stem, "{}", word);
+        }
+    }
+}
@@ -1,10 +1,24 @@
 use crate::formats::readers::get_file_size_and_reader;
+use crate::lava::analyzer::Analyzer;
 use crate::lava::error::LavaError;
 use crate::lava::plist::PListChunk;
-use arrow::array::{make_array, Array, ArrayData, LargeStringArray, UInt64Array};
+
+use super::phrase::phrase_search_one_file;
+use super::query::QueryNode;
+use super::wand::{
+    block_max_wand, Bm25Params, Bm25fScorer, ImpactScorer, TermScorer, SPARSE_SCALE,
+};
+use arrow::array::{
+    make_array, Array, ArrayData, Float32Array, LargeStringArray, ListArray, UInt32Array,
+    UInt64Array,
+};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field};
 use bincode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use std::fs::File;
 use std::io::Read;
@@ -15,28 +29,526 @@ use zstd::stream::encode_all;
 /*
 Structure of the lava file
 It is important to put the posting lists first. Just trust me bro.
-compressed_serialized_tokenizer | compressed posting lists line by line | compressed term dictionary | compressed posting list offsets|
-8 bytes = offsets of compressed term dict | 8 bytes = offset of compressed posting list offsets
+compressed_serialized_tokenizer | compressed posting blocks | compressed block directories line by line | compressed fields | compressed languages | compressed terms | compressed term dictionary | compressed posting list offsets|
+8 bytes = offsets of compressed term dict | 8 bytes = offset of compressed posting list offsets | 8 bytes = number of documents | 8 bytes = total document length | 8 bytes = flags | 8 bytes = offset of compressed fields | 8 bytes = offset of compressed languages | 8 bytes = offset of compressed terms | 8 bytes = FOOTER_MAGIC
+
+The last word names the format and its version, files of another layout are rejected instead of
+having their footer misread.
+
+Posting lists hold one (uid, term frequency, document length) triple per document, scores are only
+computed at query time so that merged files are ranked against the corpus-wide average length.
+Each posting list is cut into blocks of about BLOCK_SIZE postings that are compressed on their own.
+The block directory of a token has one entry per block:
+first uid | last uid | max term frequency | min document length | block offset | block size
+Those are enough to bound the score of any document in the block, so search can skip blocks.
+
+Positional files (FLAG_POSITIONAL) follow every posting block with a positions block, which holds
+the row of each posting followed by its token positions. Their directory entries have two more
+words: positions block offset | positions block size
+
+Multi-field files (FLAG_FIELDS) keep the summed term frequency and length of all fields in the
+postings, so every other search works on them as if the fields were one text. After the posting
+block (and positions block) comes a fields block with the per field term frequencies followed by
+the per field lengths of each posting, again with offset | size in the directory entry. The
+compressed fields region holds the field names, default weights and total lengths, it is empty
+for single field files.
+
+Files built with a language analyzer (FLAG_LANGUAGES) record the names of the detected languages
+in the languages region, so queries are only analyzed for languages that occur. The flag is set
+even when no document was indexed, files of the same analyzer always merge.
+
+Built-in analyzers hash their terms into token ids, which cannot be turned back into terms. Their
+files (FLAG_TERMS) keep the sorted distinct terms in the terms region, wildcard queries expand
+against those. Files of Hugging Face tokenizers use the vocabulary of the tokenizer instead.
+The terms are stored before stop words and stemming, e.g. "running" and not "run", so that
+running* matches it, and go through those filters again when a query expands to them. The region
+is a term dictionary of TERMS_BLOCK_SIZE terms per block:
+compressed blocks | compressed directory | 8 bytes = offset of the directory
+Every block is front coded, each term is the number of bytes it shares with the one before and
+the rest of it. The directory holds the first term and the offset of every block, a wildcard
+with a literal prefix only reads the blocks that can hold terms with that prefix.
+
+Learned sparse files (FLAG_SPARSE) hold precomputed document vectors instead of tokenized text.
+The term frequency of a posting is the quantized weight of the token in the document and the
+document length the number of tokens it has a weight for. Their analyzer is just
+{"analyzer": "sparse", "vocab_size": ..}, there is nothing to tokenize a text query with.
 */
 
-fn get_tokenizer(tokenizer_file: Option<String>) -> Result<(Tokenizer, Vec<u8>), LavaError> {
-    // if the tokenizer file is provided, check if the file exists. If it does not exist, raise an Error
-    let tokenizer = if let Some(tokenizer_file) = tokenizer_file {
-        if !std::path::Path::new(&tokenizer_file).exists() {
-            return Err(LavaError::Parse(
-                "Tokenizer file does not exist".to_string(),
-            ));
-        }
-        println!("Tokenizer file: {}", tokenizer_file);
-        Tokenizer::from_file(tokenizer_file).unwrap()
+const FOOTER_WORDS: usize = 9;
+const TERMS_BLOCK_SIZE: usize = 128;
+// "LAVABM" followed by the format version
+const FOOTER_MAGIC: u64 = 0x4c41_5641_424d_0001;
+const BLOCK_SIZE: usize = 1024;
+pub(super) const FLAG_POSITIONAL: u64 = 1;
+pub(super) const FLAG_FIELDS: u64 = 2;
+pub(super) const FLAG_LANGUAGES: u64 = 4;
+pub(super) const FLAG_SPARSE: u64 = 8;
+pub(super) const FLAG_TERMS: u64 = 16;
+// the vocabulary of BERT, which most learned sparse models use
+const DEFAULT_SPARSE_VOCAB_SIZE: usize = 30522;
+
+pub(super) fn directory_stride(flags: u64) -> usize {
+    let mut stride = 6;
+    if flags & FLAG_POSITIONAL != 0 {
+        stride += 2;
+    }
+    if flags & FLAG_FIELDS != 0 {
+        stride += 2;
+    }
+    stride
+}
+
+// where the fields block offset sits in a directory entry
+fn fields_entry_index(flags: u64) -> usize {
+    if flags & FLAG_POSITIONAL != 0 {
+        8
     } else {
-        Tokenizer::from_pretrained("bert-base-uncased", None).unwrap()
+        6
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct Bm25Fields {
+    pub names: Vec<String>,
+    pub weights: Vec<f32>,
+    pub total_lengths: Vec<u64>,
+}
+
+/// The languages detected in the documents.
+pub(super) type Bm25Languages = BTreeSet<String>;
+
+/// The distinct terms of the documents when the analyzer hashes them into token ids.
+fn collect_terms(analyzer: &Analyzer, texts: &[&str]) -> Result<Vec<String>, LavaError> {
+    let terms: Vec<Vec<String>> = texts
+        .into_maybe_par_iter()
+        .map(|text| analyzer.document_terms(text))
+        .collect::<Result<_, LavaError>>()?;
+    let terms: BTreeSet<String> = terms.into_iter().flatten().collect();
+    Ok(terms.into_iter().collect())
+}
+
+// keeps the per posting data in step with the postings, the sort is stable so rows stay in order.
+fn sort_postings(
+    postings: &mut Vec<(u64, u64, u64)>,
+    positions: &mut Vec<Vec<u64>>,
+    fields: &mut Vec<Vec<u64>>,
+) {
+    let mut order: Vec<usize> = (0..postings.len()).collect();
+    order.sort_by_key(|i| postings[*i].0);
+    *postings = order.iter().map(|i| postings[*i]).collect();
+    if !positions.is_empty() {
+        *positions = order.iter().map(|i| std::mem::take(&mut positions[*i])).collect();
+    }
+    if !fields.is_empty() {
+        *fields = order.iter().map(|i| std::mem::take(&mut fields[*i])).collect();
+    }
+}
+
+/// Writes posting blocks as they come in, the block directories are kept in memory and written
+/// after all the blocks.
+struct Bm25Writer {
+    file: File,
+    directory_chunks: Vec<Vec<u8>>,
+    plist_elems: Vec<u64>,
+    plist_chunk: PListChunk<'static>,
+    counter: u64,
+    flags: u64,
+}
+
+impl Bm25Writer {
+    fn new(
+        output_file_name: &str,
+        compressed_tokenizer: &[u8],
+        flags: u64,
+    ) -> Result<Self, LavaError> {
+        let mut file = File::create(output_file_name)?;
+        file.write_all(&(compressed_tokenizer.len() as u64).to_le_bytes())?;
+        file.write_all(compressed_tokenizer)?;
+
+        Ok(Self {
+            file,
+            directory_chunks: vec![],
+            plist_elems: vec![0],
+            plist_chunk: PListChunk::new()?,
+            counter: 0,
+            flags,
+        })
+    }
+
+    /// Adds the posting list of the next token, postings must be sorted by uid. `positions` and
+    /// `fields` have one entry per posting when the file is positional or multi-field.
+    fn add_postings(
+        &mut self,
+        postings: &[(u64, u64, u64)],
+        positions: &[Vec<u64>],
+        fields: &[Vec<u64>],
+    ) -> Result<(), LavaError> {
+        let mut directory: Vec<u64> = vec![];
+        let mut start = 0;
+        while start < postings.len() {
+            // never split a uid across blocks, search scores a uid from a single block.
+            let mut end = (start + BLOCK_SIZE).min(postings.len());
+            while end < postings.len() && postings[end].0 == postings[end - 1].0 {
+                end += 1;
+            }
+            let block = &postings[start..end];
+
+            let mut flat: Vec<u64> = Vec::with_capacity(block.len() * 3);
+            for (uid, tf, doc_len) in block {
+                flat.extend([*uid, *tf, *doc_len]);
+            }
+            let compressed = encode_all(&bincode::serialize(&flat)?[..], 0)?;
+            let offset = self.file.seek(SeekFrom::Current(0))?;
+            self.file.write_all(&compressed)?;
+
+            directory.extend([
+                block[0].0,
+                block[block.len() - 1].0,
+                block.iter().map(|p| p.1).max().unwrap(),
+                block.iter().map(|p| p.2).min().unwrap(),
+                offset,
+                compressed.len() as u64,
+            ]);
+
+            if self.flags & FLAG_POSITIONAL != 0 {
+                self.write_side_block(&positions[start..end], &mut directory)?;
+            }
+            if self.flags & FLAG_FIELDS != 0 {
+                self.write_side_block(&fields[start..end], &mut directory)?;
+            }
+            start = end;
+        }
+
+        self.counter += 1;
+        let written = self.plist_chunk.add_plist(&directory)?;
+        if written > 1024 * 1024 {
+            self.flush_directories()?;
+        }
+        Ok(())
+    }
+
+    fn write_side_block(
+        &mut self,
+        entries: &[Vec<u64>],
+        directory: &mut Vec<u64>,
+    ) -> Result<(), LavaError> {
+        let flat: Vec<u64> = entries.concat();
+        let compressed = encode_all(&bincode::serialize(&flat)?[..], 0)?;
+        let offset = self.file.seek(SeekFrom::Current(0))?;
+        self.file.write_all(&compressed)?;
+        directory.extend([offset, compressed.len() as u64]);
+        Ok(())
+    }
+
+    fn flush_directories(&mut self) -> Result<(), LavaError> {
+        let plist_chunk = std::mem::replace(&mut self.plist_chunk, PListChunk::new()?);
+        self.directory_chunks.push(plist_chunk.finalize_compression()?);
+        self.plist_elems.push(self.counter);
+        Ok(())
+    }
+
+    fn finish(
+        mut self,
+        token_counts: &[usize],
+        num_documents: u64,
+        total_doc_len: u64,
+        fields: Option<&Bm25Fields>,
+        languages: Option<&Bm25Languages>,
+        terms: Option<&[String]>,
+    ) -> Result<Vec<(usize, usize)>, LavaError> {
+        if self.counter > self.plist_elems[self.plist_elems.len() - 1] {
+            self.flush_directories()?;
+        }
+
+        let directories_offset = self.file.seek(SeekFrom::Current(0))?;
+        let mut plist_offsets: Vec<u64> = vec![directories_offset];
+        for bytes in self.directory_chunks.iter() {
+            self.file.write_all(bytes)?;
+            plist_offsets.push(plist_offsets[plist_offsets.len() - 1] + bytes.len() as u64);
+        }
+        plist_offsets.append(&mut self.plist_elems);
+
+        let fields_offset = self.file.seek(SeekFrom::Current(0))?;
+        if let Some(fields) = fields {
+            let compressed_fields = encode_all(&bincode::serialize(fields)?[..], 0)?;
+            self.file.write_all(&compressed_fields)?;
+        }
+
+        let languages_offset = self.file.seek(SeekFrom::Current(0))?;
+        if let Some(languages) = languages {
+            self.flags |= FLAG_LANGUAGES;
+            let compressed_languages = encode_all(&bincode::serialize(languages)?[..], 0)?;
+            self.file.write_all(&compressed_languages)?;
+        }
+
+        let terms_offset = self.file.seek(SeekFrom::Current(0))?;
+        if let Some(terms) = terms {
+            self.flags |= FLAG_TERMS;
+            self.write_terms(terms_offset, terms)?;
+        }
+
+        let bytes = bincode::serialize(&token_counts)?;
+        let compressed_token_counts = encode_all(&bytes[..], 0).expect("Compression failed");
+        let compressed_term_dict_offset = self.file.seek(SeekFrom::Current(0))?;
+        self.file.write_all(&compressed_token_counts)?;
+
+        let compressed_plist_offsets_offset = self.file.seek(SeekFrom::Current(0))?;
+        let serialized = bincode::serialize(&plist_offsets).unwrap();
+        let compressed_plist_offsets =
+            encode_all(&serialized[..], 0).expect("Compression of plist offsets failed");
+        self.file.write_all(&compressed_plist_offsets)?;
+
+        self.file.write_all(&compressed_term_dict_offset.to_le_bytes())?;
+        self.file.write_all(&compressed_plist_offsets_offset.to_le_bytes())?;
+        self.file.write_all(&num_documents.to_le_bytes())?;
+        self.file.write_all(&total_doc_len.to_le_bytes())?;
+        self.file.write_all(&self.flags.to_le_bytes())?;
+        self.file.write_all(&fields_offset.to_le_bytes())?;
+        self.file.write_all(&languages_offset.to_le_bytes())?;
+        self.file.write_all(&terms_offset.to_le_bytes())?;
+        self.file.write_all(&FOOTER_MAGIC.to_le_bytes())?;
+
+        // the directories are small next to the blocks, cache them with the metadata.
+        let cache_end = self.file.seek(SeekFrom::Current(0))? as usize;
+        Ok(vec![(directories_offset as usize, cache_end)])
+    }
+
+    fn write_terms(&mut self, offset: u64, terms: &[String]) -> Result<(), LavaError> {
+        let mut directory: Vec<(String, u64)> = Vec::new();
+        let mut offset = offset;
+        for block in terms.chunks(TERMS_BLOCK_SIZE) {
+            let mut coded: Vec<(u32, &str)> = Vec::with_capacity(block.len());
+            let mut previous = "";
+            for term in block {
+                let mut shared = previous
+                    .bytes()
+                    .zip(term.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                while !term.is_char_boundary(shared) {
+                    shared -= 1;
+                }
+                coded.push((shared as u32, &term[shared..]));
+                previous = term;
+            }
+            let compressed = encode_all(&bincode::serialize(&coded)?[..], 0)?;
+            self.file.write_all(&compressed)?;
+            directory.push((block[0].clone(), offset));
+            offset += compressed.len() as u64;
+        }
+        let compressed_directory = encode_all(&bincode::serialize(&directory)?[..], 0)?;
+        self.file.write_all(&compressed_directory)?;
+        self.file.write_all(&offset.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+pub(super) fn decode_block(compressed: &[u8]) -> Result<Vec<(u64, u64, u64)>, LavaError> {
+    let mut decompressed: Vec<u8> = Vec::new();
+    Decoder::new(compressed)?.read_to_end(&mut decompressed)?;
+    let flat: Vec<u64> = bincode::deserialize(&decompressed)?;
+    if flat.len() % 3 != 0 {
+        return Err(LavaError::Parse("data corruption".to_string()));
+    }
+    Ok(flat.chunks(3).map(|p| (p[0], p[1], p[2])).collect())
+}
+
+/// Splits a positions block into [row, positions..] per posting of the matching posting block.
+pub(super) fn decode_positions(
+    compressed: &[u8],
+    postings: &[(u64, u64, u64)],
+) -> Result<Vec<Vec<u64>>, LavaError> {
+    let mut decompressed: Vec<u8> = Vec::new();
+    Decoder::new(compressed)?.read_to_end(&mut decompressed)?;
+    let flat: Vec<u64> = bincode::deserialize(&decompressed)?;
+
+    let mut result = Vec::with_capacity(postings.len());
+    let mut offset = 0;
+    for (_, tf, _) in postings {
+        let end = offset + 1 + *tf as usize;
+        if end > flat.len() {
+            return Err(LavaError::Parse("data corruption".to_string()));
+        }
+        result.push(flat[offset..end].to_vec());
+        offset = end;
+    }
+    Ok(result)
+}
+
+/// Splits a fields block into [term frequencies.., lengths..] per posting.
+pub(super) fn decode_fields(compressed: &[u8], num_postings: usize) -> Result<Vec<Vec<u64>>, LavaError> {
+    let mut decompressed: Vec<u8> = Vec::new();
+    Decoder::new(compressed)?.read_to_end(&mut decompressed)?;
+    let flat: Vec<u64> = bincode::deserialize(&decompressed)?;
+    if num_postings == 0 || flat.len() % num_postings != 0 {
+        return Err(LavaError::Parse("data corruption".to_string()));
+    }
+    Ok(flat
+        .chunks(flat.len() / num_postings)
+        .map(|entry| entry.to_vec())
+        .collect())
+}
+
+/// Postings of one token with their positions and fields, those are empty if the file has none.
+pub(super) type TokenPostings = (Vec<(u64, u64, u64)>, Vec<Vec<u64>>, Vec<Vec<u64>>);
+
+/// Reads all the blocks of one directory with a single request, they are stored back to back.
+pub(super) async fn read_postings(
+    reader: &mut AsyncReader,
+    directory: &[u64],
+    flags: u64,
+) -> Result<TokenPostings, LavaError> {
+    if directory.is_empty() {
+        return Ok((vec![], vec![], vec![]));
+    }
+    let stride = directory_stride(flags);
+    let start = directory[4];
+    let last = &directory[directory.len() - stride..];
+    let end = last[stride - 2] + last[stride - 1];
+    let bytes = reader.read_range(start, end).await?;
+    let slice = |offset: u64, size: u64| {
+        let begin = (offset - start) as usize;
+        &bytes[begin..begin + size as usize]
     };
 
-    let serialized_tokenizer = serde_json::to_string(&tokenizer).unwrap();
-    let compressed_tokenizer =
-        encode_all(serialized_tokenizer.as_bytes(), 0).expect("Compression failed");
-    Ok((tokenizer, compressed_tokenizer))
+    let mut postings = vec![];
+    let mut positions = vec![];
+    let mut fields = vec![];
+    for entry in directory.chunks(stride) {
+        let block = decode_block(slice(entry[4], entry[5]))?;
+        if flags & FLAG_POSITIONAL != 0 {
+            positions.extend(decode_positions(slice(entry[6], entry[7]), &block)?);
+        }
+        if flags & FLAG_FIELDS != 0 {
+            let idx = fields_entry_index(flags);
+            fields.extend(decode_fields(slice(entry[idx], entry[idx + 1]), block.len())?);
+        }
+        postings.extend(block);
+    }
+    Ok((postings, positions, fields))
+}
+
+async fn read_region<T: DeserializeOwned>(
+    reader: &mut AsyncReader,
+    from: u64,
+    to: u64,
+) -> Result<T, LavaError> {
+    let compressed = reader.read_range(from, to).await?;
+    let mut decompressed: Vec<u8> = Vec::new();
+    Decoder::new(&compressed[..])?.read_to_end(&mut decompressed)?;
+    Ok(bincode::deserialize(&decompressed)?)
+}
+
+/// The footer of a bm25 file, checked to be of the current format.
+async fn read_footer(reader: &mut AsyncReader) -> Result<Vec<u64>, LavaError> {
+    let results = reader.read_usize_from_end(FOOTER_WORDS as u64).await?;
+    if results[FOOTER_WORDS - 1] != FOOTER_MAGIC {
+        return Err(LavaError::Parse(format!(
+            "{} is not a bm25 file of this version, rebuild it",
+            reader.filename
+        )));
+    }
+    Ok(results)
+}
+
+/// The languages of the documents in the files, empty unless they were built with a language
+/// analyzer.
+pub(crate) async fn read_bm25_languages(
+    readers: &mut [AsyncReader],
+) -> Result<BTreeSet<String>, LavaError> {
+    let mut languages: BTreeSet<String> = BTreeSet::new();
+    for reader in readers.iter_mut() {
+        let results = read_footer(reader).await?;
+        if results[4] & FLAG_LANGUAGES != 0 {
+            let this_languages: Bm25Languages =
+                read_region(reader, results[6], results[7]).await?;
+            languages.extend(this_languages);
+        }
+    }
+    Ok(languages)
+}
+
+/// The terms with `prefix` in the term dictionary that spans `from..to`, only the blocks that can
+/// hold them are read.
+async fn read_terms(
+    reader: &mut AsyncReader,
+    from: u64,
+    to: u64,
+    prefix: &str,
+) -> Result<Vec<String>, LavaError> {
+    if to < from + 8 {
+        return Err(LavaError::Parse("term dictionary is truncated".to_string()));
+    }
+    let directory_offset = reader.read_range(to - 8, to).await?;
+    let directory_offset = u64::from_le_bytes(directory_offset[..].try_into().unwrap());
+    let directory: Vec<(String, u64)> = read_region(reader, directory_offset, to - 8).await?;
+
+    // the first block that can hold the prefix is the last one starting before it
+    let first = directory
+        .partition_point(|(term, _)| term.as_str() < prefix)
+        .saturating_sub(1);
+    let last = directory
+        .partition_point(|(term, _)| term.as_str() < prefix || term.starts_with(prefix));
+    if first >= last {
+        return Ok(vec![]);
+    }
+    let start = directory[first].1;
+    let end = directory.get(last).map_or(directory_offset, |(_, offset)| *offset);
+    let bytes = reader.read_range(start, end).await?;
+
+    let mut terms: Vec<String> = Vec::new();
+    for block in first..last {
+        let begin = (directory[block].1 - start) as usize;
+        let finish = directory.get(block + 1).map_or(end, |(_, offset)| *offset) - start;
+        let mut decompressed: Vec<u8> = Vec::new();
+        Decoder::new(&bytes[begin..finish as usize])?.read_to_end(&mut decompressed)?;
+        let coded: Vec<(u32, String)> = bincode::deserialize(&decompressed)?;
+        let mut previous = String::new();
+        for (shared, rest) in coded {
+            let shared = shared as usize;
+            if shared > previous.len() || !previous.is_char_boundary(shared) {
+                return Err(LavaError::Parse("corrupted term dictionary".to_string()));
+            }
+            previous.truncate(shared);
+            previous.push_str(&rest);
+            if previous.starts_with(prefix) {
+                terms.push(previous.clone());
+            }
+        }
+    }
+    Ok(terms)
+}
+
+/// The terms with `prefix` of the files built with an analyzer that hashes its terms, empty for
+/// the others.
+pub(crate) async fn read_bm25_terms(
+    readers: &mut [AsyncReader],
+    prefix: &str,
+) -> Result<BTreeSet<String>, LavaError> {
+    let mut terms: BTreeSet<String> = BTreeSet::new();
+    for reader in readers.iter_mut() {
+        let results = read_footer(reader).await?;
+        if results[4] & FLAG_TERMS != 0 {
+            terms.extend(read_terms(reader, results[7], results[0], prefix).await?);
+        }
+    }
+    Ok(terms)
+}
+
+/// In how many documents of all the files each of the tokens occurs.
+pub(crate) async fn read_bm25_document_frequencies(
+    readers: &mut [AsyncReader],
+    tokens: &[u32],
+) -> Result<Vec<u64>, LavaError> {
+    let mut frequencies: Vec<u64> = vec![0; tokens.len()];
+    for reader in readers.iter_mut() {
+        let results = read_footer(reader).await?;
+        let token_counts = reader.read_range_and_decompress(results[0], results[1]).await?;
+        for (frequency, token) in frequencies.iter_mut().zip(tokens) {
+            *frequency += token_counts.get(*token as usize).copied().unwrap_or(0);
+        }
+    }
+    Ok(frequencies)
 }
 
 /// Function that tokenizes the input text and returns a list of tokens.
@@ -46,13 +558,9 @@ pub async fn build_lava_bm25(
     array: ArrayData,
     uid: ArrayData,
     tokenizer_file: Option<String>,
-    k1: Option<f32>,
-    b: Option<f32>,
+    positional: Option<bool>,
 ) -> Result<Vec<(usize, usize)>, LavaError> {
-    // if k1 and b are not provided, set them to default value
-    let k1: f32 = k1.unwrap_or(1.2);
-    let b: f32 = b.unwrap_or(0.75);
-
+    let positional = positional.unwrap_or(false);
     let array = make_array(array);
     // let uid = make_array(ArrayData::from_pyarrow(uid)?);
     let uid = make_array(uid);
@@ -76,8 +584,8 @@ pub async fn build_lava_bm25(
         ));
     }
 
-    let (tokenizer, compressed_tokenizer) = get_tokenizer(tokenizer_file)?;
-    let vocab_size: usize = tokenizer.get_vocab_size(false);
+    let (analyzer, compressed_tokenizer) = Analyzer::load(tokenizer_file)?;
+    let vocab_size: usize = analyzer.vocab_size();
 
     let mut texts = Vec::with_capacity(array.len());
     for i in 0..array.len() {
@@ -85,105 +593,286 @@ pub async fn build_lava_bm25(
         texts.push(text);
     }
 
-    let encodings = texts
+    let terms = if analyzer.hashes_terms() {
+        Some(collect_terms(&analyzer, &texts)?)
+    } else {
+        None
+    };
+    let (encodings, document_languages): (Vec<Vec<u32>>, Vec<Option<String>>) = texts
         .into_maybe_par_iter()
-        .map(|text| {
-            let encoding = tokenizer.encode(text, false).unwrap();
-            encoding.get_ids().to_vec()
-        })
-        .collect::<Vec<Vec<u32>>>();
-
-    let mut inverted_index: Vec<BTreeMap<usize, f32>> = vec![BTreeMap::new(); vocab_size];
+        .map(|text| analyzer.encode_document(text))
+        .collect::<Result<Vec<_>, LavaError>>()?
+        .into_iter()
+        .unzip();
+
+    let mut inverted_index: Vec<Vec<(u64, u64, u64)>> = vec![Vec::new(); vocab_size];
+    let mut inverted_positions: Vec<Vec<Vec<u64>>> =
+        vec![Vec::new(); if positional { vocab_size } else { 0 }];
     let mut token_counts: Vec<usize> = vec![0; vocab_size];
-
-    let mut avg_len: f32 = 0.0;
-    for encoding in encodings.iter() {
-        avg_len += encoding.len() as f32;
-    }
-    avg_len /= encodings.len() as f32;
+    let mut total_doc_len: u64 = 0;
 
     for (i, encoding) in encodings.iter().enumerate() {
-        let this_uid = uid.value(i) as usize;
-        let mut local_token_counts: BTreeMap<u32, usize> = BTreeMap::new();
+        let this_uid = uid.value(i);
+        let doc_len = encoding.len() as u64;
+        total_doc_len += doc_len;
+        let mut local_token_counts: BTreeMap<u32, u64> = BTreeMap::new();
         for key in encoding {
             *local_token_counts.entry(*key).or_insert(0) += 1;
         }
-        for key in local_token_counts.keys() {
-            let local_count = local_token_counts[key];
-            let local_factor: f32 = (local_count as f32) * (k1 + 1.0)
-                / (local_count as f32 + k1 * (1.0 - b + b * encoding.len() as f32 / avg_len));
-
-            inverted_index[*key as usize]
-                .entry(this_uid)
-                .and_modify(|e| *e = (*e).max(local_factor))
-                .or_insert(local_factor);
-
+        for (key, local_count) in local_token_counts.iter() {
+            inverted_index[*key as usize].push((this_uid, *local_count, doc_len));
             token_counts[*key as usize] += 1;
         }
+        if positional {
+            let mut local_positions: BTreeMap<u32, Vec<u64>> = BTreeMap::new();
+            for (position, key) in encoding.iter().enumerate() {
+                local_positions
+                    .entry(*key)
+                    .or_insert_with(|| vec![i as u64])
+                    .push(position as u64);
+            }
+            for (key, positions) in local_positions {
+                inverted_positions[key as usize].push(positions);
+            }
+        }
     }
 
-    let mut file = File::create(output_file_name)?;
-    file.write_all(&(compressed_tokenizer.len() as u64).to_le_bytes())?;
-    file.write_all(&compressed_tokenizer)?;
+    let flags = if positional { FLAG_POSITIONAL } else { 0 };
+    let mut writer = Bm25Writer::new(&output_file_name, &compressed_tokenizer, flags)?;
+    for (tok, value) in inverted_index.iter_mut().enumerate() {
+        let mut positions = if positional {
+            std::mem::take(&mut inverted_positions[tok])
+        } else {
+            vec![]
+        };
+        sort_postings(value, &mut positions, &mut vec![]);
+        writer.add_postings(value, &positions, &[])?;
+    }
 
-    let bytes = bincode::serialize(&token_counts)?;
-    let compressed_token_counts: Vec<u8> = encode_all(&bytes[..], 0).expect("Compression failed");
+    println!("number of tokens: {}", inverted_index.len());
 
-    // Handle the compressed data (for example, saving to a file or sending over a network)
-    println!(
-        "Compressed token counts size: {} number of tokens: {}",
-        compressed_token_counts.len(),
-        inverted_index.len()
-    );
+    // only language analyzers tell the language
+    let languages: Option<Bm25Languages> = matches!(analyzer, Analyzer::Language(_))
+        .then(|| document_languages.into_iter().flatten().collect());
 
-    let mut plist_offsets: Vec<u64> = vec![file.seek(SeekFrom::Current(0))?];
-    let mut plist_elems: Vec<u64> = vec![0];
-    let mut plist_chunk = PListChunk::new()?;
-    let mut counter: u64 = 0;
+    writer.finish(
+        &token_counts,
+        encodings.len() as u64,
+        total_doc_len,
+        None,
+        languages.as_ref(),
+        terms.as_deref(),
+    )
+}
 
-    for (_key, value) in inverted_index.iter().enumerate() {
-        let plist = if value.len() == 0 {
-            vec![]
-        } else {
-            let mut result = vec![];
-            for (key, val) in value.iter() {
-                result.push(*key as u64);
-                // quantize the score to int.
-                result.push((*val * 100 as f32) as u64);
-            }
-            result
-        };
+/// Builds a multi-field (BM25F) index, one string array per field with the same rows as `uid`.
+/// The per field term frequencies and lengths are stored, so the field weights saved here are
+/// only defaults that a search can override. Every other bm25 search sees the fields as one text.
+#[tokio::main]
+pub async fn build_lava_bm25f(
+    output_file_name: String,
+    arrays: Vec<ArrayData>,
+    field_names: Vec<String>,
+    field_weights: Option<Vec<f32>>,
+    uid: ArrayData,
+    tokenizer_file: Option<String>,
+) -> Result<Vec<(usize, usize)>, LavaError> {
+    let num_fields = arrays.len();
+    if num_fields == 0 || field_names.len() != num_fields {
+        return Err(LavaError::Parse(
+            "Expects one field name for every array and at least one array".to_string(),
+        ));
+    }
+    let field_weights = field_weights.unwrap_or_else(|| vec![1.0; num_fields]);
+    if field_weights.len() != num_fields {
+        return Err(LavaError::Parse(
+            "Expects one field weight for every array".to_string(),
+        ));
+    }
 
-        counter += 1;
+    let uid = make_array(uid);
+    let uid = uid
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .ok_or(LavaError::Parse(
+            "Expects uint64 array as uid argument".to_string(),
+        ))?;
 
-        let written = plist_chunk.add_plist(&plist)?;
-        if written > 1024 * 1024 || counter == inverted_index.len() as u64 {
-            let bytes = plist_chunk.finalize_compression()?;
-            file.write_all(&bytes)?;
-            plist_offsets.push(plist_offsets[plist_offsets.len() - 1] + bytes.len() as u64);
-            plist_elems.push(counter);
-            plist_chunk = PListChunk::new()?;
+    let (analyzer, compressed_tokenizer) = Analyzer::load(tokenizer_file)?;
+    let vocab_size: usize = analyzer.vocab_size();
+
+    // encodings[field][row]
+    let mut encodings: Vec<Vec<Vec<u32>>> = Vec::with_capacity(num_fields);
+    let mut terms: Option<BTreeSet<String>> = analyzer.hashes_terms().then(BTreeSet::new);
+    for array in arrays {
+        let array = make_array(array);
+        let array = array
+            .as_any()
+            .downcast_ref::<LargeStringArray>()
+            .ok_or(LavaError::Parse(
+                "Expects string arrays as fields".to_string(),
+            ))?;
+        if array.len() != uid.len() {
+            return Err(LavaError::Parse(
+                "The length of every field array and the uid array must be the same".to_string(),
+            ));
         }
+        let texts: Vec<&str> = (0..array.len()).map(|i| array.value(i)).collect();
+        if let Some(terms) = &mut terms {
+            terms.extend(collect_terms(&analyzer, &texts)?);
+        }
+        encodings.push(
+            texts
+                .into_maybe_par_iter()
+                .map(|text| analyzer.encode(text))
+                .collect::<Result<Vec<Vec<u32>>, LavaError>>()?,
+        );
     }
 
-    plist_offsets.append(&mut plist_elems);
+    let mut inverted_index: Vec<Vec<(u64, u64, u64)>> = vec![Vec::new(); vocab_size];
+    let mut inverted_fields: Vec<Vec<Vec<u64>>> = vec![Vec::new(); vocab_size];
+    let mut token_counts: Vec<usize> = vec![0; vocab_size];
+    let mut total_lengths: Vec<u64> = vec![0; num_fields];
+
+    for i in 0..uid.len() {
+        let this_uid = uid.value(i);
+        let lengths: Vec<u64> = encodings.iter().map(|e| e[i].len() as u64).collect();
+        let doc_len: u64 = lengths.iter().sum();
+        let mut local_token_counts: BTreeMap<u32, Vec<u64>> = BTreeMap::new();
+        for (field, field_encodings) in encodings.iter().enumerate() {
+            total_lengths[field] += lengths[field];
+            for key in field_encodings[i].iter() {
+                local_token_counts
+                    .entry(*key)
+                    .or_insert_with(|| vec![0; num_fields])[field] += 1;
+            }
+        }
+        for (key, mut field_counts) in local_token_counts {
+            let tf: u64 = field_counts.iter().sum();
+            inverted_index[key as usize].push((this_uid, tf, doc_len));
+            field_counts.extend(lengths.iter());
+            inverted_fields[key as usize].push(field_counts);
+            token_counts[key as usize] += 1;
+        }
+    }
 
-    let compressed_term_dict_offset = file.seek(SeekFrom::Current(0))?;
-    file.write_all(&compressed_token_counts)?;
+    let fields = Bm25Fields {
+        names: field_names,
+        weights: field_weights,
+        total_lengths,
+    };
+    let mut writer = Bm25Writer::new(&output_file_name, &compressed_tokenizer, FLAG_FIELDS)?;
+    for (tok, value) in inverted_index.iter_mut().enumerate() {
+        sort_postings(value, &mut vec![], &mut inverted_fields[tok]);
+        writer.add_postings(value, &[], &inverted_fields[tok])?;
+    }
 
-    let compressed_plist_offsets_offset = file.seek(SeekFrom::Current(0))?;
-    let serialized = bincode::serialize(&plist_offsets).unwrap();
-    let compressed_plist_offsets =
-        encode_all(&serialized[..], 0).expect("Compression of plist offsets failed");
-    file.write_all(&compressed_plist_offsets)?;
+    writer.finish(
+        &token_counts,
+        uid.len() as u64,
+        fields.total_lengths.iter().sum(),
+        Some(&fields),
+        None,
+        terms.map(|terms| terms.into_iter().collect::<Vec<String>>()).as_deref(),
+    )
+}
 
-    file.write_all(&(compressed_term_dict_offset as u64).to_le_bytes())?;
-    file.write_all(&(compressed_plist_offsets_offset as u64).to_le_bytes())?;
-    file.write_all(&(encodings.len() as u64).to_le_bytes())?;
+fn cast_list(array: ArrayData, item: DataType, name: &str) -> Result<ListArray, LavaError> {
+    let list = DataType::List(Arc::new(Field::new("item", item, true)));
+    let array = cast(&make_array(array), &list)
+        .map_err(|e| LavaError::Parse(format!("Expects a list array as {}: {}", name, e)))?;
+    Ok(array.as_any().downcast_ref::<ListArray>().unwrap().clone())
+}
 
-    let cache_end = file.seek(SeekFrom::Current(0))? as usize;
+/// Builds a learned sparse (SPLADE-style) index from precomputed document vectors, the token ids
+/// in `indices` and their weights in `weights`, one list each per row of `uid`. The weights are
+/// quantized into the term frequencies of bm25 postings, so these files are merged like any bm25
+/// file. `vocab_size` defaults to that of BERT and has to be the same for files that get merged.
+#[tokio::main]
+pub async fn build_lava_sparse(
+    output_file_name: String,
+    indices: ArrayData,
+    weights: ArrayData,
+    uid: ArrayData,
+    vocab_size: Option<usize>,
+) -> Result<Vec<(usize, usize)>, LavaError> {
+    let vocab_size = vocab_size.unwrap_or(DEFAULT_SPARSE_VOCAB_SIZE);
+    let indices = cast_list(indices, DataType::UInt32, "indices")?;
+    let weights = cast_list(weights, DataType::Float32, "weights")?;
+    let uid = make_array(uid);
+    let uid = uid
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .ok_or(LavaError::Parse(
+            "Expects uint64 array as uid argument".to_string(),
+        ))?;
+    if indices.len() != uid.len() || weights.len() != uid.len() {
+        return Err(LavaError::Parse(
+            "The length of the indices, weights and uid arrays must be the same".to_string(),
+        ));
+    }
 
-    Ok(vec![(compressed_term_dict_offset as usize, cache_end)])
+    let mut inverted_index: Vec<Vec<(u64, u64, u64)>> = vec![Vec::new(); vocab_size];
+    let mut token_counts: Vec<usize> = vec![0; vocab_size];
+    let mut total_doc_len: u64 = 0;
+
+    for i in 0..uid.len() {
+        if indices.is_null(i) {
+            continue;
+        }
+        let row_indices = indices.value(i);
+        let row_indices = row_indices.as_any().downcast_ref::<UInt32Array>().unwrap();
+        let row_weights = weights.value(i);
+        let row_weights = row_weights.as_any().downcast_ref::<Float32Array>().unwrap();
+        if row_indices.len() != row_weights.len() {
+            return Err(LavaError::Parse(format!(
+                "row {} has {} indices but {} weights",
+                i,
+                row_indices.len(),
+                row_weights.len()
+            )));
+        }
+
+        // repeated token ids add up
+        let mut impacts: BTreeMap<u32, f32> = BTreeMap::new();
+        for (token, weight) in row_indices.values().iter().zip(row_weights.values().iter()) {
+            if *token as usize >= vocab_size {
+                return Err(LavaError::Parse(format!(
+                    "token id {} in row {} is not below the vocab size {}",
+                    token, i, vocab_size
+                )));
+            }
+            if !weight.is_finite() || *weight < 0.0 {
+                return Err(LavaError::Parse(format!(
+                    "weights must be finite and non-negative, row {} has {}",
+                    i, weight
+                )));
+            }
+            *impacts.entry(*token).or_insert(0.0) += weight;
+        }
+        let impacts: Vec<(u32, u64)> = impacts
+            .into_iter()
+            .map(|(token, weight)| (token, (weight * SPARSE_SCALE).round() as u64))
+            .filter(|(_, impact)| *impact > 0)
+            .collect();
+
+        let doc_len = impacts.len() as u64;
+        total_doc_len += doc_len;
+        for (token, impact) in impacts {
+            inverted_index[token as usize].push((uid.value(i), impact, doc_len));
+            token_counts[token as usize] += 1;
+        }
+    }
+
+    let header = serde_json::json!({"analyzer": "sparse", "vocab_size": vocab_size});
+    let compressed_header = encode_all(header.to_string().as_bytes(), 0)?;
+    let mut writer = Bm25Writer::new(&output_file_name, &compressed_header, FLAG_SPARSE)?;
+    for value in inverted_index.iter_mut() {
+        sort_postings(value, &mut vec![], &mut vec![]);
+        writer.add_postings(value, &[], &[])?;
+    }
+
+    writer.finish(&token_counts, uid.len() as u64, total_doc_len, None, None, None)
 }
 
 struct PListChunkIterator {
@@ -272,18 +961,60 @@ pub(crate) async fn merge_lava_bm25(
 
     let mut combined_token_counts: Vec<usize> = Vec::new();
     let mut total_num_documents: u64 = 0;
+    let mut total_doc_len: u64 = 0;
     let mut compressed_tokenizer: Option<Vec<u8>> = None;
+    let mut flags: Option<u64> = None;
+    let mut fields: Option<Bm25Fields> = None;
+    let mut languages: Bm25Languages = BTreeSet::new();
+    let mut terms: BTreeSet<String> = BTreeSet::new();
 
-    for file in lava_files {
+    for file in lava_files.into_iter() {
         let reader_type = reader_type.clone();
         let (file_size, mut reader) = get_file_size_and_reader(file, reader_type).await?;
         let file_size = file_size as u64;
 
-        let results = reader.read_usize_from_end(3).await?;
+        let results = read_footer(&mut reader).await?;
         let compressed_term_dict_offset = results[0];
         let compressed_plist_offsets_offset = results[1];
-        let num_documents = results[2];
-        total_num_documents += num_documents;
+        total_num_documents += results[2];
+        total_doc_len += results[3];
+        match flags {
+            Some(value) if value != results[4] => {
+                return Err(LavaError::Parse(
+                    "cannot merge bm25 files with different flags (positional or fields)"
+                        .to_string(),
+                ))
+            }
+            _ => flags = Some(results[4]),
+        }
+        if results[4] & FLAG_FIELDS != 0 {
+            let this_fields: Bm25Fields = read_region(&mut reader, results[5], results[6]).await?;
+            match &mut fields {
+                Some(fields) => {
+                    if fields.names != this_fields.names {
+                        return Err(LavaError::Parse(
+                            "cannot merge bm25 files with different fields".to_string(),
+                        ));
+                    }
+                    for (total, this_total) in
+                        fields.total_lengths.iter_mut().zip(this_fields.total_lengths)
+                    {
+                        *total += this_total;
+                    }
+                }
+                None => fields = Some(this_fields),
+            }
+        }
+        if results[4] & FLAG_LANGUAGES != 0 {
+            let this_languages: Bm25Languages =
+                read_region(&mut reader, results[6], results[7]).await?;
+            languages.extend(this_languages);
+        }
+        if results[4] & FLAG_TERMS != 0 {
+            terms.extend(
+                read_terms(&mut reader, results[7], compressed_term_dict_offset, "").await?,
+            );
+        }
 
         let compressed_token_counts = reader
             .read_range(compressed_term_dict_offset, compressed_plist_offsets_offset)
@@ -305,7 +1036,10 @@ pub(crate) async fn merge_lava_bm25(
         }
 
         let buffer2 = reader
-            .read_range(compressed_plist_offsets_offset, file_size - 24)
+            .read_range(
+                compressed_plist_offsets_offset,
+                file_size - 8 * FOOTER_WORDS as u64,
+            )
             .await?;
 
         decompressor = Decoder::new(&buffer2[..])?;
@@ -344,103 +1078,106 @@ pub(crate) async fn merge_lava_bm25(
         );
     }
 
-    let mut output_file = File::create(condensed_lava_file)?;
-
     let compressed_tokenizer = compressed_tokenizer.unwrap();
-    // let compressed_tokenizer_len = compressed_tokenizer.len();
-    output_file.write_all(&(compressed_tokenizer.len() as u64).to_le_bytes())?;
-    output_file.write_all(&compressed_tokenizer)?;
-
-    let mut new_plist_offsets: Vec<u64> = vec![output_file.seek(SeekFrom::Current(0))?];
-    let mut new_plist_elems: Vec<u64> = vec![0];
-    let mut plist_chunk = PListChunk::new()?;
-    let mut counter: u64 = 0;
+    let flags = flags.unwrap_or(0);
+    let mut writer = Bm25Writer::new(condensed_lava_file, &compressed_tokenizer, flags)?;
 
-    for tok in 0..combined_token_counts.len() {
-        // Find the smallest current line
-
-        let mut plist: Vec<u64> = vec![];
+    for _tok in 0..combined_token_counts.len() {
+        let mut postings: Vec<(u64, u64, u64)> = vec![];
+        let mut positions: Vec<Vec<u64>> = vec![];
+        let mut field_entries: Vec<Vec<u64>> = vec![];
 
         for i in 0..plist_chunk_iterators.len() {
-            let this_plist: Vec<u64> = plist_chunk_iterators[i].get();
-            assert_eq!(this_plist.len() % 2, 0);
-
-            for (j, item) in this_plist.iter().enumerate() {
-                if j % 2 == 0 {
-                    // page offset
-                    plist.push(*item + uid_offsets[i]);
-                } else {
-                    // quantized score
-                    plist.push(*item);
-                }
+            let directory: Vec<u64> = plist_chunk_iterators[i].get();
+            assert_eq!(directory.len() % directory_stride(flags), 0);
+
+            let (this_postings, mut this_positions, mut this_fields) =
+                read_postings(&mut plist_chunk_iterators[i].reader, &directory, flags).await?;
+            for (uid, tf, doc_len) in this_postings {
+                postings.push((uid + uid_offsets[i], tf, doc_len));
             }
+            positions.append(&mut this_positions);
+            field_entries.append(&mut this_fields);
 
             // this will return error for the last one, but it's ok
             let _ = plist_chunk_iterators[i].advance().await;
         }
 
-        counter += 1;
+        // uid offsets are not necessarily increasing with the file order
+        sort_postings(&mut postings, &mut positions, &mut field_entries);
+        writer.add_postings(&postings, &positions, &field_entries)?;
+    }
 
-        let plist = Vec::from_iter(plist.into_iter());
-        let written = plist_chunk.add_plist(&plist)?;
-        if written > 1024 * 1024 || tok == combined_token_counts.len() - 1 {
-            let bytes = plist_chunk.finalize_compression()?;
-            let this_len: u64 = bytes.len() as u64;
+    let languages = (flags & FLAG_LANGUAGES != 0).then_some(&languages);
+    let terms: Option<Vec<String>> =
+        (flags & FLAG_TERMS != 0).then(|| terms.into_iter().collect());
+    writer.finish(
+        &combined_token_counts,
+        total_num_documents,
+        total_doc_len,
+        fields.as_ref(),
+        languages,
+        terms.as_deref(),
+    )
+}
 
-            output_file.write(&bytes)?;
-            new_plist_offsets.push(new_plist_offsets[new_plist_offsets.len() - 1] + this_len);
-            new_plist_elems.push(counter);
-            plist_chunk = PListChunk::new()?;
+async fn reopen_reader(reader: AsyncReader) -> Result<AsyncReader, LavaError> {
+    match reader.reader {
+        ClonableAsyncReader::Local(_) => {
+            let mut new_reader =
+                get_file_size_and_reader(reader.filename.clone(), ReaderType::Local)
+                    .await?
+                    .1;
+            new_reader.window = reader.window;
+            Ok(new_reader)
         }
+        _ => Ok(reader),
     }
+}
 
-    new_plist_offsets.append(&mut new_plist_elems);
-
-    let bytes = bincode::serialize(&combined_token_counts)?;
-    let compressed_token_counts = encode_all(&bytes[..], 0).expect("Compression failed");
-
-    let compressed_term_dict_offset = output_file.seek(SeekFrom::Current(0))?;
-    output_file.write(&compressed_token_counts)?;
-
-    let serialized = bincode::serialize(&new_plist_offsets).unwrap();
-    let compressed_plist_offsets =
-        encode_all(&serialized[..], 0).expect("Compression of plist offsets failed");
-
-    let compressed_plist_offsets_offset =
-        compressed_term_dict_offset + compressed_token_counts.len() as u64;
-    output_file.write(&compressed_plist_offsets)?;
-
-    output_file.write(&(compressed_term_dict_offset as u64).to_le_bytes())?;
-    output_file.write(&(compressed_plist_offsets_offset as u64).to_le_bytes())?;
-    output_file.write(&(total_num_documents as u64).to_le_bytes())?;
-
-    Ok(vec![(
-        compressed_term_dict_offset as usize,
-        output_file.seek(SeekFrom::Current(0))? as usize,
-    )])
+/// What every bm25 search needs before touching the postings.
+struct Bm25Directories {
+    params: Bm25Params,
+    term_weights: Vec<f32>,
+    // per file, (query token index, block directory) of every query token that occurs in it
+    directories: Vec<Vec<(usize, Vec<u64>)>>,
+    num_documents: u64,
+    flags: Vec<u64>,
+    fields: Vec<Option<Bm25Fields>>,
 }
 
-pub(crate) async fn search_bm25_async(
-    file_sizes: Vec<usize>,
-    mut readers: Vec<AsyncReader>,
-    query_tokens: Vec<u32>,
-    query_weights: Vec<f32>,
-    k: usize,
-) -> Result<Vec<(u64, u64)>, LavaError> {
-    let mut idf: HashMap<u32, f32> = HashMap::new();
+async fn read_bm25_directories(
+    file_sizes: &[usize],
+    readers: &mut [AsyncReader],
+    query_tokens: &[u32],
+    query_weights: &[f32],
+    k1: Option<f32>,
+    b: Option<f32>,
+) -> Result<Bm25Directories, LavaError> {
     let mut total_token_counts: HashMap<u32, usize> = HashMap::new();
     for token in query_tokens.iter() {
         total_token_counts.insert(*token, 0);
     }
     let mut total_documents: usize = 0;
+    let mut total_doc_len: u64 = 0;
+    let mut flags: Vec<u64> = Vec::new();
+    let mut fields: Vec<Option<Bm25Fields>> = Vec::new();
     let mut all_plist_offsets: Vec<Vec<u64>> = Vec::new();
-    let mut chunks_to_search: HashMap<(usize, usize), Vec<(u32, u64)>> = HashMap::new();
+    // (file, chunk) -> (query token index, offset in chunk)
+    let mut chunks_to_search: HashMap<(usize, usize), Vec<(usize, u64)>> = HashMap::new();
 
     for i in 0..readers.len() {
-        let results = readers[i].read_usize_from_end(3).await?;
+        let results = read_footer(&mut readers[i]).await?;
         let compressed_term_dictionary_offset = results[0];
         let compressed_plist_offsets_offset = results[1];
         let num_documents = results[2];
+        total_doc_len += results[3];
+        flags.push(results[4]);
+        fields.push(if results[4] & FLAG_FIELDS != 0 {
+            Some(read_region(&mut readers[i], results[5], results[6]).await?)
+        } else {
+            None
+        });
 
         // now read the term dictionary
         let token_counts = readers[i]
@@ -453,13 +1190,17 @@ pub(crate) async fn search_bm25_async(
         for query_token in query_tokens.iter() {
             total_token_counts.insert(
                 *query_token,
-                total_token_counts[query_token] + token_counts[*query_token as usize] as usize,
+                total_token_counts[query_token]
+                    + token_counts.get(*query_token as usize).copied().unwrap_or(0) as usize,
             );
         }
         total_documents += num_documents as usize;
 
         let plist_offsets = readers[i]
-            .read_range_and_decompress(compressed_plist_offsets_offset, file_sizes[i] as u64 - 24)
+            .read_range_and_decompress(
+                compressed_plist_offsets_offset,
+                file_sizes[i] as u64 - 8 * FOOTER_WORDS as u64,
+            )
             .await?;
 
         if plist_offsets.len() % 2 != 0 {
@@ -470,7 +1211,11 @@ pub(crate) async fn search_bm25_async(
         let num_chunks: usize = plist_offsets.len() / 2;
         let term_dict_len: &[u64] = &plist_offsets[num_chunks..];
 
-        for token in query_tokens.iter() {
+        for (q, token) in query_tokens.iter().enumerate() {
+            // not in the vocabulary of this file
+            if *token as usize >= token_counts.len() {
+                continue;
+            }
             let tok = *token as u64;
             let (idx, offset) = match term_dict_len.binary_search(&tok) {
                 Ok(idx) => (idx, 0),
@@ -480,20 +1225,18 @@ pub(crate) async fn search_bm25_async(
             chunks_to_search
                 .entry((i as usize, idx))
                 .or_insert_with(Vec::new)
-                .push((*token, offset as u64));
+                .push((q, offset as u64));
         }
 
         all_plist_offsets.push(plist_offsets);
     }
 
     // compute the weighted IDF for each query token
+    let mut term_weights: Vec<f32> = Vec::with_capacity(query_tokens.len());
     for (i, query_token) in query_tokens.iter().enumerate() {
-        let query_weight = query_weights[i];
-        let query_token = *query_token;
-        let token_count = total_token_counts[&query_token];
-        idf.insert(
-            query_token,
-            query_weight
+        let token_count = total_token_counts[query_token];
+        term_weights.push(
+            query_weights[i]
                 * ((total_documents as f32 - token_count as f32 + 0.5)
                     / (token_count as f32 + 0.5)
                     + 1.0)
@@ -501,81 +1244,780 @@ pub(crate) async fn search_bm25_async(
         );
     }
 
-    let mut plist_result: Vec<(u64, u64)> = Vec::new();
-    let mut page_scores: HashMap<(u64, u64), f32> = HashMap::new();
-
-    let mut join_set: JoinSet<Result<Vec<(usize, u64, u32, u64)>, LavaError>> = JoinSet::new();
-    // need to parallelize this @Rain.
-    for (file_id, chunk_id, tokens, offsets) in
-        chunks_to_search
-            .into_iter()
-            .map(|((file_id, chunk_id), token_offsets)| {
-                let (tokens, offsets): (Vec<u32>, Vec<u64>) = token_offsets.into_iter().unzip();
-                (file_id, chunk_id, Arc::new(tokens), Arc::new(offsets))
-            })
-    {
-        let reader_type = match readers[file_id].reader {
-            ClonableAsyncReader::AwsSdk(_) => ReaderType::AwsSdk,
-            ClonableAsyncReader::Http(_) => ReaderType::Http,
-            ClonableAsyncReader::Local(_) => ReaderType::Local,
-        };
+    // corpus-wide average, so the ranking does not depend on how the files were merged
+    let params = Bm25Params {
+        k1: k1.unwrap_or(1.2),
+        b: b.unwrap_or(0.75),
+        avg_len: total_doc_len as f32 / (total_documents as f32).max(1.0),
+    };
 
-        let mut reader = match reader_type {
-            ReaderType::AwsSdk | ReaderType::Http => readers[file_id].clone(),
-            ReaderType::Local => {
-                get_file_size_and_reader(readers[file_id].filename.clone(), reader_type)
-                    .await
-                    .unwrap()
-                    .1
-            }
-        };
+    let mut join_set: JoinSet<Result<Vec<(usize, usize, Vec<u64>)>, LavaError>> = JoinSet::new();
+    for ((file_id, chunk_id), token_offsets) in chunks_to_search.into_iter() {
+        let reader = reopen_reader(readers[file_id].clone());
         let start = all_plist_offsets[file_id][chunk_id];
         let end = all_plist_offsets[file_id][chunk_id + 1];
-        let tokens = tokens.clone();
-        let offsets = offsets.clone();
 
         join_set.spawn(async move {
-            // println!("file_id: {}, chunk_id: {}", file_id, chunk_id);
+            let mut reader = reader.await?;
             let buffer3 = reader.read_range(start, end).await?;
+            let (queries, offsets): (Vec<usize>, Vec<u64>) = token_offsets.into_iter().unzip();
+            let results: Vec<Vec<u64>> = PListChunk::search_compressed(buffer3.to_vec(), &offsets)?;
+
+            Ok(queries
+                .into_iter()
+                .zip(results)
+                .map(|(q, directory)| (file_id, q, directory))
+                .collect())
+        });
+    }
 
-            // get all the second item in the offsets into its own vector
+    let mut directories: Vec<Vec<(usize, Vec<u64>)>> = vec![vec![]; readers.len()];
+    while let Some(res) = join_set.join_next().await {
+        let res = res.map_err(|e| LavaError::Parse(format!("join error: {:?}", e)))??;
+        for (file_id, q, directory) in res {
+            if directory.len() % directory_stride(flags[file_id]) != 0 {
+                return Err(LavaError::Parse("data corruption".to_string()));
+            }
+            if !directory.is_empty() {
+                directories[file_id].push((q, directory));
+            }
+        }
+    }
 
-            let results: Vec<Vec<u64>> =
-                PListChunk::search_compressed(buffer3.to_vec(), offsets.as_ref())?;
+    Ok(Bm25Directories {
+        params,
+        term_weights,
+        directories,
+        num_documents: total_documents as u64,
+        flags,
+        fields,
+    })
+}
 
-            let mut res = vec![];
-            for (i, result) in results.iter().enumerate() {
-                let token = &tokens[i];
-                assert_eq!(result.len() % 2, 0);
-                for i in (0..result.len()).step_by(2) {
-                    let uid = result[i];
-                    let page_score = result[i + 1];
-                    res.push((file_id, uid, *token, page_score));
+pub(crate) async fn search_bm25_async(
+    file_sizes: Vec<usize>,
+    mut readers: Vec<AsyncReader>,
+    query_tokens: Vec<u32>,
+    query_weights: Vec<f32>,
+    k: usize,
+    k1: Option<f32>,
+    b: Option<f32>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let Bm25Directories {
+        params,
+        term_weights,
+        directories,
+        flags,
+        ..
+    } = read_bm25_directories(&file_sizes, &mut readers, &query_tokens, &query_weights, k1, b)
+        .await?;
+
+    // every file computes its own top k, only the blocks that can make it are fetched.
+    let params = Arc::new(params);
+    let mut join_set: JoinSet<Result<Vec<(u64, u64, f32)>, LavaError>> = JoinSet::new();
+    for (file_id, terms) in directories.into_iter().enumerate() {
+        if terms.is_empty() {
+            continue;
+        }
+        let terms: Vec<(f32, Vec<u64>)> = terms
+            .into_iter()
+            .map(|(q, directory)| (term_weights[q], directory))
+            .collect();
+        let stride = directory_stride(flags[file_id]);
+        let reader = reopen_reader(readers[file_id].clone());
+        let params = params.clone();
+        join_set.spawn(async move {
+            let mut reader = reader.await?;
+            let top = block_max_wand(&mut reader, terms, stride, &*params, k).await?;
+            Ok(top
+                .into_iter()
+                .map(|(uid, score)| (file_id as u64, uid, score))
+                .collect())
+        });
+    }
+
+    collect_top_k(join_set, k).await
+}
+
+/// Phrase and proximity search over positional files. `query_tokens` are the distinct tokens of
+/// the query and `phrase` the index into them of every token of the query in order.
+/// With a slop only documents that contain the phrase in order, with at most `slop` extra tokens
+/// in between, are returned. Without it this is a plain disjunctive bm25 search. Either way the
+/// score of a document is multiplied by 1 + proximity_boost * how close its query tokens are.
+pub(crate) async fn search_bm25_phrase_async(
+    file_sizes: Vec<usize>,
+    mut readers: Vec<AsyncReader>,
+    query_tokens: Vec<u32>,
+    query_weights: Vec<f32>,
+    phrase: Vec<usize>,
+    slop: Option<usize>,
+    proximity_boost: Option<f32>,
+    k: usize,
+    k1: Option<f32>,
+    b: Option<f32>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let Bm25Directories {
+        params,
+        term_weights,
+        directories,
+        flags,
+        ..
+    } = read_bm25_directories(&file_sizes, &mut readers, &query_tokens, &query_weights, k1, b)
+        .await?;
+
+    if let Some(file_id) = flags.iter().position(|f| f & FLAG_POSITIONAL == 0) {
+        return Err(LavaError::Parse(format!(
+            "phrase search needs positional bm25 files, {} is not",
+            readers[file_id].filename
+        )));
+    }
+
+    let params = Arc::new(params);
+    let term_weights = Arc::new(term_weights);
+    let phrase = Arc::new(phrase);
+    let mut join_set: JoinSet<Result<Vec<(u64, u64, f32)>, LavaError>> = JoinSet::new();
+    for (file_id, terms) in directories.into_iter().enumerate() {
+        if terms.is_empty() {
+            continue;
+        }
+        let reader = reopen_reader(readers[file_id].clone());
+        let params = params.clone();
+        let term_weights = term_weights.clone();
+        let phrase = phrase.clone();
+        let stride = directory_stride(flags[file_id]);
+        join_set.spawn(async move {
+            let mut reader = reader.await?;
+            let top = phrase_search_one_file(
+                &mut reader,
+                terms,
+                stride,
+                &term_weights,
+                &phrase,
+                &params,
+                slop,
+                proximity_boost.unwrap_or(0.0),
+                k,
+            )
+            .await?;
+            Ok(top
+                .into_iter()
+                .map(|(uid, score)| (file_id as u64, uid, score))
+                .collect())
+        });
+    }
+
+    collect_top_k(join_set, k).await
+}
+
+/// Boolean search, `query` refers to query tokens by their index. Matching uids are found by
+/// set operations on the posting lists and only those get scored, excluded tokens have weight 0.
+pub(crate) async fn search_bm25_boolean_async(
+    file_sizes: Vec<usize>,
+    mut readers: Vec<AsyncReader>,
+    query_tokens: Vec<u32>,
+    query_weights: Vec<f32>,
+    query: QueryNode<Vec<usize>>,
+    k: usize,
+    k1: Option<f32>,
+    b: Option<f32>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let Bm25Directories {
+        params,
+        term_weights,
+        directories,
+        flags,
+        ..
+    } = read_bm25_directories(&file_sizes, &mut readers, &query_tokens, &query_weights, k1, b)
+        .await?;
+
+    let params = Arc::new(params);
+    let term_weights = Arc::new(term_weights);
+    let query = Arc::new(query);
+    let mut join_set: JoinSet<Result<Vec<(u64, u64, f32)>, LavaError>> = JoinSet::new();
+    for (file_id, terms) in directories.into_iter().enumerate() {
+        if terms.is_empty() {
+            continue;
+        }
+        let reader = reopen_reader(readers[file_id].clone());
+        let params = params.clone();
+        let term_weights = term_weights.clone();
+        let query = query.clone();
+        let flags = flags[file_id];
+        join_set.spawn(async move {
+            let mut reader = reader.await?;
+            let mut token_uids: Vec<Vec<u64>> = vec![vec![]; term_weights.len()];
+            let mut token_scores: Vec<HashMap<u64, f32>> = vec![HashMap::new(); term_weights.len()];
+            for (q, directory) in terms {
+                let (postings, _, _) = read_postings(&mut reader, &directory, flags).await?;
+                for (uid, tf, doc_len) in postings {
+                    let score = term_weights[q] * params.term_score(tf as f32, doc_len as f32);
+                    token_scores[q]
+                        .entry(uid)
+                        .and_modify(|e| *e = e.max(score))
+                        .or_insert(score);
+                }
+                token_uids[q] = token_scores[q].keys().copied().collect();
+                token_uids[q].sort();
+            }
+
+            let mut top: Vec<(u64, u64, f32)> = query
+                .evaluate(&token_uids)
+                .into_iter()
+                .map(|uid| {
+                    let score = token_scores.iter().filter_map(|s| s.get(&uid)).sum();
+                    (file_id as u64, uid, score)
+                })
+                .collect();
+            top.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
+            top.truncate(k);
+            Ok(top)
+        });
+    }
+
+    collect_top_k(join_set, k).await
+}
+
+/// BM25F search over multi-field files. The per field term frequencies are normalized by the
+/// corpus-wide average length of their field and weighted before the saturation, so the field
+/// weights can change per query. `field_weights` defaults to the weights stored at build time.
+pub(crate) async fn search_bm25f_async(
+    file_sizes: Vec<usize>,
+    mut readers: Vec<AsyncReader>,
+    query_tokens: Vec<u32>,
+    query_weights: Vec<f32>,
+    field_weights: Option<Vec<f32>>,
+    k: usize,
+    k1: Option<f32>,
+    b: Option<f32>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let Bm25Directories {
+        params,
+        term_weights,
+        directories,
+        num_documents,
+        flags,
+        fields,
+    } = read_bm25_directories(&file_sizes, &mut readers, &query_tokens, &query_weights, k1, b)
+        .await?;
+
+    let mut all_fields: Option<Bm25Fields> = None;
+    for (file_id, this_fields) in fields.into_iter().enumerate() {
+        let Some(this_fields) = this_fields else {
+            return Err(LavaError::Parse(format!(
+                "bm25f search needs multi-field files, {} is not one",
+                readers[file_id].filename
+            )));
+        };
+        match &mut all_fields {
+            Some(all_fields) => {
+                if all_fields.names != this_fields.names {
+                    return Err(LavaError::Parse(
+                        "bm25f search needs files with the same fields".to_string(),
+                    ));
+                }
+                for (total, this_total) in all_fields
+                    .total_lengths
+                    .iter_mut()
+                    .zip(this_fields.total_lengths)
+                {
+                    *total += this_total;
                 }
             }
-            Ok(res)
+            None => all_fields = Some(this_fields),
+        }
+    }
+    let Some(all_fields) = all_fields else {
+        return Ok(vec![]);
+    };
+    let field_weights = field_weights.unwrap_or(all_fields.weights);
+    if field_weights.len() != all_fields.names.len() {
+        return Err(LavaError::Parse(format!(
+            "expects {} field weights, one for each of {:?}",
+            all_fields.names.len(),
+            all_fields.names
+        )));
+    }
+    if let Some(weight) = field_weights.iter().find(|w| !w.is_finite() || **w < 0.0) {
+        return Err(LavaError::Parse(format!(
+            "field weights must be finite and not negative, got {}",
+            weight
+        )));
+    }
+    let avg_lengths: Vec<f32> = all_fields
+        .total_lengths
+        .iter()
+        .map(|total| (*total as f32 / (num_documents as f32).max(1.0)).max(1.0))
+        .collect();
+
+    // every file computes its own top k with the same block skipping as bm25
+    let mut join_set: JoinSet<Result<Vec<(u64, u64, f32)>, LavaError>> = JoinSet::new();
+    for (file_id, terms) in directories.into_iter().enumerate() {
+        if terms.is_empty() {
+            continue;
+        }
+        let terms: Vec<(f32, Vec<u64>)> = terms
+            .into_iter()
+            .map(|(q, directory)| (term_weights[q], directory))
+            .collect();
+        let stride = directory_stride(flags[file_id]);
+        let scorer = Bm25fScorer {
+            k1: params.k1,
+            b: params.b,
+            field_weights: field_weights.clone(),
+            avg_lengths: avg_lengths.clone(),
+            fields_entry: fields_entry_index(flags[file_id]),
+        };
+        let reader = reopen_reader(readers[file_id].clone());
+        join_set.spawn(async move {
+            let mut reader = reader.await?;
+            let top = block_max_wand(&mut reader, terms, stride, &scorer, k).await?;
+            // documents that only have the tokens in fields switched off for this query
+            Ok(top
+                .into_iter()
+                .filter(|(_, score)| *score > 0.0)
+                .map(|(uid, score)| (file_id as u64, uid, score))
+                .collect())
+        });
+    }
+
+    collect_top_k(join_set, k).await
+}
+
+/// Learned sparse search over files built with `build_lava_sparse`, `query_weights` are the
+/// weights of the query vector. Documents are ranked by their dot product with the query, with
+/// the same block skipping as bm25.
+pub(crate) async fn search_sparse_async(
+    file_sizes: Vec<usize>,
+    mut readers: Vec<AsyncReader>,
+    query_tokens: Vec<u32>,
+    query_weights: Vec<f32>,
+    k: usize,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    if query_tokens.len() != query_weights.len() {
+        return Err(LavaError::Parse(
+            "Expects one query weight for every query token".to_string(),
+        ));
+    }
+    if let Some(weight) = query_weights.iter().find(|w| !w.is_finite()) {
+        return Err(LavaError::Parse(format!("query weights must be finite, got {}", weight)));
+    }
+
+    let Bm25Directories {
+        directories, flags, ..
+    } = read_bm25_directories(&file_sizes, &mut readers, &query_tokens, &query_weights, None, None)
+        .await?;
+
+    if let Some(file_id) = flags.iter().position(|f| f & FLAG_SPARSE == 0) {
+        return Err(LavaError::Parse(format!(
+            "sparse search needs learned sparse files, {} is not one",
+            readers[file_id].filename
+        )));
+    }
+
+    let query_weights = Arc::new(query_weights);
+    let mut join_set: JoinSet<Result<Vec<(u64, u64, f32)>, LavaError>> = JoinSet::new();
+    for (file_id, terms) in directories.into_iter().enumerate() {
+        if terms.is_empty() {
+            continue;
+        }
+        let terms: Vec<(f32, Vec<u64>)> = terms
+            .into_iter()
+            .map(|(q, directory)| (query_weights[q], directory))
+            .collect();
+        let stride = directory_stride(flags[file_id]);
+        let reader = reopen_reader(readers[file_id].clone());
+        join_set.spawn(async move {
+            let mut reader = reader.await?;
+            let top = block_max_wand(&mut reader, terms, stride, &ImpactScorer, k).await?;
+            Ok(top
+                .into_iter()
+                .map(|(uid, score)| (file_id as u64, uid, score))
+                .collect())
         });
     }
 
+    collect_top_k(join_set, k).await
+}
+
+async fn collect_top_k(
+    mut join_set: JoinSet<Result<Vec<(u64, u64, f32)>, LavaError>>,
+    k: usize,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let mut page_scores_vec: Vec<(u64, u64, f32)> = Vec::new();
     while let Some(res) = join_set.join_next().await {
         let res = res.map_err(|e| LavaError::Parse(format!("join error: {:?}", e)))??;
-        for (file_id, uid, token, page_score) in res {
-            page_scores
-                .entry((file_id as u64, uid))
-                .and_modify(|e| *e += idf[&token] * page_score as f32)
-                .or_insert(idf[&token] * page_score as f32);
+        page_scores_vec.extend(res);
+    }
+
+    // sort the page scores by descending order and get the top k results
+    page_scores_vec.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
+    page_scores_vec.truncate(k);
+
+    Ok(page_scores_vec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::readers::ReaderType;
+    use crate::lava::merge::parallel_merge_files;
+    use crate::lava::search::{search_lava_bm25_boolean, search_lava_bm25f, search_lava_sparse};
+    use arrow::datatypes::{Float32Type, UInt32Type};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("bm25f_{}_{}.lava", std::process::id(), name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn build(name: &str, titles: &[&str], bodies: &[&str], uids: &[u64]) -> String {
+        let file = temp_file(name);
+        let arrays = vec![
+            LargeStringArray::from(titles.to_vec()).into_data(),
+            LargeStringArray::from(bodies.to_vec()).into_data(),
+        ];
+        build_lava_bm25f(
+            file.clone(),
+            arrays,
+            vec!["title".to_string(), "body".to_string()],
+            None,
+            UInt64Array::from(uids.to_vec()).into_data(),
+            None,
+        )
+        .unwrap();
+        file
+    }
+
+    /// BM25F of one term by the formula, `fields` holds (tf, len, avg len) for every field.
+    fn bm25f(idf: f32, weights: &[f32], fields: &[(f32, f32, f32)]) -> f32 {
+        let (k1, b) = (1.2, 0.75);
+        let tf: f32 = weights
+            .iter()
+            .zip(fields)
+            .map(|(w, (tf, len, avg))| w * tf / (1.0 - b + b * len / avg))
+            .sum();
+        idf * tf * (k1 + 1.0) / (tf + k1)
+    }
+
+    fn search(files: &[String], weights: &[f32]) -> Vec<(u64, u64, f32)> {
+        search_lava_bm25f(
+            files.to_vec(),
+            "rust".to_string(),
+            Some(weights.to_vec()),
+            10,
+            ReaderType::default(),
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_bm25f_field_weights() {
+        let file = build(
+            "weights",
+            &["rust guide", "cooking", "garden"],
+            &["a book about many languages", "rust rust rust and iron", "flowers"],
+            &[10, 11, 12],
+        );
+        let idf = ((3.0 - 2.0 + 0.5) / (2.0 + 0.5) + 1.0f32).ln();
+        let (title_avg, body_avg) = (4.0 / 3.0, 11.0 / 3.0);
+
+        for weights in [[5.0, 1.0], [0.1, 1.0], [1.0, 0.0]] {
+            let mut expected = vec![
+                (10, bm25f(idf, &weights, &[(1.0, 2.0, title_avg), (0.0, 5.0, body_avg)])),
+                (11, bm25f(idf, &weights, &[(0.0, 1.0, title_avg), (3.0, 5.0, body_avg)])),
+            ];
+            expected.retain(|(_, score)| *score > 0.0);
+            expected.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+            let results = search(&[file.clone()], &weights);
+            assert_eq!(results.len(), expected.len(), "weights {:?}", weights);
+            for ((_, uid, score), (expected_uid, expected_score)) in results.iter().zip(&expected) {
+                assert_eq!(uid, expected_uid, "weights {:?}", weights);
+                assert!((score - expected_score).abs() < 1e-4, "{} != {}", score, expected_score);
+            }
         }
+        // a heavy title puts the title match first, a light one the body match
+        assert_eq!(search(&[file.clone()], &[5.0, 1.0])[0].1, 10);
+        assert_eq!(search(&[file.clone()], &[0.1, 1.0])[0].1, 11);
+        assert!(search_lava_bm25f(
+            vec![file.clone()],
+            "rust".to_string(),
+            Some(vec![-1.0, 1.0]),
+            10,
+            ReaderType::default(),
+            None,
+            None,
+        )
+        .is_err());
+        std::fs::remove_file(file).unwrap();
     }
 
-    // sort the page scores by descending order
-    let mut page_scores_vec: Vec<((u64, u64), f32)> = page_scores.into_iter().collect();
-    page_scores_vec.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    #[test]
+    fn test_merge_bm25f() {
+        let titles = ["rust guide", "cooking", "rust", "iron age", "weather"];
+        let bodies = ["about rust", "rust and iron", "", "rust rust", "rain and wind"];
+        let first = build("first", &titles[..2], &bodies[..2], &[0, 1]);
+        let second = build("second", &titles[2..], &bodies[2..], &[0, 1, 2]);
+        let combined = build("combined", &titles, &bodies, &[0, 1, 100, 101, 102]);
+        let merged = temp_file("merged");
+        parallel_merge_files(
+            merged.clone(),
+            vec![first.clone(), second.clone()],
+            vec![0, 100],
+            2,
+            0,
+            ReaderType::default(),
+        )
+        .unwrap();
+
+        for weights in [[1.0, 1.0], [3.0, 0.5], [0.0, 1.0]] {
+            let from_merged = search(&[merged.clone()], &weights);
+            let from_combined = search(&[combined.clone()], &weights);
+            assert_eq!(from_merged.len(), from_combined.len());
+            for (m, c) in from_merged.iter().zip(&from_combined) {
+                assert_eq!(m.1, c.1, "weights {:?}", weights);
+                assert!((m.2 - c.2).abs() < 1e-4);
+            }
 
-    // get the top k results
-    for (uid, _score) in page_scores_vec.iter().take(k) {
-        // println!("{}", score);
-        plist_result.push(*uid);
+            // searching the inputs together scores like the merged file
+            let mut from_inputs: Vec<(u64, f32)> = search(&[first.clone(), second.clone()], &weights)
+                .into_iter()
+                .map(|(file_id, uid, score)| (uid + 100 * file_id, score))
+                .collect();
+            from_inputs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+            let mut from_merged: Vec<(u64, f32)> =
+                from_merged.into_iter().map(|(_, uid, score)| (uid, score)).collect();
+            from_merged.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+            assert_eq!(from_inputs.len(), from_merged.len());
+            for (i, m) in from_inputs.iter().zip(&from_merged) {
+                assert_eq!(i.0, m.0);
+                assert!((i.1 - m.1).abs() < 1e-4);
+            }
+        }
+        for file in [first, second, combined, merged] {
+            std::fs::remove_file(file).unwrap();
+        }
     }
 
-    Ok(plist_result)
+    #[test]
+    fn test_merge_languages() {
+        let build_language = |name: &str, texts: Vec<&str>| {
+            let file = temp_file(name);
+            let uids: Vec<u64> = (0..texts.len() as u64).collect();
+            build_lava_bm25(
+                file.clone(),
+                LargeStringArray::from(texts).into_data(),
+                UInt64Array::from(uids).into_data(),
+                Some("builtin:language".to_string()),
+                None,
+            )
+            .unwrap();
+            file
+        };
+        // an empty file still carries the languages flag and merges with the others
+        let empty = build_language("empty_languages", vec![]);
+        let texts = build_language(
+            "languages",
+            vec![
+                "The connections between the servers were failing all night long",
+                "Die Verbindungen zwischen den Servern sind heute ausgefallen",
+            ],
+        );
+        let merged = temp_file("merged_languages");
+        parallel_merge_files(
+            merged.clone(),
+            vec![empty.clone(), texts.clone()],
+            vec![0, 100],
+            2,
+            0,
+            ReaderType::default(),
+        )
+        .unwrap();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let languages = |file: &String| {
+            rt.block_on(async {
+                let (_, reader) = get_file_size_and_reader(file.clone(), ReaderType::default())
+                    .await
+                    .unwrap();
+                read_bm25_languages(&mut [reader]).await.unwrap()
+            })
+        };
+        assert!(languages(&empty).is_empty());
+        let expected = BTreeSet::from(["deu".to_string(), "eng".to_string()]);
+        assert_eq!(languages(&texts), expected);
+        assert_eq!(languages(&merged), expected);
+        for file in [empty, texts, merged] {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sparse_round_trip() {
+        // few tokens and many documents, so the posting lists span several blocks
+        const VOCAB_SIZE: u32 = 8;
+        let mut rng = StdRng::seed_from_u64(39);
+        let mut documents: Vec<Vec<(u32, f32)>> = Vec::new();
+        for _ in 0..6000 {
+            let mut document = Vec::new();
+            for _ in 0..rng.gen_range(1..5) {
+                document.push((rng.gen_range(0..VOCAB_SIZE), rng.gen_range(0.0..3.0f32)));
+            }
+            documents.push(document);
+        }
+
+        let build_sparse = |name: &str, documents: &[Vec<(u32, f32)>]| {
+            let file = temp_file(name);
+            let indices = ListArray::from_iter_primitive::<UInt32Type, _, _>(
+                documents.iter().map(|d| Some(d.iter().map(|(t, _)| Some(*t)).collect::<Vec<_>>())),
+            );
+            let weights = ListArray::from_iter_primitive::<Float32Type, _, _>(
+                documents.iter().map(|d| Some(d.iter().map(|(_, w)| Some(*w)).collect::<Vec<_>>())),
+            );
+            let uids: Vec<u64> = (0..documents.len() as u64).collect();
+            build_lava_sparse(
+                file.clone(),
+                indices.into_data(),
+                weights.into_data(),
+                UInt64Array::from(uids).into_data(),
+                Some(VOCAB_SIZE as usize),
+            )
+            .unwrap();
+            file
+        };
+        let first = build_sparse("sparse_first", &documents[..3500]);
+        let second = build_sparse("sparse_second", &documents[3500..]);
+        let merged = temp_file("sparse_merged");
+        parallel_merge_files(
+            merged.clone(),
+            vec![first.clone(), second.clone()],
+            vec![0, 3500],
+            2,
+            0,
+            ReaderType::default(),
+        )
+        .unwrap();
+
+        // the dot product with the weights quantized like the postings, repeated tokens add up
+        let dot = |document: &Vec<(u32, f32)>, query: &[(u32, f32)]| -> f32 {
+            let mut impacts: BTreeMap<u32, f32> = BTreeMap::new();
+            for (token, weight) in document {
+                *impacts.entry(*token).or_insert(0.0) += weight;
+            }
+            query
+                .iter()
+                .filter_map(|(token, weight)| {
+                    impacts
+                        .get(token)
+                        .map(|w| weight * (w * SPARSE_SCALE).round() / SPARSE_SCALE)
+                })
+                .sum()
+        };
+
+        for (query, k) in [
+            (vec![(3, 1.0), (7, 0.5)], 10),
+            (vec![(0, 2.0), (5, 0.25), (6, 1.5), (2, 0.75)], 25),
+            (vec![(1, 1.0)], 5000),
+        ] {
+            let mut expected: Vec<(u64, f32)> = documents
+                .iter()
+                .enumerate()
+                .map(|(uid, document)| (uid as u64, dot(document, &query)))
+                .filter(|(_, score)| *score > 0.0)
+                .collect();
+            expected.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+            expected.truncate(k);
+
+            let (tokens, weights): (Vec<u32>, Vec<f32>) = query.iter().cloned().unzip();
+            let search = |files: Vec<String>| {
+                search_lava_sparse(files, tokens.clone(), weights.clone(), k, ReaderType::default())
+                    .unwrap()
+                    .into_iter()
+                    .map(|(file_id, uid, score)| (uid + 3500 * file_id, score))
+                    .collect::<Vec<(u64, f32)>>()
+            };
+            for results in [search(vec![merged.clone()]), search(vec![first.clone(), second.clone()])]
+            {
+                assert_eq!(results.len(), expected.len());
+                for (i, (uid, score)) in results.iter().enumerate() {
+                    // in top-k order and scored like the brute force dot product
+                    assert!((score - expected[i].1).abs() < 1e-3, "{} != {}", score, expected[i].1);
+                    assert!((score - dot(&documents[*uid as usize], &query)).abs() < 1e-3);
+                }
+            }
+        }
+        for file in [first, second, merged] {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_term_dictionary() {
+        let mut terms: Vec<String> = (0..1000).map(|i| format!("term{}", i * 7)).collect();
+        terms.extend(["über", "überall", "übung", "zebra"].map(String::from));
+        terms.sort();
+        let file = temp_file("terms");
+        let writer = Bm25Writer::new(&file, &encode_all(&b"{}"[..], 0).unwrap(), 0).unwrap();
+        writer.finish(&[], 0, 0, None, None, Some(&terms)).unwrap();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (_, mut reader) = get_file_size_and_reader(file.clone(), ReaderType::default())
+                .await
+                .unwrap();
+            for prefix in ["", "term1", "term7", "term99", "term", "üb", "über", "a", "zz", "zebra"] {
+                let expected: BTreeSet<String> =
+                    terms.iter().filter(|t| t.starts_with(prefix)).cloned().collect();
+                let found = read_bm25_terms(std::slice::from_mut(&mut reader), prefix)
+                    .await
+                    .unwrap();
+                assert_eq!(found, expected, "prefix {}", prefix);
+            }
+        });
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_wildcard_before_stemming() {
+        let file = temp_file("stemmed");
+        build_lava_bm25(
+            file.clone(),
+            LargeStringArray::from(vec!["the servers were running", "a short run", "walking"])
+                .into_data(),
+            UInt64Array::from(vec![0, 1, 2]).into_data(),
+            Some(
+                r#"builtin:{"analyzer": "unicode_words", "filters": ["lowercase", "stop_words", "stemmer"]}"#
+                    .to_string(),
+            ),
+            None,
+        )
+        .unwrap();
+        let search = |query: &str| {
+            let results = search_lava_bm25_boolean(
+                vec![file.clone()],
+                query.to_string(),
+                10,
+                ReaderType::default(),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            let mut uids: Vec<u64> = results.into_iter().map(|(_, uid, _)| uid).collect();
+            uids.sort();
+            uids
+        };
+        // running* matches the stored "running", which is stemmed to "run" like both documents
+        assert_eq!(search("running*"), vec![0, 1]);
+        assert_eq!(search("serv*"), vec![0]);
+        assert_eq!(search("walk*"), vec![2]);
+        // stop words are not in the dictionary
+        assert_eq!(search("th*"), Vec::<u64>::new());
+        std::fs::remove_file(file).unwrap();
+    }
 }
@@ -1,5 +1,18 @@
 mod bm25;
+mod phrase;
+mod query;
+mod wand;
 
 pub use bm25::build_lava_bm25;
+pub use bm25::build_lava_bm25f;
+pub use bm25::build_lava_sparse;
 pub(crate) use bm25::merge_lava_bm25;
+pub(crate) use bm25::read_bm25_document_frequencies;
+pub(crate) use bm25::read_bm25_languages;
+pub(crate) use bm25::read_bm25_terms;
 pub(crate) use bm25::search_bm25_async;
+pub(crate) use bm25::search_bm25_boolean_async;
+pub(crate) use bm25::search_bm25_phrase_async;
+pub(crate) use bm25::search_bm25f_async;
+pub(crate) use bm25::search_sparse_async;
+pub(crate) use query::{is_wildcard, parse_query, wildcard_matches};
@@ -0,0 +1,221 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::formats::readers::AsyncReader;
+use crate::lava::error::LavaError;
+
+use super::bm25::{decode_block, decode_positions};
+use super::wand::{Bm25Params, TermScorer};
+
+// a document of one query token: uid, term frequency, document length, row, token positions
+type PositionalPosting = (u64, u64, u64, u64, Vec<u64>);
+
+async fn load_block(
+    reader: &mut AsyncReader,
+    entry: &[u64],
+) -> Result<Vec<PositionalPosting>, LavaError> {
+    // the positions block directly follows its posting block
+    let start = entry[4];
+    let bytes = reader.read_range(start, entry[6] + entry[7]).await?;
+    let postings = decode_block(&bytes[..entry[5] as usize])?;
+    let positions_start = (entry[6] - start) as usize;
+    let positions = decode_positions(
+        &bytes[positions_start..positions_start + entry[7] as usize],
+        &postings,
+    )?;
+
+    Ok(postings
+        .into_iter()
+        .zip(positions)
+        .map(|((uid, tf, doc_len), positions)| (uid, tf, doc_len, positions[0], positions[1..].to_vec()))
+        .collect())
+}
+
+/// Whether the lists hold one position each, in order, spanning at most `slop` extra tokens.
+/// For a given start the earliest position of every next token gives the shortest span.
+fn phrase_matches(positions: &[&Vec<u64>], slop: usize) -> bool {
+    'start: for first in positions[0].iter() {
+        let mut previous = *first;
+        for (i, list) in positions.iter().enumerate().skip(1) {
+            let idx = list.partition_point(|p| *p <= previous);
+            if idx == list.len() {
+                // later starts cannot do better
+                return false;
+            }
+            previous = list[idx];
+            // the i tokens after the first take at least i positions, the rest is slop
+            if previous - first > i as u64 + slop as u64 {
+                continue 'start;
+            }
+        }
+        return true;
+    }
+    false
+}
+
+/// (number of distinct tokens - 1) / (smallest window holding all of them - 1), 1 when the tokens
+/// are adjacent and 0 when there are less than two of them.
+fn proximity(positions: &[&Vec<u64>]) -> f32 {
+    if positions.len() < 2 {
+        return 0.0;
+    }
+    let mut all: Vec<(u64, usize)> = positions
+        .iter()
+        .enumerate()
+        .flat_map(|(i, list)| list.iter().map(move |p| (*p, i)))
+        .collect();
+    all.sort();
+
+    let mut counts = vec![0usize; positions.len()];
+    let mut covered = 0;
+    let mut best = u64::MAX;
+    let mut left = 0;
+    for right in 0..all.len() {
+        if counts[all[right].1] == 0 {
+            covered += 1;
+        }
+        counts[all[right].1] += 1;
+        while covered == positions.len() {
+            best = best.min(all[right].0 - all[left].0);
+            counts[all[left].1] -= 1;
+            if counts[all[left].1] == 0 {
+                covered -= 1;
+            }
+            left += 1;
+        }
+    }
+    (positions.len() - 1) as f32 / best.max(1) as f32
+}
+
+/// Phrase search in one positional file. `terms` holds the query token index and block directory
+/// of every query token present in the file. With a slop only the blocks of the rarest token are
+/// read in full, the others only where they can overlap with documents that are still candidates.
+pub(crate) async fn phrase_search_one_file(
+    reader: &mut AsyncReader,
+    mut terms: Vec<(usize, Vec<u64>)>,
+    stride: usize,
+    term_weights: &[f32],
+    phrase: &[usize],
+    params: &Bm25Params,
+    slop: Option<usize>,
+    proximity_boost: f32,
+    k: usize,
+) -> Result<Vec<(u64, f32)>, LavaError> {
+    let distinct: BTreeSet<usize> = phrase.iter().copied().collect();
+    if slop.is_some() && terms.len() < distinct.len() {
+        return Ok(vec![]);
+    }
+    // fewest blocks first
+    terms.sort_by_key(|(_, directory)| directory.len());
+
+    let mut postings: HashMap<usize, Vec<PositionalPosting>> = HashMap::new();
+    let mut candidates: Option<BTreeSet<u64>> = None;
+    for (q, directory) in terms.iter() {
+        let mut this_postings = vec![];
+        for entry in directory.chunks(stride) {
+            let needed = match &candidates {
+                Some(candidates) => candidates.range(entry[0]..=entry[1]).next().is_some(),
+                None => true,
+            };
+            if needed {
+                this_postings.extend(load_block(reader, entry).await?);
+            }
+        }
+        if slop.is_some() {
+            let uids: BTreeSet<u64> = this_postings.iter().map(|p| p.0).collect();
+            candidates = Some(match candidates {
+                Some(candidates) => candidates.intersection(&uids).copied().collect(),
+                None => uids,
+            });
+        }
+        postings.insert(*q, this_postings);
+    }
+
+    // group by document, rows tell apart documents that share a uid
+    let mut documents: HashMap<(u64, u64), (u64, HashMap<usize, (u64, &Vec<u64>)>)> =
+        HashMap::new();
+    for (q, this_postings) in postings.iter() {
+        for (uid, tf, doc_len, row, positions) in this_postings.iter() {
+            if let Some(candidates) = &candidates {
+                if !candidates.contains(uid) {
+                    continue;
+                }
+            }
+            documents
+                .entry((*uid, *row))
+                .or_insert_with(|| (*doc_len, HashMap::new()))
+                .1
+                .insert(*q, (*tf, positions));
+        }
+    }
+
+    let mut uid_scores: HashMap<u64, f32> = HashMap::new();
+    for ((uid, _row), (doc_len, matched)) in documents.iter() {
+        if let Some(slop) = slop {
+            if matched.len() < distinct.len() {
+                continue;
+            }
+            let positions: Vec<&Vec<u64>> = phrase.iter().map(|q| matched[q].1).collect();
+            if !phrase_matches(&positions, slop) {
+                continue;
+            }
+        }
+
+        let mut score: f32 = matched
+            .iter()
+            .map(|(q, (tf, _))| term_weights[*q] * params.term_score(*tf as f32, *doc_len as f32))
+            .sum();
+        if proximity_boost > 0.0 {
+            let positions: Vec<&Vec<u64>> = matched.values().map(|(_, p)| *p).collect();
+            score *= 1.0 + proximity_boost * proximity(&positions);
+        }
+        uid_scores
+            .entry(*uid)
+            .and_modify(|e| *e = e.max(score))
+            .or_insert(score);
+    }
+
+    let mut top: Vec<(u64, f32)> = uid_scores.into_iter().collect();
+    top.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    top.truncate(k);
+    Ok(top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phrase_matches() {
+        let cell = vec![1, 7];
+        let phones = vec![4, 8];
+        assert!(phrase_matches(&[&cell, &phones], 0));
+        assert!(!phrase_matches(&[&phones, &cell], 0));
+        assert!(phrase_matches(&[&phones, &cell], 2));
+
+        let a = vec![0];
+        let b = vec![3];
+        let c = vec![4];
+        assert!(!phrase_matches(&[&a, &b, &c], 1));
+        assert!(phrase_matches(&[&a, &b, &c], 2));
+
+        // adjacent, and sloppy where the gap comes before the last token
+        let a = vec![0, 9];
+        let b = vec![1, 10];
+        let c = vec![2, 13];
+        assert!(phrase_matches(&[&a, &b, &c], 0));
+        assert!(!phrase_matches(&[&c, &b, &a], 0));
+        let c = vec![4, 13];
+        assert!(!phrase_matches(&[&a, &b, &c], 1));
+        assert!(phrase_matches(&[&a, &b, &c], 2));
+    }
+
+    #[test]
+    fn test_proximity() {
+        let a = vec![0, 10];
+        let b = vec![11];
+        assert_eq!(proximity(&[&a, &b]), 1.0);
+        let c = vec![5];
+        assert_eq!(proximity(&[&a, &c]), 0.2);
+        assert_eq!(proximity(&[&a]), 0.0);
+    }
+}
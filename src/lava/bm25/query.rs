@@ -0,0 +1,391 @@
+use crate::lava::error::LavaError;
+
+/*
+Boolean bm25 queries.
+query := alternative ('|' alternative)*
+alternative := clause*
+clause := ('+' | '-')? (term | '"' words '"' | '(' query ')')
+A required clause (+) has to match, an excluded one (-) must not. When an alternative has no
+required clauses at least one of the others has to match. Quoted terms and words that the
+tokenizer splits up need all of their tokens.
+A term with a '*' is a wildcard, e.g. conn* or *timeout*, where '*' stands for any characters. It
+matches the terms of the vocabulary that fit the pattern, any of which is enough.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Occur {
+    Should,
+    Must,
+    MustNot,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Clause<T> {
+    pub occur: Occur,
+    pub node: QueryNode<T>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum QueryNode<T> {
+    Term(T),
+    And(Vec<Clause<T>>),
+    Or(Vec<QueryNode<T>>),
+}
+
+pub(crate) fn parse_query(query: &str) -> Result<QueryNode<String>, LavaError> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut pos = 0;
+    let node = parse_alternatives(&chars, &mut pos)?;
+    if pos < chars.len() {
+        return Err(LavaError::Parse(format!(
+            "unexpected '{}' at position {} in query",
+            chars[pos], pos
+        )));
+    }
+    Ok(node)
+}
+
+fn skip_whitespace(chars: &[char], pos: &mut usize) {
+    while *pos < chars.len() && chars[*pos].is_whitespace() {
+        *pos += 1;
+    }
+}
+
+fn parse_alternatives(chars: &[char], pos: &mut usize) -> Result<QueryNode<String>, LavaError> {
+    let mut alternatives = vec![QueryNode::And(parse_clauses(chars, pos)?)];
+    while *pos < chars.len() && chars[*pos] == '|' {
+        *pos += 1;
+        alternatives.push(QueryNode::And(parse_clauses(chars, pos)?));
+    }
+    Ok(if alternatives.len() == 1 {
+        alternatives.pop().unwrap()
+    } else {
+        QueryNode::Or(alternatives)
+    })
+}
+
+// stops at '|', ')' or the end of the query
+fn parse_clauses(chars: &[char], pos: &mut usize) -> Result<Vec<Clause<String>>, LavaError> {
+    let mut clauses = vec![];
+    loop {
+        skip_whitespace(chars, pos);
+        if *pos == chars.len() || chars[*pos] == '|' || chars[*pos] == ')' {
+            return Ok(clauses);
+        }
+
+        let occur = match chars[*pos] {
+            '+' => Occur::Must,
+            '-' => Occur::MustNot,
+            _ => Occur::Should,
+        };
+        if occur != Occur::Should {
+            *pos += 1;
+        }
+
+        let node = match chars.get(*pos) {
+            Some('(') => {
+                *pos += 1;
+                let node = parse_alternatives(chars, pos)?;
+                if chars.get(*pos) != Some(&')') {
+                    return Err(LavaError::Parse("unbalanced '(' in query".to_string()));
+                }
+                *pos += 1;
+                node
+            }
+            Some('"') => {
+                let start = *pos + 1;
+                let end = chars[start..]
+                    .iter()
+                    .position(|c| *c == '"')
+                    .ok_or_else(|| LavaError::Parse("unbalanced '\"' in query".to_string()))?;
+                *pos = start + end + 1;
+                QueryNode::Term(chars[start..start + end].iter().collect())
+            }
+            _ => {
+                let start = *pos;
+                while *pos < chars.len()
+                    && !chars[*pos].is_whitespace()
+                    && !matches!(chars[*pos], '(' | ')' | '|' | '"')
+                {
+                    *pos += 1;
+                }
+                if *pos == start {
+                    return Err(LavaError::Parse(format!(
+                        "expected a term at position {} in query",
+                        start
+                    )));
+                }
+                QueryNode::Term(chars[start..*pos].iter().collect())
+            }
+        };
+        clauses.push(Clause { occur, node });
+    }
+}
+
+impl<T> QueryNode<T> {
+    pub(crate) fn map_terms<U, E>(
+        self,
+        f: &mut impl FnMut(T) -> Result<U, E>,
+    ) -> Result<QueryNode<U>, E> {
+        Ok(match self {
+            QueryNode::Term(term) => QueryNode::Term(f(term)?),
+            QueryNode::And(clauses) => QueryNode::And(
+                clauses
+                    .into_iter()
+                    .map(|clause| {
+                        Ok(Clause {
+                            occur: clause.occur,
+                            node: clause.node.map_terms(f)?,
+                        })
+                    })
+                    .collect::<Result<_, E>>()?,
+            ),
+            QueryNode::Or(alternatives) => QueryNode::Or(
+                alternatives
+                    .into_iter()
+                    .map(|node| node.map_terms(f))
+                    .collect::<Result<_, E>>()?,
+            ),
+        })
+    }
+
+    /// Calls `f` on every term with whether it sits under an excluded clause.
+    pub(crate) fn visit_terms(&self, excluded: bool, f: &mut impl FnMut(&T, bool)) {
+        match self {
+            QueryNode::Term(term) => f(term, excluded),
+            QueryNode::And(clauses) => {
+                for clause in clauses {
+                    clause
+                        .node
+                        .visit_terms(excluded || clause.occur == Occur::MustNot, f);
+                }
+            }
+            QueryNode::Or(alternatives) => {
+                for node in alternatives {
+                    node.visit_terms(excluded, f);
+                }
+            }
+        }
+    }
+}
+
+impl<T> QueryNode<Vec<T>> {
+    /// Replaces every term by its alternatives, several of them are matched as an Or.
+    pub(crate) fn expand_alternatives(self) -> QueryNode<T> {
+        match self {
+            QueryNode::Term(mut alternatives) => {
+                if alternatives.len() == 1 {
+                    QueryNode::Term(alternatives.pop().unwrap())
+                } else {
+                    QueryNode::Or(alternatives.into_iter().map(QueryNode::Term).collect())
+                }
+            }
+            QueryNode::And(clauses) => QueryNode::And(
+                clauses
+                    .into_iter()
+                    .map(|clause| Clause {
+                        occur: clause.occur,
+                        node: clause.node.expand_alternatives(),
+                    })
+                    .collect(),
+            ),
+            QueryNode::Or(alternatives) => QueryNode::Or(
+                alternatives
+                    .into_iter()
+                    .map(|node| node.expand_alternatives())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+pub(crate) fn is_wildcard(term: &str) -> bool {
+    term.contains('*')
+}
+
+/// Whether the term fits the pattern, '*' stands for any run of characters, also an empty one.
+pub(crate) fn wildcard_matches(pattern: &str, term: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if parts.len() == 1 {
+        return pattern == term;
+    }
+    if term.len() < first.len() + last.len() || !term.starts_with(first) || !term.ends_with(last)
+    {
+        return false;
+    }
+    let mut rest = &term[first.len()..term.len() - last.len()];
+    for part in parts[1..parts.len() - 1].iter() {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+fn intersect(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let (mut i, mut j) = (0, 0);
+    let mut result = vec![];
+    while i < a.len() && j < b.len() {
+        if a[i] < b[j] {
+            i += 1;
+        } else if a[i] > b[j] {
+            j += 1;
+        } else {
+            result.push(a[i]);
+            i += 1;
+            j += 1;
+        }
+    }
+    result
+}
+
+fn union(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let (mut i, mut j) = (0, 0);
+    let mut result = Vec::with_capacity(a.len().max(b.len()));
+    while i < a.len() || j < b.len() {
+        if j == b.len() || (i < a.len() && a[i] < b[j]) {
+            result.push(a[i]);
+            i += 1;
+        } else if i == a.len() || b[j] < a[i] {
+            result.push(b[j]);
+            j += 1;
+        } else {
+            result.push(a[i]);
+            i += 1;
+            j += 1;
+        }
+    }
+    result
+}
+
+fn subtract(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut j = 0;
+    let mut result = vec![];
+    for uid in a {
+        while j < b.len() && b[j] < *uid {
+            j += 1;
+        }
+        if j == b.len() || b[j] != *uid {
+            result.push(*uid);
+        }
+    }
+    result
+}
+
+impl QueryNode<Vec<usize>> {
+    /// The sorted uids matching the query, `token_uids` are the sorted uids of every query token.
+    pub(crate) fn evaluate(&self, token_uids: &[Vec<u64>]) -> Vec<u64> {
+        match self {
+            QueryNode::Term(tokens) => match tokens.split_first() {
+                Some((first, rest)) => rest.iter().fold(token_uids[*first].clone(), |acc, t| {
+                    intersect(&acc, &token_uids[*t])
+                }),
+                None => vec![],
+            },
+            QueryNode::Or(alternatives) => alternatives
+                .iter()
+                .fold(vec![], |acc, node| union(&acc, &node.evaluate(token_uids))),
+            QueryNode::And(clauses) => {
+                let mut must: Option<Vec<u64>> = None;
+                let mut should: Vec<u64> = vec![];
+                let mut must_not: Vec<u64> = vec![];
+                for clause in clauses {
+                    let uids = clause.node.evaluate(token_uids);
+                    match clause.occur {
+                        Occur::Must => {
+                            must = Some(match must {
+                                Some(must) => intersect(&must, &uids),
+                                None => uids,
+                            })
+                        }
+                        Occur::Should => should = union(&should, &uids),
+                        Occur::MustNot => must_not = union(&must_not, &uids),
+                    }
+                }
+                subtract(&must.unwrap_or(should), &must_not)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(s: &str) -> QueryNode<String> {
+        QueryNode::Term(s.to_string())
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = parse_query("error -timeout +payment").unwrap();
+        assert_eq!(
+            query,
+            QueryNode::And(vec![
+                Clause { occur: Occur::Should, node: term("error") },
+                Clause { occur: Occur::MustNot, node: term("timeout") },
+                Clause { occur: Occur::Must, node: term("payment") },
+            ])
+        );
+
+        let query = parse_query("+(card | \"bank transfer\") e-mail").unwrap();
+        assert_eq!(
+            query,
+            QueryNode::And(vec![
+                Clause {
+                    occur: Occur::Must,
+                    node: QueryNode::Or(vec![
+                        QueryNode::And(vec![Clause { occur: Occur::Should, node: term("card") }]),
+                        QueryNode::And(vec![Clause {
+                            occur: Occur::Should,
+                            node: term("bank transfer")
+                        }]),
+                    ])
+                },
+                Clause { occur: Occur::Should, node: term("e-mail") },
+            ])
+        );
+
+        assert!(parse_query("(a | b").is_err());
+        assert!(parse_query("a )").is_err());
+        assert!(parse_query("+ a").is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let names = ["error", "timeout", "payment"];
+        let token_uids = vec![vec![1, 2, 3, 4], vec![2, 5], vec![3, 4, 5]];
+        let resolve = |query: &str| {
+            parse_query(query)
+                .unwrap()
+                .map_terms(&mut |term: String| {
+                    Ok::<_, ()>(
+                        term.split(' ')
+                            .map(|word| names.iter().position(|n| *n == word).unwrap())
+                            .collect(),
+                    )
+                })
+                .unwrap()
+        };
+
+        assert_eq!(resolve("error -timeout +payment").evaluate(&token_uids), vec![3, 4]);
+        assert_eq!(resolve("error -timeout").evaluate(&token_uids), vec![1, 3, 4]);
+        assert_eq!(resolve("timeout | \"error payment\"").evaluate(&token_uids), vec![2, 3, 4, 5]);
+        assert_eq!(resolve("-error").evaluate(&token_uids), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_wildcard_matches() {
+        assert!(wildcard_matches("conn*", "connection"));
+        assert!(wildcard_matches("conn*", "conn"));
+        assert!(!wildcard_matches("conn*", "disconnect"));
+        assert!(wildcard_matches("*conn*", "disconnected"));
+        assert!(wildcard_matches("*out", "timeout"));
+        assert!(wildcard_matches("a*b*a", "aba"));
+        assert!(!wildcard_matches("a*a", "a"));
+        assert!(wildcard_matches("req*id*42", "request_id_0042"));
+        assert!(!wildcard_matches("req*id*42", "request_42_id"));
+    }
+}
@@ -0,0 +1,498 @@
+use crate::formats::readers::AsyncReader;
+use crate::lava::error::LavaError;
+
+use super::bm25::{decode_block, decode_fields};
+
+/// Query time BM25 parameters, `avg_len` is taken over all the files that are searched.
+pub(crate) struct Bm25Params {
+    pub k1: f32,
+    pub b: f32,
+    pub avg_len: f32,
+}
+
+/// The score of a posting for a query token of weight 1. It must not decrease with the term
+/// frequency nor increase with the document length, block bounds rely on that.
+pub(crate) trait TermScorer {
+    fn term_score(&self, tf: f32, doc_len: f32) -> f32;
+
+    /// Where the fields block sits in a directory entry, for scorers that need the per field term
+    /// frequencies and lengths of the postings.
+    fn fields_entry(&self) -> Option<usize> {
+        None
+    }
+
+    /// The score of a posting, `fields` is its entry of the fields block if there is one.
+    fn posting_score(&self, tf: f32, doc_len: f32, _fields: &[u64]) -> f32 {
+        self.term_score(tf, doc_len)
+    }
+}
+
+impl TermScorer for Bm25Params {
+    fn term_score(&self, tf: f32, doc_len: f32) -> f32 {
+        tf * (self.k1 + 1.0) / (tf + self.k1 * (1.0 - self.b + self.b * doc_len / self.avg_len))
+    }
+}
+
+/// Learned sparse weights are stored as integer impacts in place of the term frequencies,
+/// rounded to multiples of 1 / SPARSE_SCALE.
+pub(crate) const SPARSE_SCALE: f32 = 1000.0;
+
+/// Scores learned sparse postings, the score of a document is the dot product of its weights
+/// with the query weights.
+pub(crate) struct ImpactScorer;
+
+impl TermScorer for ImpactScorer {
+    fn term_score(&self, impact: f32, _doc_len: f32) -> f32 {
+        impact / SPARSE_SCALE
+    }
+}
+
+/// BM25F over multi-field postings. The term frequency of every field is normalized by the average
+/// length of the field and weighted before the saturation. Field weights must not be negative.
+pub(crate) struct Bm25fScorer {
+    pub k1: f32,
+    pub b: f32,
+    pub field_weights: Vec<f32>,
+    pub avg_lengths: Vec<f32>,
+    pub fields_entry: usize,
+}
+
+impl Bm25fScorer {
+    fn saturate(&self, tf: f32) -> f32 {
+        tf * (self.k1 + 1.0) / (tf + self.k1)
+    }
+
+    fn field_tf(&self, field: usize, tf: f32, len: f32) -> f32 {
+        self.field_weights[field] * tf / (1.0 - self.b + self.b * len / self.avg_lengths[field])
+    }
+}
+
+impl TermScorer for Bm25fScorer {
+    // only used for block bounds: a field holds at most the max term frequency of the block and
+    // is at least as long as its term frequency, the bound takes both for every field.
+    fn term_score(&self, tf: f32, _doc_len: f32) -> f32 {
+        let tf: f32 = (0..self.field_weights.len())
+            .map(|field| self.field_tf(field, tf, tf))
+            .sum();
+        self.saturate(tf)
+    }
+
+    fn fields_entry(&self) -> Option<usize> {
+        Some(self.fields_entry)
+    }
+
+    fn posting_score(&self, _tf: f32, _doc_len: f32, fields: &[u64]) -> f32 {
+        let num_fields = self.field_weights.len();
+        let tf: f32 = (0..num_fields)
+            .map(|field| self.field_tf(field, fields[field] as f32, fields[num_fields + field] as f32))
+            .sum();
+        self.saturate(tf)
+    }
+}
+
+/// Walks the posting list of one query token block by block. Blocks are only fetched when a
+/// document in them could make it into the top k.
+struct BlockCursor {
+    weight: f32,
+    directory: Vec<u64>,
+    stride: usize,
+    block: usize,
+    // no posting below this uid is of interest anymore
+    floor: u64,
+    postings: Option<Vec<(u64, f32)>>,
+    pos: usize,
+    max_bound: f32,
+}
+
+impl BlockCursor {
+    fn new(weight: f32, directory: Vec<u64>, stride: usize, params: &impl TermScorer) -> Self {
+        let mut cursor = Self {
+            weight,
+            directory,
+            stride,
+            block: 0,
+            floor: 0,
+            postings: None,
+            pos: 0,
+            max_bound: 0.0,
+        };
+        cursor.max_bound = (0..cursor.num_blocks())
+            .map(|block| cursor.block_bound(block, params))
+            .fold(0.0, f32::max);
+        cursor
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.directory.len() / self.stride
+    }
+
+    fn entry(&self, block: usize) -> &[u64] {
+        &self.directory[block * self.stride..(block + 1) * self.stride]
+    }
+
+    fn exhausted(&self) -> bool {
+        self.block >= self.num_blocks()
+    }
+
+    // max term frequency with min document length bounds every document in the block.
+    fn block_bound(&self, block: usize, params: &impl TermScorer) -> f32 {
+        let entry = self.entry(block);
+        (self.weight * params.term_score(entry[2] as f32, entry[3] as f32)).max(0.0)
+    }
+
+    fn first_uid(&self) -> u64 {
+        self.entry(self.block)[0]
+    }
+
+    fn last_uid(&self) -> u64 {
+        self.entry(self.block)[1]
+    }
+
+    /// Exact when the block is loaded, otherwise a lower bound of the next uid.
+    fn current_uid(&self) -> u64 {
+        match &self.postings {
+            Some(postings) => postings[self.pos].0,
+            None => self.first_uid().max(self.floor),
+        }
+    }
+
+    fn next_block(&mut self) {
+        self.block += 1;
+        self.postings = None;
+        self.pos = 0;
+    }
+
+    /// Moves to the first uid >= target without fetching anything.
+    fn shallow_advance(&mut self, target: u64) {
+        self.floor = self.floor.max(target);
+        while !self.exhausted() {
+            if self.last_uid() < self.floor {
+                self.next_block();
+                continue;
+            }
+            if let Some(postings) = &self.postings {
+                while self.pos < postings.len() && postings[self.pos].0 < self.floor {
+                    self.pos += 1;
+                }
+                if self.pos == postings.len() {
+                    self.next_block();
+                    continue;
+                }
+            }
+            break;
+        }
+    }
+
+    async fn load(
+        &mut self,
+        reader: &mut AsyncReader,
+        params: &impl TermScorer,
+    ) -> Result<(), LavaError> {
+        if self.postings.is_some() || self.exhausted() {
+            return Ok(());
+        }
+        let entry = self.entry(self.block).to_vec();
+        // the fields block follows the posting block, one request reads both
+        let end = match params.fields_entry() {
+            Some(idx) => entry[idx] + entry[idx + 1],
+            None => entry[4] + entry[5],
+        };
+        let bytes = reader.read_range(entry[4], end).await?;
+        let block = decode_block(&bytes[..entry[5] as usize])?;
+        let fields = match params.fields_entry() {
+            Some(idx) => {
+                let begin = (entry[idx] - entry[4]) as usize;
+                decode_fields(&bytes[begin..], block.len())?
+            }
+            None => vec![],
+        };
+
+        // several documents can share a uid, the uid keeps the best scoring one.
+        let mut postings: Vec<(u64, f32)> = vec![];
+        for (i, (uid, tf, doc_len)) in block.into_iter().enumerate() {
+            let posting_fields = fields.get(i).map_or(&[][..], |f| &f[..]);
+            let score = self.weight * params.posting_score(tf as f32, doc_len as f32, posting_fields);
+            match postings.last_mut() {
+                Some(last) if last.0 == uid => last.1 = last.1.max(score),
+                _ => postings.push((uid, score)),
+            }
+        }
+        // last_uid >= floor, so this always finds a posting
+        self.pos = postings.iter().position(|p| p.0 >= self.floor).unwrap_or(0);
+        self.postings = Some(postings);
+        Ok(())
+    }
+}
+
+/// Block-Max WAND over the posting lists of one file. `terms` holds the weight (for bm25 the query
+/// weight times IDF) and the block directory of every query token, `stride` is the size of a
+/// directory entry and `params` scores the postings. Returns up to k (uid, score) pairs.
+pub(crate) async fn block_max_wand(
+    reader: &mut AsyncReader,
+    terms: Vec<(f32, Vec<u64>)>,
+    stride: usize,
+    params: &impl TermScorer,
+    k: usize,
+) -> Result<Vec<(u64, f32)>, LavaError> {
+    let total_blocks: usize = terms.iter().map(|t| t.1.len() / stride).sum();
+    let mut fetched_blocks: usize = 0;
+
+    let mut cursors: Vec<BlockCursor> = terms
+        .into_iter()
+        .map(|(weight, directory)| BlockCursor::new(weight, directory, stride, params))
+        .collect();
+
+    // the top k so far, the smallest score is the threshold a document has to beat.
+    let mut top: Vec<(u64, f32)> = Vec::with_capacity(k + 1);
+    let threshold = |top: &Vec<(u64, f32)>| {
+        if top.len() < k {
+            f32::NEG_INFINITY
+        } else {
+            top.iter().map(|t| t.1).fold(f32::INFINITY, f32::min)
+        }
+    };
+
+    while k > 0 {
+        cursors.retain(|c| !c.exhausted());
+        if cursors.is_empty() {
+            break;
+        }
+        cursors.sort_by_key(|c| c.current_uid());
+        let theta = threshold(&top);
+
+        // documents before the pivot cannot beat the threshold even with every max score
+        let mut acc: f32 = 0.0;
+        let pivot = cursors.iter().position(|c| {
+            acc += c.max_bound;
+            acc > theta
+        });
+        let Some(pivot) = pivot else {
+            break;
+        };
+        let d = cursors[pivot].current_uid();
+        let mut last = pivot;
+        while last + 1 < cursors.len() && cursors[last + 1].current_uid() == d {
+            last += 1;
+        }
+
+        for c in cursors[..=last].iter_mut() {
+            c.shallow_advance(d);
+        }
+        if cursors[..=last].iter().any(|c| c.exhausted()) {
+            continue;
+        }
+
+        // second check with the max scores of the blocks that may actually contain d
+        let mut block_sum: f32 = 0.0;
+        let mut next = u64::MAX;
+        for c in cursors[..=last].iter() {
+            if c.current_uid() == d {
+                block_sum += c.block_bound(c.block, params);
+                next = next.min(c.last_uid().saturating_add(1));
+            } else {
+                next = next.min(c.current_uid());
+            }
+        }
+        if last + 1 < cursors.len() {
+            next = next.min(cursors[last + 1].current_uid());
+        }
+
+        if block_sum <= theta {
+            // nothing in [d, next) can make it
+            for c in cursors[..=last].iter_mut() {
+                c.shallow_advance(next);
+            }
+            continue;
+        }
+
+        let mut score: f32 = 0.0;
+        let mut found = false;
+        for c in cursors[..=last].iter_mut() {
+            if c.current_uid() != d {
+                continue;
+            }
+            if c.postings.is_none() {
+                c.load(reader, params).await?;
+                fetched_blocks += 1;
+            }
+            let (uid, term_score) = c.postings.as_ref().unwrap()[c.pos];
+            if uid == d {
+                score += term_score;
+                found = true;
+            }
+        }
+
+        if found && score > theta {
+            if top.len() == k {
+                let min_pos = (0..top.len())
+                    .min_by(|a, b| top[*a].1.partial_cmp(&top[*b].1).unwrap())
+                    .unwrap();
+                top.swap_remove(min_pos);
+            }
+            top.push((d, score));
+        }
+
+        for c in cursors[..=last].iter_mut() {
+            c.shallow_advance(d + 1);
+        }
+    }
+
+    log::debug!(
+        "block max wand fetched {} of {} blocks",
+        fetched_blocks,
+        total_blocks
+    );
+
+    top.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    Ok(top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::readers::{get_file_size_and_reader, ReaderType};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+    use std::io::Write;
+    use zstd::stream::encode_all;
+
+    const PARAMS: Bm25Params = Bm25Params {
+        k1: 1.2,
+        b: 0.75,
+        avg_len: 20.0,
+    };
+
+    /// Writes the postings of every term in blocks of `block_size` the way bm25 files do and
+    /// returns the block directory of every term.
+    fn write_terms(
+        file: &mut std::fs::File,
+        terms: &[Vec<(u64, u64, u64)>],
+        block_size: usize,
+    ) -> Vec<Vec<u64>> {
+        let mut offset = 0;
+        let mut directories = vec![];
+        for postings in terms {
+            let mut directory = vec![];
+            for block in postings.chunks(block_size) {
+                let flat: Vec<u64> = block.iter().flat_map(|p| [p.0, p.1, p.2]).collect();
+                let compressed = encode_all(&bincode::serialize(&flat).unwrap()[..], 0).unwrap();
+                file.write_all(&compressed).unwrap();
+                directory.extend([
+                    block[0].0,
+                    block[block.len() - 1].0,
+                    block.iter().map(|p| p.1).max().unwrap(),
+                    block.iter().map(|p| p.2).min().unwrap(),
+                    offset,
+                    compressed.len() as u64,
+                ]);
+                offset += compressed.len() as u64;
+            }
+            directories.push(directory);
+        }
+        directories
+    }
+
+    /// Block-Max WAND and exhaustive scoring of the same terms, both sorted by descending score.
+    async fn wand_and_exhaustive(
+        name: &str,
+        terms: Vec<(f32, Vec<(u64, u64, u64)>)>,
+        block_size: usize,
+        k: usize,
+    ) -> (Vec<(u64, f32)>, Vec<(u64, f32)>) {
+        let path = std::env::temp_dir()
+            .join(format!("rottnest_wand_{}_{}", name, std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        let postings: Vec<Vec<(u64, u64, u64)>> = terms.iter().map(|t| t.1.clone()).collect();
+        let directories = write_terms(&mut file, &postings, block_size);
+        drop(file);
+
+        let mut exhaustive: BTreeMap<u64, f32> = BTreeMap::new();
+        for (weight, postings) in &terms {
+            for (uid, tf, doc_len) in postings {
+                *exhaustive.entry(*uid).or_default() +=
+                    weight * PARAMS.term_score(*tf as f32, *doc_len as f32);
+            }
+        }
+        let mut exhaustive: Vec<(u64, f32)> = exhaustive.into_iter().collect();
+        exhaustive.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+        let (_, mut reader) =
+            get_file_size_and_reader(path.to_str().unwrap().to_string(), ReaderType::default())
+                .await
+                .unwrap();
+        let weighted: Vec<(f32, Vec<u64>)> = terms.iter().map(|t| t.0).zip(directories).collect();
+        let top = block_max_wand(&mut reader, weighted, 6, &PARAMS, k).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (top, exhaustive)
+    }
+
+    /// The top k has the k best scores and every score is the exhaustive score of its uid.
+    fn assert_top_k(top: &[(u64, f32)], exhaustive: &[(u64, f32)], k: usize) {
+        assert_eq!(top.len(), k.min(exhaustive.len()));
+        let scores: BTreeMap<u64, f32> = exhaustive.iter().cloned().collect();
+        for (i, (uid, score)) in top.iter().enumerate() {
+            assert!((scores[uid] - score).abs() < 1e-4);
+            assert!((exhaustive[i].1 - score).abs() < 1e-4);
+        }
+    }
+
+    fn random_postings(rng: &mut StdRng, num_docs: u64, density: f64) -> Vec<(u64, u64, u64)> {
+        let mut postings = vec![];
+        for uid in 0..num_docs {
+            if rng.gen_bool(density) {
+                postings.push((uid, rng.gen_range(1..8), rng.gen_range(5..60)));
+            }
+        }
+        postings
+    }
+
+    #[tokio::test]
+    async fn test_block_max_wand_many_blocks() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let terms = vec![
+            (0.4, random_postings(&mut rng, 5000, 0.5)),
+            (1.3, random_postings(&mut rng, 5000, 0.1)),
+            (2.5, random_postings(&mut rng, 5000, 0.02)),
+        ];
+        for k in [1, 10, 100] {
+            let (top, exhaustive) = wand_and_exhaustive("many", terms.clone(), 64, k).await;
+            assert_top_k(&top, &exhaustive, k);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_block_max_wand_ties() {
+        // every document scores the same
+        let postings: Vec<(u64, u64, u64)> = (0..500).map(|uid| (uid * 3, 2, 20)).collect();
+        let (top, exhaustive) =
+            wand_and_exhaustive("ties", vec![(1.0, postings.clone()), (1.0, postings)], 32, 10)
+                .await;
+        assert_top_k(&top, &exhaustive, 10);
+    }
+
+    #[tokio::test]
+    async fn test_block_max_wand_k_above_matches() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let terms = vec![
+            (1.0, random_postings(&mut rng, 200, 0.2)),
+            (0.5, random_postings(&mut rng, 200, 0.1)),
+        ];
+        let (top, exhaustive) = wand_and_exhaustive("above", terms, 8, 1000).await;
+        assert!(exhaustive.len() < 1000);
+        assert_top_k(&top, &exhaustive, 1000);
+    }
+
+    #[tokio::test]
+    async fn test_block_max_wand_term_in_one_block() {
+        let mut rng = StdRng::seed_from_u64(13);
+        // a rare heavy term whose few postings all sit in one block late in the uid range
+        let rare: Vec<(u64, u64, u64)> = vec![(4100, 3, 10), (4200, 1, 40), (4300, 5, 15)];
+        let terms = vec![(0.3, random_postings(&mut rng, 5000, 0.6)), (4.0, rare)];
+        for k in [2, 5] {
+            let (top, exhaustive) = wand_and_exhaustive("one_block", terms.clone(), 64, k).await;
+            assert_top_k(&top, &exhaustive, k);
+            assert!(top.iter().any(|(uid, _)| *uid == 4300));
+        }
+    }
+}
@@ -14,7 +14,10 @@ use crate::{
         error::LavaError,
         logcloud::logcloud_common::{get_all_types, get_type, PListChunk, PlistSize},
         search::_search_lava_substring_char,
-        substring::{_build_lava_substring_char, _build_lava_substring_char_wavelet},
+        substring::normalization::Normalization,
+        substring::{
+            _build_lava_substring_char, _build_lava_substring_char_wavelet, read_fm_footer,
+        },
     },
 };
 use serde::de::DeserializeOwned;
@@ -874,13 +877,25 @@ pub async fn index_logcloud(index_name: &str, num_groups: usize, use_wavelet: Op
     let _ = write_kauai(index_name, num_groups).unwrap();
     let texts: Vec<(u64, String)> = write_oahu(index_name);
     if use_wavelet {
-        let _ = _build_lava_substring_char_wavelet(format!("{}.hawaii", index_name), texts, 1)
-            .await
-            .unwrap();
+        let _ = _build_lava_substring_char_wavelet(
+            format!("{}.hawaii", index_name),
+            texts,
+            1,
+            &Normalization::default(),
+            false,
+            None,
+        )
+        .await
+        .unwrap();
     } else {
-        let _ = _build_lava_substring_char(format!("{}.hawaii", index_name), texts, 1)
-            .await
-            .unwrap();
+        let _ = _build_lava_substring_char(
+            format!("{}.hawaii", index_name),
+            texts,
+            1,
+            &Normalization::default(),
+        )
+        .await
+        .unwrap();
     }
 }
 
@@ -914,7 +929,7 @@ pub async fn index_analysis(split_index_prefixes: Vec<String>, reader_type: Read
 
     for (hawaii_size, mut reader_hawaii) in hawaii_sizes.into_iter().zip(reader_hawaiis.into_iter())
     {
-        let results = reader_hawaii.read_usize_from_end(4).await.unwrap();
+        let results = read_fm_footer(&mut reader_hawaii).await.unwrap();
         let posting_list_offsets_offset = results[1];
         let total_counts_offset = results[2];
 
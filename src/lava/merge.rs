@@ -8,7 +8,7 @@ use crate::formats::readers::ReaderType;
 
 use crate::lava::bm25::merge_lava_bm25;
 use crate::lava::error::LavaError;
-use crate::lava::substring::merge_lava_substring;
+use crate::lava::substring::{merge_lava_substring, merge_lava_substring_char};
 use crate::lava::uuid::merge_lava_uuid;
 
 // @Rain chore: we need to simplify all the iterator impls
@@ -20,12 +20,12 @@ async fn async_parallel_merge_files(
     do_not_delete: BTreeSet<String>,
     uid_offsets: Vec<u64>,
     k: usize,
-    mode: usize, // 0 for bm25 1 for substring 2 for uuid
+    mode: usize, // 0 for bm25 1 for substring 2 for uuid 3 for char substring with a wavelet tree
     reader_type: ReaderType,
     cache_ranges: Option<Vec<Vec<(usize, usize)>>>,
 ) -> Result<Vec<(usize, usize)>, LavaError> {
-    assert!(mode == 1 || mode == 0 || mode == 2);
-    if mode == 2 || mode == 1 {
+    assert!(mode <= 3);
+    if mode != 0 {
         assert_eq!(k, 2);
     }
 
@@ -80,7 +80,7 @@ async fn async_parallel_merge_files(
                 let do_not_delete_clone = do_not_delete.clone();
                 let reader_type = reader_type.clone();
 
-                let task: tokio::task::JoinHandle<Vec<(usize, usize)>> = tokio::spawn(async move {
+                let task = tokio::spawn(async move {
                     let my_uuid = uuid::Uuid::new_v4();
                     let merged_filename = my_uuid.to_string(); // Define this function based on your requirements
 
@@ -105,6 +105,15 @@ async fn async_parallel_merge_files(
                             )
                             .await
                         }
+                        3 => {
+                            merge_lava_substring_char(
+                                &merged_filename,
+                                file_chunk.to_vec(),
+                                uid_chunk.to_vec(),
+                                reader_type.clone(),
+                            )
+                            .await
+                        }
                         2 => {
                             merge_lava_uuid(
                                 &merged_filename,
@@ -115,8 +124,7 @@ async fn async_parallel_merge_files(
                             .await
                         }
                         _ => unreachable!(),
-                    }
-                    .unwrap();
+                    }?;
 
                     // now go delete the input files
 
@@ -130,7 +138,7 @@ async fn async_parallel_merge_files(
                     // no race condition since everybody pushes the same value to new_uid_offsets_clone
                     merged_files_clone.lock().unwrap().push(merged_filename);
                     new_uid_offsets_clone.lock().unwrap().push(0);
-                    cache_ranges
+                    Ok::<_, LavaError>(cache_ranges)
                 });
 
                 tasks.push(task);
@@ -141,7 +149,9 @@ async fn async_parallel_merge_files(
                 .await
                 .into_iter()
                 .collect::<Result<Vec<_>, _>>()
-                .unwrap();
+                .unwrap()
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()?;
 
             // Extract the merged files for the next level of merging
             let merged_files: Vec<String> = Arc::try_unwrap(merged_files_shared)
@@ -176,7 +186,7 @@ pub async fn parallel_merge_files(
     files: Vec<String>,
     uid_offsets: Vec<u64>,
     k: usize,
-    mode: usize, // 0 for bm25 1 for substring 2 for uuid
+    mode: usize, // 0 for bm25 1 for substring 2 for uuid 3 for char substring with a wavelet tree
     reader_type: ReaderType,
 ) -> Result<Vec<(usize, usize)>, LavaError> {
     let do_not_delete = BTreeSet::from_iter(files.clone().into_iter());
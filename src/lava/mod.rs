@@ -1,5 +1,6 @@
 pub mod error;
 
+mod analyzer;
 mod bm25;
 mod logcloud;
 mod merge;
@@ -10,15 +11,30 @@ mod uuid;
 mod vector;
 
 pub use bm25::build_lava_bm25;
+pub use bm25::build_lava_bm25f;
+pub use bm25::build_lava_sparse;
 pub use substring::build_lava_substring;
 pub use substring::build_lava_substring_char;
 pub use uuid::build_lava_uuid;
 
 pub use merge::parallel_merge_files;
 
+pub use search::count_lava_substring;
+pub use search::count_lava_substring_char;
 pub use search::get_tokenizer_vocab;
+pub use search::regex_matches;
+pub use search::search_lava_bm25_boolean;
+pub use search::search_lava_bm25_phrase;
+pub use search::search_lava_bm25_text;
+pub use search::search_lava_bm25f;
+pub use search::search_lava_sparse;
 pub use search::search_lava_substring;
 pub use search::search_lava_substring_char;
+pub use search::search_lava_substring_char_fuzzy;
+pub use search::search_lava_substring_char_occurrences;
+pub use search::search_lava_substring_counts;
+pub use search::search_lava_substring_fuzzy;
+pub use search::search_lava_substring_regex;
 pub use search::search_lava_uuid;
 pub use vector::search_lava_vector;
 
@@ -1,31 +1,45 @@
 use crate::lava::constants::*;
 use crate::lava::fm_chunk::FMChunk;
 use crate::lava::plist::PListChunk;
+use crate::lava::analyzer::Analyzer;
+use crate::lava::substring::normalization::read_normalization_async;
+use crate::lava::substring::{
+    _count_lava_substring_char, _search_lava_substring_char_fuzzy,
+    _search_lava_substring_char_occurrences, _search_lava_substring_regex, check_fuzzy_query,
+    closest_matches, count_occurrences, count_substring_one_file, fuzzy_substring_one_file,
+    substring_occurrences_one_file, sum_counts, FUZZY_MAX_BRANCHES,
+};
 use crate::{
     formats::readers::{
-        get_file_size_and_reader, get_file_sizes_and_readers, get_reader, get_readers, AsyncReader,
-        ClonableAsyncReader, ReaderType,
+        get_file_size_and_reader, get_index_file_sizes_and_readers, get_reader, get_readers,
+        AsyncReader, ClonableAsyncReader, ReaderType,
     },
     lava::error::LavaError,
 };
+use arrow::array::{make_array, Array, ArrayData, BooleanArray, LargeStringArray};
+use arrow::compute::cast;
+use arrow::datatypes::DataType;
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
 use itertools::Itertools;
 use serde::de::DeserializeOwned;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 use std::time::Instant;
 use std::{
     collections::{HashMap, HashSet},
     io::Read,
 };
-use tokenizers::tokenizer::Tokenizer;
 use tokio::task::JoinSet;
 
 use futures::stream::{FuturesUnordered, StreamExt};
 use std::cmp::Ordering;
 use std::io::{self, Cursor};
 
-use super::bm25::search_bm25_async;
+use super::bm25::{
+    is_wildcard, parse_query, read_bm25_document_frequencies, read_bm25_languages,
+    read_bm25_terms, search_bm25_async, search_bm25_boolean_async, search_bm25_phrase_async,
+    search_bm25f_async, search_sparse_async, wildcard_matches,
+};
 
 enum QueryParam {
     SubstringCharWavelet(Vec<Vec<u8>>),
@@ -33,9 +47,7 @@ enum QueryParam {
     Substring(Vec<Vec<u32>>),
     Uuid(String),
 }
-async fn get_tokenizer_async(
-    mut readers: Vec<AsyncReader>,
-) -> Result<(Tokenizer, Vec<String>), LavaError> {
+async fn read_tokenizer_async(readers: &mut [AsyncReader]) -> Result<Analyzer, LavaError> {
     let mut compressed_tokenizer: Option<Vec<u8>> = None;
 
     for i in 0..readers.len() {
@@ -54,20 +66,7 @@ async fn get_tokenizer_async(
         }
     }
 
-    let slice = &compressed_tokenizer.unwrap()[..];
-    let mut decompressor = Decoder::new(slice)?;
-    let mut decompressed_serialized_tokenizer: Vec<u8> = Vec::with_capacity(slice.len() as usize);
-    decompressor.read_to_end(&mut decompressed_serialized_tokenizer)?;
-
-    let mut result: Vec<String> = Vec::new();
-    let tokenizer = Tokenizer::from_bytes(decompressed_serialized_tokenizer).unwrap();
-
-    for i in 0..tokenizer.get_vocab_size(false) {
-        let tok = tokenizer.decode(&vec![i as u32], false).unwrap();
-        result.push(tok);
-    }
-
-    Ok((tokenizer, result))
+    Analyzer::from_compressed(&compressed_tokenizer.unwrap())
 }
 
 async fn search_uuid_one_file(
@@ -163,9 +162,274 @@ pub async fn search_lava_bm25(
     query_weights: Vec<f32>,
     k: usize,
     reader_type: ReaderType,
+    k1: Option<f32>,
+    b: Option<f32>,
 ) -> Result<Vec<(u64, u64)>, LavaError> {
-    let (file_sizes, readers) = get_file_sizes_and_readers(&files, reader_type).await?;
-    search_bm25_async(file_sizes, readers, query_tokens, query_weights, k).await
+    let (file_sizes, readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let result = search_bm25_async(file_sizes, readers, query_tokens, query_weights, k, k1, b).await?;
+    Ok(result.into_iter().map(|(file_id, uid, _score)| (file_id, uid)).collect())
+}
+
+/// Learned sparse search over files built with `build_lava_sparse`. The query is a sparse vector
+/// from the same model, documents are ranked by their dot product with it.
+#[tokio::main]
+pub async fn search_lava_sparse(
+    files: Vec<String>,
+    query_tokens: Vec<u32>,
+    query_weights: Vec<f32>,
+    k: usize,
+    reader_type: ReaderType,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let (file_sizes, readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    search_sparse_async(file_sizes, readers, query_tokens, query_weights, k).await
+}
+
+/// Same as `search_lava_bm25`, but the query is tokenized with the tokenizer stored in the lava
+/// files. Repeated query tokens are weighted by how often they occur.
+#[tokio::main]
+pub async fn search_lava_bm25_text(
+    files: Vec<String>,
+    query: String,
+    k: usize,
+    reader_type: ReaderType,
+    k1: Option<f32>,
+    b: Option<f32>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let tokenizer = read_tokenizer_async(&mut readers).await?;
+    let languages = read_query_languages(&tokenizer, &mut readers).await?;
+    let (query_tokens, query_weights, _phrase) =
+        tokenize_bm25_query(&tokenizer, &query, &languages)?;
+
+    search_bm25_async(file_sizes, readers, query_tokens, query_weights, k, k1, b).await
+}
+
+/// BM25F search over files built with `build_lava_bm25f`. `field_weights` has one weight per field
+/// in build order and replaces the weights stored in the files for this query.
+#[tokio::main]
+pub async fn search_lava_bm25f(
+    files: Vec<String>,
+    query: String,
+    field_weights: Option<Vec<f32>>,
+    k: usize,
+    reader_type: ReaderType,
+    k1: Option<f32>,
+    b: Option<f32>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let tokenizer = read_tokenizer_async(&mut readers).await?;
+    let languages = read_query_languages(&tokenizer, &mut readers).await?;
+    let (query_tokens, query_weights, _phrase) =
+        tokenize_bm25_query(&tokenizer, &query, &languages)?;
+
+    search_bm25f_async(
+        file_sizes,
+        readers,
+        query_tokens,
+        query_weights,
+        field_weights,
+        k,
+        k1,
+        b,
+    )
+    .await
+}
+
+/// The document languages a query has to be analyzed for, only language analyzers use them.
+async fn read_query_languages(
+    tokenizer: &Analyzer,
+    readers: &mut [AsyncReader],
+) -> Result<BTreeSet<String>, LavaError> {
+    match tokenizer {
+        Analyzer::Language(_) => read_bm25_languages(readers).await,
+        _ => Ok(BTreeSet::new()),
+    }
+}
+
+/// Returns the distinct tokens of the query, how often each occurs and, for every token of the
+/// query in order, its index into the distinct tokens. When the query is analyzed in several
+/// ways a token counts as often as in the analysis it occurs most in, the phrase is the first.
+fn tokenize_bm25_query(
+    tokenizer: &Analyzer,
+    query: &str,
+    languages: &BTreeSet<String>,
+) -> Result<(Vec<u32>, Vec<f32>, Vec<usize>), LavaError> {
+    let encodings = tokenizer.encode_query(query, languages)?;
+
+    let mut query_counts: BTreeMap<u32, f32> = BTreeMap::new();
+    for encoding in encodings.iter() {
+        let mut counts: BTreeMap<u32, f32> = BTreeMap::new();
+        for token in encoding.iter() {
+            *counts.entry(*token).or_insert(0.0) += 1.0;
+        }
+        for (token, count) in counts {
+            let entry = query_counts.entry(token).or_insert(0.0);
+            *entry = entry.max(count);
+        }
+    }
+    let (query_tokens, query_weights): (Vec<u32>, Vec<f32>) = query_counts.into_iter().unzip();
+    let phrase = encodings[0]
+        .iter()
+        .map(|token| query_tokens.binary_search(token).unwrap())
+        .collect();
+
+    Ok((query_tokens, query_weights, phrase))
+}
+
+/// Phrase search over bm25 files built with `positional`. With a slop only documents containing
+/// the query tokens in order, with at most `slop` other tokens in between, match (0 is an exact
+/// phrase). `proximity_boost` rewards documents whose query tokens are close together.
+#[tokio::main]
+pub async fn search_lava_bm25_phrase(
+    files: Vec<String>,
+    query: String,
+    slop: Option<usize>,
+    proximity_boost: Option<f32>,
+    k: usize,
+    reader_type: ReaderType,
+    k1: Option<f32>,
+    b: Option<f32>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let tokenizer = read_tokenizer_async(&mut readers).await?;
+    let languages = read_query_languages(&tokenizer, &mut readers).await?;
+    let (query_tokens, query_weights, phrase) =
+        tokenize_bm25_query(&tokenizer, &query, &languages)?;
+    if phrase.is_empty() {
+        return Ok(vec![]);
+    }
+
+    search_bm25_phrase_async(
+        file_sizes,
+        readers,
+        query_tokens,
+        query_weights,
+        phrase,
+        slop,
+        proximity_boost,
+        k,
+        k1,
+        b,
+    )
+    .await
+}
+
+const MAX_WILDCARD_EXPANSIONS: usize = 64;
+
+/// The token ids of the terms that match each wildcard pattern, at most `max_expansions` per
+/// pattern, the terms in the most documents first. Terms in none of the documents are left out.
+async fn expand_wildcards(
+    tokenizer: &Analyzer,
+    readers: &mut [AsyncReader],
+    patterns: &[String],
+    max_expansions: usize,
+) -> Result<HashMap<String, Vec<u32>>, LavaError> {
+    let vocabulary: Option<Vec<String>> = tokenizer.vocabulary_terms();
+
+    let mut matches: Vec<Vec<u32>> = Vec::with_capacity(patterns.len());
+    for pattern in patterns {
+        if pattern.chars().all(|c| c == '*') {
+            return Err(LavaError::Parse(format!(
+                "wildcard {} needs at least one character besides '*'",
+                pattern
+            )));
+        }
+        let mut tokens: Vec<u32> = vec![];
+        for normalized in tokenizer.normalize_patterns(pattern)? {
+            // only the part of the term dictionary with the literal prefix of the pattern is read
+            let terms: Vec<String> = match &vocabulary {
+                Some(vocabulary) => vocabulary.clone(),
+                None => {
+                    let prefix = normalized.split('*').next().unwrap_or("");
+                    read_bm25_terms(readers, prefix).await?.into_iter().collect()
+                }
+            };
+            tokens.extend(
+                terms
+                    .iter()
+                    .filter(|term| wildcard_matches(&normalized, term))
+                    .flat_map(|term| tokenizer.term_ids(term)),
+            );
+        }
+        tokens.sort();
+        tokens.dedup();
+        matches.push(tokens);
+    }
+
+    let candidates: Vec<u32> = matches.concat();
+    let frequencies: HashMap<u32, u64> = candidates
+        .iter()
+        .copied()
+        .zip(read_bm25_document_frequencies(readers, &candidates).await?)
+        .collect();
+
+    Ok(patterns
+        .iter()
+        .zip(matches)
+        .map(|(pattern, mut tokens)| {
+            tokens.retain(|token| frequencies[token] > 0);
+            tokens.sort_by_key(|token| std::cmp::Reverse(frequencies[token]));
+            tokens.truncate(max_expansions);
+            (pattern.clone(), tokens)
+        })
+        .collect())
+}
+
+/// Boolean search over bm25 files, e.g. "error -timeout +payment" or "+(card | \"bank transfer\")".
+/// Excluded terms only filter, the other terms are scored as usual. Wildcard terms, e.g. "conn*",
+/// match up to `max_expansions` terms of the vocabulary and score like a disjunction of them.
+#[tokio::main]
+pub async fn search_lava_bm25_boolean(
+    files: Vec<String>,
+    query: String,
+    k: usize,
+    reader_type: ReaderType,
+    k1: Option<f32>,
+    b: Option<f32>,
+    max_expansions: Option<usize>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let query = parse_query(&query)?;
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let tokenizer = read_tokenizer_async(&mut readers).await?;
+    let languages = read_query_languages(&tokenizer, &mut readers).await?;
+
+    let mut patterns: Vec<String> = vec![];
+    query.visit_terms(false, &mut |term: &String, _| {
+        if is_wildcard(term) {
+            patterns.push(term.clone());
+        }
+    });
+    let expansions = if patterns.is_empty() {
+        HashMap::new()
+    } else {
+        let max_expansions = max_expansions.unwrap_or(MAX_WILDCARD_EXPANSIONS);
+        expand_wildcards(&tokenizer, &mut readers, &patterns, max_expansions).await?
+    };
+
+    let query = query
+        .map_terms(&mut |term: String| match expansions.get(&term) {
+            Some(tokens) => Ok(tokens.iter().map(|token| vec![*token]).collect()),
+            None => tokenizer.encode_query(&term, &languages),
+        })?
+        .expand_alternatives();
+
+    let mut query_counts: BTreeMap<u32, f32> = BTreeMap::new();
+    query.visit_terms(false, &mut |tokens: &Vec<u32>, excluded| {
+        for token in tokens {
+            *query_counts.entry(*token).or_insert(0.0) += if excluded { 0.0 } else { 1.0 };
+        }
+    });
+    let (query_tokens, query_weights): (Vec<u32>, Vec<f32>) = query_counts.into_iter().unzip();
+    let query = query.map_terms(&mut |tokens: Vec<u32>| {
+        Ok::<_, LavaError>(
+            tokens
+                .iter()
+                .map(|token| query_tokens.binary_search(token).unwrap())
+                .collect(),
+        )
+    })?;
+
+    search_bm25_boolean_async(file_sizes, readers, query_tokens, query_weights, query, k, k1, b).await
 }
 
 #[tokio::main]
@@ -175,10 +439,28 @@ pub async fn search_lava_uuid(
     k: usize,
     reader_type: ReaderType,
 ) -> Result<Vec<(u64, u64)>, LavaError> {
-    let (file_sizes, readers) = get_file_sizes_and_readers(&files, reader_type).await?;
+    let (file_sizes, readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
     search_generic_async(file_sizes, readers, QueryParam::Uuid(query), k).await
 }
 
+/// The tokens of a substring query, normalized like the indexed text.
+async fn tokenize_substring_query(
+    readers: &mut [AsyncReader],
+    query: &str,
+) -> Result<Vec<u32>, LavaError> {
+    let tokenizer = read_tokenizer_async(readers).await?;
+    let normalization = read_normalization_async(readers, true).await?;
+    let skip_tokens: HashSet<u32> = normalization.skip_tokens(&tokenizer)?;
+
+    let folded: String = normalization.fold(query);
+    let encoding = tokenizer.encode(&folded)?;
+    Ok(encoding
+        .iter()
+        .filter(|id| !skip_tokens.contains(id))
+        .cloned()
+        .collect())
+}
+
 #[tokio::main]
 pub async fn search_lava_substring(
     files: Vec<String>,
@@ -188,43 +470,9 @@ pub async fn search_lava_substring(
     token_viable_limit: Option<usize>,
     sample_factor: Option<usize>,
 ) -> Result<Vec<(u64, u64)>, LavaError> {
-    let (_file_sizes, readers) = get_file_sizes_and_readers(&files, reader_type.clone()).await?;
-    let tokenizer = get_tokenizer_async(readers).await?.0;
-
-    let mut skip_tokens: HashSet<u32> = HashSet::new();
-    for char in SKIP.chars() {
-        let char_str = char.to_string();
-        skip_tokens.extend(
-            tokenizer
-                .encode(char_str.clone(), false)
-                .unwrap()
-                .get_ids()
-                .to_vec(),
-        );
-        skip_tokens.extend(
-            tokenizer
-                .encode(format!(" {}", char_str), false)
-                .unwrap()
-                .get_ids()
-                .to_vec(),
-        );
-        skip_tokens.extend(
-            tokenizer
-                .encode(format!("{} ", char_str), false)
-                .unwrap()
-                .get_ids()
-                .to_vec(),
-        );
-    }
-
-    let lower: String = query.chars().flat_map(|c| c.to_lowercase()).collect();
-    let encoding = tokenizer.encode(lower, false).unwrap();
-    let result: Vec<u32> = encoding
-        .get_ids()
-        .iter()
-        .filter(|id| !skip_tokens.contains(id))
-        .cloned()
-        .collect();
+    let (_file_sizes, mut readers) =
+        get_index_file_sizes_and_readers(&files, reader_type.clone()).await?;
+    let result: Vec<u32> = tokenize_substring_query(&mut readers, &query).await?;
 
     let mut query: Vec<Vec<u32>> = if let Some(sample_factor) = sample_factor {
         (0..sample_factor)
@@ -261,10 +509,200 @@ pub async fn search_lava_substring(
 
     // println!("query {:?}", query);
 
-    let (file_sizes, readers) = get_file_sizes_and_readers(&files, reader_type).await?;
+    let (file_sizes, readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
     search_generic_async(file_sizes, readers, QueryParam::Substring(query), k).await
 }
 
+/// Occurrence counts per document of token files, (file_id, uid, count) with the most
+/// occurrences first. The whole query is searched, counts are exact for indices built without a
+/// token skip factor.
+#[tokio::main]
+pub async fn search_lava_substring_counts(
+    files: Vec<String>,
+    query: String,
+    k: usize,
+    reader_type: ReaderType,
+) -> Result<Vec<(u64, u64, u64)>, LavaError> {
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let query: Vec<u32> = tokenize_substring_query(&mut readers, &query).await?;
+    if query.is_empty() {
+        return Err(LavaError::Parse(
+            "the query has no tokens after normalization".to_string(),
+        ));
+    }
+
+    let mut join_set = JoinSet::new();
+    for (file_id, (reader, file_size)) in readers.into_iter().zip(file_sizes).enumerate() {
+        join_set.spawn(substring_occurrences_one_file(
+            file_id as u64,
+            reader,
+            file_size,
+            query.clone(),
+        ));
+    }
+
+    let mut occurrences: Vec<(u64, u64, Option<u64>)> = vec![];
+    while let Some(res) = join_set.join_next().await {
+        occurrences.extend(res.unwrap()?);
+    }
+    Ok(count_occurrences(occurrences, k)
+        .into_iter()
+        .map(|(file_id, uid, count, _)| (file_id, uid, count))
+        .collect())
+}
+
+/// Documents of token files with a match within `max_distance` edits of the query, (file_id,
+/// uid, distance) with the closest first. Without `indels` only substituted tokens count. At most
+/// `max_branches` search states are expanded per file, the closest matches are found first.
+#[tokio::main]
+pub async fn search_lava_substring_fuzzy(
+    files: Vec<String>,
+    query: String,
+    max_distance: usize,
+    k: usize,
+    reader_type: ReaderType,
+    indels: Option<bool>,
+    max_branches: Option<usize>,
+) -> Result<Vec<(u64, u64, u64)>, LavaError> {
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let query: Vec<u32> = tokenize_substring_query(&mut readers, &query).await?;
+    check_fuzzy_query(query.len(), max_distance)?;
+
+    let mut join_set = JoinSet::new();
+    for (file_id, (reader, file_size)) in readers.into_iter().zip(file_sizes).enumerate() {
+        join_set.spawn(fuzzy_substring_one_file(
+            file_id as u64,
+            reader,
+            file_size,
+            query.clone(),
+            max_distance,
+            indels.unwrap_or(true),
+            max_branches.unwrap_or(FUZZY_MAX_BRANCHES),
+        ));
+    }
+
+    let mut matches: Vec<(u64, u64, u64)> = vec![];
+    while let Some(res) = join_set.join_next().await {
+        matches.extend(res.unwrap()?);
+    }
+    Ok(closest_matches(matches, k))
+}
+
+/// `search_lava_substring_fuzzy` for char files, the distance counts bytes of the normalized
+/// text, one per character for ascii.
+#[tokio::main]
+pub async fn search_lava_substring_char_fuzzy(
+    files: Vec<String>,
+    query: String,
+    max_distance: usize,
+    k: usize,
+    reader_type: ReaderType,
+    indels: Option<bool>,
+    max_branches: Option<usize>,
+) -> Result<Vec<(u64, u64, u64)>, LavaError> {
+    _search_lava_substring_char_fuzzy(
+        files,
+        query,
+        max_distance,
+        k,
+        reader_type,
+        indels.unwrap_or(true),
+        max_branches.unwrap_or(FUZZY_MAX_BRANCHES),
+    )
+    .await
+}
+
+/// How often the query occurs in token files, from the FM index alone without reading any
+/// posting list. With `distinct` also an estimate of the number of documents that contain it.
+#[tokio::main]
+pub async fn count_lava_substring(
+    files: Vec<String>,
+    query: String,
+    reader_type: ReaderType,
+    distinct: Option<bool>,
+) -> Result<(u64, Option<u64>), LavaError> {
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let query: Vec<u32> = tokenize_substring_query(&mut readers, &query).await?;
+    if query.is_empty() {
+        return Err(LavaError::Parse(
+            "the query has no tokens after normalization".to_string(),
+        ));
+    }
+
+    let mut join_set = JoinSet::new();
+    for (reader, file_size) in readers.into_iter().zip(file_sizes) {
+        join_set.spawn(count_substring_one_file(reader, file_size, query.clone()));
+    }
+
+    let mut counts: Vec<(u64, u64)> = vec![];
+    while let Some(res) = join_set.join_next().await {
+        counts.push(res.unwrap()?);
+    }
+    Ok(sum_counts(counts, distinct.unwrap_or(false)))
+}
+
+/// `count_lava_substring` for char files.
+#[tokio::main]
+pub async fn count_lava_substring_char(
+    files: Vec<String>,
+    query: String,
+    reader_type: ReaderType,
+    distinct: Option<bool>,
+) -> Result<(u64, Option<u64>), LavaError> {
+    _count_lava_substring_char(files, query, reader_type, distinct.unwrap_or(false)).await
+}
+
+/// Occurrence counts per document of char files, (file_id, uid, count, offsets) with the most
+/// occurrences first. With `positions` the offsets are the character offsets of the matches in
+/// the original text, which needs an index built with positional.
+#[tokio::main]
+pub async fn search_lava_substring_char_occurrences(
+    files: Vec<String>,
+    query: String,
+    k: usize,
+    reader_type: ReaderType,
+    positions: Option<bool>,
+) -> Result<Vec<(u64, u64, u64, Vec<u64>)>, LavaError> {
+    _search_lava_substring_char_occurrences(
+        files,
+        query,
+        k,
+        reader_type,
+        positions.unwrap_or(false),
+    )
+    .await
+}
+
+/// Candidate documents of char files for a regex, (file_id, uid) sorted. Only documents that
+/// contain every literal the regex requires are returned, the caller reads their pages and
+/// confirms the matches with the full regex.
+#[tokio::main]
+pub async fn search_lava_substring_regex(
+    files: Vec<String>,
+    pattern: String,
+    reader_type: ReaderType,
+) -> Result<Vec<(u64, u64)>, LavaError> {
+    _search_lava_substring_regex(files, pattern, reader_type).await
+}
+
+/// Which strings of `array` match the regex, to confirm the candidates of
+/// `search_lava_substring_regex` once their pages are read. The pattern is parsed by the same regex
+/// syntax the literals are taken from, null strings do not match.
+pub fn regex_matches(array: ArrayData, pattern: String) -> Result<ArrayData, LavaError> {
+    let regex = regex::Regex::new(&pattern)
+        .map_err(|e| LavaError::Parse(format!("invalid regex {}: {}", pattern, e)))?;
+    let array = cast(&make_array(array), &DataType::LargeUtf8)?;
+    let array = array
+        .as_any()
+        .downcast_ref::<LargeStringArray>()
+        .ok_or(LavaError::Parse("Expects a string array".to_string()))?;
+    let matches: BooleanArray = array
+        .iter()
+        .map(|text| Some(text.is_some_and(|text| regex.is_match(text))))
+        .collect();
+    Ok(matches.into_data())
+}
+
 #[tokio::main]
 pub async fn search_lava_substring_char(
     files: Vec<String>,
@@ -291,8 +729,8 @@ pub async fn get_tokenizer_vocab(
     files: Vec<String>,
     reader_type: ReaderType,
 ) -> Result<Vec<String>, LavaError> {
-    let (_file_sizes, readers) = get_file_sizes_and_readers(&files, reader_type).await?;
-    Ok(get_tokenizer_async(readers).await?.1)
+    let (_file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    read_tokenizer_async(&mut readers).await?.vocabulary()
 }
 
 #[cfg(test)]
@@ -301,6 +739,8 @@ mod tests {
 
     use super::search_lava_bm25;
     use super::search_lava_substring;
+    use super::{regex_matches, LavaError};
+    use arrow::array::{Array, BooleanArray, StringArray};
 
     #[test]
     pub fn test_search_lava_one() {
@@ -312,6 +752,8 @@ mod tests {
             vec![0.1, 0.2],
             10,
             ReaderType::default(),
+            None,
+            None,
         )
         .unwrap();
 
@@ -326,6 +768,8 @@ mod tests {
             vec![0.1, 0.2],
             10,
             ReaderType::default(),
+            None,
+            None,
         )
         .unwrap();
 
@@ -344,4 +788,23 @@ mod tests {
         );
         println!("{:?}", result.unwrap());
     }
+
+    #[test]
+    fn test_regex_matches() {
+        let texts = StringArray::from(vec![
+            Some("error 404 at /api"),
+            None,
+            Some("ok 200"),
+            Some("ERROR 500"),
+        ]);
+        let matches = regex_matches(texts.into_data(), r"error \d{3}".to_string()).unwrap();
+        assert_eq!(
+            BooleanArray::from(matches),
+            BooleanArray::from(vec![true, false, false, false])
+        );
+        assert!(matches!(
+            regex_matches(StringArray::from(vec!["a"]).into_data(), "(".to_string()),
+            Err(LavaError::Parse(_))
+        ));
+    }
 }
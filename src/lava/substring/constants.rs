@@ -1,2 +1,10 @@
 pub const FM_CHUNK_TOKS: usize = 4000000;
+// the symbols of an FM chunk, a rank reads one chunk and scans it
+pub const FM_CHECKPOINT_TOKS: usize = 4096;
+// the FM chunks whose directory ranks share a base, their deltas from it must fit a u16
+pub const FM_CHECKPOINTS_PER_BASE: usize = 16;
 pub const SKIP: &str = "!#$%&'()*+,-./:;<=>?@[^_`{|}~ ，。、；：！？“”‘’《》（）【】——…";
+// states a fuzzy substring search expands per file unless told otherwise
+pub const FUZZY_MAX_BRANCHES: usize = 10000;
+// the symbols of both inputs a char merge holds in memory at about 17 bytes each, beyond it refuses
+pub const CHAR_MERGE_MAX_SYMBOLS: usize = 1 << 27;
@@ -1,65 +1,577 @@
+use super::constants::{FM_CHECKPOINTS_PER_BASE, FM_CHECKPOINT_TOKS};
+use crate::formats::readers::AsyncReader;
 use crate::lava::error::LavaError;
 use bytes::Bytes;
+use num_traits::AsPrimitive;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::Read;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
 use zstd::stream::encode_all;
 use zstd::stream::read::Decoder;
 
-pub(crate) struct FMChunk<T>
+/*
+The bwt of an FM index is stored as chunks of FM_CHECKPOINT_TOKS symbols, each compressed on its
+own, followed by a sparse directory of the ranks at the chunk starts. Every group of
+FM_CHECKPOINTS_PER_BASE chunks has a record for each symbol that occurs in it, in symbol order: the
+symbol's row, its count in the group, its rank before the group as a u64 base and its rank before
+each chunk of the group as a u16 delta from the base. After the records of all groups every row
+lists the groups it occurs in and the index of its record there. Both are fixed size and stored
+uncompressed, so the directory takes FM_RECORD_BYTES + FM_ENTRY_BYTES per symbol occurring in a
+group, at most that per symbol of the bwt whatever the alphabet. Where the records of a group and
+the entries of a row start is kept with the metadata, see FMDirectoryIndex.
+A rank reads the entries of its symbol together with the chunk holding pos - 1, then one record:
+that of the group of the chunk, or the last one before it if the symbol does not occur there, as
+its rank is then the base plus the count of that group. The entries of a row take at most
+FM_ENTRY_BYTES per group. The ranks of every symbol of a range, for fuzzy search, read the records
+of every group the range spans, see `range_ranks`.
+*/
+
+const FM_RECORD_BYTES: usize = 16 + 2 * FM_CHECKPOINTS_PER_BASE;
+const FM_ENTRY_BYTES: usize = 8;
+const FM_GROUP_TOKS: usize = FM_CHECKPOINT_TOKS * FM_CHECKPOINTS_PER_BASE;
+
+/// The record index at which every group starts and the entry index at which every row starts,
+/// each with the end as the last one.
+pub(crate) type FMDirectoryIndex = (Vec<u64>, Vec<u64>);
+
+/// Writes the bwt as FM chunks and their directory, returns the chunk offsets, the last one is
+/// where the directory starts, the directory index and the count of every symbol.
+pub(crate) fn write_fm_chunks<T>(
+    file: &mut File,
+    bwt: &[T],
+) -> Result<(Vec<usize>, FMDirectoryIndex, HashMap<T, u64>), LavaError>
 where
-    T: Serialize + for<'de> Deserialize<'de> + Clone + Eq + std::hash::Hash,
+    T: Serialize + Copy + Ord + std::hash::Hash + AsPrimitive<usize>,
 {
-    pub counts_so_far: HashMap<T, u64>,
+    let mut total_counts: HashMap<T, u64> = HashMap::new();
+    for token in bwt {
+        *total_counts.entry(*token).or_insert(0) += 1;
+    }
+    let mut symbols: Vec<T> = total_counts.keys().cloned().collect();
+    symbols.sort();
+    let mut rows: Vec<u32> = vec![0; symbols.last().map(|s| s.as_() + 1).unwrap_or(0)];
+    for (row, symbol) in symbols.iter().enumerate() {
+        rows[symbol.as_()] = row as u32;
+    }
+
+    let mut fm_chunk_offsets: Vec<usize> = vec![file.seek(SeekFrom::Current(0))? as usize];
+    for chunk in bwt.chunks(FM_CHECKPOINT_TOKS) {
+        let serialized_chunk = bincode::serialize(chunk)?;
+        let compressed_chunk = encode_all(&serialized_chunk[..], 10).expect("Compression failed");
+        file.write_all(&compressed_chunk)?;
+        fm_chunk_offsets.push(file.seek(SeekFrom::Current(0))? as usize);
+    }
+
+    let mut counts: Vec<u64> = vec![0; symbols.len()];
+    let mut group_starts: Vec<u64> = vec![0];
+    let mut row_groups: Vec<Vec<(u32, u32)>> = vec![vec![]; symbols.len()];
+    for (group_id, group) in bwt.chunks(FM_GROUP_TOKS).enumerate() {
+        // the count of every row in every chunk of the group
+        let mut chunk_counts: BTreeMap<usize, [u32; FM_CHECKPOINTS_PER_BASE]> = BTreeMap::new();
+        for (i, chunk) in group.chunks(FM_CHECKPOINT_TOKS).enumerate() {
+            for token in chunk {
+                let row = rows[token.as_()] as usize;
+                chunk_counts
+                    .entry(row)
+                    .or_insert([0; FM_CHECKPOINTS_PER_BASE])[i] += 1;
+            }
+        }
+
+        let mut records: Vec<u8> = Vec::with_capacity(chunk_counts.len() * FM_RECORD_BYTES);
+        for (index, (row, chunk_counts)) in chunk_counts.iter().enumerate() {
+            let count: u32 = chunk_counts.iter().sum();
+            records.extend((*row as u32).to_le_bytes());
+            records.extend(count.to_le_bytes());
+            records.extend(counts[*row].to_le_bytes());
+            // the rank before a chunk of the group is at most FM_GROUP_TOKS - FM_CHECKPOINT_TOKS
+            let mut delta: u32 = 0;
+            for chunk_count in chunk_counts {
+                records.extend((delta as u16).to_le_bytes());
+                delta += chunk_count;
+            }
+            counts[*row] += count as u64;
+            row_groups[*row].push((group_id as u32, index as u32));
+        }
+        file.write_all(&records)?;
+        group_starts.push(group_starts.last().unwrap() + chunk_counts.len() as u64);
+    }
+
+    let mut row_starts: Vec<u64> = vec![0];
+    let mut entries: Vec<u8> =
+        Vec::with_capacity(*group_starts.last().unwrap() as usize * FM_ENTRY_BYTES);
+    for groups in row_groups {
+        for (group_id, index) in groups.iter() {
+            entries.extend(group_id.to_le_bytes());
+            entries.extend(index.to_le_bytes());
+        }
+        row_starts.push(row_starts.last().unwrap() + groups.len() as u64);
+    }
+    file.write_all(&entries)?;
+
+    Ok((fm_chunk_offsets, (group_starts, row_starts), total_counts))
+}
+
+/// One chunk of the bwt.
+pub(crate) struct FMChunk<T> {
     pub bwt_chunk: Vec<T>,
 }
 
 impl<T> FMChunk<T>
 where
-    T: Serialize + for<'de> Deserialize<'de> + Clone + Eq + std::hash::Hash,
+    T: for<'de> Deserialize<'de> + Eq,
 {
     pub fn new(chunk: Bytes) -> Result<Self, LavaError> {
-        let compressed_counts_size = u64::from_le_bytes(chunk[0..8].try_into().unwrap());
-        let compressed_counts = &chunk[8..(compressed_counts_size + 8) as usize];
-        let mut decompressor = Decoder::new(compressed_counts)?;
-        let mut serialized_counts: Vec<u8> = Vec::with_capacity(compressed_counts_size as usize);
-        decompressor.read_to_end(&mut serialized_counts)?;
-        let counts: HashMap<T, u64> = bincode::deserialize(&serialized_counts)?;
-        let compressed_fm_chunk = &chunk[(compressed_counts_size + 8) as usize..];
-        let mut decompressor = Decoder::new(compressed_fm_chunk)?;
-        let mut serialized_fm_chunk: Vec<u8> =
-            Vec::with_capacity(compressed_fm_chunk.len() as usize);
-        decompressor.read_to_end(&mut serialized_fm_chunk)?;
-        let fm_chunk: Vec<T> = bincode::deserialize(&serialized_fm_chunk)?;
-
+        let mut decompressor = Decoder::new(&chunk[..])?;
+        let mut serialized_chunk: Vec<u8> = Vec::with_capacity(chunk.len());
+        decompressor.read_to_end(&mut serialized_chunk)?;
         Ok(Self {
-            counts_so_far: counts,
-            bwt_chunk: fm_chunk,
+            bwt_chunk: bincode::deserialize(&serialized_chunk)?,
+        })
+    }
+
+    /// The occurrences of `token` in the first `pos` symbols of the chunk.
+    pub fn count(&self, token: &T, pos: usize) -> u64 {
+        self.bwt_chunk[..pos].iter().filter(|t| *t == token).count() as u64
+    }
+}
+
+/// The chunk offsets and the directory index of an FM index, read with its metadata.
+#[derive(Debug, Clone)]
+pub(crate) struct FMDirectory {
+    chunk_offsets: Vec<u64>,
+    group_starts: Vec<u64>,
+    row_starts: Vec<u64>,
+    /// The directory row of every symbol up to the largest one, u32::MAX if it does not occur.
+    rows: Vec<u32>,
+    /// The symbol and the total count of every row.
+    symbols: Vec<(u32, u64)>,
+}
+
+impl FMDirectory {
+    /// The rows are the symbols with a count in `cumulative_counts`, which must match the index.
+    pub(crate) fn new(
+        chunk_offsets: Vec<u64>,
+        cumulative_counts: &[u64],
+        index: FMDirectoryIndex,
+    ) -> Result<Self, LavaError> {
+        let (group_starts, row_starts) = index;
+        let symbols: Vec<(u32, u64)> = cumulative_counts
+            .windows(2)
+            .enumerate()
+            .filter(|(_, counts)| counts[1] > counts[0])
+            .map(|(symbol, counts)| (symbol as u32, counts[1] - counts[0]))
+            .collect();
+        let num_groups = chunk_offsets
+            .len()
+            .saturating_sub(1)
+            .div_ceil(FM_CHECKPOINTS_PER_BASE);
+        if chunk_offsets.is_empty()
+            || symbols.len() + 1 != row_starts.len()
+            || num_groups + 1 != group_starts.len()
+            || group_starts.last() != row_starts.last()
+        {
+            return Err(LavaError::Parse(
+                "the FM directory does not match the symbol counts".to_string(),
+            ));
+        }
+        let mut rows: Vec<u32> =
+            vec![u32::MAX; symbols.last().map(|(s, _)| *s as usize + 1).unwrap_or(0)];
+        for (row, (symbol, _)) in symbols.iter().enumerate() {
+            rows[*symbol as usize] = row as u32;
+        }
+        Ok(FMDirectory {
+            chunk_offsets,
+            group_starts,
+            row_starts,
+            rows,
+            symbols,
         })
     }
 
-    #[allow(dead_code)]
-    pub fn serialize(&mut self) -> Result<Vec<u8>, LavaError> {
-        let serialized_counts = bincode::serialize(&self.counts_so_far)?;
-        let mut compressed_counts =
-            encode_all(&serialized_counts[..], 0).expect("Compression failed");
-        let mut result: Vec<u8> = vec![];
-        result.append(&mut (compressed_counts.len() as u64).to_le_bytes().to_vec());
-        result.append(&mut compressed_counts);
-        let serialized_chunk = bincode::serialize(&self.bwt_chunk)?;
-        let mut compressed_chunk =
-            encode_all(&serialized_chunk[..], 0).expect("Compression failed");
-        result.append(&mut compressed_chunk);
-        Ok(result)
-    }
-
-    pub fn search(&self, token: T, pos: usize) -> Result<u64, LavaError> {
-        let mut result = *self.counts_so_far.get(&token).unwrap_or(&0);
-        for j in 0..pos {
-            if self.bwt_chunk[j] == token {
-                result += 1;
+    fn row(&self, symbol: usize) -> Option<usize> {
+        self.rows
+            .get(symbol)
+            .filter(|row| **row != u32::MAX)
+            .map(|row| *row as usize)
+    }
+
+    /// The byte range of the records of the groups first..=last.
+    fn records_range(&self, first: usize, last: usize) -> (u64, u64) {
+        let records_start = *self.chunk_offsets.last().unwrap();
+        (
+            records_start + self.group_starts[first] * FM_RECORD_BYTES as u64,
+            records_start + self.group_starts[last + 1] * FM_RECORD_BYTES as u64,
+        )
+    }
+
+    /// The byte range of the entries of a row.
+    fn entries_range(&self, row: usize) -> (u64, u64) {
+        let entries_start = *self.chunk_offsets.last().unwrap()
+            + self.group_starts.last().unwrap() * FM_RECORD_BYTES as u64;
+        (
+            entries_start + self.row_starts[row] * FM_ENTRY_BYTES as u64,
+            entries_start + self.row_starts[row + 1] * FM_ENTRY_BYTES as u64,
+        )
+    }
+
+    /// The chunk holding symbol pos - 1, as pos may be the end of the bwt, and pos in it.
+    fn chunk_of(pos: usize) -> (usize, usize) {
+        let chunk_id = (pos - 1) / FM_CHECKPOINT_TOKS;
+        (chunk_id, pos - chunk_id * FM_CHECKPOINT_TOKS)
+    }
+
+    pub(crate) async fn read_chunk<T>(
+        &self,
+        reader: &mut AsyncReader,
+        chunk_id: usize,
+    ) -> Result<FMChunk<T>, LavaError>
+    where
+        T: for<'de> Deserialize<'de> + Eq,
+    {
+        FMChunk::new(
+            reader
+                .read_range(
+                    self.chunk_offsets[chunk_id],
+                    self.chunk_offsets[chunk_id + 1],
+                )
+                .await?,
+        )
+    }
+
+    /// The rank of `token` before `pos`, from its entries, a record and the chunk holding pos - 1.
+    pub(crate) async fn rank<T>(
+        &self,
+        reader: &mut AsyncReader,
+        token: T,
+        pos: usize,
+    ) -> Result<u64, LavaError>
+    where
+        T: for<'de> Deserialize<'de> + Eq + AsPrimitive<usize>,
+    {
+        let row = match self.row(token.as_()) {
+            Some(row) if pos > 0 => row,
+            _ => return Ok(0),
+        };
+        let (chunk_id, local_pos) = Self::chunk_of(pos);
+        let group = chunk_id / FM_CHECKPOINTS_PER_BASE;
+        let (start, end) = self.entries_range(row);
+        let mut chunk_reader = reader.clone();
+        let (entries, chunk) = tokio::try_join!(
+            reader.read_range(start, end),
+            self.read_chunk::<T>(&mut chunk_reader, chunk_id)
+        )?;
+
+        // the last group up to this one the row occurs in
+        let entries: Vec<(usize, u64)> = entries
+            .chunks(FM_ENTRY_BYTES)
+            .map(|entry| (read_u32(entry, 0) as usize, read_u32(entry, 4) as u64))
+            .collect();
+        let (entry_group, index) = match entries.partition_point(|(g, _)| *g <= group) {
+            0 => return Ok(0),
+            i => entries[i - 1],
+        };
+        let start = *self.chunk_offsets.last().unwrap()
+            + (self.group_starts[entry_group] + index) * FM_RECORD_BYTES as u64;
+        let record = reader
+            .read_range(start, start + FM_RECORD_BYTES as u64)
+            .await?;
+        Ok(if entry_group == group {
+            checkpoint_rank(&record, chunk_id) + chunk.count(&token, local_pos)
+        } else {
+            record_end(&record)
+        })
+    }
+
+    /// The records of each of the groups first..=last, read at once, for `range_ranks`.
+    pub(crate) async fn read_groups(
+        &self,
+        reader: &mut AsyncReader,
+        first: usize,
+        last: usize,
+    ) -> Result<Vec<Bytes>, LavaError> {
+        let (start, end) = self.records_range(first, last);
+        let records = reader.read_range(start, end).await?;
+        Ok((first..=last)
+            .map(|group| {
+                let offset = |group: usize| {
+                    ((self.group_starts[group] - self.group_starts[first]) as usize)
+                        * FM_RECORD_BYTES
+                };
+                records.slice(offset(group)..offset(group + 1))
+            })
+            .collect())
+    }
+
+    /// The groups first..=last and the chunks to read for the ranks of the symbols in start..end,
+    /// the chunk of start is None for start 0. The range must not be empty.
+    pub(crate) fn range_reads(start: usize, end: usize) -> (usize, usize, Option<usize>, usize) {
+        let start_chunk = (start > 0).then(|| Self::chunk_of(start).0);
+        let (end_chunk, _) = Self::chunk_of(end);
+        (
+            start_chunk.unwrap_or(0) / FM_CHECKPOINTS_PER_BASE,
+            end_chunk / FM_CHECKPOINTS_PER_BASE,
+            start_chunk,
+            end_chunk,
+        )
+    }
+
+    /// The ranks before start and before end of every symbol in start..end, (symbol, start rank,
+    /// end rank), from the records of the groups and the chunks of `range_reads`. A symbol not in
+    /// the first group has the base of the next group it occurs in as its start rank, one not in
+    /// the last group the end of the last group it occurs in as its end rank.
+    pub(crate) fn range_ranks(
+        &self,
+        groups: &[&[u8]],
+        start_chunk: Option<&FMChunk<u32>>,
+        end_chunk: &FMChunk<u32>,
+        start: usize,
+        end: usize,
+    ) -> Vec<(u32, u64, u64)> {
+        let (start_chunk_id, end_chunk_id) = (
+            (start > 0).then(|| Self::chunk_of(start).0),
+            Self::chunk_of(end).0,
+        );
+        let mut start_ranks: HashMap<usize, u64> = HashMap::new();
+        let mut end_ranks: HashMap<usize, u64> = HashMap::new();
+        for (i, records) in groups.iter().enumerate() {
+            for record in records.chunks(FM_RECORD_BYTES) {
+                let row = read_u32(record, 0) as usize;
+                match start_chunk_id {
+                    Some(chunk_id) if i == 0 => {
+                        start_ranks.insert(row, checkpoint_rank(record, chunk_id));
+                    }
+                    Some(_) => {
+                        start_ranks.entry(row).or_insert(record_base(record));
+                    }
+                    None => {}
+                }
+                if i + 1 == groups.len() {
+                    end_ranks.insert(row, checkpoint_rank(record, end_chunk_id));
+                } else {
+                    end_ranks.insert(row, record_end(record));
+                }
+            }
+        }
+        if let Some(chunk) = start_chunk {
+            let (_, local_pos) = Self::chunk_of(start);
+            for token in &chunk.bwt_chunk[..local_pos] {
+                *start_ranks
+                    .get_mut(&(self.rows[*token as usize] as usize))
+                    .unwrap() += 1;
             }
         }
-        Ok(result)
+        let (_, local_pos) = Self::chunk_of(end);
+        for token in &end_chunk.bwt_chunk[..local_pos] {
+            *end_ranks
+                .get_mut(&(self.rows[*token as usize] as usize))
+                .unwrap() += 1;
+        }
+
+        let mut ranks: Vec<(u32, u64, u64)> = end_ranks
+            .into_iter()
+            .filter_map(|(row, end_rank)| {
+                let start_rank = *start_ranks.get(&row).unwrap_or(&0);
+                (end_rank > start_rank).then(|| (self.symbols[row].0, start_rank, end_rank))
+            })
+            .collect();
+        ranks.sort();
+        ranks
+    }
+
+    /// The count of every symbol, (symbol, count): its ranks before 0 and before the end.
+    pub(crate) fn totals(&self) -> &[(u32, u64)] {
+        &self.symbols
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// The rank before the group of a record.
+fn record_base(record: &[u8]) -> u64 {
+    u64::from_le_bytes(record[8..16].try_into().unwrap())
+}
+
+/// The rank after the group of a record.
+fn record_end(record: &[u8]) -> u64 {
+    record_base(record) + read_u32(record, 4) as u64
+}
+
+/// The rank before the start of `chunk_id` from the record of a row in its group.
+fn checkpoint_rank(record: &[u8], chunk_id: usize) -> u64 {
+    let at = 16 + 2 * (chunk_id % FM_CHECKPOINTS_PER_BASE);
+    record_base(record) + u16::from_le_bytes(record[at..at + 2].try_into().unwrap()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::readers::get_file_size_and_reader;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Writes the bwt and reads its directory back.
+    fn write(name: &str, bwt: &[u32]) -> (std::path::PathBuf, FMDirectory, usize, usize) {
+        let path = std::env::temp_dir().join(format!(
+            "rottnest_fm_chunks_{}_{}.bin",
+            name,
+            std::process::id()
+        ));
+        let mut file = File::create(&path).unwrap();
+        let (offsets, index, total_counts) = write_fm_chunks(&mut file, bwt).unwrap();
+        let file_size = file.seek(SeekFrom::Current(0)).unwrap() as usize;
+        assert_eq!(offsets.len(), bwt.len().div_ceil(FM_CHECKPOINT_TOKS) + 1);
+        assert_eq!(index.1.len(), total_counts.len() + 1);
+
+        let max_symbol = *bwt.iter().max().unwrap() as usize;
+        let mut cumulative_counts: Vec<u64> = vec![0];
+        for symbol in 0..=max_symbol + 1 {
+            cumulative_counts
+                .push(cumulative_counts[symbol] + total_counts.get(&(symbol as u32)).unwrap_or(&0));
+        }
+        let offsets: Vec<u64> = offsets.iter().map(|offset| *offset as u64).collect();
+        let mut bad_index = index.clone();
+        bad_index.1.pop();
+        assert!(FMDirectory::new(offsets.clone(), &cumulative_counts, bad_index).is_err());
+        let chunks_size = (offsets.last().unwrap() - offsets[0]) as usize;
+        let directory_size = file_size - *offsets.last().unwrap() as usize;
+        let directory = FMDirectory::new(offsets, &cumulative_counts, index).unwrap();
+        (path, directory, chunks_size, directory_size)
+    }
+
+    #[test]
+    fn test_fm_chunks() {
+        // sparse symbols like hashed tokens, some only in parts of the bwt, over several groups
+        let mut rng = StdRng::seed_from_u64(48);
+        let alphabet: Vec<u32> = (0..300u32).map(|i| i * 7919 % 262144).collect();
+        let n = 2 * FM_GROUP_TOKS + 5000;
+        let bwt: Vec<u32> = (0..n)
+            .map(|i| {
+                let symbols = if i / FM_GROUP_TOKS == 1 || i / FM_CHECKPOINT_TOKS % 5 == 3 {
+                    20
+                } else {
+                    alphabet.len()
+                };
+                alphabet[rng.gen_range(0..symbols)]
+            })
+            .collect();
+        let (path, directory, _, _) = write("ranks", &bwt);
+
+        let naive =
+            |symbol: u32, pos: usize| bwt[..pos].iter().filter(|&&s| s == symbol).count() as u64;
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (_, mut reader) =
+                get_file_size_and_reader(path.to_str().unwrap().to_string(), Default::default())
+                    .await
+                    .unwrap();
+            let positions = [
+                0,
+                1,
+                FM_CHECKPOINT_TOKS - 1,
+                FM_CHECKPOINT_TOKS,
+                FM_CHECKPOINT_TOKS + 1,
+                3 * FM_CHECKPOINT_TOKS + 100,
+                FM_GROUP_TOKS - 1,
+                FM_GROUP_TOKS,
+                FM_GROUP_TOKS + 1,
+                FM_GROUP_TOKS + 3 * FM_CHECKPOINT_TOKS + 17,
+                n - 1,
+                n,
+            ];
+            for pos in positions {
+                for symbol in [alphabet[0], alphabet[1], alphabet[150], alphabet[299], 5] {
+                    assert_eq!(
+                        directory.rank(&mut reader, symbol, pos).await.unwrap(),
+                        naive(symbol, pos),
+                        "symbol {} pos {}",
+                        symbol,
+                        pos
+                    );
+                }
+            }
+
+            // the counts before every position, in one pass
+            let mut counts: HashMap<u32, u64> = HashMap::new();
+            let mut naive_ranks: Vec<Vec<(u32, u64)>> = vec![];
+            for (pos, symbol) in bwt.iter().chain([&u32::MAX]).enumerate() {
+                if positions.contains(&pos) {
+                    naive_ranks.push(
+                        alphabet
+                            .iter()
+                            .map(|symbol| (*symbol, *counts.get(symbol).unwrap_or(&0)))
+                            .collect(),
+                    );
+                }
+                *counts.entry(*symbol).or_insert(0) += 1;
+            }
+            for (i, &start) in positions.iter().enumerate() {
+                for (j, &end) in positions.iter().enumerate().skip(i + 1) {
+                    if (start, end) == (0, n) {
+                        continue;
+                    }
+                    let (first, last, start_chunk, end_chunk) =
+                        FMDirectory::range_reads(start, end);
+                    let groups = directory
+                        .read_groups(&mut reader, first, last)
+                        .await
+                        .unwrap();
+                    let groups: Vec<&[u8]> = groups.iter().map(|records| &records[..]).collect();
+                    let start_chunk = match start_chunk {
+                        Some(chunk_id) => {
+                            Some(directory.read_chunk(&mut reader, chunk_id).await.unwrap())
+                        }
+                        None => None,
+                    };
+                    let end_chunk = directory.read_chunk(&mut reader, end_chunk).await.unwrap();
+                    let mut expected: Vec<(u32, u64, u64)> = naive_ranks[i]
+                        .iter()
+                        .zip(naive_ranks[j].iter())
+                        .map(|((symbol, start_rank), (_, end_rank))| {
+                            (*symbol, *start_rank, *end_rank)
+                        })
+                        .filter(|(_, start_rank, end_rank)| end_rank > start_rank)
+                        .collect();
+                    expected.sort();
+                    assert_eq!(
+                        directory.range_ranks(
+                            &groups,
+                            start_chunk.as_ref(),
+                            &end_chunk,
+                            start,
+                            end
+                        ),
+                        expected,
+                        "start {} end {}",
+                        start,
+                        end
+                    );
+                }
+            }
+        });
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_fm_directory_size() {
+        // a large alphabet of hashed ids, every group of chunks only has its own 500 symbols
+        let mut rng = StdRng::seed_from_u64(48);
+        let num_groups = 16;
+        let bwt: Vec<u32> = (0..num_groups * FM_GROUP_TOKS)
+            .map(|i| (i / FM_GROUP_TOKS * 16000 + rng.gen_range(0..500)) as u32)
+            .collect();
+        let (path, _, chunks_size, directory_size) = write("size", &bwt);
+        std::fs::remove_file(path).unwrap();
+
+        let alphabet_size = num_groups * 500;
+        assert_eq!(
+            directory_size,
+            alphabet_size * (FM_RECORD_BYTES + FM_ENTRY_BYTES)
+        );
+        assert!(directory_size < chunks_size);
+        // a record of every symbol for every group would be many times larger
+        assert!(directory_size * 8 < alphabet_size * num_groups * FM_RECORD_BYTES);
     }
 }
@@ -0,0 +1,515 @@
+use bytes::Bytes;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use tokio::task::JoinSet;
+
+use super::fm_chunk::{FMChunk, FMDirectory};
+use super::normalization::read_normalization_async;
+use super::substring::{read_fm_metadata, read_rows, read_wavelet_metadata, wavelet_uids};
+use super::wavelet_tree::CachedWaveletTree;
+use crate::formats::readers::{get_index_file_sizes_and_readers, AsyncReader, ReaderType};
+use crate::lava::error::LavaError;
+
+/*
+Approximate substring search. The backward search of the FM index becomes a best first search over
+(distance, query symbols left, suffix array range) states. A step extends the range by every symbol
+that occurs before it, a match or a mismatch, and with indels also by a text symbol the query does
+not have, or skips a query symbol the text does not have. The closest states are expanded first, so
+once the branch cap is hit the matches found so far are the closest ones.
+The symbols of char files are the UTF-8 bytes of the normalized text, so there a distance counts
+bytes: a mismatch of two ascii characters costs 1, that of two 3 byte characters up to 3.
+Overlapping ranges of different paths are read once, see `merge_ranges`.
+*/
+
+/// The FM chunks of a token file and the directory records of their groups, keeping all it has
+/// read.
+struct CachedFmIndex {
+    reader: AsyncReader,
+    directory: FMDirectory,
+    n: usize,
+    groups: HashMap<usize, Bytes>,
+    chunks: HashMap<usize, FMChunk<u32>>,
+}
+
+impl CachedFmIndex {
+    async fn chunk(&mut self, chunk_id: usize) -> Result<&FMChunk<u32>, LavaError> {
+        if !self.chunks.contains_key(&chunk_id) {
+            let chunk = self
+                .directory
+                .read_chunk(&mut self.reader, chunk_id)
+                .await?;
+            self.chunks.insert(chunk_id, chunk);
+        }
+        Ok(&self.chunks[&chunk_id])
+    }
+
+    /// The symbols in start..end with their ranks before start and before end. The whole bwt is
+    /// answered from the symbol counts, other ranges read the records of every group they span.
+    async fn symbol_ranks(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> Result<Vec<(u32, usize, usize)>, LavaError> {
+        if start == 0 && end == self.n {
+            return Ok(self
+                .directory
+                .totals()
+                .iter()
+                .map(|(token, count)| (*token, 0, *count as usize))
+                .collect());
+        }
+        let (first, last, start_chunk, end_chunk) = FMDirectory::range_reads(start, end);
+        if let Some(missing) = (first..=last).find(|group| !self.groups.contains_key(group)) {
+            let last_missing = (missing..=last)
+                .rev()
+                .find(|group| !self.groups.contains_key(group))
+                .unwrap();
+            let records = self
+                .directory
+                .read_groups(&mut self.reader, missing, last_missing)
+                .await?;
+            self.groups.extend((missing..=last_missing).zip(records));
+        }
+        if let Some(chunk_id) = start_chunk {
+            self.chunk(chunk_id).await?;
+        }
+        self.chunk(end_chunk).await?;
+
+        let groups: Vec<&[u8]> = (first..=last)
+            .map(|group| &self.groups[&group][..])
+            .collect();
+        Ok(self
+            .directory
+            .range_ranks(
+                &groups,
+                start_chunk.map(|chunk_id| &self.chunks[&chunk_id]),
+                &self.chunks[&end_chunk],
+                start,
+                end,
+            )
+            .into_iter()
+            .map(|(token, start, end)| (token, start as usize, end as usize))
+            .collect())
+    }
+}
+
+enum FuzzyIndex {
+    Tokens(CachedFmIndex, Vec<u64>),
+    Chars(CachedWaveletTree, Vec<usize>),
+}
+
+impl FuzzyIndex {
+    /// The range of every one symbol extension of start..end, with the symbol.
+    async fn extensions(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> Result<Vec<(u32, usize, usize)>, LavaError> {
+        Ok(match self {
+            FuzzyIndex::Tokens(index, cumulative_counts) => index
+                .symbol_ranks(start, end)
+                .await?
+                .into_iter()
+                .map(|(token, start, end)| {
+                    let offset = cumulative_counts[token as usize] as usize;
+                    (token, offset + start, offset + end)
+                })
+                .collect(),
+            FuzzyIndex::Chars(tree, cumulative_counts) => tree
+                .symbol_ranks(start, end)
+                .await?
+                .into_iter()
+                .map(|(char, start, end)| {
+                    let offset = cumulative_counts[char as usize];
+                    (char as u32, offset + start, offset + end)
+                })
+                .collect(),
+        })
+    }
+}
+
+/// The suffix array ranges within `max_distance` of the query, (start, end, distance) with the
+/// closest first. Without `indels` only mismatches count, else the edit distance. At most
+/// `max_branches` states are expanded.
+async fn fuzzy_ranges(
+    index: &mut FuzzyIndex,
+    query: &[u32],
+    n: usize,
+    max_distance: usize,
+    indels: bool,
+    max_branches: usize,
+) -> Result<Vec<(usize, usize, usize)>, LavaError> {
+    let mut ranges = vec![];
+    let mut visited: HashSet<(usize, usize, usize)> = HashSet::new();
+    let mut heap = BinaryHeap::new();
+    heap.push(Reverse((0, query.len(), 0, n)));
+    let mut branches = 0;
+
+    while let Some(Reverse((distance, left, start, end))) = heap.pop() {
+        // popped closest first, a state seen before was reached with a smaller distance
+        if !visited.insert((left, start, end)) {
+            continue;
+        }
+        if left == 0 {
+            ranges.push((start, end, distance));
+            continue;
+        }
+        if branches == max_branches {
+            break;
+        }
+        branches += 1;
+
+        let edit = distance < max_distance;
+        for (symbol, symbol_start, symbol_end) in index.extensions(start, end).await? {
+            if symbol == query[left - 1] {
+                heap.push(Reverse((distance, left - 1, symbol_start, symbol_end)));
+            } else if edit {
+                heap.push(Reverse((distance + 1, left - 1, symbol_start, symbol_end)));
+            }
+            // a text symbol the query does not have, only inside the match
+            if edit && indels && left < query.len() {
+                heap.push(Reverse((distance + 1, left, symbol_start, symbol_end)));
+            }
+        }
+        // a query symbol the text does not have
+        if edit && indels {
+            heap.push(Reverse((distance + 1, left - 1, start, end)));
+        }
+    }
+    Ok(ranges)
+}
+
+/// The ranges merged where they overlap or touch, so that every suffix array row is read once.
+fn merge_ranges(ranges: &[(usize, usize, usize)]) -> Vec<(usize, usize)> {
+    let mut sorted: Vec<(usize, usize)> = ranges.iter().map(|(start, end, _)| (*start, *end)).collect();
+    sorted.sort();
+    let mut merged: Vec<(usize, usize)> = vec![];
+    for (start, end) in sorted {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// The rows of every range with its distance, from the rows of the merged ranges.
+fn split_rows(
+    ranges: &[(usize, usize, usize)],
+    merged: &[(usize, usize)],
+    rows: &[Vec<u64>],
+) -> Vec<(Vec<u64>, usize)> {
+    ranges
+        .iter()
+        .map(|(start, end, distance)| {
+            let i = merged.partition_point(|(_, merged_end)| merged_end < end);
+            let offset = merged[i].0;
+            (rows[i][start - offset..end - offset].to_vec(), *distance)
+        })
+        .collect()
+}
+
+/// The smallest distance of every uid, (file_id, uid, distance).
+fn best_distances(file_id: u64, matches: Vec<(Vec<u64>, usize)>) -> Vec<(u64, u64, u64)> {
+    let mut distances: BTreeMap<u64, u64> = BTreeMap::new();
+    for (uids, distance) in matches {
+        for uid in uids {
+            let best = distances.entry(uid).or_insert(distance as u64);
+            *best = (*best).min(distance as u64);
+        }
+    }
+    distances
+        .into_iter()
+        .map(|(uid, distance)| (file_id, uid, distance))
+        .collect()
+}
+
+/// The documents of a token file with a match within `max_distance` of the query, see
+/// `fuzzy_ranges`.
+pub(crate) async fn fuzzy_substring_one_file(
+    file_id: u64,
+    mut reader: AsyncReader,
+    file_size: usize,
+    query: Vec<u32>,
+    max_distance: usize,
+    indels: bool,
+    max_branches: usize,
+) -> Result<Vec<(u64, u64, u64)>, LavaError> {
+    let (n, directory, posting_list_offsets, cumulative_counts, _) =
+        read_fm_metadata(&mut reader, file_size).await?;
+    let mut index = FuzzyIndex::Tokens(
+        CachedFmIndex {
+            reader: reader.clone(),
+            directory,
+            n: n as usize,
+            groups: HashMap::new(),
+            chunks: HashMap::new(),
+        },
+        cumulative_counts,
+    );
+    let ranges = fuzzy_ranges(
+        &mut index,
+        &query,
+        n as usize,
+        max_distance,
+        indels,
+        max_branches,
+    )
+    .await?;
+    let posting_list_offsets: Vec<usize> =
+        posting_list_offsets.iter().map(|x| *x as usize).collect();
+    let merged = merge_ranges(&ranges);
+    let mut rows = vec![];
+    for (start, end) in merged.iter() {
+        rows.push(read_rows(&mut reader, &posting_list_offsets, *start, *end).await?);
+    }
+    Ok(best_distances(file_id, split_rows(&ranges, &merged, &rows)))
+}
+
+/// `fuzzy_substring_one_file` for char files.
+pub(crate) async fn fuzzy_wavelet_one_file(
+    file_id: u64,
+    mut reader: AsyncReader,
+    file_size: usize,
+    query: Vec<u8>,
+    max_distance: usize,
+    indels: bool,
+    max_branches: usize,
+) -> Result<Vec<(u64, u64, u64)>, LavaError> {
+    let metadata = read_wavelet_metadata(&mut reader, file_size).await?;
+    let (offsets, level_offsets, _, cumulative_counts, n, _, _, _) = &metadata;
+    let mut index = FuzzyIndex::Chars(
+        CachedWaveletTree::new(reader.clone(), offsets.clone(), level_offsets.clone()),
+        cumulative_counts.clone(),
+    );
+    let query: Vec<u32> = query.into_iter().map(|c| c as u32).collect();
+    let ranges = fuzzy_ranges(&mut index, &query, *n, max_distance, indels, max_branches).await?;
+    let merged = merge_ranges(&ranges);
+    let mut rows = vec![];
+    for (start, end) in merged.iter() {
+        rows.push(wavelet_uids(&mut reader, &metadata, *start, *end).await?);
+    }
+    Ok(best_distances(file_id, split_rows(&ranges, &merged, &rows)))
+}
+
+/// Sorts the matches of all files by distance, then (file_id, uid), and keeps the top k.
+pub(crate) fn closest_matches(mut matches: Vec<(u64, u64, u64)>, k: usize) -> Vec<(u64, u64, u64)> {
+    matches.sort_by_key(|(file_id, uid, distance)| (*distance, *file_id, *uid));
+    matches.truncate(k);
+    matches
+}
+
+/// Checks a fuzzy query: a distance as long as the query would match everything.
+pub(crate) fn check_fuzzy_query(query_len: usize, max_distance: usize) -> Result<(), LavaError> {
+    if query_len == 0 {
+        return Err(LavaError::Parse(
+            "the query is empty after normalization".to_string(),
+        ));
+    }
+    if max_distance >= query_len {
+        return Err(LavaError::Parse(format!(
+            "the distance {} must be smaller than the query length {}",
+            max_distance, query_len
+        )));
+    }
+    Ok(())
+}
+
+/// The documents of char files with a match within `max_distance` of the query, (file_id, uid,
+/// distance) with the closest first.
+pub async fn _search_lava_substring_char_fuzzy(
+    files: Vec<String>,
+    query: String,
+    max_distance: usize,
+    k: usize,
+    reader_type: ReaderType,
+    indels: bool,
+    max_branches: usize,
+) -> Result<Vec<(u64, u64, u64)>, LavaError> {
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let normalization = read_normalization_async(&mut readers, false).await?;
+    normalization.require_every_char("fuzzy searches")?;
+    let query: Vec<u8> = normalization.encode_chars(&query);
+    check_fuzzy_query(query.len(), max_distance)?;
+
+    let mut join_set = JoinSet::new();
+    for (file_id, (reader, file_size)) in readers.into_iter().zip(file_sizes).enumerate() {
+        join_set.spawn(fuzzy_wavelet_one_file(
+            file_id as u64,
+            reader,
+            file_size,
+            query.clone(),
+            max_distance,
+            indels,
+            max_branches,
+        ));
+    }
+
+    let mut matches: Vec<(u64, u64, u64)> = vec![];
+    while let Some(res) = join_set.join_next().await {
+        matches.extend(res.unwrap()?);
+    }
+    Ok(closest_matches(matches, k))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lava::substring::{build_lava_substring_char, FUZZY_MAX_BRANCHES};
+    use arrow::array::{Array, LargeStringArray, UInt64Array};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_closest_matches() {
+        let matches = vec![(1, 4, 2), (0, 9, 0), (1, 2, 1), (0, 3, 1)];
+        assert_eq!(
+            closest_matches(matches, 3),
+            vec![(0, 9, 0), (0, 3, 1), (1, 2, 1)]
+        );
+    }
+
+    #[test]
+    fn test_merge_ranges() {
+        let ranges = vec![(5, 9, 1), (0, 2, 0), (7, 12, 2), (2, 3, 1), (20, 21, 0)];
+        let merged = merge_ranges(&ranges);
+        assert_eq!(merged, vec![(0, 3), (5, 12), (20, 21)]);
+        let rows: Vec<Vec<u64>> = merged.iter().map(|(s, e)| (*s as u64..*e as u64).collect()).collect();
+        assert_eq!(
+            split_rows(&ranges, &merged, &rows),
+            vec![
+                (vec![5, 6, 7, 8], 1),
+                (vec![0, 1], 0),
+                (vec![7, 8, 9, 10, 11], 2),
+                (vec![2], 1),
+                (vec![20], 0),
+            ]
+        );
+    }
+
+    /// The smallest distance of the query to a match starting in each document, over the
+    /// concatenated texts like the index. With indels the first and last text symbol of a match
+    /// are matched or substituted, not inserted.
+    fn brute_force(
+        texts: &[Vec<u8>],
+        query: &[u8],
+        max_distance: usize,
+        indels: bool,
+    ) -> BTreeMap<u64, u64> {
+        // the bwt is of the rotations, so a match may wrap around to the first text
+        let mut text: Vec<u8> = texts.concat();
+        let n = text.len();
+        text.extend_from_within(..n.min(query.len() + max_distance));
+        let starts: Vec<u64> = texts
+            .iter()
+            .enumerate()
+            .flat_map(|(uid, text)| vec![uid as u64; text.len()])
+            .collect();
+        let (len, m) = (text.len(), query.len());
+        let sub = |i: usize, j: usize| (query[i] != text[j]) as usize;
+
+        // rest[i][j]: the distance of query[i..] to a prefix of text[j..]
+        let mut rest = vec![vec![0; len + 1]; m + 1];
+        for i in (0..m).rev() {
+            rest[i][len] = rest[i + 1][len] + 1;
+            for j in (0..len).rev() {
+                rest[i][j] = (rest[i + 1][j] + 1)
+                    .min(sub(i, j) + rest[i + 1][j + 1])
+                    .min(rest[i][j + 1] + 1);
+            }
+        }
+
+        let mut distances: BTreeMap<u64, u64> = BTreeMap::new();
+        for p in 0..n {
+            let distance = if indels {
+                (0..m).map(|i| i + sub(i, p) + rest[i + 1][p + 1]).min().unwrap()
+            } else if p + m <= len {
+                (0..m).map(|i| sub(i, p + i)).sum()
+            } else {
+                continue;
+            };
+            if distance <= max_distance {
+                let best = distances.entry(starts[p]).or_insert(distance as u64);
+                *best = (*best).min(distance as u64);
+            }
+        }
+        distances
+    }
+
+    fn fuzzy(file: &str, query: &str, max_distance: usize, indels: bool, max_branches: usize) -> BTreeMap<u64, u64> {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(_search_lava_substring_char_fuzzy(
+            vec![file.to_string()],
+            query.to_string(),
+            max_distance,
+            usize::MAX,
+            Default::default(),
+            indels,
+            max_branches,
+        ))
+        .unwrap()
+        .into_iter()
+        .map(|(_, uid, distance)| (uid, distance))
+        .collect()
+    }
+
+    #[test]
+    fn test_fuzzy_against_brute_force() {
+        let mut rng = StdRng::seed_from_u64(46);
+        // the last text is a unique smallest symbol, so the suffixes sort like the BWT rotations
+        let texts: Vec<Vec<u8>> = (0..60)
+            .map(|_| (0..rng.gen_range(3..25)).map(|_| b"abcd"[rng.gen_range(0..4)]).collect())
+            .chain(std::iter::once(b"0".to_vec()))
+            .collect();
+        let file = std::env::temp_dir()
+            .join(format!("rottnest_fuzzy_{}.lava", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let strings: Vec<String> = texts.iter().map(|t| String::from_utf8(t.clone()).unwrap()).collect();
+        build_lava_substring_char(
+            file.clone(),
+            LargeStringArray::from(strings.iter().map(|s| s.as_str()).collect::<Vec<&str>>()).into_data(),
+            UInt64Array::from((0..texts.len() as u64).collect::<Vec<u64>>()).into_data(),
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        for query in ["abcab", "ddcba", "bbbb", "acdcad", "abc"] {
+            for max_distance in 0..query.len().min(3) {
+                for indels in [false, true] {
+                    assert_eq!(
+                        fuzzy(&file, query, max_distance, indels, FUZZY_MAX_BRANCHES),
+                        brute_force(&texts, query.as_bytes(), max_distance, indels),
+                        "query {} distance {} indels {}",
+                        query,
+                        max_distance,
+                        indels
+                    );
+                }
+            }
+        }
+
+        // with a branch cap the documents found are the closest ones, at their true distance
+        let exact = brute_force(&texts, b"acdcad", 2, true);
+        let mut capped_out = false;
+        for max_branches in [1, 5, 20, 60] {
+            let found = fuzzy(&file, "acdcad", 2, true, max_branches);
+            for (uid, distance) in found.iter() {
+                assert_eq!(exact[uid], *distance);
+            }
+            let farthest_found = found.values().max().copied().unwrap_or(0);
+            for (uid, distance) in exact.iter() {
+                if !found.contains_key(uid) {
+                    capped_out = true;
+                    assert!(*distance >= farthest_found);
+                }
+            }
+        }
+        assert!(capped_out);
+        std::fs::remove_file(file).unwrap();
+    }
+}
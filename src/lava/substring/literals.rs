@@ -0,0 +1,186 @@
+use regex_syntax::hir::{Class, Hir, HirKind};
+
+use super::normalization::Normalization;
+use crate::lava::error::LavaError;
+
+/*
+The literals every match of a regex contains, so a regex query can be answered from the candidates
+of plain substring searches. They are collected in the normalized text: a class whose characters
+all normalize the same, like the [Kk] of a case insensitive regex in a lowercased index, is a
+literal character, and skip characters simply vanish.
+*/
+
+// classes larger than this are never a single normalized character
+const MAX_LITERAL_CLASS: usize = 8;
+
+struct Required {
+    /// The text the node always matches, if it only matches one text.
+    exact: Option<String>,
+    /// Texts every match of the node contains.
+    literals: Vec<String>,
+}
+
+impl Required {
+    fn exact(text: String) -> Self {
+        Required {
+            exact: Some(text),
+            literals: vec![],
+        }
+    }
+
+    fn none() -> Self {
+        Required {
+            exact: None,
+            literals: vec![],
+        }
+    }
+
+    /// The literals, with the exact text as one more.
+    fn into_literals(self) -> Vec<String> {
+        let mut literals = self.literals;
+        literals.extend(self.exact);
+        literals
+    }
+}
+
+/// The one character a class stands for in the normalized text, if it does.
+fn class_literal(class: &Class, normalization: &Normalization) -> Option<String> {
+    let chars: Vec<char> = match class {
+        Class::Unicode(class) => {
+            if class.ranges().iter().map(|range| range.len()).sum::<usize>() > MAX_LITERAL_CLASS {
+                return None;
+            }
+            class
+                .ranges()
+                .iter()
+                .flat_map(|range| range.start()..=range.end())
+                .collect()
+        }
+        Class::Bytes(class) => {
+            if class.ranges().iter().map(|range| range.len()).sum::<usize>() > MAX_LITERAL_CLASS
+                || !class.is_ascii()
+            {
+                return None;
+            }
+            class
+                .ranges()
+                .iter()
+                .flat_map(|range| range.start()..=range.end())
+                .map(char::from)
+                .collect()
+        }
+    };
+    let first = *chars.first()?;
+    let normalized = normalization.apply(&first.to_string());
+    chars
+        .iter()
+        .all(|c| normalization.apply(&c.to_string()) == normalized)
+        .then(|| first.to_string())
+}
+
+fn required(hir: &Hir, normalization: &Normalization) -> Required {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => Required::exact(String::new()),
+        HirKind::Literal(literal) => match std::str::from_utf8(&literal.0) {
+            Ok(text) => Required::exact(text.to_string()),
+            Err(_) => Required::none(),
+        },
+        HirKind::Class(class) => match class_literal(class, normalization) {
+            Some(text) => Required::exact(text),
+            None => Required::none(),
+        },
+        HirKind::Capture(capture) => required(&capture.sub, normalization),
+        HirKind::Repetition(repetition) => {
+            if repetition.min == 0 {
+                return Required::none();
+            }
+            let sub = required(&repetition.sub, normalization);
+            match (&sub.exact, repetition.max) {
+                (Some(text), Some(max)) if max == repetition.min => {
+                    Required::exact(text.repeat(max as usize))
+                }
+                _ => Required {
+                    exact: None,
+                    literals: sub.into_literals(),
+                },
+            }
+        }
+        HirKind::Concat(subs) => {
+            let mut literals: Vec<String> = vec![];
+            let mut run = String::new();
+            let mut exact = true;
+            for sub in subs {
+                let sub = required(sub, normalization);
+                match sub.exact {
+                    Some(text) => run.push_str(&text),
+                    None => {
+                        exact = false;
+                        literals.push(std::mem::take(&mut run));
+                        literals.extend(sub.literals);
+                    }
+                }
+            }
+            if exact {
+                Required::exact(run)
+            } else {
+                literals.push(run);
+                Required {
+                    exact: None,
+                    literals,
+                }
+            }
+        }
+        // only literals shared by all branches would be required, those are rare
+        HirKind::Alternation(_) => Required::none(),
+    }
+}
+
+/// The normalized and encoded literals every match of `pattern` contains, longest first.
+pub(crate) fn required_literals(
+    pattern: &str,
+    normalization: &Normalization,
+) -> Result<Vec<Vec<u8>>, LavaError> {
+    let hir = regex_syntax::parse(pattern)
+        .map_err(|e| LavaError::Parse(format!("invalid regex: {}", e)))?;
+    let mut literals: Vec<Vec<u8>> = required(&hir, normalization)
+        .into_literals()
+        .iter()
+        .map(|literal| normalization.encode_chars(literal))
+        .filter(|literal| !literal.is_empty())
+        .collect();
+    literals.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+    literals.dedup();
+    Ok(literals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literals(pattern: &str, normalization: &Normalization) -> Vec<String> {
+        required_literals(pattern, normalization)
+            .unwrap()
+            .into_iter()
+            .map(|literal| String::from_utf8(literal).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_required_literals() {
+        let default = Normalization::default();
+        assert_eq!(literals(r"error \d+ in Foo\.bar", &default), vec!["infoobar", "error"]);
+        assert_eq!(literals(r"(?i)timeout", &default), vec!["timeout"]);
+        assert_eq!(literals(r"^abc(de)+x?$", &default), vec!["abc", "de"]);
+        assert_eq!(literals(r"a{3}b", &default), vec!["aaab"]);
+        assert_eq!(literals(r"(foo|bar)baz", &default), vec!["baz"]);
+        assert!(literals(r"foo|bar", &default).is_empty());
+        assert!(literals(r"[a-z]+", &default).is_empty());
+
+        let exact =
+            Normalization::load(Some(r#"{"lowercase": false, "skip": ""}"#.to_string())).unwrap();
+        assert_eq!(literals(r"Foo\.bar\(", &exact), vec!["Foo.bar("]);
+        assert!(literals(r"(?i)x", &exact).is_empty());
+
+        assert!(required_literals(r"(unclosed", &default).is_err());
+    }
+}
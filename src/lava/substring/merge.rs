@@ -1,5 +1,13 @@
 use super::constants::*;
-use super::fm_chunk::FMChunk;
+use super::fm_chunk::{write_fm_chunks, FMChunk};
+use super::normalization::read_compressed_normalization;
+use super::substring::{
+    read_fm_footer, read_wavelet_metadata, write_fm_footer, write_rows, write_wavelet_metadata,
+    WaveletMetadata,
+};
+use super::wavelet_tree::{
+    construct_wavelet_tree, read_wavelet_tree_bwt, write_wavelet_tree_to_disk,
+};
 use crate::formats::readers::{
     get_file_size_and_reader, get_file_sizes_and_readers, AsyncReader, ReaderType,
 };
@@ -42,56 +50,64 @@ impl PListIterator {
     }
 }
 
+// fm chunks are small so ranks read little, the merge reads about FM_CHUNK_TOKS symbols at a time
 struct FMChunkIterator {
     reader: AsyncReader,
     fm_chunk_offsets: Vec<u64>,
+    chunks_per_read: usize,
     current_chunk_offset: usize,
     pub current_chunk: FMChunk<u32>,
 }
 
 impl FMChunkIterator {
     // take ownership of the data structures
-    pub async fn new(
-        mut reader: AsyncReader,
-        fm_chunk_offsets: Vec<u64>,
-    ) -> Result<Self, LavaError> {
-        let buffer3 = reader
-            .read_range(fm_chunk_offsets[0], fm_chunk_offsets[1])
-            .await?;
-        let current_chunk = FMChunk::new(buffer3)?;
-
-        Ok(Self {
+    pub async fn new(reader: AsyncReader, fm_chunk_offsets: Vec<u64>) -> Result<Self, LavaError> {
+        let mut iterator = Self {
             reader: reader,
             fm_chunk_offsets: fm_chunk_offsets,
+            chunks_per_read: FM_CHUNK_TOKS / FM_CHECKPOINT_TOKS,
             current_chunk_offset: 0,
-            current_chunk: current_chunk,
-        })
+            current_chunk: FMChunk { bwt_chunk: vec![] },
+        };
+        iterator.reset().await?;
+        Ok(iterator)
+    }
+
+    /// The chunks from `first` on, read at once and joined into one.
+    async fn read_chunks(&mut self, first: usize) -> Result<FMChunk<u32>, LavaError> {
+        let last = (first + self.chunks_per_read).min(self.fm_chunk_offsets.len() - 1);
+        let start = self.fm_chunk_offsets[first];
+        let buffer = self
+            .reader
+            .read_range(start, self.fm_chunk_offsets[last])
+            .await?;
+
+        let mut chunks = (first..last).map(|i| {
+            FMChunk::<u32>::new(buffer.slice(
+                (self.fm_chunk_offsets[i] - start) as usize
+                    ..(self.fm_chunk_offsets[i + 1] - start) as usize,
+            ))
+        });
+        let mut joined = chunks.next().unwrap()?;
+        for chunk in chunks {
+            joined.bwt_chunk.extend(chunk?.bwt_chunk);
+        }
+        Ok(joined)
     }
 
     pub async fn advance(&mut self) -> Result<(), LavaError> {
-        self.current_chunk_offset += 1;
+        self.current_chunk_offset += self.chunks_per_read;
 
         if self.current_chunk_offset + 2 > self.fm_chunk_offsets.len() {
             return Err(LavaError::Parse("out of chunks".to_string()));
         }
-        let buffer3 = self
-            .reader
-            .read_range(
-                self.fm_chunk_offsets[self.current_chunk_offset],
-                self.fm_chunk_offsets[self.current_chunk_offset + 1],
-            )
-            .await?;
-        self.current_chunk = FMChunk::new(buffer3)?;
+        self.current_chunk = self.read_chunks(self.current_chunk_offset).await?;
 
         Ok(())
     }
 
     pub async fn reset(&mut self) -> Result<(), LavaError> {
-        self.current_chunk = FMChunk::new(
-            self.reader
-                .read_range(self.fm_chunk_offsets[0], self.fm_chunk_offsets[1])
-                .await?,
-        )?;
+        self.current_chunk = self.read_chunks(0).await?;
         self.current_chunk_offset = 0;
 
         Ok(())
@@ -177,12 +193,14 @@ pub(crate) async fn merge_lava_substring(
     // let operator = Operator::new(builder)?.finish();
 
     let mut compressed_tokenizer: Option<Vec<u8>> = None;
+    let mut compressed_normalization: Option<Vec<u8>> = None;
 
     // currently only support merging two files, but can support more in the future.
     assert_eq!(lava_files.len(), 2);
     assert_eq!(uid_offsets.len(), 2);
 
     let mut ns: Vec<u64> = vec![];
+    let mut num_documents: u64 = 0;
     let mut combined_cumulative_counts: Vec<u64> = vec![];
     let mut fm_chunk_iterators: Vec<FMChunkIterator> = vec![];
     let mut plist_iterators: Vec<PListIterator> = vec![];
@@ -192,15 +210,15 @@ pub(crate) async fn merge_lava_substring(
         // instead of bothering with wrapping this thing in Arc<Mutex<>>. Lots of tech debt to clean up
         // needed for the FMChunkIterator and PListIterator
         let (_, mut reader) = get_file_size_and_reader(file.clone(), reader_type.clone()).await?;
-        let (file_size, reader1) =
-            get_file_size_and_reader(file.clone(), reader_type.clone()).await?;
-        let file_size = file_size as u64;
+        let (_, reader1) = get_file_size_and_reader(file.clone(), reader_type.clone()).await?;
 
-        let results = reader.read_usize_from_end(4).await?;
+        let results = read_fm_footer(&mut reader).await?;
         let fm_chunk_offsets_offset = results[0];
         let posting_list_offsets_offset = results[1];
         let total_counts_offset = results[2];
         let n = results[3];
+        num_documents += results[4];
+        let fm_directory_index_offset = results[5];
 
         ns.push(n);
 
@@ -216,6 +234,17 @@ pub(crate) async fn merge_lava_substring(
             None => compressed_tokenizer = Some(this_compressed_tokenizer.to_vec()),
         }
 
+        let this_compressed_normalization = read_compressed_normalization(&mut reader, true).await?;
+        match &compressed_normalization {
+            Some(value) if *value != this_compressed_normalization => {
+                return Err(LavaError::Parse(
+                    "detected different normalizations, cannot merge".to_string(),
+                ))
+            }
+            Some(_) => {}
+            None => compressed_normalization = Some(this_compressed_normalization),
+        }
+
         let fm_chunk_offsets: Vec<u64> = reader
             .read_range_and_decompress(fm_chunk_offsets_offset, posting_list_offsets_offset)
             .await?;
@@ -223,7 +252,7 @@ pub(crate) async fn merge_lava_substring(
             .read_range_and_decompress(posting_list_offsets_offset, total_counts_offset)
             .await?;
         let cumulative_counts: Vec<u64> = reader
-            .read_range_and_decompress(total_counts_offset, (file_size - 32) as u64)
+            .read_range_and_decompress(total_counts_offset, fm_directory_index_offset)
             .await?;
 
         // println!("{} {}", file, cumulative_counts.len());
@@ -265,6 +294,9 @@ pub(crate) async fn merge_lava_substring(
     let compressed_tokenizer = compressed_tokenizer.unwrap();
     output_file.write_all(&(compressed_tokenizer.len() as u64).to_le_bytes())?;
     output_file.write_all(&compressed_tokenizer)?;
+    let compressed_normalization = compressed_normalization.unwrap();
+    output_file.write_all(&(compressed_normalization.len() as u64).to_le_bytes())?;
+    output_file.write_all(&compressed_normalization)?;
 
     let mut bwt_output: Vec<u32> = Vec::with_capacity(interleave.len());
     let mut index_output: Vec<u64> = Vec::with_capacity(interleave.len());
@@ -317,34 +349,7 @@ pub(crate) async fn merge_lava_substring(
         }
     }
 
-    let mut current_chunk: Vec<u32> = vec![];
-    let mut current_chunk_counts: HashMap<u32, u64> = HashMap::new();
-    let mut next_chunk_counts: HashMap<u32, u64> = HashMap::new();
-    let mut fm_chunk_offsets: Vec<usize> = vec![output_file.seek(SeekFrom::Current(0))? as usize];
-
-    for i in 0..bwt_output.len() {
-        let current_tok = bwt_output[i];
-        next_chunk_counts
-            .entry(current_tok)
-            .and_modify(|count| *count += 1)
-            .or_insert(1);
-        current_chunk.push(current_tok);
-
-        if ((i + 1) % FM_CHUNK_TOKS == 0) || i == bwt_output.len() - 1 {
-            let serialized_counts = bincode::serialize(&current_chunk_counts)?;
-            let compressed_counts =
-                encode_all(&serialized_counts[..], 0).expect("Compression failed");
-            output_file.write_all(&(compressed_counts.len() as u64).to_le_bytes())?;
-            output_file.write_all(&compressed_counts)?;
-            let serialized_chunk = bincode::serialize(&current_chunk)?;
-            let compressed_chunk =
-                encode_all(&serialized_chunk[..], 0).expect("Compression failed");
-            output_file.write_all(&compressed_chunk)?;
-            fm_chunk_offsets.push(output_file.seek(SeekFrom::Current(0))? as usize);
-            current_chunk_counts = next_chunk_counts.clone();
-            current_chunk = vec![];
-        }
-    }
+    let (fm_chunk_offsets, fm_directory_index, _) = write_fm_chunks(&mut output_file, &bwt_output)?;
 
     let mut posting_list_offsets: Vec<usize> =
         vec![output_file.seek(SeekFrom::Current(0))? as usize];
@@ -377,13 +382,398 @@ pub(crate) async fn merge_lava_substring(
         encode_all(&serialized_total_counts[..], 0).expect("Compression failed");
     output_file.write_all(&compressed_total_counts)?;
 
-    output_file.write_all(&(fm_chunk_offsets_offset as u64).to_le_bytes())?;
-    output_file.write_all(&(posting_list_offsets_offset as u64).to_le_bytes())?;
-    output_file.write_all(&(total_counts_offset as u64).to_le_bytes())?;
-    output_file.write_all(&(bwt_output.len() as u64).to_le_bytes())?;
+    let fm_directory_index_offset = output_file.seek(SeekFrom::Current(0))? as usize;
+    let serialized_fm_directory_index = bincode::serialize(&fm_directory_index)?;
+    let compressed_fm_directory_index: Vec<u8> =
+        encode_all(&serialized_fm_directory_index[..], 0).expect("Compression failed");
+    output_file.write_all(&compressed_fm_directory_index)?;
+
+    write_fm_footer(
+        &mut output_file,
+        [
+            fm_chunk_offsets_offset as u64,
+            posting_list_offsets_offset as u64,
+            total_counts_offset as u64,
+            bwt_output.len() as u64,
+            num_documents,
+            fm_directory_index_offset as u64,
+        ],
+    )?;
 
     Ok(vec![(
         cache_start,
         output_file.seek(SeekFrom::Current(0))? as usize,
     )])
 }
+
+/// Every row of a chunked per row array of a char file, the uids or the positions. These are
+/// held in memory, 8 bytes per row.
+async fn read_all_rows(
+    reader: &mut AsyncReader,
+    chunk_offsets: &[usize],
+) -> Result<Vec<u64>, LavaError> {
+    let mut rows = vec![];
+    for chunk in chunk_offsets.windows(2) {
+        rows.extend(
+            reader
+                .read_range_and_decompress(chunk[0] as u64, chunk[1] as u64)
+                .await?,
+        );
+    }
+    Ok(rows)
+}
+
+/// The interleave of two in memory bwts. The suffixes of a char index are sorted in full, so
+/// unlike a token index this runs until the interleave stops changing. Pass t orders the suffixes
+/// by their first t symbols, ties keeping the first input's first. Two cyclic texts that agree on
+/// more than their summed lengths agree forever, so it settles within n + 1 passes, one more than
+/// the longest prefix suffixes of the two inputs share: O(n · longest repeat), slow on repetitive
+/// text. Bwts still changing past that bound are corrupt.
+fn compute_char_interleave(
+    bwt0: &[u8],
+    bwt1: &[u8],
+    cumulative_counts: &[usize],
+) -> Result<BitVec, LavaError> {
+    let mut interleave = BitVec::from_elem(bwt0.len() + bwt1.len(), true);
+    for i in 0..bwt0.len() {
+        interleave.set(i, false);
+    }
+
+    for _ in 0..=interleave.len() + 1 {
+        let mut ind: [usize; 2] = [0, 0];
+        let mut offsets = cumulative_counts.to_vec();
+        let mut new_interleave = BitVec::from_elem(interleave.len(), false);
+        for i in 0..interleave.len() {
+            if interleave[i] {
+                new_interleave.set(offsets[bwt1[ind[1]] as usize], true);
+                offsets[bwt1[ind[1]] as usize] += 1;
+                ind[1] += 1;
+            } else {
+                offsets[bwt0[ind[0]] as usize] += 1;
+                ind[0] += 1;
+            }
+        }
+
+        if new_interleave == interleave {
+            return Ok(interleave);
+        }
+        interleave = new_interleave;
+    }
+    Err(LavaError::Parse(
+        "char index bwts did not interleave, cannot merge".to_string(),
+    ))
+}
+
+/// Merges two char indices with the wavelet layout into one, the rows of the second file after
+/// the first wherever their suffixes tie. Nothing is streamed: the bwts, uids and positions of
+/// both inputs and of the output are held in memory, about 17 bytes per symbol of each input and
+/// again of the output with positions, 9 without, plus the wavelet tree being written. Inputs of
+/// more than CHAR_MERGE_MAX_SYMBOLS symbols together and indices with a sampled suffix array are
+/// rejected as unsupported, rebuild them from the texts.
+pub(crate) async fn merge_lava_substring_char(
+    condensed_lava_file: &str,
+    lava_files: Vec<String>,
+    uid_offsets: Vec<u64>,
+    reader_type: ReaderType,
+) -> Result<Vec<(usize, usize)>, LavaError> {
+    merge_char_files(
+        condensed_lava_file,
+        lava_files,
+        uid_offsets,
+        reader_type,
+        CHAR_MERGE_MAX_SYMBOLS,
+    )
+    .await
+}
+
+async fn merge_char_files(
+    condensed_lava_file: &str,
+    lava_files: Vec<String>,
+    uid_offsets: Vec<u64>,
+    reader_type: ReaderType,
+    max_symbols: usize,
+) -> Result<Vec<(usize, usize)>, LavaError> {
+    // currently only support merging two files, but can support more in the future.
+    assert_eq!(lava_files.len(), 2);
+    assert_eq!(uid_offsets.len(), 2);
+
+    let mut compressed_normalization: Option<Vec<u8>> = None;
+    let mut inputs: Vec<(AsyncReader, WaveletMetadata, u64)> = vec![];
+    let mut positional: Option<bool> = None;
+    let mut num_symbols = 0;
+    let mut num_documents = 0;
+    let mut combined_cumulative_counts: Vec<usize> = vec![0; 256];
+
+    // check every input before reading any of them into memory
+    for (file, uid_offset) in lava_files.into_iter().zip(uid_offsets) {
+        let (file_size, mut reader) = get_file_size_and_reader(file, reader_type.clone()).await?;
+
+        let this_compressed_normalization = read_compressed_normalization(&mut reader, false).await?;
+        match &compressed_normalization {
+            Some(value) if *value != this_compressed_normalization => {
+                return Err(LavaError::Parse(
+                    "detected different normalizations, cannot merge".to_string(),
+                ))
+            }
+            Some(_) => {}
+            None => compressed_normalization = Some(this_compressed_normalization),
+        }
+
+        let metadata: WaveletMetadata = read_wavelet_metadata(&mut reader, file_size).await?;
+        let (_, _, _, cumulative_counts, n, position_offsets, this_num_documents, sampled) =
+            &metadata;
+
+        if sampled.is_some() {
+            return Err(LavaError::Unsupported(
+                "cannot merge char indices with a sampled suffix array".to_string(),
+            ));
+        }
+        let this_positional = !position_offsets.is_empty();
+        if *positional.get_or_insert(this_positional) != this_positional {
+            return Err(LavaError::Unsupported(
+                "cannot merge a positional char index with one without positions".to_string(),
+            ));
+        }
+
+        num_symbols += n;
+        if num_symbols > max_symbols {
+            return Err(LavaError::Unsupported(format!(
+                "cannot merge char indices of more than {} symbols in memory, rebuild them from the texts",
+                max_symbols
+            )));
+        }
+        num_documents += this_num_documents;
+        for (i, count) in cumulative_counts.iter().enumerate() {
+            combined_cumulative_counts[i] += count;
+        }
+        inputs.push((reader, metadata, uid_offset));
+    }
+
+    let mut bwts: Vec<Vec<u8>> = vec![];
+    let mut uids: Vec<Vec<u64>> = vec![];
+    let mut positions: Vec<Vec<u64>> = vec![];
+    for (mut reader, metadata, uid_offset) in inputs {
+        let (offsets, level_offsets, posting_list_offsets, _, n, position_offsets, _, _) = metadata;
+        bwts.push(read_wavelet_tree_bwt(&mut reader, &offsets, &level_offsets, n).await?);
+        uids.push(
+            read_all_rows(&mut reader, &posting_list_offsets)
+                .await?
+                .into_iter()
+                .map(|uid| uid + uid_offset)
+                .collect(),
+        );
+        positions.push(read_all_rows(&mut reader, &position_offsets).await?);
+    }
+
+    let interleave = compute_char_interleave(&bwts[0], &bwts[1], &combined_cumulative_counts)?;
+
+    let mut bwt_output: Vec<u8> = Vec::with_capacity(interleave.len());
+    let mut index_output: Vec<u64> = Vec::with_capacity(interleave.len());
+    let mut position_output: Vec<u64> = vec![];
+    let mut ind: [usize; 2] = [0, 0];
+    for from_second in interleave.iter() {
+        let file = from_second as usize;
+        bwt_output.push(bwts[file][ind[file]]);
+        index_output.push(uids[file][ind[file]]);
+        if positional == Some(true) {
+            position_output.push(positions[file][ind[file]]);
+        }
+        ind[file] += 1;
+    }
+
+    let mut output_file = File::create(condensed_lava_file)?;
+    let compressed_normalization = compressed_normalization.unwrap();
+    output_file.write_all(&(compressed_normalization.len() as u64).to_le_bytes())?;
+    output_file.write_all(&compressed_normalization)?;
+
+    let wavelet_tree = construct_wavelet_tree(&bwt_output);
+    let (offsets, level_offsets) = write_wavelet_tree_to_disk(&wavelet_tree, &mut output_file)?;
+
+    let posting_list_offsets = write_rows(&mut output_file, &index_output)?;
+    let mut position_offsets: Vec<usize> = vec![];
+    if positional == Some(true) {
+        position_offsets = write_rows(&mut output_file, &position_output)?;
+    }
+
+    let metadata: WaveletMetadata = (
+        offsets,
+        level_offsets,
+        posting_list_offsets,
+        combined_cumulative_counts,
+        bwt_output.len(),
+        position_offsets,
+        num_documents,
+        None,
+    );
+
+    write_wavelet_metadata(&mut output_file, &metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lava::substring::normalization::Normalization;
+    use crate::lava::substring::substring::{
+        _build_lava_substring_char_wavelet, _count_lava_substring_char,
+        _search_lava_substring_char_occurrences,
+    };
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "rottnest_merge_char_{}_{}.lava",
+                name,
+                std::process::id()
+            ))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_merge_lava_substring_char() {
+        let mut rng = StdRng::seed_from_u64(50);
+        // the last text of each input ends with a unique smallest symbol, so the suffixes of
+        // each sort like its bwt rotations
+        let mut inputs: Vec<Vec<(u64, String)>> = [80, 50]
+            .into_iter()
+            .map(|num_texts| {
+                (0..num_texts)
+                    .map(|uid| {
+                        let len = rng.gen_range(10..40);
+                        let text: String = (0..len)
+                            .map(|_| b"abcd"[rng.gen_range(0..4)] as char)
+                            .collect();
+                        (uid, text)
+                    })
+                    .collect()
+            })
+            .collect();
+        for texts in inputs.iter_mut() {
+            texts.last_mut().unwrap().1.push('0');
+        }
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            for positional in [false, true] {
+                let mut files = vec![];
+                for (i, texts) in inputs.iter().enumerate() {
+                    let file = temp_file(&format!("{}_{}", i, positional));
+                    _build_lava_substring_char_wavelet(
+                        file.clone(),
+                        texts.clone(),
+                        1,
+                        &Normalization::default(),
+                        positional,
+                        None,
+                    )
+                    .await
+                    .unwrap();
+                    files.push(file);
+                }
+                let merged = temp_file(&format!("merged_{}", positional));
+                merge_lava_substring_char(
+                    &merged,
+                    files.clone(),
+                    vec![0, 1000],
+                    Default::default(),
+                )
+                .await
+                .unwrap();
+
+                for query in ["a", "abc", "dcba", "bbb", "cadb"] {
+                    let mut count = (0, Some(0));
+                    let mut occurrences = vec![];
+                    for (file, uid_offset) in files.iter().zip([0, 1000]) {
+                        let (matches, documents) = _count_lava_substring_char(
+                            vec![file.clone()],
+                            query.to_string(),
+                            Default::default(),
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                        count = (
+                            count.0 + matches,
+                            Some(count.1.unwrap() + documents.unwrap()),
+                        );
+                        occurrences.extend(
+                            _search_lava_substring_char_occurrences(
+                                vec![file.clone()],
+                                query.to_string(),
+                                usize::MAX,
+                                Default::default(),
+                                positional,
+                            )
+                            .await
+                            .unwrap()
+                            .into_iter()
+                            .map(|(file_id, uid, matches, offsets)| {
+                                (file_id, uid + uid_offset, matches, offsets)
+                            }),
+                        );
+                    }
+                    assert!(count.0 > 0, "{}", query);
+                    assert_eq!(
+                        _count_lava_substring_char(
+                            vec![merged.clone()],
+                            query.to_string(),
+                            Default::default(),
+                            true,
+                        )
+                        .await
+                        .unwrap(),
+                        count,
+                        "{}",
+                        query
+                    );
+
+                    let mut merged_occurrences = _search_lava_substring_char_occurrences(
+                        vec![merged.clone()],
+                        query.to_string(),
+                        usize::MAX,
+                        Default::default(),
+                        positional,
+                    )
+                    .await
+                    .unwrap();
+                    merged_occurrences.sort();
+                    occurrences.sort();
+                    assert_eq!(merged_occurrences, occurrences, "{}", query);
+                }
+
+                let refused = temp_file(&format!("refused_{}", positional));
+                assert!(matches!(
+                    merge_char_files(
+                        &refused,
+                        files.clone(),
+                        vec![0, 1000],
+                        Default::default(),
+                        10
+                    )
+                    .await,
+                    Err(LavaError::Unsupported(_))
+                ));
+                assert!(!std::path::Path::new(&refused).exists());
+
+                for file in files.into_iter().chain([merged]) {
+                    std::fs::remove_file(file).unwrap();
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_compute_char_interleave_bound() {
+        let mut cumulative_counts = vec![0; 256];
+        cumulative_counts[b'b' as usize] = 2;
+        // both inputs are the rotations of "ab", which tie forever
+        let interleave = compute_char_interleave(b"ba", b"ba", &cumulative_counts).unwrap();
+        assert_eq!(
+            interleave.iter().collect::<Vec<_>>(),
+            [false, true, false, true]
+        );
+    }
+}
@@ -1,11 +1,26 @@
 mod constants;
 pub(crate) mod fm_chunk;
+mod fuzzy;
+mod literals;
 pub(crate) mod merge;
+pub(crate) mod normalization;
+mod sampled;
 mod substring;
 pub(crate) mod wavelet_tree;
-pub(crate) use merge::merge_lava_substring;
+pub(crate) use constants::FUZZY_MAX_BRANCHES;
+pub(crate) use fuzzy::_search_lava_substring_char_fuzzy;
+pub(crate) use fuzzy::{check_fuzzy_query, closest_matches, fuzzy_substring_one_file};
+pub(crate) use merge::{merge_lava_substring, merge_lava_substring_char};
 
 pub(crate) use substring::_build_lava_substring_char;
 pub(crate) use substring::_build_lava_substring_char_wavelet;
+pub(crate) use substring::_count_lava_substring_char;
+pub(crate) use substring::_search_lava_substring_char_occurrences;
+pub(crate) use substring::_search_lava_substring_regex;
+pub(crate) use substring::count_occurrences;
+pub(crate) use substring::count_substring_one_file;
+pub(crate) use substring::sum_counts;
+pub(crate) use substring::substring_occurrences_one_file;
+pub(crate) use substring::{read_fm_footer, FM_FOOTER_WORDS};
 pub use substring::build_lava_substring;
 pub use substring::build_lava_substring_char;
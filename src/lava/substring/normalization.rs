@@ -0,0 +1,286 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Write};
+use zstd::stream::encode_all;
+use zstd::stream::read::Decoder;
+
+use super::constants::SKIP;
+use crate::formats::readers::AsyncReader;
+use crate::lava::analyzer::Analyzer;
+use crate::lava::error::LavaError;
+
+/*
+How the text of a substring index is normalized before it is indexed. The policy is chosen at build
+time, e.g. {"lowercase": false, "skip": ""} keeps case and punctuation so `foo.bar(` can be found,
+and is stored compressed in the file header right after the analyzer of a token index, or at the
+start of a char index. Queries go through the stored policy, so they always match the indexed text.
+A char index built with a char skip factor records it here as well, it is not part of the json
+of a policy passed to a build.
+*/
+
+fn default_lowercase() -> bool {
+    true
+}
+
+fn default_skip() -> String {
+    SKIP.to_string()
+}
+
+fn default_char_skip_factor() -> u32 {
+    1
+}
+
+fn is_default_char_skip_factor(char_skip_factor: &u32) -> bool {
+    *char_skip_factor == 1
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Normalization {
+    #[serde(default = "default_lowercase")]
+    pub lowercase: bool,
+    /// Characters dropped from the text, by default punctuation and spaces.
+    #[serde(default = "default_skip")]
+    pub skip: String,
+    /// Turns every run of whitespace into a single space, before the skip characters are dropped.
+    #[serde(default)]
+    pub collapse_whitespace: bool,
+    /// Only every char_skip_factor-th character of a char index is indexed, left out when 1 so
+    /// the header of other indices does not change.
+    #[serde(
+        default = "default_char_skip_factor",
+        skip_serializing_if = "is_default_char_skip_factor"
+    )]
+    pub char_skip_factor: u32,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Normalization {
+            lowercase: default_lowercase(),
+            skip: default_skip(),
+            collapse_whitespace: false,
+            char_skip_factor: default_char_skip_factor(),
+        }
+    }
+}
+
+impl Normalization {
+    /// `spec` is the json of the policy, the default lower cases and drops `SKIP` as substring
+    /// indices always did.
+    pub(crate) fn load(spec: Option<String>) -> Result<Self, LavaError> {
+        match spec {
+            Some(spec) => serde_json::from_str(&spec)
+                .map_err(|e| LavaError::Parse(format!("invalid normalization: {}", e))),
+            None => Ok(Normalization::default()),
+        }
+    }
+
+    /// For searches that need every character indexed, `search` names them in the error.
+    pub(crate) fn require_every_char(&self, search: &str) -> Result<(), LavaError> {
+        if self.char_skip_factor > 1 {
+            return Err(LavaError::Unsupported(format!(
+                "{} need every character indexed, the index has a char skip factor of {}",
+                search, self.char_skip_factor
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn compress(&self) -> Result<Vec<u8>, LavaError> {
+        let serialized = serde_json::to_string(self)
+            .map_err(|e| LavaError::Parse(format!("failed to serialize normalization: {}", e)))?;
+        encode_all(serialized.as_bytes(), 0).map_err(|e| LavaError::Compression(e.to_string()))
+    }
+
+    pub(crate) fn from_compressed(compressed: &[u8]) -> Result<Self, LavaError> {
+        let mut serialized: Vec<u8> = Vec::with_capacity(compressed.len());
+        Decoder::new(compressed)?.read_to_end(&mut serialized)?;
+        serde_json::from_slice(&serialized)
+            .map_err(|e| LavaError::Parse(format!("failed to load normalization: {}", e)))
+    }
+
+    /// The folded characters with the offset of the character of `text` they come from.
+    fn fold_chars(&self, text: &str) -> Vec<(usize, char)> {
+        let mut folded = Vec::with_capacity(text.len());
+        let mut in_whitespace = false;
+        for (offset, c) in text.chars().enumerate() {
+            if self.collapse_whitespace && c.is_whitespace() {
+                if !in_whitespace {
+                    folded.push((offset, ' '));
+                }
+                in_whitespace = true;
+                continue;
+            }
+            in_whitespace = false;
+            if self.lowercase {
+                folded.extend(c.to_lowercase().map(|c| (offset, c)));
+            } else {
+                folded.push((offset, c));
+            }
+        }
+        folded
+    }
+
+    /// Case folding and whitespace collapsing, what a tokenizer sees. The skip characters are
+    /// dropped as tokens, see `skip_tokens`.
+    pub(crate) fn fold(&self, text: &str) -> String {
+        self.fold_chars(text).into_iter().map(|(_, c)| c).collect()
+    }
+
+    /// The folded text without the skip characters.
+    pub(crate) fn apply(&self, text: &str) -> String {
+        self.fold(text)
+            .chars()
+            .filter(|c| !self.skip.contains(*c))
+            .collect()
+    }
+
+    /// The symbols of a char index: the UTF-8 bytes of the normalized text. Queries are encoded
+    /// the same way, and as UTF-8 is self-synchronizing an encoded query only matches at character
+    /// boundaries, so substring search works for any script.
+    pub(crate) fn encode_chars(&self, text: &str) -> Vec<u8> {
+        self.apply(text).into_bytes()
+    }
+
+    /// `encode_chars` and, for every byte, the offset of the character of `text` it comes from.
+    pub(crate) fn encode_chars_with_offsets(&self, text: &str) -> (Vec<u8>, Vec<u64>) {
+        let mut bytes = Vec::with_capacity(text.len());
+        let mut offsets = Vec::with_capacity(text.len());
+        let mut buffer = [0u8; 4];
+        for (offset, c) in self.fold_chars(text) {
+            if self.skip.contains(c) {
+                continue;
+            }
+            let encoded = c.encode_utf8(&mut buffer).as_bytes();
+            bytes.extend_from_slice(encoded);
+            offsets.extend(std::iter::repeat(offset as u64).take(encoded.len()));
+        }
+        (bytes, offsets)
+    }
+
+    /// The tokens of the skip characters, alone or next to a space, for token indices.
+    pub(crate) fn skip_tokens(&self, tokenizer: &Analyzer) -> Result<HashSet<u32>, LavaError> {
+        let mut skip_tokens: HashSet<u32> = HashSet::new();
+        for char in self.skip.chars() {
+            let char_str = char.to_string();
+            skip_tokens.extend(tokenizer.encode(&char_str)?);
+            skip_tokens.extend(tokenizer.encode(&format!(" {}", char_str))?);
+            skip_tokens.extend(tokenizer.encode(&format!("{} ", char_str))?);
+        }
+        Ok(skip_tokens)
+    }
+}
+
+pub(crate) fn write_normalization(
+    file: &mut File,
+    normalization: &Normalization,
+) -> Result<(), LavaError> {
+    let compressed = normalization.compress()?;
+    file.write_all(&(compressed.len() as u64).to_le_bytes())?;
+    file.write_all(&compressed)?;
+    Ok(())
+}
+
+/// Where the normalization starts: after the analyzer of a token index, at 0 in a char index.
+pub(crate) async fn normalization_offset(
+    reader: &mut AsyncReader,
+    token_index: bool,
+) -> Result<u64, LavaError> {
+    if token_index {
+        let compressed_tokenizer_size = reader.read_usize_from_start(0, 1).await?[0];
+        Ok(8 + compressed_tokenizer_size)
+    } else {
+        Ok(0)
+    }
+}
+
+/// The compressed normalization of one file, as stored.
+pub(crate) async fn read_compressed_normalization(
+    reader: &mut AsyncReader,
+    token_index: bool,
+) -> Result<Vec<u8>, LavaError> {
+    let offset = normalization_offset(reader, token_index).await?;
+    let size = reader.read_usize_from_start(offset, 1).await?[0];
+    Ok(reader
+        .read_range(offset + 8, offset + 8 + size)
+        .await?
+        .to_vec())
+}
+
+/// The normalization shared by all files, files normalized differently cannot be searched together.
+pub(crate) async fn read_normalization_async(
+    readers: &mut [AsyncReader],
+    token_index: bool,
+) -> Result<Normalization, LavaError> {
+    let mut compressed: Option<Vec<u8>> = None;
+    for reader in readers.iter_mut() {
+        let this_compressed = read_compressed_normalization(reader, token_index).await?;
+        match &compressed {
+            Some(value) if *value != this_compressed => {
+                return Err(LavaError::Parse(
+                    "detected different normalizations between lava files, can't search across them"
+                        .to_string(),
+                ))
+            }
+            Some(_) => {}
+            None => compressed = Some(this_compressed),
+        }
+    }
+    match compressed {
+        Some(compressed) => Normalization::from_compressed(&compressed),
+        None => Ok(Normalization::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalization() {
+        let default = Normalization::default();
+        assert_eq!(default.apply("Foo.Bar(x)"), "foobarx");
+
+        let exact =
+            Normalization::load(Some(r#"{"lowercase": false, "skip": ""}"#.to_string())).unwrap();
+        assert_eq!(exact.apply("Foo.Bar(x)"), "Foo.Bar(x)");
+
+        let collapsed = Normalization::load(Some(
+            r#"{"skip": "", "collapse_whitespace": true}"#.to_string(),
+        ))
+        .unwrap();
+        assert_eq!(collapsed.apply("A \t\n b  C"), "a b c");
+
+        assert!(Normalization::load(Some(r#"{"lowercas": false}"#.to_string())).is_err());
+
+        // the skip factor is only stored when there is one
+        assert_eq!(
+            Normalization::from_compressed(&default.compress().unwrap()).unwrap(),
+            default
+        );
+        let skipping = Normalization {
+            char_skip_factor: 3,
+            ..Normalization::default()
+        };
+        assert_eq!(
+            Normalization::from_compressed(&skipping.compress().unwrap()).unwrap(),
+            skipping
+        );
+        assert!(default.require_every_char("counts").is_ok());
+        assert!(matches!(
+            skipping.require_every_char("counts"),
+            Err(LavaError::Unsupported(_))
+        ));
+
+        // non-ascii characters keep all of their bytes
+        assert_eq!(default.encode_chars("Привет"), "привет".as_bytes().to_vec());
+        assert_eq!(default.encode_chars("数据库，连接"), "数据库连接".as_bytes().to_vec());
+        assert_ne!(default.encode_chars("ф"), default.encode_chars("D"));
+
+        let (bytes, offsets) = default.encode_chars_with_offsets("Ab, Щ");
+        assert_eq!(bytes, "abщ".as_bytes().to_vec());
+        assert_eq!(offsets, vec![0, 1, 4, 4]);
+    }
+}
@@ -0,0 +1,345 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use zstd::stream::encode_all;
+
+use super::wavelet_tree::CachedWaveletTree;
+use crate::formats::readers::AsyncReader;
+use crate::lava::error::LavaError;
+
+/*
+A sampled suffix array replaces the uid of every row of a char index. It keeps the text position of
+the rows whose suffix starts at a multiple of the sample rate, and a bitvector of the positions
+where documents start. The uid of any other row is found by LF mapping back through the text to
+the closest sampled position, at most sample rate - 1 steps, so the rate trades index size against
+query latency.
+*/
+
+// the u64 words of a compressed chunk of values or bits, small as a lookup decompresses its chunk
+const CHUNK_WORDS: usize = 1 << 14;
+
+/// A bitvector stored as compressed chunks of u64 words, with the number of ones before each
+/// chunk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ChunkedBitvector {
+    offsets: Vec<u64>,
+    ranks: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SampledSuffixArray {
+    /// The rows whose suffix starts at a multiple of the sample rate.
+    sampled_rows: ChunkedBitvector,
+    /// The suffix start of every sampled row, in row order.
+    sample_offsets: Vec<u64>,
+    /// The positions where a document starts.
+    document_starts: ChunkedBitvector,
+    /// The uid of every document, in text order.
+    uid_offsets: Vec<u64>,
+    /// The bwt symbol of the row of the suffix at 0 is the last symbol of the text, which does not
+    /// precede it, LF mapping corrects for that row.
+    first_row: u64,
+    last_symbol: u8,
+}
+
+fn write_bitvector(
+    file: &mut File,
+    len: usize,
+    ones: impl Iterator<Item = usize>,
+) -> Result<ChunkedBitvector, LavaError> {
+    let mut words: Vec<u64> = vec![0; (len + 63) / 64];
+    for one in ones {
+        words[one / 64] |= 1 << (one % 64);
+    }
+
+    let mut offsets: Vec<u64> = vec![file.seek(SeekFrom::Current(0))?];
+    let mut ranks: Vec<u64> = vec![0];
+    for chunk in words.chunks(CHUNK_WORDS) {
+        let serialized_chunk = bincode::serialize(chunk)?;
+        file.write_all(&encode_all(&serialized_chunk[..], 0)?)?;
+        offsets.push(file.seek(SeekFrom::Current(0))?);
+        ranks.push(ranks.last().unwrap() + chunk.iter().map(|w| w.count_ones() as u64).sum::<u64>());
+    }
+    Ok(ChunkedBitvector { offsets, ranks })
+}
+
+fn write_values(file: &mut File, values: &[u64]) -> Result<Vec<u64>, LavaError> {
+    let mut offsets: Vec<u64> = vec![file.seek(SeekFrom::Current(0))?];
+    for chunk in values.chunks(CHUNK_WORDS) {
+        let serialized_chunk = bincode::serialize(chunk)?;
+        file.write_all(&encode_all(&serialized_chunk[..], 0)?)?;
+        offsets.push(file.seek(SeekFrom::Current(0))?);
+    }
+    Ok(offsets)
+}
+
+/// Writes the sampled suffix array of a char index, `uids` has the uid of every text position.
+pub(crate) fn write_sampled_suffix_array(
+    file: &mut File,
+    sa: &[i32],
+    uids: &[u64],
+    last_symbol: u8,
+    sample_rate: u32,
+) -> Result<SampledSuffixArray, LavaError> {
+    let sample_rate = sample_rate as usize;
+    let sampled = |start: &i32| *start as usize % sample_rate == 0;
+
+    let sampled_rows = write_bitvector(
+        file,
+        sa.len(),
+        sa.iter()
+            .enumerate()
+            .filter(|(_, start)| sampled(start))
+            .map(|(row, _)| row),
+    )?;
+    let samples: Vec<u64> = sa
+        .iter()
+        .filter(|start| sampled(start))
+        .map(|start| *start as u64)
+        .collect();
+    let sample_offsets = write_values(file, &samples)?;
+
+    // consecutive texts of the same uid are one document
+    let starts: Vec<usize> = (0..uids.len())
+        .filter(|&pos| pos == 0 || uids[pos] != uids[pos - 1])
+        .collect();
+    let document_starts = write_bitvector(file, uids.len(), starts.iter().cloned())?;
+    let document_uids: Vec<u64> = starts.iter().map(|&pos| uids[pos]).collect();
+    let uid_offsets = write_values(file, &document_uids)?;
+
+    Ok(SampledSuffixArray {
+        sampled_rows,
+        sample_offsets,
+        document_starts,
+        uid_offsets,
+        first_row: sa.iter().position(|&start| start == 0).unwrap_or(0) as u64,
+        last_symbol,
+    })
+}
+
+/// Decompressed chunks of one chunked array or bitvector.
+struct ChunkCache {
+    chunks: HashMap<usize, Vec<u64>>,
+}
+
+impl ChunkCache {
+    fn new() -> Self {
+        ChunkCache {
+            chunks: HashMap::new(),
+        }
+    }
+
+    async fn get(
+        &mut self,
+        reader: &mut AsyncReader,
+        offsets: &[u64],
+        chunk_id: usize,
+    ) -> Result<&Vec<u64>, LavaError> {
+        if !self.chunks.contains_key(&chunk_id) {
+            let chunk: Vec<u64> = reader
+                .read_range_and_decompress(offsets[chunk_id], offsets[chunk_id + 1])
+                .await?;
+            self.chunks.insert(chunk_id, chunk);
+        }
+        Ok(&self.chunks[&chunk_id])
+    }
+
+    async fn value(
+        &mut self,
+        reader: &mut AsyncReader,
+        offsets: &[u64],
+        index: usize,
+    ) -> Result<u64, LavaError> {
+        let chunk = self.get(reader, offsets, index / CHUNK_WORDS).await?;
+        Ok(chunk[index % CHUNK_WORDS])
+    }
+
+    async fn bit(
+        &mut self,
+        reader: &mut AsyncReader,
+        bitvector: &ChunkedBitvector,
+        pos: usize,
+    ) -> Result<bool, LavaError> {
+        let words = self
+            .get(reader, &bitvector.offsets, pos / 64 / CHUNK_WORDS)
+            .await?;
+        Ok((words[pos / 64 % CHUNK_WORDS] >> (pos % 64)) & 1 == 1)
+    }
+
+    /// The number of ones before pos.
+    async fn rank(
+        &mut self,
+        reader: &mut AsyncReader,
+        bitvector: &ChunkedBitvector,
+        pos: usize,
+    ) -> Result<u64, LavaError> {
+        let chunk_id = pos / 64 / CHUNK_WORDS;
+        if chunk_id + 1 == bitvector.offsets.len() {
+            return Ok(*bitvector.ranks.last().unwrap());
+        }
+        let words = self.get(reader, &bitvector.offsets, chunk_id).await?;
+        let word = pos / 64 % CHUNK_WORDS;
+        let mut rank = bitvector.ranks[chunk_id];
+        rank += words[..word]
+            .iter()
+            .map(|w| w.count_ones() as u64)
+            .sum::<u64>();
+        if pos % 64 > 0 {
+            rank += (words[word] & ((1 << (pos % 64)) - 1)).count_ones() as u64;
+        }
+        Ok(rank)
+    }
+}
+
+/// The uid of every row start..end, in row order, like the posting lists of a dense index.
+pub(crate) async fn sampled_uids(
+    reader: &mut AsyncReader,
+    sampled: &SampledSuffixArray,
+    tree: &mut CachedWaveletTree,
+    cumulative_counts: &[usize],
+    start: usize,
+    end: usize,
+) -> Result<Vec<u64>, LavaError> {
+    let mut row_bits = ChunkCache::new();
+    let mut samples = ChunkCache::new();
+    let mut document_bits = ChunkCache::new();
+    let mut document_uids = ChunkCache::new();
+
+    let mut uids = Vec::with_capacity(end - start);
+    for row in start..end {
+        let mut row = row;
+        let mut steps = 0;
+        while !row_bits.bit(reader, &sampled.sampled_rows, row).await? {
+            let (symbol, rank) = tree.symbol_at(row).await?;
+            let correction = (symbol == sampled.last_symbol && (row as u64) < sampled.first_row)
+                as usize;
+            row = cumulative_counts[symbol as usize] + rank + correction;
+            steps += 1;
+        }
+        let sample = row_bits.rank(reader, &sampled.sampled_rows, row).await? as usize;
+        let pos = samples
+            .value(reader, &sampled.sample_offsets, sample)
+            .await? as usize
+            + steps;
+        let document = document_bits
+            .rank(reader, &sampled.document_starts, pos + 1)
+            .await? as usize
+            - 1;
+        uids.push(
+            document_uids
+                .value(reader, &sampled.uid_offsets, document)
+                .await?,
+        );
+    }
+    Ok(uids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::normalization::Normalization;
+    use super::super::substring::{
+        _build_lava_substring_char_wavelet, read_wavelet_metadata, wavelet_uids,
+    };
+    use super::super::wavelet_tree::search_wavelet_tree_from_reader;
+    use super::*;
+    use crate::formats::readers::{get_file_size_and_reader, AsyncReader};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    async fn build(
+        texts: &[(u64, String)],
+        sa_sample_rate: Option<u32>,
+    ) -> (String, AsyncReader, usize) {
+        let file = std::env::temp_dir()
+            .join(format!(
+                "rottnest_sampled_{}_{}.lava",
+                sa_sample_rate.unwrap_or(0),
+                std::process::id()
+            ))
+            .to_str()
+            .unwrap()
+            .to_string();
+        _build_lava_substring_char_wavelet(
+            file.clone(),
+            texts.to_vec(),
+            1,
+            &Normalization::default(),
+            false,
+            sa_sample_rate,
+        )
+        .await
+        .unwrap();
+        let (file_size, reader) = get_file_size_and_reader(file.clone(), Default::default())
+            .await
+            .unwrap();
+        (file, reader, file_size)
+    }
+
+    #[test]
+    fn test_sampled_uids() {
+        // more samples than a chunk holds at rate 1, and runs of texts with the same uid
+        let mut rng = StdRng::seed_from_u64(47);
+        let texts: Vec<(u64, String)> = (0..500)
+            .map(|i| {
+                let len = rng.gen_range(20..60);
+                let text: String = (0..len)
+                    .map(|_| b"abcde"[rng.gen_range(0..5)] as char)
+                    .collect();
+                (i / 2 * 3 + 1, text)
+            })
+            .collect();
+        let first = (texts[0].0, texts[0].1[..6].to_string());
+        let last_text = &texts.last().unwrap().1;
+        let last = (
+            texts.last().unwrap().0,
+            last_text[last_text.len() - 6..].to_string(),
+        );
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (dense_file, mut dense, dense_size) = build(&texts, None).await;
+            let dense_metadata = read_wavelet_metadata(&mut dense, dense_size).await.unwrap();
+            let n = dense_metadata.4;
+            assert!(n > CHUNK_WORDS);
+            let all_uids = wavelet_uids(&mut dense, &dense_metadata, 0, n)
+                .await
+                .unwrap();
+
+            for sa_sample_rate in [1, 3, 7] {
+                let (file, mut reader, file_size) = build(&texts, Some(sa_sample_rate)).await;
+                let metadata = read_wavelet_metadata(&mut reader, file_size).await.unwrap();
+                assert!(metadata.7.is_some() && metadata.2.is_empty());
+                assert_eq!(
+                    wavelet_uids(&mut reader, &metadata, 0, n).await.unwrap(),
+                    all_uids,
+                    "sample rate {}",
+                    sa_sample_rate
+                );
+
+                // a match at text position 0 and one in the last document
+                for (uid, pattern) in [&first, &last] {
+                    let (start, end) = search_wavelet_tree_from_reader(
+                        &mut reader,
+                        pattern.as_bytes(),
+                        n,
+                        &metadata.0,
+                        &metadata.1,
+                        &metadata.3,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                    assert!(start < end);
+                    let uids = wavelet_uids(&mut reader, &metadata, start, end)
+                        .await
+                        .unwrap();
+                    assert_eq!(uids, all_uids[start..end]);
+                    assert!(uids.contains(uid));
+                }
+                std::fs::remove_file(file).unwrap();
+            }
+            std::fs::remove_file(dense_file).unwrap();
+        });
+    }
+}
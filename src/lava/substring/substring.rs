@@ -1,17 +1,23 @@
 use super::constants::*;
-use super::fm_chunk::FMChunk;
-use crate::formats::readers::{get_file_sizes_and_readers, AsyncReader};
+use super::fm_chunk::{write_fm_chunks, FMDirectory, FMDirectoryIndex};
+use super::literals::required_literals;
+use super::normalization::{read_normalization_async, write_normalization, Normalization};
+use super::sampled::{sampled_uids, write_sampled_suffix_array, SampledSuffixArray};
+use crate::formats::readers::{get_index_file_sizes_and_readers, AsyncReader};
+use crate::lava::analyzer::Analyzer;
 use crate::lava::error::LavaError;
 
-use crate::lava::substring::wavelet_tree::{construct_wavelet_tree, write_wavelet_tree_to_disk};
+use crate::lava::substring::wavelet_tree::{
+    construct_wavelet_tree, write_wavelet_tree_to_disk, CachedWaveletTree,
+};
 use arrow::array::{make_array, Array, ArrayData, LargeStringArray, UInt64Array};
 use bincode;
 use bytes;
 use divsufsort::sort_in_place;
-use itertools::Itertools;
-use serde_json;
 
 use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt::Debug;
@@ -19,48 +25,127 @@ use std::fs::File;
 use std::io::Read;
 use std::io::{BufWriter, Seek, SeekFrom, Write};
 use tokenizers::parallelism::MaybeParallelIterator;
-use tokenizers::tokenizer::Tokenizer; // You'll need the `byteorder` crate
 use tokio::task::JoinSet;
 use zstd::stream::encode_all;
 use zstd::stream::read::Decoder;
 
+// wavelet tree chunk offsets, level offsets, posting list offsets, cumulative counts, number of
+// symbols, offsets of the position chunks, which are empty unless the index is positional, number
+// of documents and the sampled suffix array, which replaces the posting lists if there is one.
+// Char files end with it, where it starts and WAVELET_MAGIC.
+pub(super) type WaveletMetadata = (
+    Vec<usize>,
+    Vec<usize>,
+    Vec<usize>,
+    Vec<usize>,
+    usize,
+    Vec<usize>,
+    usize,
+    Option<SampledSuffixArray>,
+);
+
+/*
+Files with an FM index end with a footer of seven u64: the offsets of the fm chunk offsets, the
+posting list offsets and the cumulative counts, the number of symbols, the number of documents,
+the offset of the fm directory index, and FM_FOOTER_MAGIC, which names the format and its version
+so files of another layout are rejected instead of misread.
+*/
+pub(crate) const FM_FOOTER_WORDS: u64 = 7;
+// "LAVAFM" followed by the format version
+const FM_FOOTER_MAGIC: u64 = 0x4c41_5641_464d_0003;
+// "LAVAWT" followed by the format version of char files, like FM_FOOTER_MAGIC
+const WAVELET_MAGIC: u64 = 0x4c41_5641_5754_0001;
+
+/// Ends a file with an FM index with its footer.
+pub(super) fn write_fm_footer(file: &mut File, words: [u64; 6]) -> Result<(), LavaError> {
+    for word in words.iter().chain([FM_FOOTER_MAGIC].iter()) {
+        file.write_all(&word.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// The footer of a file with an FM index, checked to be of the current format.
+pub(crate) async fn read_fm_footer(reader: &mut AsyncReader) -> Result<Vec<u64>, LavaError> {
+    let results = reader.read_usize_from_end(FM_FOOTER_WORDS).await?;
+    if results[FM_FOOTER_WORDS as usize - 1] != FM_FOOTER_MAGIC {
+        return Err(LavaError::Parse(format!(
+            "{} is not an FM index of this version, rebuild it",
+            reader.filename
+        )));
+    }
+    Ok(results)
+}
+
 pub async fn _build_lava_substring_char_wavelet(
     output_file_name: String,
     texts: Vec<(u64, String)>,
     char_skip_factor: u32,
+    normalization: &Normalization,
+    positional: bool,
+    sa_sample_rate: Option<u32>,
 ) -> Result<Vec<(usize, usize)>, LavaError> {
+    match sa_sample_rate {
+        Some(0) => {
+            return Err(LavaError::Parse(
+                "the suffix array sample rate must be positive".to_string(),
+            ))
+        }
+        Some(_) if positional => {
+            return Err(LavaError::Unsupported(
+                "positions need the dense layout, build without a suffix array sample rate"
+                    .to_string(),
+            ))
+        }
+        _ => {}
+    }
+    let num_documents = texts.len();
+    // the character offset in its text of every symbol, only kept for positional indices
     let named_encodings = texts
         .into_iter()
         .map(|(uid, text)| {
-            let lower: String = text.chars().flat_map(|c| c.to_lowercase()).collect();
-            let result: Vec<u8> = if char_skip_factor == 1 {
-                lower
-                    .chars()
-                    .filter(|id| !SKIP.chars().contains(id))
-                    .map(|c| c as u8)
-                    .collect()
+            let (bytes, offsets) = if positional {
+                normalization.encode_chars_with_offsets(&text)
             } else {
-                lower
-                    .chars()
-                    .filter(|id| !SKIP.chars().contains(id))
-                    .enumerate()
-                    .filter(|&(index, _)| index % char_skip_factor as usize == 1)
-                    .map(|(_, c)| c as u8)
-                    .collect()
+                (normalization.encode_chars(&text), vec![])
             };
-            (vec![uid; result.len()], result)
+            let (result, offsets): (Vec<u8>, Vec<u64>) = if char_skip_factor == 1 {
+                (bytes, offsets)
+            } else {
+                let sampled = |index: usize| index % char_skip_factor as usize == 1;
+                (
+                    bytes
+                        .into_iter()
+                        .enumerate()
+                        .filter(|&(index, _)| sampled(index))
+                        .map(|(_, c)| c)
+                        .collect(),
+                    offsets
+                        .into_iter()
+                        .enumerate()
+                        .filter(|&(index, _)| sampled(index))
+                        .map(|(_, offset)| offset)
+                        .collect(),
+                )
+            };
+            (vec![uid; result.len()], result, offsets)
         })
-        .collect::<Vec<(Vec<u64>, Vec<u8>)>>();
+        .collect::<Vec<(Vec<u64>, Vec<u8>, Vec<u64>)>>();
 
     let uids: Vec<u64> = named_encodings
         .iter()
-        .map(|(uid, _)| uid)
+        .map(|(uid, _, _)| uid)
+        .flatten()
+        .cloned()
+        .collect::<Vec<u64>>();
+    let char_offsets: Vec<u64> = named_encodings
+        .iter()
+        .map(|(_, _, offsets)| offsets)
         .flatten()
         .cloned()
         .collect::<Vec<u64>>();
     let encodings: Vec<u8> = named_encodings
         .into_iter()
-        .map(|(_, text)| text)
+        .map(|(_, text, _)| text)
         .flatten()
         .collect::<Vec<u8>>();
 
@@ -69,6 +154,7 @@ pub async fn _build_lava_substring_char_wavelet(
     sort_in_place(&encodings, &mut sa);
 
     let mut idx: Vec<u64> = Vec::with_capacity(encodings.len());
+    let mut positions: Vec<u64> = Vec::with_capacity(char_offsets.len());
     let mut bwt: Vec<u8> = Vec::with_capacity(encodings.len());
     let mut total_counts: Vec<usize> = vec![0; 256];
     for i in 0..sa.len() {
@@ -79,10 +165,12 @@ pub async fn _build_lava_substring_char_wavelet(
         };
         bwt.push(char);
         total_counts[char as usize] += 1;
-        if sa[i] == 0 {
-            idx.push(uids[uids.len() - 1]);
-        } else {
-            idx.push(uids[(sa[i] - 1) as usize]);
+        // the text the suffix starts in, a match belongs to the text of its first symbol
+        if sa_sample_rate.is_none() {
+            idx.push(uids[sa[i] as usize]);
+        }
+        if positional {
+            positions.push(char_offsets[sa[i] as usize]);
         }
     }
 
@@ -95,36 +183,73 @@ pub async fn _build_lava_substring_char_wavelet(
     let wavelet_tree = construct_wavelet_tree(&bwt);
 
     let mut file = File::create(output_file_name)?;
+    write_normalization(&mut file, normalization)?;
 
     let (offsets, level_offsets) = write_wavelet_tree_to_disk(&wavelet_tree, &mut file).unwrap();
 
     // print out total file size so far
     println!("total file size: {}", file.seek(SeekFrom::Current(0))?);
 
-    let mut posting_list_offsets: Vec<usize> = vec![file.seek(SeekFrom::Current(0))? as usize];
+    // a sampled index has no posting lists
+    let mut posting_list_offsets: Vec<usize> = vec![];
+    let mut sampled: Option<SampledSuffixArray> = None;
+    if let Some(sa_sample_rate) = sa_sample_rate {
+        let last_symbol = *encodings.last().unwrap_or(&0);
+        sampled = Some(write_sampled_suffix_array(
+            &mut file,
+            &sa,
+            &uids,
+            last_symbol,
+            sa_sample_rate,
+        )?);
+    } else {
+        posting_list_offsets = write_rows(&mut file, &idx)?;
+    }
 
-    for i in (0..idx.len()).step_by(FM_CHUNK_TOKS) {
-        let slice = &idx[i..std::cmp::min(idx.len(), i + FM_CHUNK_TOKS)];
-        let serialized_slice = bincode::serialize(slice)?;
-        let compressed_slice = encode_all(&serialized_slice[..], 0).expect("Compression failed");
-        file.write_all(&compressed_slice)?;
-        posting_list_offsets.push(file.seek(SeekFrom::Current(0))? as usize);
+    // empty unless positional
+    let mut position_offsets: Vec<usize> = vec![];
+    if positional {
+        position_offsets = write_rows(&mut file, &positions)?;
     }
 
-    let metadata: (Vec<usize>, Vec<usize>, Vec<usize>, Vec<usize>, usize) = (
+    let metadata: WaveletMetadata = (
         offsets,
         level_offsets,
         posting_list_offsets,
         cumulative_counts,
         bwt.len(),
+        position_offsets,
+        num_documents,
+        sampled,
     );
 
+    write_wavelet_metadata(&mut file, &metadata)
+}
+
+/// Writes rows of a char file in chunks of FM_CHUNK_TOKS and returns the chunk offsets.
+pub(super) fn write_rows(file: &mut File, rows: &[u64]) -> Result<Vec<usize>, LavaError> {
+    let mut offsets = vec![file.seek(SeekFrom::Current(0))? as usize];
+    for slice in rows.chunks(FM_CHUNK_TOKS) {
+        let serialized_slice = bincode::serialize(slice)?;
+        let compressed_slice = encode_all(&serialized_slice[..], 0).expect("Compression failed");
+        file.write_all(&compressed_slice)?;
+        offsets.push(file.seek(SeekFrom::Current(0))? as usize);
+    }
+    Ok(offsets)
+}
+
+/// Ends a char file with its metadata and where the metadata starts, the range to cache.
+pub(super) fn write_wavelet_metadata(
+    file: &mut File,
+    metadata: &WaveletMetadata,
+) -> Result<Vec<(usize, usize)>, LavaError> {
     let cache_start = file.seek(SeekFrom::Current(0))? as usize;
 
-    let serialized_metadata = bincode::serialize(&metadata)?;
+    let serialized_metadata = bincode::serialize(metadata)?;
     let compressed_metadata = encode_all(&serialized_metadata[..], 0).expect("Compression failed");
     file.write_all(&compressed_metadata)?;
     file.write_all(&cache_start.to_le_bytes())?;
+    file.write_all(&WAVELET_MAGIC.to_le_bytes())?;
 
     let cache_end = file.seek(SeekFrom::Current(0))? as usize;
 
@@ -135,24 +260,21 @@ pub async fn _build_lava_substring_char(
     output_file_name: String,
     texts: Vec<(u64, String)>,
     char_skip_factor: u32,
+    normalization: &Normalization,
 ) -> Result<Vec<(usize, usize)>, LavaError> {
+    let num_documents = texts.len();
     let named_encodings = texts
         .into_iter()
         .map(|(uid, text)| {
-            let lower: String = text.chars().flat_map(|c| c.to_lowercase()).collect();
+            let bytes = normalization.encode_chars(&text);
             let result: Vec<u8> = if char_skip_factor == 1 {
-                lower
-                    .chars()
-                    .filter(|id| !SKIP.chars().contains(id))
-                    .map(|c| c as u8)
-                    .collect()
+                bytes
             } else {
-                lower
-                    .chars()
-                    .filter(|id| !SKIP.chars().contains(id))
+                bytes
+                    .into_iter()
                     .enumerate()
                     .filter(|&(index, _)| index % char_skip_factor as usize == 1)
-                    .map(|(_, c)| c as u8)
+                    .map(|(_, c)| c)
                     .collect()
             };
             (vec![uid; result.len()], result)
@@ -180,52 +302,24 @@ pub async fn _build_lava_substring_char(
     for i in 0..sa.len() {
         if sa[i] == 0 {
             bwt.push(encodings[encodings.len() - 1]);
-            idx.push(uids[uids.len() - 1]);
         } else {
             bwt.push(encodings[(sa[i] - 1) as usize]);
-            idx.push(uids[(sa[i] - 1) as usize]);
         }
+        // the text the suffix starts in, a match belongs to the text of its first symbol
+        idx.push(uids[sa[i] as usize]);
     }
 
     let mut file = File::create(output_file_name)?;
+    write_normalization(&mut file, normalization)?;
 
-    let mut fm_chunk_offsets: Vec<usize> = vec![file.seek(SeekFrom::Current(0))? as usize];
-
-    let mut current_chunk: Vec<u8> = vec![];
-    let mut current_chunk_counts: HashMap<u8, u64> = HashMap::new();
-    let mut next_chunk_counts: HashMap<u8, u64> = HashMap::new();
-
-    for i in 0..bwt.len() {
-        let current_tok = bwt[i];
-        next_chunk_counts
-            .entry(current_tok)
-            .and_modify(|count| *count += 1)
-            .or_insert(1);
-        current_chunk.push(current_tok);
-
-        if ((i + 1) % FM_CHUNK_TOKS == 0) || i == bwt.len() - 1 {
-            let serialized_counts = bincode::serialize(&current_chunk_counts)?;
-            let compressed_counts =
-                encode_all(&serialized_counts[..], 10).expect("Compression failed");
-            println!("chunk size: {}", compressed_counts.len());
-            file.write_all(&(compressed_counts.len() as u64).to_le_bytes())?;
-            file.write_all(&compressed_counts)?;
-            let serialized_chunk = bincode::serialize(&current_chunk)?;
-            let compressed_chunk =
-                encode_all(&serialized_chunk[..], 10).expect("Compression failed");
-            file.write_all(&compressed_chunk)?;
-            fm_chunk_offsets.push(file.seek(SeekFrom::Current(0))? as usize);
-            current_chunk_counts = next_chunk_counts.clone();
-            current_chunk = vec![];
-        }
-    }
+    let (fm_chunk_offsets, fm_directory_index, total_counts) = write_fm_chunks(&mut file, &bwt)?;
     // print out total file size so far
     println!("total file size: {}", file.seek(SeekFrom::Current(0))?);
 
     let mut cumulative_counts: Vec<u64> = vec![0];
     for i in 0..256 {
         cumulative_counts
-            .push(cumulative_counts[i] + *current_chunk_counts.get(&(i as u8)).unwrap_or(&0));
+            .push(cumulative_counts[i] + *total_counts.get(&(i as u8)).unwrap_or(&0));
     }
 
     let mut posting_list_offsets: Vec<usize> = vec![file.seek(SeekFrom::Current(0))? as usize];
@@ -258,10 +352,23 @@ pub async fn _build_lava_substring_char(
         encode_all(&serialized_total_counts[..], 0).expect("Compression failed");
     file.write_all(&compressed_total_counts)?;
 
-    file.write_all(&(fm_chunk_offsets_offset as u64).to_le_bytes())?;
-    file.write_all(&(posting_list_offsets_offset as u64).to_le_bytes())?;
-    file.write_all(&(total_counts_offset as u64).to_le_bytes())?;
-    file.write_all(&(bwt.len() as u64).to_le_bytes())?;
+    let fm_directory_index_offset = file.seek(SeekFrom::Current(0))? as usize;
+    let serialized_fm_directory_index = bincode::serialize(&fm_directory_index)?;
+    let compressed_fm_directory_index: Vec<u8> =
+        encode_all(&serialized_fm_directory_index[..], 0).expect("Compression failed");
+    file.write_all(&compressed_fm_directory_index)?;
+
+    write_fm_footer(
+        &mut file,
+        [
+            fm_chunk_offsets_offset as u64,
+            posting_list_offsets_offset as u64,
+            total_counts_offset as u64,
+            bwt.len() as u64,
+            num_documents as u64,
+            fm_directory_index_offset as u64,
+        ],
+    )?;
 
     let cache_end = file.seek(SeekFrom::Current(0))? as usize;
 
@@ -274,12 +381,23 @@ pub async fn build_lava_substring_char(
     array: ArrayData,
     uid: ArrayData,
     char_skip_factor: Option<u32>,
+    normalization: Option<String>,
+    positional: Option<bool>,
+    sa_sample_rate: Option<u32>,
 ) -> Result<Vec<(usize, usize)>, LavaError> {
     let array = make_array(array);
     // let uid = make_array(ArrayData::from_pyarrow(uid)?);
     let uid = make_array(uid);
 
     let char_skip_factor = char_skip_factor.unwrap_or(1);
+    if char_skip_factor == 0 {
+        return Err(LavaError::Parse(
+            "the char skip factor must be positive".to_string(),
+        ));
+    }
+    // stored with the normalization, searches read it back from the file
+    let mut normalization = Normalization::load(normalization)?;
+    normalization.char_skip_factor = char_skip_factor;
 
     let array: &arrow_array::GenericByteArray<arrow_array::types::GenericStringType<i64>> = array
         .as_any()
@@ -308,10 +426,20 @@ pub async fn build_lava_substring_char(
     }
 
     println!("made it to this point");
-    // _build_lava_substring_char(output_file_name, texts, char_skip_factor).await
-    _build_lava_substring_char_wavelet(output_file_name, texts, char_skip_factor).await
+    // _build_lava_substring_char(output_file_name, texts, char_skip_factor, &normalization).await
+    _build_lava_substring_char_wavelet(
+        output_file_name,
+        texts,
+        char_skip_factor,
+        &normalization,
+        positional.unwrap_or(false),
+        sa_sample_rate,
+    )
+    .await
 }
 
+/// Token substring index of `array`. The uid of a suffix is that of the text its first token is
+/// in, so a match is reported for the text it starts in, like in the char indices.
 #[tokio::main]
 pub async fn build_lava_substring(
     output_file_name: String,
@@ -319,6 +447,7 @@ pub async fn build_lava_substring(
     uid: ArrayData,
     tokenizer_file: Option<String>,
     token_skip_factor: Option<u32>,
+    normalization: Option<String>,
 ) -> Result<Vec<(usize, usize)>, LavaError> {
     let array = make_array(array);
     // let uid = make_array(ArrayData::from_pyarrow(uid)?);
@@ -326,21 +455,8 @@ pub async fn build_lava_substring(
 
     let token_skip_factor = token_skip_factor.unwrap_or(1);
 
-    let tokenizer = if let Some(tokenizer_file) = tokenizer_file {
-        if !std::path::Path::new(&tokenizer_file).exists() {
-            return Err(LavaError::Parse(
-                "Tokenizer file does not exist".to_string(),
-            ));
-        }
-        println!("Tokenizer file: {}", tokenizer_file);
-        Tokenizer::from_file(tokenizer_file).unwrap()
-    } else {
-        Tokenizer::from_pretrained("bert-base-uncased", None).unwrap()
-    };
-
-    let serialized_tokenizer = serde_json::to_string(&tokenizer).unwrap();
-    let compressed_tokenizer =
-        encode_all(serialized_tokenizer.as_bytes(), 0).expect("Compression failed");
+    let (tokenizer, compressed_tokenizer) = Analyzer::load(tokenizer_file)?;
+    let normalization = Normalization::load(normalization)?;
 
     let array: &arrow_array::GenericByteArray<arrow_array::types::GenericStringType<i64>> = array
         .as_any()
@@ -368,48 +484,24 @@ pub async fn build_lava_substring(
         texts.push((uid.value(i), text));
     }
 
-    let mut skip_tokens: HashSet<u32> = HashSet::new();
-    for char in SKIP.chars() {
-        let char_str = char.to_string();
-        skip_tokens.extend(
-            tokenizer
-                .encode(char_str.clone(), false)
-                .unwrap()
-                .get_ids()
-                .to_vec(),
-        );
-        skip_tokens.extend(
-            tokenizer
-                .encode(format!(" {}", char_str), false)
-                .unwrap()
-                .get_ids()
-                .to_vec(),
-        );
-        skip_tokens.extend(
-            tokenizer
-                .encode(format!("{} ", char_str), false)
-                .unwrap()
-                .get_ids()
-                .to_vec(),
-        );
-    }
+    let skip_tokens: HashSet<u32> = normalization.skip_tokens(&tokenizer)?;
+    let num_documents = texts.len();
 
     let named_encodings = texts
         .into_maybe_par_iter()
         .map(|(uid, text)| {
             // strip out things in skip in text
 
-            let lower: String = text.chars().flat_map(|c| c.to_lowercase()).collect();
-            let encoding = tokenizer.encode(lower, false).unwrap();
+            let folded: String = normalization.fold(text);
+            let encoding = tokenizer.encode(&folded)?;
             let result: Vec<u32> = encoding
-                .get_ids()
                 .iter()
                 .filter(|id| !skip_tokens.contains(id))
                 .cloned()
                 .collect();
-            (vec![uid; result.len()], result)
+            Ok((vec![uid; result.len()], result))
         })
-        .collect::<Vec<(Vec<u64>, Vec<u32>)>>();
+        .collect::<Result<Vec<(Vec<u64>, Vec<u32>)>, LavaError>>()?;
 
     let uids: Vec<u64> = named_encodings
         .iter()
@@ -463,55 +555,26 @@ pub async fn build_lava_substring(
     for i in 0..sa.len() {
         if sa[i] == 0 {
             bwt.push(encodings[encodings.len() - 1]);
-            idx.push(uids[uids.len() - 1]);
         } else {
             bwt.push(encodings[(sa[i] - 1) as usize]);
-            idx.push(uids[(sa[i] - 1) as usize]);
         }
+        // the text the suffix starts in, a match belongs to the text of its first symbol
+        idx.push(uids[sa[i] as usize]);
     }
 
     let mut file = File::create(output_file_name)?;
     file.write_all(&(compressed_tokenizer.len() as u64).to_le_bytes())?;
     file.write_all(&compressed_tokenizer)?;
+    write_normalization(&mut file, &normalization)?;
 
-    let mut fm_chunk_offsets: Vec<usize> = vec![file.seek(SeekFrom::Current(0))? as usize];
-
-    let mut current_chunk: Vec<u32> = vec![];
-    let mut current_chunk_counts: HashMap<u32, u64> = HashMap::new();
-    let mut next_chunk_counts: HashMap<u32, u64> = HashMap::new();
-
-    for i in 0..bwt.len() {
-        let current_tok = bwt[i];
-        next_chunk_counts
-            .entry(current_tok)
-            .and_modify(|count| *count += 1)
-            .or_insert(1);
-        current_chunk.push(current_tok);
-
-        if ((i + 1) % FM_CHUNK_TOKS == 0) || i == bwt.len() - 1 {
-            let serialized_counts = bincode::serialize(&current_chunk_counts)?;
-            let compressed_counts =
-                encode_all(&serialized_counts[..], 10).expect("Compression failed");
-
-            file.write_all(&(compressed_counts.len() as u64).to_le_bytes())?;
-            file.write_all(&compressed_counts)?;
-            let serialized_chunk = bincode::serialize(&current_chunk)?;
-            let compressed_chunk =
-                encode_all(&serialized_chunk[..], 10).expect("Compression failed");
-            file.write_all(&compressed_chunk)?;
-
-            fm_chunk_offsets.push(file.seek(SeekFrom::Current(0))? as usize);
-            current_chunk_counts = next_chunk_counts.clone();
-            current_chunk = vec![];
-        }
-    }
+    let (fm_chunk_offsets, fm_directory_index, total_counts) = write_fm_chunks(&mut file, &bwt)?;
     // print out total file size so far
     println!("total file size: {}", file.seek(SeekFrom::Current(0))?);
 
     let mut cumulative_counts: Vec<u64> = vec![0];
-    for i in 0..tokenizer.get_vocab_size(false) {
+    for i in 0..tokenizer.vocab_size() {
         cumulative_counts
-            .push(cumulative_counts[i] + *current_chunk_counts.get(&(i as u32)).unwrap_or(&0));
+            .push(cumulative_counts[i] + *total_counts.get(&(i as u32)).unwrap_or(&0));
     }
 
     let mut posting_list_offsets: Vec<usize> = vec![file.seek(SeekFrom::Current(0))? as usize];
@@ -544,10 +607,23 @@ pub async fn build_lava_substring(
         encode_all(&serialized_total_counts[..], 0).expect("Compression failed");
     file.write_all(&compressed_total_counts)?;
 
-    file.write_all(&(fm_chunk_offsets_offset as u64).to_le_bytes())?;
-    file.write_all(&(posting_list_offsets_offset as u64).to_le_bytes())?;
-    file.write_all(&(total_counts_offset as u64).to_le_bytes())?;
-    file.write_all(&(bwt.len() as u64).to_le_bytes())?;
+    let fm_directory_index_offset = file.seek(SeekFrom::Current(0))? as usize;
+    let serialized_fm_directory_index = bincode::serialize(&fm_directory_index)?;
+    let compressed_fm_directory_index: Vec<u8> =
+        encode_all(&serialized_fm_directory_index[..], 0).expect("Compression failed");
+    file.write_all(&compressed_fm_directory_index)?;
+
+    write_fm_footer(
+        &mut file,
+        [
+            fm_chunk_offsets_offset as u64,
+            posting_list_offsets_offset as u64,
+            total_counts_offset as u64,
+            bwt.len() as u64,
+            num_documents as u64,
+            fm_directory_index_offset as u64,
+        ],
+    )?;
 
     let cache_end = file.seek(SeekFrom::Current(0))? as usize;
 
@@ -558,15 +634,17 @@ use num_traits::{AsPrimitive, PrimInt, Unsigned};
 use serde::{Deserialize, Serialize};
 use std::ops::Add;
 
-async fn process_substring_query<T>(
+/// The suffix array range of the query from the FM index alone, None when it does not occur.
+/// Unless `exact`, the search stops once at most two suffixes are left, those may not match all
+/// of the query.
+async fn fm_range<T>(
     query: Vec<T>,
     n: u64,
-    fm_chunk_offsets: &[u64],
+    directory: &FMDirectory,
     cumulative_counts: &[u64],
-    posting_list_offsets: &[u64],
     reader: &mut AsyncReader,
-    file_id: u64,
-) -> Vec<(u64, u64)>
+    exact: bool,
+) -> Result<Option<(usize, usize)>, LavaError>
 where
     T: PrimInt
         + Unsigned
@@ -579,46 +657,63 @@ where
         + 'static,
     usize: AsPrimitive<T>,
 {
-    let mut res: Vec<(u64, u64)> = vec![];
     let mut start: usize = 0;
     let mut end: usize = n as usize;
 
     for i in (0..query.len()).rev() {
         let current_token = query[i];
 
-        let start_byte = fm_chunk_offsets[start / FM_CHUNK_TOKS];
-        let end_byte = fm_chunk_offsets[start / FM_CHUNK_TOKS + 1];
-        let start_chunk = reader.read_range(start_byte, end_byte).await.unwrap();
-
-        let start_byte = fm_chunk_offsets[end / FM_CHUNK_TOKS];
-        let end_byte = fm_chunk_offsets[end / FM_CHUNK_TOKS + 1];
-        let end_chunk = reader.read_range(start_byte, end_byte).await.unwrap();
-
         start = cumulative_counts[current_token.as_()] as usize
-            + FMChunk::<T>::new(start_chunk)
-                .unwrap()
-                .search(current_token, start % FM_CHUNK_TOKS)
-                .unwrap() as usize;
+            + directory.rank(reader, current_token, start).await? as usize;
         end = cumulative_counts[current_token.as_()] as usize
-            + FMChunk::<T>::new(end_chunk)
-                .unwrap()
-                .search(current_token, end % FM_CHUNK_TOKS)
-                .unwrap() as usize;
+            + directory.rank(reader, current_token, end).await? as usize;
 
         if start >= end {
-            return res;
+            return Ok(None);
         }
 
-        if end <= start + 2 {
+        if !exact && end <= start + 2 {
             break;
         }
     }
+    Ok(Some((start, end)))
+}
+
+/// One (file_id, uid) per occurrence of the query, see `fm_range`.
+async fn process_substring_query<T>(
+    query: Vec<T>,
+    n: u64,
+    directory: &FMDirectory,
+    cumulative_counts: &[u64],
+    posting_list_offsets: &[u64],
+    reader: &mut AsyncReader,
+    file_id: u64,
+    exact: bool,
+) -> Result<Vec<(u64, u64)>, LavaError>
+where
+    T: PrimInt
+        + Unsigned
+        + Serialize
+        + for<'de> Deserialize<'de>
+        + Clone
+        + Eq
+        + std::hash::Hash
+        + AsPrimitive<usize>
+        + 'static,
+    usize: AsPrimitive<T>,
+{
+    let mut res: Vec<(u64, u64)> = vec![];
+    let range = fm_range(query, n, directory, cumulative_counts, reader, exact).await?;
+    let (start, end) = match range {
+        Some(range) => range,
+        None => return Ok(res),
+    };
 
     let start_offset = posting_list_offsets[start / FM_CHUNK_TOKS];
     let end_offset = posting_list_offsets[end / FM_CHUNK_TOKS + 1];
     let total_chunks = end / FM_CHUNK_TOKS - start / FM_CHUNK_TOKS + 1;
 
-    let plist_chunks = reader.read_range(start_offset, end_offset).await.unwrap();
+    let plist_chunks = reader.read_range(start_offset, end_offset).await?;
 
     let mut chunk_set = JoinSet::new();
 
@@ -666,12 +761,107 @@ where
         res.extend(chunk_res.unwrap());
     }
 
-    res
+    Ok(res)
 }
 
 use super::wavelet_tree::search_wavelet_tree_from_reader;
 use crate::formats::readers::read_and_decompress;
 
+/// The values of a chunked per row array, the uids or the positions, for the rows start..end.
+pub(super) async fn read_rows(
+    reader: &mut AsyncReader,
+    chunk_offsets: &[usize],
+    start: usize,
+    end: usize,
+) -> Result<Vec<u64>, LavaError> {
+    let start_offset = chunk_offsets[start / FM_CHUNK_TOKS];
+    let end_offset = chunk_offsets[end / FM_CHUNK_TOKS + 1];
+    let total_chunks = end / FM_CHUNK_TOKS - start / FM_CHUNK_TOKS + 1;
+
+    let chunks = reader
+        .read_range(start_offset as u64, end_offset as u64)
+        .await?;
+
+    let mut chunk_set = JoinSet::new();
+
+    for i in 0..total_chunks {
+        let this_start = chunk_offsets[start / FM_CHUNK_TOKS + i];
+        let this_end = chunk_offsets[start / FM_CHUNK_TOKS + i + 1];
+        let this_chunk =
+            chunks[(this_start - start_offset) as usize..(this_end - start_offset) as usize].to_vec();
+
+        chunk_set.spawn(async move {
+            let mut decompressor = Decoder::new(&this_chunk[..]).unwrap();
+            let mut serialized_chunk: Vec<u8> = Vec::with_capacity(this_chunk.len());
+            decompressor.read_to_end(&mut serialized_chunk).unwrap();
+            let chunk: Vec<u64> = bincode::deserialize(&serialized_chunk).unwrap();
+
+            let rows: Vec<u64> = if i == 0 {
+                if total_chunks == 1 {
+                    chunk[start % FM_CHUNK_TOKS..end % FM_CHUNK_TOKS].to_vec()
+                } else {
+                    chunk[start % FM_CHUNK_TOKS..].to_vec()
+                }
+            } else if i == total_chunks - 1 {
+                chunk[..end % FM_CHUNK_TOKS].to_vec()
+            } else {
+                chunk
+            };
+
+            (i, rows)
+        });
+    }
+
+    // in row order, the uids and positions of the same rows are zipped
+    let mut chunk_rows: Vec<(usize, Vec<u64>)> = vec![];
+    while let Some(rows) = chunk_set.join_next().await {
+        chunk_rows.push(rows.unwrap());
+    }
+    chunk_rows.sort_by_key(|(i, _)| *i);
+    Ok(chunk_rows.into_iter().flat_map(|(_, rows)| rows).collect())
+}
+
+/// The metadata of a char file, checked to be of the current format.
+pub(super) async fn read_wavelet_metadata(
+    reader: &mut AsyncReader,
+    file_size: usize,
+) -> Result<WaveletMetadata, LavaError> {
+    let results = reader.read_usize_from_end(2).await?;
+    if results[1] != WAVELET_MAGIC {
+        return Err(LavaError::Parse(format!(
+            "{} is not a char index of this version, rebuild it",
+            reader.filename
+        )));
+    }
+    let metadata_start = results[0];
+    read_and_decompress(
+        reader,
+        metadata_start as u64,
+        file_size as u64 - metadata_start - 16,
+    )
+    .await
+}
+
+/// The uid of every row start..end of a char file, from its posting lists or by LF mapping to the
+/// samples of its sampled suffix array.
+pub(super) async fn wavelet_uids(
+    reader: &mut AsyncReader,
+    metadata: &WaveletMetadata,
+    start: usize,
+    end: usize,
+) -> Result<Vec<u64>, LavaError> {
+    let (offsets, level_offsets, posting_list_offsets, cumulative_counts, _, _, _, sampled) =
+        metadata;
+    match sampled {
+        Some(sampled) => {
+            let mut tree =
+                CachedWaveletTree::new(reader.clone(), offsets.clone(), level_offsets.clone());
+            sampled_uids(reader, sampled, &mut tree, cumulative_counts, start, end).await
+        }
+        None => read_rows(reader, posting_list_offsets, start, end).await,
+    }
+}
+
 async fn search_substring_wavelet_one_file(
     file_id: u64,
     mut reader: AsyncReader,
@@ -680,16 +870,8 @@ async fn search_substring_wavelet_one_file(
 ) -> Result<Vec<(u64, u64)>, LavaError> {
     println!("{:?}", queries);
 
-    let metadata_start = reader.read_usize_from_end(1).await?[0];
-
-    let metadata: (Vec<usize>, Vec<usize>, Vec<usize>, Vec<usize>, usize) = read_and_decompress(
-        &mut reader,
-        metadata_start as u64,
-        file_size as u64 - metadata_start - 8,
-    )
-    .await
-    .unwrap();
-    let (offsets, level_offsets, posting_list_offsets, cumulative_counts, n) = metadata;
+    let metadata = read_wavelet_metadata(&mut reader, file_size).await?;
+    let (offsets, level_offsets, _, cumulative_counts, n, _, _, _) = &metadata;
 
     // let mut query_set = JoinSet::new();
 
@@ -700,10 +882,11 @@ async fn search_substring_wavelet_one_file(
         let (start, end) = search_wavelet_tree_from_reader(
             &mut reader,
             &query,
-            n,
-            &offsets,
-            &level_offsets,
-            &cumulative_counts,
+            *n,
+            offsets,
+            level_offsets,
+            cumulative_counts,
+            true,
         )
         .await?;
 
@@ -713,60 +896,8 @@ async fn search_substring_wavelet_one_file(
             continue;
         }
 
-        let start_offset = posting_list_offsets[start / FM_CHUNK_TOKS];
-        let end_offset = posting_list_offsets[end / FM_CHUNK_TOKS + 1];
-        let total_chunks = end / FM_CHUNK_TOKS - start / FM_CHUNK_TOKS + 1;
-
-        let plist_chunks = reader
-            .read_range(start_offset as u64, end_offset as u64)
-            .await
-            .unwrap();
-
-        let mut chunk_set = JoinSet::new();
-
-        for i in 0..total_chunks {
-            let this_start = posting_list_offsets[start / FM_CHUNK_TOKS + i];
-            let this_end = posting_list_offsets[start / FM_CHUNK_TOKS + i + 1];
-            let this_chunk = plist_chunks
-                [(this_start - start_offset) as usize..(this_end - start_offset) as usize]
-                .to_vec();
-
-            chunk_set.spawn(async move {
-                let mut decompressor = Decoder::new(&this_chunk[..]).unwrap();
-                let mut serialized_plist_chunk: Vec<u8> = Vec::with_capacity(this_chunk.len());
-                decompressor
-                    .read_to_end(&mut serialized_plist_chunk)
-                    .unwrap();
-                let plist_chunk: Vec<u64> = bincode::deserialize(&serialized_plist_chunk).unwrap();
-
-                let chunk_res: Vec<(u64, u64)> = if i == 0 {
-                    if total_chunks == 1 {
-                        plist_chunk[start % FM_CHUNK_TOKS..end % FM_CHUNK_TOKS]
-                            .iter()
-                            .map(|&uid| (file_id, uid))
-                            .collect()
-                    } else {
-                        plist_chunk[start % FM_CHUNK_TOKS..]
-                            .iter()
-                            .map(|&uid| (file_id, uid))
-                            .collect()
-                    }
-                } else if i == total_chunks - 1 {
-                    plist_chunk[..end % FM_CHUNK_TOKS]
-                        .iter()
-                        .map(|&uid| (file_id, uid))
-                        .collect()
-                } else {
-                    plist_chunk.iter().map(|&uid| (file_id, uid)).collect()
-                };
-
-                chunk_res
-            });
-        }
-
-        while let Some(chunk_res) = chunk_set.join_next().await {
-            res.extend(chunk_res.unwrap());
-        }
+        let uids = wavelet_uids(&mut reader, &metadata, start, end).await?;
+        res.extend(uids.into_iter().map(|uid| (file_id, uid)));
     }
 
     // let mut res = Vec::new();
@@ -777,6 +908,45 @@ async fn search_substring_wavelet_one_file(
     Ok(res)
 }
 
+/// The number of symbols, fm chunk directory, posting list offsets, cumulative counts and number of
+/// documents.
+pub(super) async fn read_fm_metadata(
+    reader: &mut AsyncReader,
+    file_size: usize,
+) -> Result<(u64, FMDirectory, Vec<u64>, Vec<u64>, u64), LavaError> {
+    let results = read_fm_footer(reader).await?;
+    let fm_chunk_offsets_offset = results[0];
+    let posting_list_offsets_offset = results[1];
+    let total_counts_offset = results[2];
+    let n = results[3];
+    let num_documents = results[4];
+    let fm_directory_index_offset = results[5];
+
+    let fm_chunk_offsets: Vec<u64> = reader
+        .read_range_and_decompress(fm_chunk_offsets_offset, posting_list_offsets_offset)
+        .await?;
+    let posting_list_offsets: Vec<u64> = reader
+        .read_range_and_decompress(posting_list_offsets_offset, total_counts_offset)
+        .await?;
+    let cumulative_counts: Vec<u64> = reader
+        .read_range_and_decompress(total_counts_offset, fm_directory_index_offset)
+        .await?;
+    let fm_directory_index: FMDirectoryIndex = read_and_decompress(
+        reader,
+        fm_directory_index_offset,
+        file_size as u64 - 8 * FM_FOOTER_WORDS - fm_directory_index_offset,
+    )
+    .await?;
+    let directory = FMDirectory::new(fm_chunk_offsets, &cumulative_counts, fm_directory_index)?;
+    Ok((
+        n,
+        directory,
+        posting_list_offsets,
+        cumulative_counts,
+        num_documents,
+    ))
+}
+
 async fn search_substring_one_file<T>(
     file_id: u64,
     mut reader: AsyncReader,
@@ -799,26 +969,13 @@ where
 {
     println!("{:?}", queries);
 
-    let results = reader.read_usize_from_end(4).await?;
-    let fm_chunk_offsets_offset = results[0];
-    let posting_list_offsets_offset = results[1];
-    let total_counts_offset = results[2];
-    let n = results[3];
-
-    let fm_chunk_offsets: Vec<u64> = reader
-        .read_range_and_decompress(fm_chunk_offsets_offset, posting_list_offsets_offset)
-        .await?;
-    let posting_list_offsets: Vec<u64> = reader
-        .read_range_and_decompress(posting_list_offsets_offset, total_counts_offset)
-        .await?;
-    let cumulative_counts: Vec<u64> = reader
-        .read_range_and_decompress(total_counts_offset, (file_size - 32) as u64)
-        .await?;
+    let (n, directory, posting_list_offsets, cumulative_counts, _) =
+        read_fm_metadata(&mut reader, file_size).await?;
 
     let mut query_set = JoinSet::new();
 
     for query in queries {
-        let fm_chunk_offsets = fm_chunk_offsets.clone();
+        let directory = directory.clone();
         let cumulative_counts = cumulative_counts.clone();
         let posting_list_offsets = posting_list_offsets.clone();
         let mut reader = reader.clone();
@@ -827,11 +984,12 @@ where
             process_substring_query::<T>(
                 query,
                 n,
-                &fm_chunk_offsets,
+                &directory,
                 &cumulative_counts,
                 &posting_list_offsets,
                 &mut reader,
                 file_id,
+                false,
             )
             .await
         });
@@ -839,11 +997,319 @@ where
 
     let mut res = Vec::new();
     while let Some(query_res) = query_set.join_next().await {
-        res.extend(query_res.unwrap());
+        res.extend(query_res.unwrap()?);
     }
     Ok(res)
 }
 
+/// Every occurrence of the query in a token file, (file_id, uid) each.
+pub(crate) async fn substring_occurrences_one_file(
+    file_id: u64,
+    mut reader: AsyncReader,
+    file_size: usize,
+    query: Vec<u32>,
+) -> Result<Vec<(u64, u64, Option<u64>)>, LavaError> {
+    let (n, directory, posting_list_offsets, cumulative_counts, _) =
+        read_fm_metadata(&mut reader, file_size).await?;
+    let occurrences = process_substring_query::<u32>(
+        query,
+        n,
+        &directory,
+        &cumulative_counts,
+        &posting_list_offsets,
+        &mut reader,
+        file_id,
+        true,
+    )
+    .await?;
+    Ok(occurrences
+        .into_iter()
+        .map(|(file_id, uid)| (file_id, uid, None))
+        .collect())
+}
+
+/// Every occurrence of the query in a char file, (file_id, uid, character offset) each. The
+/// offsets are only read with `positions`, which needs a positional file.
+async fn wavelet_occurrences_one_file(
+    file_id: u64,
+    mut reader: AsyncReader,
+    file_size: usize,
+    query: Vec<u8>,
+    positions: bool,
+) -> Result<Vec<(u64, u64, Option<u64>)>, LavaError> {
+    let metadata = read_wavelet_metadata(&mut reader, file_size).await?;
+    let (offsets, level_offsets, _, cumulative_counts, n, position_offsets, _, _) = &metadata;
+    if positions && position_offsets.is_empty() {
+        return Err(LavaError::Unsupported(
+            "the index has no positions, build it with positional".to_string(),
+        ));
+    }
+
+    let (start, end) = search_wavelet_tree_from_reader(
+        &mut reader,
+        &query,
+        *n,
+        offsets,
+        level_offsets,
+        cumulative_counts,
+        false,
+    )
+    .await?;
+    if start >= end {
+        return Ok(vec![]);
+    }
+
+    let uids = wavelet_uids(&mut reader, &metadata, start, end).await?;
+    if !positions {
+        return Ok(uids.into_iter().map(|uid| (file_id, uid, None)).collect());
+    }
+    let offsets = read_rows(&mut reader, position_offsets, start, end).await?;
+    Ok(uids
+        .into_iter()
+        .zip(offsets)
+        .map(|(uid, offset)| (file_id, uid, Some(offset)))
+        .collect())
+}
+
+/// The number of occurrences of the query in a token file, from the FM index alone, and the
+/// number of documents of the file.
+pub(crate) async fn count_substring_one_file(
+    mut reader: AsyncReader,
+    file_size: usize,
+    query: Vec<u32>,
+) -> Result<(u64, u64), LavaError> {
+    let (n, directory, _, cumulative_counts, num_documents) =
+        read_fm_metadata(&mut reader, file_size).await?;
+    let count = match fm_range(query, n, &directory, &cumulative_counts, &mut reader, true).await? {
+        Some((start, end)) => (end - start) as u64,
+        None => 0,
+    };
+    Ok((count, num_documents))
+}
+
+/// The number of occurrences of the query in a char file, from the wavelet tree alone, and the
+/// number of documents of the file.
+async fn count_wavelet_one_file(
+    mut reader: AsyncReader,
+    file_size: usize,
+    query: Vec<u8>,
+) -> Result<(u64, u64), LavaError> {
+    let (offsets, level_offsets, _, cumulative_counts, n, _, num_documents, _) =
+        read_wavelet_metadata(&mut reader, file_size).await?;
+    let (start, end) = search_wavelet_tree_from_reader(
+        &mut reader,
+        &query,
+        n,
+        &offsets,
+        &level_offsets,
+        &cumulative_counts,
+        false,
+    )
+    .await?;
+    let count = if start < end { (end - start) as u64 } else { 0 };
+    Ok((count, num_documents as u64))
+}
+
+/// The expected number of distinct documents hit by `occurrences` matches that fall uniformly at
+/// random into `documents` documents. Matches cluster in practice, so this is an upper estimate.
+pub(crate) fn estimate_documents(occurrences: u64, documents: u64) -> u64 {
+    if documents == 0 {
+        return 0;
+    }
+    let n = documents as f64;
+    let expected = n * (1.0 - (1.0 - 1.0 / n).powf(occurrences as f64));
+    (expected.round() as u64).min(occurrences)
+}
+
+/// The total number of occurrences and, with `distinct`, the estimated number of documents that
+/// contain them, summed over the files. No posting lists are read.
+pub(crate) fn sum_counts(counts: Vec<(u64, u64)>, distinct: bool) -> (u64, Option<u64>) {
+    let total = counts.iter().map(|(count, _)| count).sum();
+    let documents = distinct.then(|| {
+        counts
+            .iter()
+            .map(|(count, documents)| estimate_documents(*count, *documents))
+            .sum()
+    });
+    (total, documents)
+}
+
+/// Occurrence count of the query in char files, see `sum_counts`. Indices built with a char skip
+/// factor are rejected.
+pub async fn _count_lava_substring_char(
+    files: Vec<String>,
+    query: String,
+    reader_type: ReaderType,
+    distinct: bool,
+) -> Result<(u64, Option<u64>), LavaError> {
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let normalization = read_normalization_async(&mut readers, false).await?;
+    normalization.require_every_char("counts")?;
+    let query: Vec<u8> = normalization.encode_chars(&query);
+    if query.is_empty() {
+        return Err(LavaError::Parse(
+            "the query is empty after normalization".to_string(),
+        ));
+    }
+
+    let mut join_set = JoinSet::new();
+    for (reader, file_size) in readers.into_iter().zip(file_sizes) {
+        join_set.spawn(count_wavelet_one_file(reader, file_size, query.clone()));
+    }
+
+    let mut counts: Vec<(u64, u64)> = vec![];
+    while let Some(res) = join_set.join_next().await {
+        counts.push(res.unwrap()?);
+    }
+    Ok(sum_counts(counts, distinct))
+}
+
+/// Groups occurrences by document into (file_id, uid, count, sorted offsets), most occurrences
+/// first, and keeps the top k.
+pub(crate) fn count_occurrences(
+    occurrences: Vec<(u64, u64, Option<u64>)>,
+    k: usize,
+) -> Vec<(u64, u64, u64, Vec<u64>)> {
+    let mut documents: BTreeMap<(u64, u64), (u64, Vec<u64>)> = BTreeMap::new();
+    for (file_id, uid, offset) in occurrences {
+        let document = documents.entry((file_id, uid)).or_insert((0, vec![]));
+        document.0 += 1;
+        document.1.extend(offset);
+    }
+    let mut counts: Vec<(u64, u64, u64, Vec<u64>)> = documents
+        .into_iter()
+        .map(|((file_id, uid), (count, mut offsets))| {
+            offsets.sort();
+            (file_id, uid, count, offsets)
+        })
+        .collect();
+    // stable, ties stay in (file_id, uid) order
+    counts.sort_by(|a, b| b.2.cmp(&a.2));
+    counts.truncate(k);
+    counts
+}
+
+/// Occurrence counts, and with `positions` the character offsets of the matches, per document
+/// of char files. The whole query is searched, so counts are exact. Indices built with a char
+/// skip factor are rejected.
+pub async fn _search_lava_substring_char_occurrences(
+    files: Vec<String>,
+    query: String,
+    k: usize,
+    reader_type: ReaderType,
+    positions: bool,
+) -> Result<Vec<(u64, u64, u64, Vec<u64>)>, LavaError> {
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let normalization = read_normalization_async(&mut readers, false).await?;
+    normalization.require_every_char("occurrence counts")?;
+    let query: Vec<u8> = normalization.encode_chars(&query);
+    if query.is_empty() {
+        return Err(LavaError::Parse(
+            "the query is empty after normalization".to_string(),
+        ));
+    }
+
+    let mut join_set = JoinSet::new();
+    for (file_id, (reader, file_size)) in readers.into_iter().zip(file_sizes).enumerate() {
+        join_set.spawn(wavelet_occurrences_one_file(
+            file_id as u64,
+            reader,
+            file_size,
+            query.clone(),
+            positions,
+        ));
+    }
+
+    let mut occurrences: Vec<(u64, u64, Option<u64>)> = vec![];
+    while let Some(res) = join_set.join_next().await {
+        occurrences.extend(res.unwrap()?);
+    }
+    Ok(count_occurrences(occurrences, k))
+}
+
+/// The uids of a char file that contain every literal, (file_id, uid) each.
+async fn regex_candidates_one_file(
+    file_id: u64,
+    mut reader: AsyncReader,
+    file_size: usize,
+    literals: Vec<Vec<u8>>,
+) -> Result<Vec<(u64, u64)>, LavaError> {
+    let metadata = read_wavelet_metadata(&mut reader, file_size).await?;
+    let (offsets, level_offsets, _, cumulative_counts, n, _, _, _) = &metadata;
+
+    let mut candidates: Option<BTreeSet<u64>> = None;
+    for literal in literals {
+        let (start, end) = search_wavelet_tree_from_reader(
+            &mut reader,
+            &literal,
+            *n,
+            offsets,
+            level_offsets,
+            cumulative_counts,
+            false,
+        )
+        .await?;
+        let uids: BTreeSet<u64> = if start < end {
+            wavelet_uids(&mut reader, &metadata, start, end)
+                .await?
+                .into_iter()
+                .collect()
+        } else {
+            BTreeSet::new()
+        };
+        let this_candidates = match candidates {
+            Some(candidates) => candidates.intersection(&uids).cloned().collect(),
+            None => uids,
+        };
+        if this_candidates.is_empty() {
+            return Ok(vec![]);
+        }
+        candidates = Some(this_candidates);
+    }
+
+    Ok(candidates
+        .unwrap_or_default()
+        .into_iter()
+        .map(|uid| (file_id, uid))
+        .collect())
+}
+
+/// The documents of char files that may match the regex: those containing every literal the
+/// regex requires, see `required_literals`. These are candidates, the matches are confirmed with
+/// the full regex on the text. Indices built with a char skip factor are rejected.
+pub async fn _search_lava_substring_regex(
+    files: Vec<String>,
+    pattern: String,
+    reader_type: ReaderType,
+) -> Result<Vec<(u64, u64)>, LavaError> {
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let normalization = read_normalization_async(&mut readers, false).await?;
+    normalization.require_every_char("regex searches")?;
+    let literals = required_literals(&pattern, &normalization)?;
+    if literals.is_empty() {
+        return Err(LavaError::Unsupported(
+            "the regex requires no literal, it can't be searched with the index".to_string(),
+        ));
+    }
+
+    let mut join_set = JoinSet::new();
+    for (file_id, (reader, file_size)) in readers.into_iter().zip(file_sizes).enumerate() {
+        join_set.spawn(regex_candidates_one_file(
+            file_id as u64,
+            reader,
+            file_size,
+            literals.clone(),
+        ));
+    }
+
+    let mut candidates: Vec<(u64, u64)> = vec![];
+    while let Some(res) = join_set.join_next().await {
+        candidates.extend(res.unwrap()?);
+    }
+    candidates.sort();
+    Ok(candidates)
+}
+
 pub async fn _search_lava_substring_char(
     files: Vec<String>,
     query: String,
@@ -853,13 +1319,14 @@ pub async fn _search_lava_substring_char(
     sample_factor: Option<usize>,
     wavelet_tree: bool,
 ) -> Result<Vec<(u64, u64)>, LavaError> {
-    let lower: String = query.chars().flat_map(|c| c.to_lowercase()).collect();
-    let result: Vec<u8> = lower
-        .chars()
-        .filter(|id| !SKIP.chars().contains(id))
-        .map(|c| c as u8)
-        .collect();
+    let (file_sizes, mut readers) = get_index_file_sizes_and_readers(&files, reader_type).await?;
+    let normalization = read_normalization_async(&mut readers, false).await?;
+    let result: Vec<u8> = normalization.encode_chars(&query);
 
+    // an index of every n-th character is searched with every n-th character of the query
+    let sample_factor = sample_factor.or(
+        (normalization.char_skip_factor > 1).then_some(normalization.char_skip_factor as usize),
+    );
     let mut query: Vec<Vec<u8>> = if let Some(sample_factor) = sample_factor {
         (0..sample_factor)
             .map(|offset| {
@@ -895,7 +1362,6 @@ pub async fn _search_lava_substring_char(
 
     // println!("query {:?}", query);
 
-    let (file_sizes, readers) = get_file_sizes_and_readers(&files, reader_type).await?;
     search_generic_async(
         file_sizes,
         readers,
@@ -908,3 +1374,294 @@ pub async fn _search_lava_substring_char(
     )
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_estimate_documents() {
+        assert_eq!(estimate_documents(0, 100), 0);
+        assert_eq!(estimate_documents(1, 100), 1);
+        assert_eq!(estimate_documents(100, 100), 63);
+        assert_eq!(estimate_documents(5, 0), 0);
+        assert_eq!(
+            sum_counts(vec![(3, 1), (1, 10)], true),
+            (4, Some(2))
+        );
+    }
+
+    #[test]
+    fn test_count_occurrences() {
+        let occurrences = vec![
+            (0, 7, Some(12)),
+            (1, 3, Some(0)),
+            (0, 7, Some(2)),
+            (0, 5, Some(4)),
+        ];
+        assert_eq!(
+            count_occurrences(occurrences, 2),
+            vec![(0, 7, 2, vec![2, 12]), (0, 5, 1, vec![4])]
+        );
+    }
+
+    #[test]
+    fn test_fm_footer_magic() {
+        let dir = std::env::temp_dir();
+        let current = dir.join(format!("rottnest_fm_footer_{}.lava", std::process::id()));
+        let old = dir.join(format!("rottnest_fm_footer_old_{}.lava", std::process::id()));
+        let mut file = File::create(&current).unwrap();
+        write_fm_footer(&mut file, [1, 2, 3, 4, 5, 6]).unwrap();
+        // a footer of the previous layout, without the magic word
+        let mut file = File::create(&old).unwrap();
+        for word in 0..FM_FOOTER_WORDS {
+            file.write_all(&word.to_le_bytes()).unwrap();
+        }
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let files = [current, old].map(|file| file.to_str().unwrap().to_string());
+            let (_, mut readers) =
+                get_index_file_sizes_and_readers(&files, Default::default()).await.unwrap();
+            assert_eq!(
+                read_fm_footer(&mut readers[0]).await.unwrap()[..6],
+                [1, 2, 3, 4, 5, 6]
+            );
+            assert!(read_fm_footer(&mut readers[1]).await.is_err());
+            for file in files {
+                std::fs::remove_file(file).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_wavelet_metadata_version() {
+        let dir = std::env::temp_dir();
+        let current =
+            dir.join(format!("rottnest_wavelet_meta_{}.lava", std::process::id()));
+        let old = dir.join(format!("rottnest_wavelet_meta_old_{}.lava", std::process::id()));
+        let mut metadata: WaveletMetadata = Default::default();
+        metadata.3 = vec![0, 3];
+        metadata.4 = 3;
+        let mut file = File::create(&current).unwrap();
+        write_wavelet_metadata(&mut file, &metadata).unwrap();
+        // the previous layout ends with where the metadata starts
+        let mut file = File::create(&old).unwrap();
+        let compressed = encode_all(&bincode::serialize(&metadata).unwrap()[..], 0).unwrap();
+        file.write_all(&compressed).unwrap();
+        file.write_all(&0u64.to_le_bytes()).unwrap();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let files = [current, old].map(|file| file.to_str().unwrap().to_string());
+            let (file_sizes, mut readers) =
+                get_index_file_sizes_and_readers(&files, Default::default())
+                    .await
+                    .unwrap();
+            let read = read_wavelet_metadata(&mut readers[0], file_sizes[0])
+                .await
+                .unwrap();
+            assert_eq!((read.3, read.4), (vec![0, 3], 3));
+            assert!(matches!(
+                read_wavelet_metadata(&mut readers[1], file_sizes[1]).await,
+                Err(LavaError::Parse(_))
+            ));
+            for file in files {
+                std::fs::remove_file(file).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_token_matches_report_their_text() {
+        let file = std::env::temp_dir()
+            .join(format!("rottnest_token_uids_{}.lava", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        build_lava_substring(
+            file.clone(),
+            LargeStringArray::from(vec![
+                "alpha beta zeta eta",
+                "gamma delta gamma theta iota",
+                "kappa lambda epsilon",
+            ])
+            .into_data(),
+            UInt64Array::from(vec![10, 20, 30]).into_data(),
+            Some("builtin:whitespace".to_string()),
+            None,
+            None,
+        )
+        .unwrap();
+
+        // a match belongs to the text of its first token, also at the start of a text, the
+        // first text of the file and the last one
+        let search = |query: &str| {
+            crate::lava::search::search_lava_substring_counts(
+                vec![file.clone()],
+                query.to_string(),
+                10,
+                Default::default(),
+            )
+            .unwrap()
+        };
+        assert_eq!(search("alpha"), vec![(0, 10, 1)]);
+        assert_eq!(search("gamma"), vec![(0, 20, 2)]);
+        assert_eq!(search("gamma delta"), vec![(0, 20, 1)]);
+        assert_eq!(search("epsilon"), vec![(0, 30, 1)]);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_token_fm_index() {
+        // several fm chunks of tokens, the last word is unique so every match is in the bwt
+        let words = [
+            "red", "green", "blue", "cyan", "magenta", "yellow", "black", "white",
+        ];
+        let mut rng = StdRng::seed_from_u64(48);
+        let mut texts: Vec<Vec<&str>> = (0..1500)
+            .map(|_| {
+                (0..rng.gen_range(10..20))
+                    .map(|_| words[rng.gen_range(0..8)])
+                    .collect()
+            })
+            .collect();
+        texts.last_mut().unwrap().push("end");
+        let stream: Vec<(&str, u64)> = texts
+            .iter()
+            .enumerate()
+            .flat_map(|(uid, text)| text.iter().map(move |word| (*word, uid as u64)))
+            .collect();
+        assert!(stream.len() > 4 * FM_CHECKPOINT_TOKS);
+
+        let file = std::env::temp_dir()
+            .join(format!("rottnest_token_fm_{}.lava", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let joined: Vec<String> = texts.iter().map(|text| text.join(" ")).collect();
+        build_lava_substring(
+            file.clone(),
+            LargeStringArray::from(joined.iter().map(|t| t.as_str()).collect::<Vec<&str>>())
+                .into_data(),
+            UInt64Array::from((0..texts.len() as u64).collect::<Vec<u64>>()).into_data(),
+            Some("builtin:whitespace".to_string()),
+            None,
+            None,
+        )
+        .unwrap();
+
+        for query in [
+            "red",
+            "blue cyan",
+            "black white red",
+            "yellow magenta cyan blue",
+        ] {
+            let pattern: Vec<&str> = query.split(' ').collect();
+            let mut naive: BTreeMap<u64, u64> = BTreeMap::new();
+            for start in 0..=stream.len() - pattern.len() {
+                if (0..pattern.len()).all(|i| stream[start + i].0 == pattern[i]) {
+                    *naive.entry(stream[start].1).or_insert(0) += 1;
+                }
+            }
+            let (count, _) = crate::lava::search::count_lava_substring(
+                vec![file.clone()],
+                query.to_string(),
+                Default::default(),
+                None,
+            )
+            .unwrap();
+            assert_eq!(count, naive.values().sum::<u64>(), "{}", query);
+            let counts: BTreeMap<u64, u64> = crate::lava::search::search_lava_substring_counts(
+                vec![file.clone()],
+                query.to_string(),
+                texts.len(),
+                Default::default(),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|(_, uid, count)| (uid, count))
+            .collect();
+            assert_eq!(counts, naive, "{}", query);
+        }
+
+        // one substituted token, the bwt is of the rotations so a match may wrap around
+        for query in ["red green blue", "white black black yellow"] {
+            let pattern: Vec<&str> = query.split(' ').collect();
+            let mut naive: BTreeMap<u64, u64> = BTreeMap::new();
+            for start in 0..stream.len() {
+                let distance = (0..pattern.len())
+                    .filter(|i| stream[(start + i) % stream.len()].0 != pattern[*i])
+                    .count() as u64;
+                if distance <= 1 {
+                    let best = naive.entry(stream[start].1).or_insert(distance);
+                    *best = (*best).min(distance);
+                }
+            }
+            let matches: BTreeMap<u64, u64> = crate::lava::search::search_lava_substring_fuzzy(
+                vec![file.clone()],
+                query.to_string(),
+                1,
+                texts.len(),
+                Default::default(),
+                Some(false),
+                None,
+            )
+            .unwrap()
+            .into_iter()
+            .map(|(_, uid, distance)| (uid, distance))
+            .collect();
+            assert_eq!(matches, naive, "{}", query);
+        }
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_char_skip_factor_is_stored() {
+        let file = std::env::temp_dir()
+            .join(format!("rottnest_char_skip_{}.lava", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        build_lava_substring_char(
+            file.clone(),
+            LargeStringArray::from(vec!["hello world", "another text"]).into_data(),
+            UInt64Array::from(vec![0, 1]).into_data(),
+            Some(2),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let files = vec![file.clone()];
+            let (_, mut readers) =
+                get_index_file_sizes_and_readers(&files, Default::default()).await.unwrap();
+            let normalization = read_normalization_async(&mut readers, false).await.unwrap();
+            assert_eq!(normalization.char_skip_factor, 2);
+
+            let query = "world".to_string();
+            let occurrences = _search_lava_substring_char_occurrences(
+                files.clone(),
+                query.clone(),
+                10,
+                Default::default(),
+                false,
+            )
+            .await;
+            assert!(matches!(occurrences, Err(LavaError::Unsupported(_))));
+            let counts =
+                _count_lava_substring_char(files.clone(), query.clone(), Default::default(), true)
+                    .await;
+            assert!(matches!(counts, Err(LavaError::Unsupported(_))));
+            let candidates =
+                _search_lava_substring_regex(files, "wor.d".to_string(), Default::default()).await;
+            assert!(matches!(candidates, Err(LavaError::Unsupported(_))));
+        });
+        std::fs::remove_file(file).unwrap();
+    }
+}
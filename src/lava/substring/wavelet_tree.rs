@@ -6,6 +6,8 @@ use crate::{
     lava::error::LavaError,
 };
 use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use rand::distributions::Alphanumeric;
 use rand::{Rng, SeedableRng};
 use std::fs::File;
@@ -15,12 +17,102 @@ use zstd::stream::{decode_all, encode_all};
 const ALPHABET: usize = 256;
 const LOG_ALPHABET: usize = 8;
 const CHUNK_BITS: usize = 32768;
+// a cache line of words
+const BLOCK_BITS: usize = 512;
+
+/*
+The bitvectors of the wavelet tree are packed into u64 words. On disk every node is cut into chunks
+of CHUNK_BITS, each with the number of ones before it in the node and before each of its blocks of
+BLOCK_BITS, so a rank reads one chunk and counts the ones of at most one block.
+*/
+
+/// A packed bitvector, bit i is bit i % 64 of word i / 64.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Bitvector {
+    words: Vec<u64>,
+    len: usize,
+}
 
-pub(crate) type Bitvector = Vec<bool>;
-pub(crate) type WaveletTree = Vec<Bitvector>;
+impl Bitvector {
+    pub(crate) fn new() -> Self {
+        Bitvector::default()
+    }
+
+    pub(crate) fn push(&mut self, bit: bool) {
+        if self.len % 64 == 0 {
+            self.words.push(0);
+        }
+        if bit {
+            *self.words.last_mut().unwrap() |= 1 << (self.len % 64);
+        }
+        self.len += 1;
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// The number of ones in the first `pos` bits of the words.
+fn count_ones(words: &[u64], pos: usize) -> usize {
+    let mut ones: usize = words[..pos / 64]
+        .iter()
+        .map(|word| word.count_ones() as usize)
+        .sum();
+    if pos % 64 > 0 {
+        ones += (words[pos / 64] & ((1 << (pos % 64)) - 1)).count_ones() as usize;
+    }
+    ones
+}
 
 fn bitvector_rank(bitvector: &Bitvector, bit: bool, pos: usize) -> usize {
-    bitvector.iter().take(pos).filter(|&&b| b == bit).count()
+    let pos = pos.min(bitvector.len);
+    let ones = count_ones(&bitvector.words, pos);
+    if bit {
+        ones
+    } else {
+        pos - ones
+    }
+}
+
+pub(crate) type WaveletTree = Vec<Bitvector>;
+
+/// One chunk of a node with its rank directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RankedChunk {
+    /// The ones in the node before the chunk.
+    ones_before: u64,
+    /// The ones in the chunk before every block, and in the whole chunk.
+    block_ones: Vec<u16>,
+    words: Vec<u64>,
+}
+
+impl RankedChunk {
+    fn new(words: &[u64], ones_before: usize) -> Self {
+        let mut block_ones = vec![0u16];
+        for block in words.chunks(BLOCK_BITS / 64) {
+            let ones: u32 = block.iter().map(|word| word.count_ones()).sum();
+            block_ones.push(block_ones.last().unwrap() + ones as u16);
+        }
+        RankedChunk {
+            ones_before: ones_before as u64,
+            block_ones,
+            words: words.to_vec(),
+        }
+    }
+
+    fn get(&self, pos: usize) -> bool {
+        (self.words[pos / 64] >> (pos % 64)) & 1 == 1
+    }
+
+    /// The ones before `pos` of the chunk, counting the ones before the chunk.
+    fn ones(&self, pos: usize) -> usize {
+        let block = pos / BLOCK_BITS;
+        let block_words = &self.words[block * BLOCK_BITS / 64..];
+        self.ones_before as usize
+            + self.block_ones[block] as usize
+            + count_ones(block_words, pos % BLOCK_BITS)
+    }
 }
 
 fn wavelet_tree_rank(tree: &WaveletTree, c: u8, pos: usize) -> usize {
@@ -37,18 +129,13 @@ fn wavelet_tree_rank(tree: &WaveletTree, c: u8, pos: usize) -> usize {
     curr_pos
 }
 
-fn pack_bits(bits: &[bool]) -> Vec<u8> {
-    bits.chunks(8)
-        .map(|chunk| chunk.iter().enumerate().fold(0, |acc, (i, &bit)| acc | ((bit as u8) << (7 - i))))
-        .collect()
-}
-
 pub(crate) fn write_wavelet_tree_to_disk(
     tree: &WaveletTree,
     file: &mut File,
 ) -> std::io::Result<(Vec<usize>, Vec<usize>)> {
     let mut total_length = 0;
-    let mut offsets = vec![0];
+    // absolute, the tree follows the file header
+    let mut offsets = vec![file.stream_position()? as usize];
     let mut level_offsets = vec![0];
 
     for (i, bitvector) in tree.iter().enumerate() {
@@ -57,21 +144,17 @@ pub(crate) fn write_wavelet_tree_to_disk(
             continue;
         }
 
-        let mut rank_0 = 0;
-        let mut rank_1 = 0;
+        let mut ones = 0;
 
         info!("{}", i);
 
-        for chunk in bitvector.chunks(CHUNK_BITS) {
-            let mut packed_chunks = Vec::new();
-            packed_chunks.extend_from_slice(&(rank_0 as u64).to_le_bytes());
-            packed_chunks.extend_from_slice(&(rank_1 as u64).to_le_bytes());
-            packed_chunks.extend(pack_bits(chunk));
-
-            rank_0 += bitvector_rank(&chunk.to_vec(), false, chunk.len());
-            rank_1 += bitvector_rank(&chunk.to_vec(), true, chunk.len());
+        for words in bitvector.words.chunks(CHUNK_BITS / 64) {
+            let chunk = RankedChunk::new(words, ones);
+            ones += *chunk.block_ones.last().unwrap() as usize;
 
-            let compressed_chunk = encode_all(&packed_chunks[..], 0)?;
+            let serialized_chunk = bincode::serialize(&chunk)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            let compressed_chunk = encode_all(&serialized_chunk[..], 0)?;
             file.write_all(&compressed_chunk)?;
 
             offsets.push(offsets.last().unwrap() + compressed_chunk.len());
@@ -117,20 +200,27 @@ async fn read_chunk_from_reader(
     reader: &mut AsyncReader,
     start_byte: usize,
     end_byte: usize,
-) -> (usize, usize, Bitvector) {
-    let compressed_chunk = reader.read_range(start_byte as u64, end_byte as u64).await.unwrap();
-    let decompressed_chunk = decode_all(&compressed_chunk[..]).unwrap();
-
-    let rank_0 = usize::from_le_bytes(decompressed_chunk[..8].try_into().unwrap());
-    let rank_1 = usize::from_le_bytes(decompressed_chunk[8..16].try_into().unwrap());
+) -> Result<RankedChunk, LavaError> {
+    let compressed_chunk = reader.read_range(start_byte as u64, end_byte as u64).await?;
+    let decompressed_chunk = decode_all(&compressed_chunk[..])?;
+    Ok(bincode::deserialize(&decompressed_chunk)?)
+}
 
-    let chunk: Bitvector = decompressed_chunk[16..]
-        .iter()
-        .flat_map(|&byte| (0..8).map(move |i| (byte >> (7 - i)) & 1 == 1))
-        .take(CHUNK_BITS)
-        .collect();
+/// The chunk of `node` holding bit pos - 1, as pos may be the end of the node, and the position
+/// of pos in it.
+fn chunk_of(level_offsets: &[usize], node: usize, pos: usize) -> (usize, usize) {
+    let chunk = (pos - 1) / CHUNK_BITS;
+    (level_offsets[node] + chunk, pos - chunk * CHUNK_BITS)
+}
 
-    (rank_0, rank_1, chunk)
+/// The rank of `bit` at `pos` of a node from its chunk, `local_pos` is pos in the chunk.
+fn chunk_rank(chunk: &RankedChunk, bit: bool, pos: usize, local_pos: usize) -> usize {
+    let ones = chunk.ones(local_pos);
+    if bit {
+        ones
+    } else {
+        pos - ones
+    }
 }
 
 async fn wavelet_tree_rank_from_reader(
@@ -139,18 +229,21 @@ async fn wavelet_tree_rank_from_reader(
     offsets: &[usize],
     c: u8,
     pos: usize,
-) -> std::io::Result<usize> {
+) -> Result<usize, LavaError> {
     let mut curr_pos = pos;
     let mut counter = 0;
 
     for i in 0..LOG_ALPHABET {
+        if curr_pos == 0 {
+            break;
+        }
         let bit = (c >> (LOG_ALPHABET - 1 - i)) & 1 == 1;
-        let chunk_id = level_offsets[counter] + curr_pos / CHUNK_BITS;
+        let (chunk_id, local_pos) = chunk_of(level_offsets, counter, curr_pos);
         let chunk_start = offsets[chunk_id];
         let chunk_end = offsets[chunk_id + 1];
 
-        let (rank_0, rank_1, chunk) = read_chunk_from_reader(reader, chunk_start, chunk_end).await;
-        curr_pos = bitvector_rank(&chunk, bit, curr_pos % CHUNK_BITS) + if bit { rank_1 } else { rank_0 };
+        let chunk = read_chunk_from_reader(reader, chunk_start, chunk_end).await?;
+        curr_pos = chunk_rank(&chunk, bit, curr_pos, local_pos);
 
         counter = counter * 2 + if bit { 1 } else { 0 } + 1;
     }
@@ -158,6 +251,8 @@ async fn wavelet_tree_rank_from_reader(
     Ok(curr_pos)
 }
 
+/// The suffix array range of `p`. With `early_exit` the search stops once the range stops
+/// shrinking, which can leave suffixes in it that do not match all of `p`.
 pub(crate) async fn search_wavelet_tree_from_reader(
     reader: &mut AsyncReader,
     p: &[u8],
@@ -165,9 +260,10 @@ pub(crate) async fn search_wavelet_tree_from_reader(
     offsets: &[usize],
     level_offsets: &[usize],
     c: &[usize],
-) -> std::io::Result<(usize, usize)> {
+    early_exit: bool,
+) -> Result<(usize, usize), LavaError> {
     let mut start = 0;
-    let mut end = n + 1;
+    let mut end = n;
     let mut previous_range = usize::MAX;
 
     for &ch in p.iter().rev() {
@@ -185,7 +281,7 @@ pub(crate) async fn search_wavelet_tree_from_reader(
             return Ok((usize::MAX, usize::MAX));
         }
 
-        if end - start == previous_range {
+        if early_exit && end - start == previous_range {
             info!("early exit");
             return Ok((start, end));
         }
@@ -196,6 +292,141 @@ pub(crate) async fn search_wavelet_tree_from_reader(
     Ok((start, end))
 }
 
+/// The wavelet tree of a file, read chunk by chunk and keeping every chunk it has read, for
+/// searches that rank the same nodes many times.
+pub(crate) struct CachedWaveletTree {
+    reader: AsyncReader,
+    offsets: Vec<usize>,
+    level_offsets: Vec<usize>,
+    chunks: HashMap<usize, RankedChunk>,
+}
+
+impl CachedWaveletTree {
+    pub(crate) fn new(reader: AsyncReader, offsets: Vec<usize>, level_offsets: Vec<usize>) -> Self {
+        CachedWaveletTree {
+            reader,
+            offsets,
+            level_offsets,
+            chunks: HashMap::new(),
+        }
+    }
+
+    async fn chunk(&mut self, chunk_id: usize) -> Result<&RankedChunk, LavaError> {
+        if !self.chunks.contains_key(&chunk_id) {
+            let chunk = read_chunk_from_reader(
+                &mut self.reader,
+                self.offsets[chunk_id],
+                self.offsets[chunk_id + 1],
+            )
+            .await?;
+            self.chunks.insert(chunk_id, chunk);
+        }
+        Ok(&self.chunks[&chunk_id])
+    }
+
+    async fn rank(&mut self, node: usize, bit: bool, pos: usize) -> Result<usize, LavaError> {
+        if pos == 0 {
+            return Ok(0);
+        }
+        let (chunk_id, local_pos) = chunk_of(&self.level_offsets, node, pos);
+        Ok(chunk_rank(self.chunk(chunk_id).await?, bit, pos, local_pos))
+    }
+
+    /// The bwt symbol at pos and its rank there, what LF mapping needs.
+    pub(crate) async fn symbol_at(&mut self, pos: usize) -> Result<(u8, usize), LavaError> {
+        let mut node = 0;
+        let mut symbol = 0usize;
+        let mut curr_pos = pos;
+        for _ in 0..LOG_ALPHABET {
+            let chunk_id = self.level_offsets[node] + curr_pos / CHUNK_BITS;
+            let bit = self.chunk(chunk_id).await?.get(curr_pos % CHUNK_BITS);
+            curr_pos = self.rank(node, bit, curr_pos).await?;
+            symbol = symbol * 2 + bit as usize;
+            node = node * 2 + bit as usize + 1;
+        }
+        Ok((symbol as u8, curr_pos))
+    }
+
+    /// Every symbol in the bwt range start..end with its rank at start and at end.
+    pub(crate) async fn symbol_ranks(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> Result<Vec<(u8, usize, usize)>, LavaError> {
+        let mut symbols = vec![];
+        // (node, depth, bits of the symbol so far, start, end)
+        let mut stack = vec![(0usize, 0usize, 0usize, start, end)];
+        while let Some((node, depth, prefix, start, end)) = stack.pop() {
+            for bit in [false, true] {
+                let child_start = self.rank(node, bit, start).await?;
+                let child_end = self.rank(node, bit, end).await?;
+                if child_start >= child_end {
+                    continue;
+                }
+                let child_prefix = prefix * 2 + bit as usize;
+                if depth + 1 == LOG_ALPHABET {
+                    symbols.push((child_prefix as u8, child_start, child_end));
+                } else {
+                    let child = node * 2 + bit as usize + 1;
+                    stack.push((child, depth + 1, child_prefix, child_start, child_end));
+                }
+            }
+        }
+        Ok(symbols)
+    }
+}
+
+/// The whole bwt of a file, from every chunk of its wavelet tree. A node holds as many bits as
+/// its parent has zeros or ones, so the bwt is rebuilt by merging the symbols of the children of
+/// every node in the order of its bits. The tree is read in one range and the bwt held in
+/// memory, a byte per symbol.
+pub(crate) async fn read_wavelet_tree_bwt(
+    reader: &mut AsyncReader,
+    offsets: &[usize],
+    level_offsets: &[usize],
+    n: usize,
+) -> Result<Vec<u8>, LavaError> {
+    if n == 0 {
+        return Ok(vec![]);
+    }
+    let tree_start = offsets[0];
+    let bytes = reader
+        .read_range(tree_start as u64, *offsets.last().unwrap() as u64)
+        .await?;
+
+    let mut nodes: Vec<Vec<u64>> = vec![vec![]; ALPHABET];
+    for (node, words) in nodes.iter_mut().enumerate() {
+        for chunk_id in level_offsets[node]..level_offsets[node + 1] {
+            let compressed_chunk =
+                &bytes[offsets[chunk_id] - tree_start..offsets[chunk_id + 1] - tree_start];
+            let chunk: RankedChunk = bincode::deserialize(&decode_all(compressed_chunk)?)?;
+            words.extend(chunk.words);
+        }
+    }
+
+    Ok(node_symbols(&nodes, 0, 0, 0, n))
+}
+
+/// The symbols below `node`, which holds `len` bits.
+fn node_symbols(nodes: &[Vec<u64>], node: usize, depth: usize, prefix: usize, len: usize) -> Vec<u8> {
+    if len == 0 {
+        return vec![];
+    }
+    let words = &nodes[node];
+    let bit = |i: usize| (words[i / 64] >> (i % 64)) & 1 == 1;
+    if depth + 1 == LOG_ALPHABET {
+        return (0..len).map(|i| (prefix * 2 + bit(i) as usize) as u8).collect();
+    }
+
+    let ones = count_ones(words, len);
+    let zeros = node_symbols(nodes, node * 2 + 1, depth + 1, prefix * 2, len - ones);
+    let ones = node_symbols(nodes, node * 2 + 2, depth + 1, prefix * 2 + 1, ones);
+    let (mut zeros, mut ones) = (zeros.into_iter(), ones.into_iter());
+    (0..len)
+        .map(|i| if bit(i) { ones.next() } else { zeros.next() }.unwrap())
+        .collect()
+}
+
 pub(crate) fn construct_wavelet_tree(p: &[u8]) -> WaveletTree {
     let mut tree = vec![Bitvector::new(); ALPHABET];
 
@@ -212,3 +443,54 @@ pub(crate) fn construct_wavelet_tree(p: &[u8]) -> WaveletTree {
 
     tree
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranked_chunk() {
+        let mut bitvector = Bitvector::new();
+        for i in 0..(CHUNK_BITS + 1000) {
+            bitvector.push(i % 3 == 0 || i % 7 == 0);
+        }
+        let words = &bitvector.words;
+        let first = RankedChunk::new(&words[..CHUNK_BITS / 64], 0);
+        let second = RankedChunk::new(&words[CHUNK_BITS / 64..], first.ones(CHUNK_BITS));
+        for pos in [0, 1, 63, 64, 511, 512, 513, 20000, CHUNK_BITS] {
+            assert_eq!(first.ones(pos), bitvector_rank(&bitvector, true, pos));
+        }
+        for pos in [1, 700, 1000] {
+            assert_eq!(
+                second.ones(pos),
+                bitvector_rank(&bitvector, true, CHUNK_BITS + pos)
+            );
+        }
+        assert!(first.get(0) && !first.get(1) && first.get(7));
+    }
+
+    #[test]
+    fn test_corrupt_chunk() {
+        let path = std::env::temp_dir()
+            .join(format!("rottnest_wavelet_chunk_{}.bin", std::process::id()));
+        std::fs::write(&path, b"not a zstd frame").unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut reader = get_reader(path.to_str().unwrap().to_string(), Default::default())
+                .await
+                .unwrap();
+            assert!(read_chunk_from_reader(&mut reader, 0, 16).await.is_err());
+        });
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_node_symbols() {
+        let bwt: Vec<u8> = (0..5000u32).map(|i| (i * i % 251) as u8).collect();
+        let nodes: Vec<Vec<u64>> = construct_wavelet_tree(&bwt)
+            .into_iter()
+            .map(|bitvector| bitvector.words)
+            .collect();
+        assert_eq!(node_symbols(&nodes, 0, 0, 0, bwt.len()), bwt);
+    }
+}
@@ -1,6 +1,6 @@
 use ndarray::{concatenate, stack, Array1, Array2, Axis};
 
-use crate::formats::readers::{get_file_sizes_and_readers, get_reader};
+use crate::formats::readers::{get_index_file_sizes_and_readers, get_reader};
 fn bytes_to_f32_vec(bytes: &[u8]) -> Vec<f32> {
     let mut vec = Vec::with_capacity(bytes.len() / 4);
     let mut i = 0;
@@ -19,7 +19,7 @@ async fn search_lava_vector_async(
 ) -> Result<(Vec<usize>, Vec<Array1<u8>>, Vec<(usize, Array1<u8>)>), LavaError> {
     let start = Instant::now();
 
-    let (_, mut readers) = get_file_sizes_and_readers(&files, reader_type.clone()).await?;
+    let (_, mut readers) = get_index_file_sizes_and_readers(&files, reader_type.clone()).await?;
 
     let mut futures = Vec::new();
 
@@ -121,7 +121,8 @@ async fn search_lava_vector_async(
 
     let start = Instant::now();
 
-    let (_, mut readers) = get_file_sizes_and_readers(&files, reader_type.clone()).await?;
+    let (_, mut readers) = get_index_file_sizes_and_readers(&files, reader_type.clone()).await?;
+    let windows: Vec<Option<(u64, u64)>> = readers.iter().map(|reader| reader.window).collect();
 
     let mut file_ids = vec![];
     let mut futures = Vec::new();
@@ -188,6 +189,7 @@ async fn search_lava_vector_async(
         for (file_id, start, end) in to_read.into_iter() {
             let mut reader_c = reader.clone();
             reader_c.update_filename(files[file_id].clone()).unwrap();
+            reader_c.window = windows[file_id];
 
             futures.push(tokio::spawn(async move {
                 let start_time = Instant::now();
@@ -1,4 +1,6 @@
-use crate::formats::{cache, parquet, MatchResult, ParquetLayout};
+use crate::formats::{
+    arrow_ipc, cache, lines, parquet, ArrowIpcLayout, LinesLayout, MatchResult, ParquetLayout,
+};
 use crate::lava::error::LavaError;
 use arrow::array::ArrayData;
 use arrow::pyarrow::{PyArrowType, ToPyArrow};
@@ -42,6 +44,58 @@ impl ParquetLayoutWrapper {
     }
 }
 
+#[pyclass]
+pub struct ArrowIpcLayoutWrapper {
+    #[pyo3(get, set)]
+    pub num_batches: usize,
+    #[pyo3(get, set)]
+    pub metadata_bytes: PyObject,
+    #[pyo3(get, set)]
+    pub batch_offsets: Vec<usize>,
+    #[pyo3(get, set)]
+    pub batch_sizes: Vec<usize>,
+    #[pyo3(get, set)]
+    pub batch_num_rows: Vec<usize>,
+}
+
+impl ArrowIpcLayoutWrapper {
+    fn from_arrow_ipc_layout(py: Python, arrow_ipc_layout: ArrowIpcLayout) -> Self {
+        ArrowIpcLayoutWrapper {
+            num_batches: arrow_ipc_layout.num_batches,
+            metadata_bytes: PyBytes::new(py, &arrow_ipc_layout.metadata_bytes.slice(..)).into_py(py),
+            batch_offsets: arrow_ipc_layout.batch_offsets,
+            batch_sizes: arrow_ipc_layout.batch_sizes,
+            batch_num_rows: arrow_ipc_layout.batch_num_rows,
+        }
+    }
+}
+
+#[pyclass]
+pub struct LinesLayoutWrapper {
+    #[pyo3(get, set)]
+    pub compressed: bool,
+    #[pyo3(get, set)]
+    pub block_offsets: Vec<usize>,
+    #[pyo3(get, set)]
+    pub block_sizes: Vec<usize>,
+    #[pyo3(get, set)]
+    pub block_first_lines: Vec<usize>,
+    #[pyo3(get, set)]
+    pub block_num_lines: Vec<usize>,
+}
+
+impl From<LinesLayout> for LinesLayoutWrapper {
+    fn from(lines_layout: LinesLayout) -> Self {
+        LinesLayoutWrapper {
+            compressed: lines_layout.compressed,
+            block_offsets: lines_layout.block_offsets,
+            block_sizes: lines_layout.block_sizes,
+            block_first_lines: lines_layout.block_first_lines,
+            block_num_lines: lines_layout.block_num_lines,
+        }
+    }
+}
+
 #[pyclass]
 pub struct MatchResultWrapper {
     #[pyo3(get, set)]
@@ -112,6 +166,17 @@ pub fn populate_cache(
     py.allow_threads(|| cache::populate_cache(range_dict, reader_type.into()))
 }
 
+#[pyfunction]
+pub fn embed_lava_in_parquet(
+    py: Python,
+    lava_file: &PyString,
+    parquet_file: &PyString,
+) -> Result<(), LavaError> {
+    let lava_file = lava_file.to_string();
+    let parquet_file = parquet_file.to_string();
+    py.allow_threads(|| parquet::embed_lava_in_parquet(&lava_file, &parquet_file))
+}
+
 #[pyfunction]
 pub fn get_parquet_layout(
     py: Python,
@@ -178,3 +243,117 @@ pub fn read_indexed_pages(
     })?;
     Ok(match_result.into_iter().map(|x| PyArrowType(x)).collect())
 }
+
+fn extract_file_metadatas(
+    metadata_bytes: Option<&PyDict>,
+) -> Result<Option<HashMap<String, Bytes>>, LavaError> {
+    match metadata_bytes {
+        Some(dict) => {
+            let mut metadata_map: HashMap<String, Bytes> = HashMap::new();
+            for (key, value) in dict.iter() {
+                let key_str = key.extract::<&PyString>()?.to_string();
+                let value_bytes = Bytes::copy_from_slice(value.extract::<&PyBytes>()?.as_bytes());
+                metadata_map.insert(key_str, value_bytes);
+            }
+            Ok(Some(metadata_map))
+        }
+        None => Ok(None),
+    }
+}
+
+#[pyfunction]
+pub fn get_arrow_ipc_layout(
+    py: Python,
+    column_name: &PyString,
+    file: &PyString,
+    reader_type: Option<&PyString>,
+) -> Result<(Vec<PyArrowType<ArrayData>>, ArrowIpcLayoutWrapper), LavaError> {
+    let column_name = column_name.to_string();
+    let file = file.to_string();
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+    let (arrs, arrow_ipc_layout) = py.allow_threads(|| {
+        arrow_ipc::get_arrow_ipc_layout(&column_name, &file, reader_type.into())
+    })?;
+    Ok((
+        arrs.into_iter().map(|x| PyArrowType(x)).collect(),
+        ArrowIpcLayoutWrapper::from_arrow_ipc_layout(py, arrow_ipc_layout),
+    ))
+}
+
+#[pyfunction]
+pub fn read_indexed_batches(
+    py: Python,
+    column_name: &PyString,
+    file_paths: Vec<&PyString>,
+    batch_offsets: Vec<usize>,
+    batch_sizes: Vec<usize>,
+    reader_type: Option<&PyString>,
+    metadata_bytes: Option<&PyDict>,
+    in_order: Option<bool>,
+) -> Result<Vec<PyArrowType<ArrayData>>, LavaError> {
+    let column_name = column_name.to_string();
+    let file_metadata = extract_file_metadatas(metadata_bytes)?;
+
+    let file_paths: Vec<String> = file_paths.iter().map(|x| x.to_string()).collect();
+    let batch_offsets: Vec<u64> = batch_offsets.iter().map(|x| *x as u64).collect();
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+    let result = py.allow_threads(|| {
+        arrow_ipc::read_indexed_batches(
+            column_name,
+            file_paths,
+            batch_offsets,
+            batch_sizes,
+            reader_type.into(),
+            file_metadata,
+            in_order,
+        )
+    })?;
+    Ok(result.into_iter().map(|x| PyArrowType(x)).collect())
+}
+
+#[pyfunction]
+pub fn get_lines_layout(
+    py: Python,
+    file: &PyString,
+    json_field: Option<&PyString>,
+    block_size: Option<usize>,
+    reader_type: Option<&PyString>,
+) -> Result<(Vec<PyArrowType<ArrayData>>, LinesLayoutWrapper), LavaError> {
+    let file = file.to_string();
+    let json_field = json_field.map(|x| x.to_string());
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+    let (arrs, lines_layout) = py.allow_threads(|| {
+        lines::get_lines_layout(&file, json_field, block_size, reader_type.into())
+    })?;
+    Ok((
+        arrs.into_iter().map(|x| PyArrowType(x)).collect(),
+        lines_layout.into(),
+    ))
+}
+
+#[pyfunction]
+pub fn read_indexed_lines(
+    py: Python,
+    file_paths: Vec<&PyString>,
+    block_offsets: Vec<usize>,
+    block_sizes: Vec<usize>,
+    json_field: Option<&PyString>,
+    reader_type: Option<&PyString>,
+    in_order: Option<bool>,
+) -> Result<Vec<PyArrowType<ArrayData>>, LavaError> {
+    let file_paths: Vec<String> = file_paths.iter().map(|x| x.to_string()).collect();
+    let block_offsets: Vec<u64> = block_offsets.iter().map(|x| *x as u64).collect();
+    let json_field = json_field.map(|x| x.to_string());
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+    let result = py.allow_threads(|| {
+        lines::read_indexed_lines(
+            file_paths,
+            block_offsets,
+            block_sizes,
+            json_field,
+            reader_type.into(),
+            in_order,
+        )
+    })?;
+    Ok(result.into_iter().map(|x| PyArrowType(x)).collect())
+}
@@ -1,5 +1,5 @@
 use arrow::array::ArrayData;
-use arrow::pyarrow::FromPyArrow;
+use arrow::pyarrow::{FromPyArrow, PyArrowType};
 use pyo3::{pyfunction, types::PyString, PyAny};
 use pyo3::{PyNativeType, Python};
 
@@ -18,10 +18,96 @@ pub fn search_lava_bm25(
     query_weights: Vec<f32>,
     k: usize,
     reader_type: Option<&PyString>,
+    k1: Option<f32>,
+    b: Option<f32>,
 ) -> Result<Vec<(u64, u64)>, LavaError> {
     let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
 
-    py.allow_threads(|| lava::search_lava_bm25(files, query_tokens, query_weights, k, reader_type.into()))
+    py.allow_threads(|| lava::search_lava_bm25(files, query_tokens, query_weights, k, reader_type.into(), k1, b))
+}
+
+#[pyfunction]
+pub fn search_lava_bm25_text(
+    py: Python,
+    files: Vec<String>,
+    query: String,
+    k: usize,
+    reader_type: Option<&PyString>,
+    k1: Option<f32>,
+    b: Option<f32>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+
+    py.allow_threads(|| lava::search_lava_bm25_text(files, query, k, reader_type.into(), k1, b))
+}
+
+#[pyfunction]
+pub fn search_lava_bm25f(
+    py: Python,
+    files: Vec<String>,
+    query: String,
+    k: usize,
+    field_weights: Option<Vec<f32>>,
+    reader_type: Option<&PyString>,
+    k1: Option<f32>,
+    b: Option<f32>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+
+    py.allow_threads(|| {
+        lava::search_lava_bm25f(files, query, field_weights, k, reader_type.into(), k1, b)
+    })
+}
+
+#[pyfunction]
+pub fn search_lava_sparse(
+    py: Python,
+    files: Vec<String>,
+    query_tokens: Vec<u32>,
+    query_weights: Vec<f32>,
+    k: usize,
+    reader_type: Option<&PyString>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+
+    py.allow_threads(|| lava::search_lava_sparse(files, query_tokens, query_weights, k, reader_type.into()))
+}
+
+#[pyfunction]
+pub fn search_lava_bm25_boolean(
+    py: Python,
+    files: Vec<String>,
+    query: String,
+    k: usize,
+    reader_type: Option<&PyString>,
+    k1: Option<f32>,
+    b: Option<f32>,
+    max_expansions: Option<usize>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+
+    py.allow_threads(|| {
+        lava::search_lava_bm25_boolean(files, query, k, reader_type.into(), k1, b, max_expansions)
+    })
+}
+
+#[pyfunction]
+pub fn search_lava_bm25_phrase(
+    py: Python,
+    files: Vec<String>,
+    query: String,
+    k: usize,
+    slop: Option<usize>,
+    proximity_boost: Option<f32>,
+    reader_type: Option<&PyString>,
+    k1: Option<f32>,
+    b: Option<f32>,
+) -> Result<Vec<(u64, u64, f32)>, LavaError> {
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+
+    py.allow_threads(|| {
+        lava::search_lava_bm25_phrase(files, query, slop, proximity_boost, k, reader_type.into(), k1, b)
+    })
 }
 
 #[pyfunction]
@@ -49,6 +135,106 @@ pub fn search_lava_substring(
     }
 }
 
+#[pyfunction]
+pub fn count_lava_substring(
+    py: Python,
+    files: Vec<String>,
+    query: String,
+    reader_type: Option<&PyString>,
+    char_index: Option<bool>,
+    distinct: Option<bool>,
+) -> Result<(u64, Option<u64>), LavaError> {
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+    let char_index = char_index.unwrap_or(false);
+
+    if char_index {
+        py.allow_threads(|| lava::count_lava_substring_char(files, query, reader_type.into(), distinct))
+    } else {
+        py.allow_threads(|| lava::count_lava_substring(files, query, reader_type.into(), distinct))
+    }
+}
+
+#[pyfunction]
+pub fn search_lava_substring_occurrences(
+    py: Python,
+    files: Vec<String>,
+    query: String,
+    k: usize,
+    reader_type: Option<&PyString>,
+    char_index: Option<bool>,
+    positions: Option<bool>,
+) -> Result<Vec<(u64, u64, u64, Vec<u64>)>, LavaError> {
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+    let char_index = char_index.unwrap_or(false);
+
+    if char_index {
+        py.allow_threads(|| {
+            lava::search_lava_substring_char_occurrences(files, query, k, reader_type.into(), positions)
+        })
+    } else if positions.unwrap_or(false) {
+        Err(LavaError::Unsupported(
+            "positions are only stored by char substring indices".to_string(),
+        ))
+    } else {
+        let counts = py.allow_threads(|| {
+            lava::search_lava_substring_counts(files, query, k, reader_type.into())
+        })?;
+        Ok(counts
+            .into_iter()
+            .map(|(file_id, uid, count)| (file_id, uid, count, vec![]))
+            .collect())
+    }
+}
+
+#[pyfunction]
+pub fn search_lava_substring_fuzzy(
+    py: Python,
+    files: Vec<String>,
+    query: String,
+    max_distance: usize,
+    k: usize,
+    reader_type: Option<&PyString>,
+    char_index: Option<bool>,
+    indels: Option<bool>,
+    max_branches: Option<usize>,
+) -> Result<Vec<(u64, u64, u64)>, LavaError> {
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+    let char_index = char_index.unwrap_or(false);
+
+    if char_index {
+        py.allow_threads(|| {
+            lava::search_lava_substring_char_fuzzy(files, query, max_distance, k, reader_type.into(), indels, max_branches)
+        })
+    } else {
+        py.allow_threads(|| {
+            lava::search_lava_substring_fuzzy(files, query, max_distance, k, reader_type.into(), indels, max_branches)
+        })
+    }
+}
+
+#[pyfunction]
+pub fn search_lava_substring_regex(
+    py: Python,
+    files: Vec<String>,
+    pattern: String,
+    reader_type: Option<&PyString>,
+) -> Result<Vec<(u64, u64)>, LavaError> {
+    let reader_type = reader_type.map(|x| x.to_string()).unwrap_or_default();
+
+    py.allow_threads(|| lava::search_lava_substring_regex(files, pattern, reader_type.into()))
+}
+
+#[pyfunction]
+pub fn regex_matches(
+    py: Python,
+    array: &PyAny,
+    pattern: String,
+) -> Result<PyArrowType<ArrayData>, LavaError> {
+    let array = ArrayData::from_pyarrow_bound(&array.as_borrowed())?;
+
+    Ok(PyArrowType(py.allow_threads(|| lava::regex_matches(array, pattern))?))
+}
+
 #[pyfunction]
 pub fn search_lava_uuid(
     py: Python,
@@ -126,13 +312,54 @@ pub fn build_lava_bm25(
     array: &PyAny,
     uid: &PyAny,
     tokenizer_file: Option<&PyString>,
+    positional: Option<bool>,
 ) -> Result<Vec<(usize, usize)>, LavaError> {
     let output_file_name = output_file_name.to_string();
     let array = ArrayData::from_pyarrow_bound(&array.as_borrowed())?;
     let uid = ArrayData::from_pyarrow_bound(&uid.as_borrowed())?;
     let tokenizer_file = tokenizer_file.map(|x| x.to_string());
 
-    py.allow_threads(|| lava::build_lava_bm25(output_file_name, array, uid, tokenizer_file, Some(1.2), Some(0.75)))
+    py.allow_threads(|| lava::build_lava_bm25(output_file_name, array, uid, tokenizer_file, positional))
+}
+
+#[pyfunction]
+pub fn build_lava_bm25f(
+    py: Python,
+    output_file_name: &PyString,
+    arrays: Vec<&PyAny>,
+    field_names: Vec<String>,
+    uid: &PyAny,
+    field_weights: Option<Vec<f32>>,
+    tokenizer_file: Option<&PyString>,
+) -> Result<Vec<(usize, usize)>, LavaError> {
+    let output_file_name = output_file_name.to_string();
+    let arrays = arrays
+        .into_iter()
+        .map(|array| ArrayData::from_pyarrow_bound(&array.as_borrowed()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let uid = ArrayData::from_pyarrow_bound(&uid.as_borrowed())?;
+    let tokenizer_file = tokenizer_file.map(|x| x.to_string());
+
+    py.allow_threads(|| {
+        lava::build_lava_bm25f(output_file_name, arrays, field_names, field_weights, uid, tokenizer_file)
+    })
+}
+
+#[pyfunction]
+pub fn build_lava_sparse(
+    py: Python,
+    output_file_name: &PyString,
+    indices: &PyAny,
+    weights: &PyAny,
+    uid: &PyAny,
+    vocab_size: Option<usize>,
+) -> Result<Vec<(usize, usize)>, LavaError> {
+    let output_file_name = output_file_name.to_string();
+    let indices = ArrayData::from_pyarrow_bound(&indices.as_borrowed())?;
+    let weights = ArrayData::from_pyarrow_bound(&weights.as_borrowed())?;
+    let uid = ArrayData::from_pyarrow_bound(&uid.as_borrowed())?;
+
+    py.allow_threads(|| lava::build_lava_sparse(output_file_name, indices, weights, uid, vocab_size))
 }
 
 #[pyfunction]
@@ -157,17 +384,29 @@ pub fn build_lava_substring(
     tokenizer_file: Option<&PyString>,
     token_skip_factor: Option<u32>,
     char_index: Option<bool>,
+    normalization: Option<&PyString>,
+    positional: Option<bool>,
+    sa_sample_rate: Option<u32>,
 ) -> Result<Vec<(usize, usize)>, LavaError> {
     let output_file_name = output_file_name.to_string();
     let array = ArrayData::from_pyarrow_bound(&array.as_borrowed())?;
     let uid = ArrayData::from_pyarrow_bound(&uid.as_borrowed())?;
     let tokenizer_file = tokenizer_file.map(|x| x.to_string());
+    let normalization = normalization.map(|x| x.to_string());
 
     let char_index = char_index.unwrap_or(false);
 
     if char_index {
-        py.allow_threads(|| lava::build_lava_substring_char(output_file_name, array, uid, token_skip_factor))
+        py.allow_threads(|| lava::build_lava_substring_char(output_file_name, array, uid, token_skip_factor, normalization, positional, sa_sample_rate))
+    } else if positional.unwrap_or(false) {
+        Err(LavaError::Unsupported(
+            "positions are only stored by char substring indices".to_string(),
+        ))
+    } else if sa_sample_rate.is_some() {
+        Err(LavaError::Unsupported(
+            "sampled suffix arrays are only built for char substring indices".to_string(),
+        ))
     } else {
-        py.allow_threads(|| lava::build_lava_substring(output_file_name, array, uid, tokenizer_file, token_skip_factor))
+        py.allow_threads(|| lava::build_lava_substring(output_file_name, array, uid, tokenizer_file, token_skip_factor, normalization))
     }
 }
@@ -9,10 +9,22 @@ fn rottnest(_py: Python, m: &PyModule) -> PyResult<()> {
     pyo3_log::init();
 
     m.add_function(wrap_pyfunction!(lava::build_lava_bm25, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::build_lava_bm25f, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::build_lava_sparse, m)?)?;
     m.add_function(wrap_pyfunction!(lava::build_lava_uuid, m)?)?;
     m.add_function(wrap_pyfunction!(lava::build_lava_substring, m)?)?;
     m.add_function(wrap_pyfunction!(lava::search_lava_bm25, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::search_lava_bm25_text, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::search_lava_bm25_phrase, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::search_lava_bm25_boolean, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::search_lava_bm25f, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::search_lava_sparse, m)?)?;
     m.add_function(wrap_pyfunction!(lava::search_lava_substring, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::search_lava_substring_occurrences, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::count_lava_substring, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::search_lava_substring_regex, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::regex_matches, m)?)?;
+    m.add_function(wrap_pyfunction!(lava::search_lava_substring_fuzzy, m)?)?;
     m.add_function(wrap_pyfunction!(lava::search_lava_vector, m)?)?;
     m.add_function(wrap_pyfunction!(lava::search_lava_uuid, m)?)?;
     m.add_function(wrap_pyfunction!(lava::get_tokenizer_vocab, m)?)?;
@@ -20,6 +32,11 @@ fn rottnest(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(format::get_parquet_layout, m)?)?;
     m.add_function(wrap_pyfunction!(format::read_indexed_pages, m)?)?;
     m.add_function(wrap_pyfunction!(format::populate_cache, m)?)?;
+    m.add_function(wrap_pyfunction!(format::embed_lava_in_parquet, m)?)?;
+    m.add_function(wrap_pyfunction!(format::get_arrow_ipc_layout, m)?)?;
+    m.add_function(wrap_pyfunction!(format::read_indexed_batches, m)?)?;
+    m.add_function(wrap_pyfunction!(format::get_lines_layout, m)?)?;
+    m.add_function(wrap_pyfunction!(format::read_indexed_lines, m)?)?;
 
     m.add_function(wrap_pyfunction!(logcloud::index_logcloud, m)?)?;
     m.add_function(wrap_pyfunction!(logcloud::search_logcloud, m)?)?;